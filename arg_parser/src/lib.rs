@@ -34,10 +34,12 @@ struct ArgData {
     description: Option<String>,
     required: bool,
     has_parameter: bool,
+    // Set to `Some` of the parameter when the option is given
+    is_option: bool,
 }
 
 impl ArgData {
-    fn from_arg_attribs(attrs: ArgDefinition, has_parameter: bool) -> ArgData {
+    fn from_arg_attribs(attrs: ArgDefinition, field: &Type) -> ArgData {
         ArgData {
             short: attrs.short,
             long: match attrs.long.len() {
@@ -49,16 +51,17 @@ impl ArgData {
                 _ => Some(attrs.description),
             },
             required: attrs.required,
-            has_parameter,
+            has_parameter: !is_type(field, "bool"),
+            is_option: is_type(field, "Option"),
         }
     }
 }
 
-fn is_bool(field: &Type) -> bool {
+fn is_type(field: &Type, name: &str) -> bool {
     if let Type::Path(type_path) = field {
         if let Some(path_segment) = type_path.path.segments.first() {
             let field_type = path_segment.ident.to_string();
-            return field_type == "bool";
+            return field_type == name;
         }
         panic!("WTF IS THIS I DUNNO 2");
     }
@@ -72,10 +75,7 @@ fn extract_arg_field_attrs(ast: &mut DeriveInput) -> deluxe::Result<BTreeMap<Str
         for field in s.fields.iter_mut() {
             let field_name = field.ident.as_ref().unwrap().to_string();
             let attrs: ArgDefinition = deluxe::extract_attributes(field)?;
-            field_attrs.insert(
-                field_name,
-                ArgData::from_arg_attribs(attrs, !is_bool(&field.ty)),
-            );
+            field_attrs.insert(field_name, ArgData::from_arg_attribs(attrs, &field.ty));
         }
     } else {
         panic!("Only structs are supported");
@@ -89,9 +89,14 @@ fn build_match_arms(
     value: &str,
     field_name: &String,
     has_parameter: bool,
+    is_option: bool,
 ) -> proc_macro2::TokenStream {
     let value_str = syn::LitStr::new(value, proc_macro2::Span::call_site());
     let field_name = format_ident!("{}", field_name);
+    let parsed_value = match is_option {
+        true => quote! { Some(value) },
+        false => quote! { value },
+    };
     let set_struct_field = match has_parameter {
         true => quote! {
             i += 1;
@@ -100,7 +105,13 @@ fn build_match_arms(
                 error(&format!("'{}' requires an argument to be passed", #value_str));
             }
 
-            return_struct.#field_name = arg.to_owned();
+            return_struct.#field_name = match arg.parse() {
+                Ok(value) => #parsed_value,
+                Err(_) => {
+                    error(&format!("'{}' is not a valid value for '{}'", arg, #value_str));
+                    unreachable!();
+                }
+            };
         },
         false => quote! {
             return_struct.#field_name = true;
@@ -135,6 +146,7 @@ fn parse_cmd_args_derive2(
     let mut long_commands = Vec::new();
     let mut required = Vec::new();
     let mut has_parameter = Vec::new();
+    let mut is_option = Vec::new();
 
     let mut usage_str = String::from("Usage: EXEC_NAME [OPTIONS]");
     let mut options_str = String::from("");
@@ -151,6 +163,7 @@ fn parse_cmd_args_derive2(
 
         required.push(req);
         has_parameter.push(attr.has_parameter);
+        is_option.push(attr.is_option);
 
         if let Some(val) = attr.long {
             long = val.clone();
@@ -169,12 +182,11 @@ fn parse_cmd_args_derive2(
         if req {
             usage_str += &format!(" {} {}", short, field);
         }
-        let parameter;
-        if attr.has_parameter {
-            parameter = format!("<{}>", field.to_uppercase());
+        let parameter = if attr.has_parameter {
+            format!("<{}>", field.to_uppercase())
         } else {
-            parameter = "".to_owned();
-        }
+            "".to_owned()
+        };
         options_str += &format!(
             "   {:03} {:20} {:20} {}",
             short, long, parameter, description
@@ -189,7 +201,7 @@ fn parse_cmd_args_derive2(
         "-h", "--help", "", "Displays help"
     );
 
-    if meta_data.description.len() > 0 {
+    if !meta_data.description.is_empty() {
         usage_str += &format!("\n\n{}", &meta_data.description);
     }
 
@@ -200,10 +212,22 @@ fn parse_cmd_args_derive2(
 
     // Generate
     let match_arms_short_commands = short_commands.iter().enumerate().map(|(index, value)| {
-        build_match_arms(index, value, &field_names[index], has_parameter[index])
+        build_match_arms(
+            index,
+            value,
+            &field_names[index],
+            has_parameter[index],
+            is_option[index],
+        )
     });
     let match_arms_long_commands = long_commands.iter().enumerate().map(|(index, value)| {
-        build_match_arms(index, value, &field_names[index], has_parameter[index])
+        build_match_arms(
+            index,
+            value,
+            &field_names[index],
+            has_parameter[index],
+            is_option[index],
+        )
     });
 
     let code = quote! {
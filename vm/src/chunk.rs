@@ -1,6 +1,8 @@
-use crate::op_code::OpCode;
+use std::collections::BTreeMap;
 
-#[derive(Debug, PartialEq)]
+use crate::{op_code::OpCode, value::ValueArray};
+
+#[derive(Debug, Clone, PartialEq)]
 struct Line {
     line: u32,
     count: u32,
@@ -16,13 +18,20 @@ impl Line {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
     name: String,
     code: Vec<OpCode>,
     pub current_instruction: usize,
     lines: Vec<Line>,
     is_main_chunk: bool,
+    // Source file the chunk was compiled from, shown in diagnostics
+    file_name: String,
+    // First line of each file when the source joins several files
+    source_files: Vec<(u32, String)>,
+    // Locals on the stack of the frame when the statement starting at the
+    // instruction runs, checked by `--verify-stack`
+    statement_depths: BTreeMap<usize, usize>,
 }
 
 impl Chunk {
@@ -33,31 +42,66 @@ impl Chunk {
             current_instruction: 0,
             lines: Vec::new(),
             is_main_chunk,
+            file_name: String::from("<repl>"),
+            source_files: Vec::new(),
+            statement_depths: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_owned();
+    }
+
+    /// Marks the source as several files joined together, the file starts at
+    /// `first_line` of the joined source
+    pub fn add_source_file(&mut self, file_name: &str, first_line: u32) {
+        self.source_files.push((first_line, file_name.to_owned()));
+    }
+
+    /// Source location of a line in the form `file:line`
+    pub fn location(&self, line: u32) -> String {
+        match self
+            .source_files
+            .iter()
+            .rev()
+            .find(|(first_line, _)| *first_line <= line)
+        {
+            Some((first_line, file_name)) => format!("{}:{}", file_name, line - first_line + 1),
+            None => format!("{}:{}", self.file_name, line),
         }
     }
 
-    pub fn disassemble(&self) {
+    pub fn disassemble(&self, constants: &ValueArray) {
         println!("==== Begin: {} ====", self.name);
 
         let mut op_index: usize = 0;
         while op_index < self.code.len() {
-            let op_code = &self.code[op_index];
-            op_index = self.disassemble_instruction(op_code, op_index);
+            op_index = self.disassemble_instruction(op_index, constants);
         }
         println!("==== End:   {} ====", self.name);
     }
 
-    pub fn disassemble_current_instruction(&self) {
-        let op_code = &self.code[self.current_instruction];
-        self.disassemble_instruction(op_code, self.current_instruction);
+    pub fn disassemble_current_instruction(&self, constants: &ValueArray) {
+        self.disassemble_instruction(self.current_instruction, constants);
     }
 
     pub fn get_instruction_line(&self, instruction: usize) -> u32 {
         self.get_line(instruction).unwrap_or(0)
     }
 
-    pub fn get_current_instruction_line(&self) -> u32 {
-        self.get_line(self.current_instruction).unwrap_or(0)
+    /// Moves back to the first instruction so the chunk runs from the start
+    pub fn reset(&mut self) {
+        self.current_instruction = 0;
+    }
+
+    /// Line of the instruction that ran last, `next` has already moved
+    /// `current_instruction` past it
+    pub fn get_last_instruction_line(&self) -> u32 {
+        self.get_instruction_line(self.current_instruction.saturating_sub(1))
     }
 
     pub fn get_main_start(&self) -> usize {
@@ -70,15 +114,30 @@ impl Chunk {
         unreachable!()
     }
 
-    fn disassemble_instruction(&self, op_code: &OpCode, op_index: usize) -> usize {
+    fn disassemble_instruction(&self, op_index: usize, constants: &ValueArray) -> usize {
+        println!("{}", self.instruction_to_string(op_index, constants));
+        op_index + 1
+    }
+
+    /// Instructions that refer to a constant are followed by the constant
+    /// and its type.
+    fn instruction_to_string(&self, op_index: usize, constants: &ValueArray) -> String {
+        let op_code = &self.code[op_index];
         // If this lines panics, there is something wrong with the implementation
         let identifier = format!("{:08} {:08}", op_index, self.get_line(op_index).unwrap());
 
         match op_code {
-            _ => {
-                println!("{}: {:?}", identifier, op_code);
-                op_index + 1
+            OpCode::Constant(constant_index) | OpCode::IsType(constant_index) => {
+                let constant = constants.get(*constant_index);
+                format!(
+                    "{}: {:<16} {} {}",
+                    identifier,
+                    format!("{:?}", op_code),
+                    constant,
+                    constant.get_type()
+                )
             }
+            _ => format!("{}: {:?}", identifier, op_code),
         }
     }
 
@@ -97,10 +156,19 @@ impl Chunk {
     }
 
     pub fn set_jump_at(&mut self, location: usize, offset: usize) {
+        // The jump lands on the instruction after `location + offset`, which may be the next one to be written
+        assert!(
+            location + 1 + offset <= self.code.len(),
+            "Jump at {} with offset {} lands outside of the chunk of size {}",
+            location,
+            offset,
+            self.code.len()
+        );
         match self.code[location] {
             OpCode::JumpIfFalse(_) => self.code[location] = OpCode::JumpIfFalse(offset),
             OpCode::Jump(_) => self.code[location] = OpCode::Jump(offset),
             OpCode::JumpIfTrue(_) => self.code[location] = OpCode::JumpIfTrue(offset),
+            OpCode::Try(_) => self.code[location] = OpCode::Try(offset),
             _ => unreachable!(
                 "Trying to modify instruction {:?} into a jump instruction",
                 self.code[location]
@@ -108,6 +176,213 @@ impl Chunk {
         };
     }
 
+    /// A statement starts at the next instruction that is written, with
+    /// `local_count` values on the stack of its frame
+    pub fn mark_statement(&mut self, local_count: usize) {
+        self.statement_depths
+            .entry(self.code.len())
+            .or_insert(local_count);
+    }
+
+    pub fn get_statement_depth(&self, op_index: usize) -> Option<usize> {
+        self.statement_depths.get(&op_index).copied()
+    }
+
+    /// The statements in `start..end` run with `count` more values on the
+    /// stack
+    pub fn deepen_statements(&mut self, start: usize, end: usize, count: usize) {
+        for (_, depth) in self.statement_depths.range_mut(start..end) {
+            *depth += count;
+        }
+    }
+
+    pub fn set_instruction(&mut self, op_index: usize, op_code: OpCode) {
+        self.code[op_index] = op_code;
+    }
+
+    /// Inserts instructions in front of the loop starting at `loop_start`.
+    /// The loop has to be the last code in the chunk, its `Loop`s are moved
+    /// past the inserted instructions so they only run once.
+    pub fn insert_before_loop(&mut self, loop_start: usize, op_codes: &[OpCode]) {
+        for (inserted, op_code) in op_codes.iter().enumerate() {
+            self.insert_instruction(loop_start + inserted, *op_code);
+        }
+        let body_start = loop_start + op_codes.len();
+        for op_index in body_start..self.code.len() {
+            if self.code[op_index] == OpCode::Loop(loop_start) {
+                self.code[op_index] = OpCode::Loop(body_start);
+            }
+        }
+    }
+
+    /// Inserts an instruction in front of the one at `at` and moves every
+    /// jump so it still lands on the same instruction. Jumps that landed on
+    /// `at` land on the inserted instruction, so it runs whenever the old
+    /// one would have.
+    pub fn insert_instruction(&mut self, at: usize, op_code: OpCode) {
+        assert!(
+            at <= self.code.len(),
+            "Cannot insert at {} in a chunk of size {}",
+            at,
+            self.code.len()
+        );
+        let mut lines = self.expanded_lines();
+        let line = lines.get(at).or(lines.last()).copied().unwrap_or_default();
+        lines.insert(at, line);
+
+        let new_index = |op_index: usize| {
+            if op_index > at {
+                op_index + 1
+            } else {
+                op_index
+            }
+        };
+        let relocate = |op_index: usize, offset: usize| {
+            // Jumps that are not patched yet are left for the compiler
+            if offset == usize::MAX {
+                return offset;
+            }
+            let source = if op_index >= at {
+                op_index + 1
+            } else {
+                op_index
+            };
+            new_index(op_index + 1 + offset) - source - 1
+        };
+        let mut code: Vec<OpCode> = std::mem::take(&mut self.code)
+            .into_iter()
+            .enumerate()
+            .map(|(op_index, op_code)| match op_code {
+                OpCode::Jump(offset) => OpCode::Jump(relocate(op_index, offset)),
+                OpCode::JumpIfFalse(offset) => OpCode::JumpIfFalse(relocate(op_index, offset)),
+                OpCode::JumpIfTrue(offset) => OpCode::JumpIfTrue(relocate(op_index, offset)),
+                OpCode::Try(offset) => OpCode::Try(relocate(op_index, offset)),
+                OpCode::JumpTo(target) => OpCode::JumpTo(new_index(target)),
+                OpCode::Loop(target) => OpCode::Loop(new_index(target)),
+                op_code => op_code,
+            })
+            .collect();
+        code.insert(at, op_code);
+        // A statement starting at `at` starts with the inserted instruction
+        self.statement_depths = std::mem::take(&mut self.statement_depths)
+            .into_iter()
+            .map(|(op_index, depth)| (new_index(op_index), depth))
+            .collect();
+
+        self.lines.clear();
+        for (op_code, line) in code.into_iter().zip(lines) {
+            self.write(op_code, line);
+        }
+    }
+
+    /// Checks that the function in `start..=end` ends with a `Return` or a
+    /// `Stop` and that none of its jumps leave it, so execution can never
+    /// fall off its end into unrelated code
+    pub fn verify_function(&self, start: usize, end: usize) -> Result<(), String> {
+        if !matches!(self.code.get(end), Some(OpCode::Return | OpCode::Stop)) {
+            return Err(format!(
+                "Instruction {} at the end of the function is not a Return or a Stop",
+                end
+            ));
+        }
+        for op_index in start..=end {
+            let target = match self.code[op_index] {
+                OpCode::Jump(offset)
+                | OpCode::JumpIfFalse(offset)
+                | OpCode::JumpIfTrue(offset)
+                | OpCode::Try(offset) => op_index + 1 + offset,
+                OpCode::JumpTo(target) | OpCode::Loop(target) => target,
+                _ => continue,
+            };
+            if target < start || target > end {
+                return Err(format!(
+                    "Jump at {} lands on {} outside of the function {}..={}",
+                    op_index, target, start, end
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes every instruction from `size` on
+    pub fn truncate(&mut self, size: usize) {
+        self.statement_depths.split_off(&size);
+        let lines = self.expanded_lines();
+        let code = std::mem::take(&mut self.code);
+        self.lines.clear();
+        for (op_code, line) in code.into_iter().zip(lines).take(size) {
+            self.write(op_code, line);
+        }
+    }
+
+    /// Removes an instruction without shifting the ones after it
+    pub fn replace_with_nop(&mut self, op_index: usize) {
+        self.code[op_index] = OpCode::Nop;
+    }
+
+    pub fn get_instruction(&self, op_index: usize) -> OpCode {
+        self.code[op_index]
+    }
+
+    /// Removes every instruction that is not marked in `keep` and fixes up the
+    /// jumps and line information of the remaining ones.
+    ///
+    /// Returns the new index of every old instruction index. Removed
+    /// instructions map to the index of the next instruction that was kept.
+    pub fn remove_instructions(&mut self, keep: &[bool]) -> Vec<usize> {
+        let mut new_indices = Vec::with_capacity(self.code.len() + 1);
+        let mut kept = 0;
+        for keep in keep.iter() {
+            new_indices.push(kept);
+            if *keep {
+                kept += 1;
+            }
+        }
+        new_indices.push(kept);
+
+        let lines = self.expanded_lines();
+        // The next kept instruction may belong to another statement
+        self.statement_depths = std::mem::take(&mut self.statement_depths)
+            .into_iter()
+            .filter(|(op_index, _)| keep.get(*op_index).copied().unwrap_or(false))
+            .map(|(op_index, depth)| (new_indices[op_index], depth))
+            .collect();
+
+        let relocate = |op_index: usize, offset: usize| {
+            new_indices[op_index + 1 + offset] - new_indices[op_index] - 1
+        };
+        let code = std::mem::take(&mut self.code);
+        self.lines.clear();
+        for (op_index, op_code) in code.into_iter().enumerate() {
+            if !keep[op_index] {
+                continue;
+            }
+            let op_code = match op_code {
+                OpCode::Jump(offset) => OpCode::Jump(relocate(op_index, offset)),
+                OpCode::JumpIfFalse(offset) => OpCode::JumpIfFalse(relocate(op_index, offset)),
+                OpCode::JumpIfTrue(offset) => OpCode::JumpIfTrue(relocate(op_index, offset)),
+                OpCode::Try(offset) => OpCode::Try(relocate(op_index, offset)),
+                OpCode::JumpTo(target) => OpCode::JumpTo(new_indices[target]),
+                OpCode::Loop(target) => OpCode::Loop(new_indices[target]),
+                op_code => op_code,
+            };
+            self.write(op_code, lines[op_index]);
+        }
+
+        new_indices
+    }
+
+    /// Line of every instruction
+    fn expanded_lines(&self) -> Vec<u32> {
+        let mut lines = Vec::with_capacity(self.code.len());
+        for line in self.lines.iter() {
+            for _ in 0..line.count {
+                lines.push(line.line);
+            }
+        }
+        lines
+    }
+
     pub fn get_size(&self) -> usize {
         self.code.len()
     }
@@ -131,8 +406,150 @@ impl Chunk {
         }
         self.lines.push(Line::new(line));
     }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::value::squat_value::SquatValue;
+
+    /// Index of the instruction every jump lands on
+    fn jump_targets(chunk: &Chunk) -> Vec<usize> {
+        (0..chunk.get_size())
+            .filter_map(|op_index| match chunk.get_instruction(op_index) {
+                OpCode::Jump(offset) | OpCode::JumpIfFalse(offset) | OpCode::JumpIfTrue(offset) => {
+                    Some(op_index + 1 + offset)
+                }
+                OpCode::JumpTo(target) | OpCode::Loop(target) => Some(target),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // 0: Start, 1: True, 2: JumpIfFalse -> 5, 3: Pop, 4: Loop -> 1, 5: Pop,
+    // 6: Jump -> 8, 7: Nil, 8: Stop, 9: JumpTo -> 1
+    fn jumping_chunk() -> Chunk {
+        let mut chunk = Chunk::new("Test", true);
+        chunk.write(OpCode::Start, 1);
+        chunk.write(OpCode::True, 2);
+        chunk.write(OpCode::JumpIfFalse(2), 2);
+        chunk.write(OpCode::Pop, 3);
+        chunk.write(OpCode::Loop(1), 3);
+        chunk.write(OpCode::Pop, 4);
+        chunk.write(OpCode::Jump(1), 4);
+        chunk.write(OpCode::Nil, 5);
+        chunk.write(OpCode::Stop, 6);
+        chunk.write(OpCode::JumpTo(1), 6);
+        chunk
+    }
+
+    #[test]
+    fn insert_before_jump_target() {
+        let mut chunk = jumping_chunk();
+        chunk.insert_instruction(5, OpCode::Nop);
+
+        // Jumps to the old instruction 5 now run the inserted one first
+        assert_eq!(jump_targets(&chunk), vec![5, 1, 9, 1]);
+        assert_eq!(chunk.get_instruction(5), OpCode::Nop);
+        assert_eq!(chunk.get_instruction(6), OpCode::Pop);
+        assert_eq!(chunk.get_instruction(9), OpCode::Stop);
+        assert_eq!(chunk.get_instruction_line(5), 4);
+        assert_eq!(chunk.get_instruction_line(6), 4);
+        assert_eq!(chunk.get_instruction_line(8), 5);
+    }
+
+    #[test]
+    fn insert_after_jump_target() {
+        let mut chunk = jumping_chunk();
+        chunk.insert_instruction(2, OpCode::Nop);
+
+        // The loop still lands on `True`, the forward jumps move with their targets
+        assert_eq!(jump_targets(&chunk), vec![6, 1, 9, 1]);
+        assert_eq!(chunk.get_instruction(1), OpCode::True);
+        assert_eq!(chunk.get_instruction(6), OpCode::Pop);
+        assert_eq!(chunk.get_instruction(9), OpCode::Stop);
+        assert_eq!(chunk.get_instruction_line(2), 2);
+        assert_eq!(chunk.get_instruction_line(4), 3);
+    }
+
+    #[test]
+    fn insert_at_end_of_chunk() {
+        let mut chunk = jumping_chunk();
+        chunk.insert_instruction(chunk.get_size(), OpCode::Nop);
+
+        assert_eq!(jump_targets(&chunk), vec![5, 1, 8, 1]);
+        assert_eq!(chunk.get_instruction(10), OpCode::Nop);
+        assert_eq!(chunk.get_instruction_line(10), 6);
+    }
+
+    #[test]
+    fn function_must_not_fall_through() {
+        let chunk = jumping_chunk();
+        assert_eq!(chunk.verify_function(0, 8), Ok(()));
+        assert_eq!(
+            chunk.verify_function(0, 7),
+            Err("Instruction 7 at the end of the function is not a Return or a Stop".to_owned())
+        );
+        assert_eq!(
+            chunk.verify_function(2, 8),
+            Err("Jump at 4 lands on 1 outside of the function 2..=8".to_owned())
+        );
+    }
+
+    #[test]
+    fn location_contains_file_name() {
+        let mut chunk = Chunk::new("Test", true);
+        assert_eq!(chunk.location(3), "<repl>:3");
+        chunk.set_file_name("test_scripts/fib.squat");
+        assert_eq!(chunk.location(12), "test_scripts/fib.squat:12");
+    }
+
+    #[test]
+    fn location_of_joined_files() {
+        let mut chunk = Chunk::new("Test", true);
+        chunk.add_source_file("project/a.squat", 1);
+        chunk.add_source_file("project/b.squat", 8);
+        assert_eq!(chunk.location(7), "project/a.squat:7");
+        assert_eq!(chunk.location(8), "project/b.squat:1");
+        assert_eq!(chunk.location(10), "project/b.squat:3");
+    }
+
+    #[test]
+    fn constant_is_shown_in_disassembly() {
+        let mut chunk = Chunk::new("Test", true);
+        let mut constants = ValueArray::new("Constants");
+        let index = constants.write(SquatValue::Int(42));
+        chunk.write(OpCode::Constant(index), 1);
+        chunk.write(OpCode::Pop, 1);
+
+        assert_eq!(
+            chunk.instruction_to_string(0, &constants),
+            "00000000 00000001: Constant(0)      42 <type Int>"
+        );
+        assert_eq!(
+            chunk.instruction_to_string(1, &constants),
+            "00000001 00000001: Pop"
+        );
+    }
+
+    #[test]
+    fn statements_move_with_their_instructions() {
+        let mut chunk = Chunk::new("Test", true);
+        chunk.write(OpCode::Nil, 1);
+        chunk.write(OpCode::Pop, 1);
+        chunk.mark_statement(1);
+        chunk.write(OpCode::True, 2);
+        chunk.write(OpCode::Pop, 2);
+        chunk.mark_statement(2);
+        chunk.write(OpCode::False, 3);
+        chunk.write(OpCode::Pop, 3);
+
+        chunk.insert_instruction(0, OpCode::Nop);
+        assert_eq!(chunk.get_statement_depth(3), Some(1));
+        assert_eq!(chunk.get_statement_depth(5), Some(2));
 
-    pub fn clear_instructions(&mut self) {
-        self.code.clear();
+        chunk.remove_instructions(&[true, true, true, false, false, true, true]);
+        assert_eq!(chunk.get_statement_depth(3), Some(2));
+        assert_eq!(chunk.get_statement_depth(5), None);
     }
 }
@@ -1,6 +1,11 @@
+pub mod const_eval;
+pub mod doc_comment;
+pub mod error_code;
+pub mod parse_tree;
 pub mod variable;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 use crate::chunk::Chunk;
 use crate::lexer::{Lexer, LexerError};
@@ -9,6 +14,10 @@ use crate::op_code::OpCode;
 use crate::token::{Token, TokenType};
 use crate::value::squat_type::{SquatFunctionTypeData, SquatStructTypeData, SquatType};
 use crate::value::{squat_value::SquatValue, ValueArray};
+use const_eval::ConstEvaluator;
+use doc_comment::DocComment;
+use error_code::ErrorCode;
+use parse_tree::ParseNode;
 use variable::{CompilerGlobal, CompilerLocal};
 
 use self::variable::CompilerNative;
@@ -25,6 +34,7 @@ enum Precedence {
     And,
     Equality,
     Comparison,
+    Shift,
     Term,
     Factor,
     Unary,
@@ -63,6 +73,89 @@ pub enum CompileStatus {
     Fail,
 }
 
+/// Instruction range of a compiled function, from the `Jump` over its body to
+/// the `DefineGlobal` that stores the function object.
+struct FunctionRange {
+    global_index: usize,
+    start: usize,
+    end: usize,
+}
+
+/// A `defer` block that is compiled again before every return of the function
+/// it was declared in, or a `finally` block compiled again at every exit of its
+/// try statement.
+#[derive(Clone)]
+struct DeferredBlock<'a> {
+    lexer: Lexer<'a>,
+    previous_token: Option<Token>,
+    current_token: Option<Token>,
+    // Locals declared after the block are hidden from it
+    local_count: usize,
+}
+
+/// The loop that `break` and `continue` statements in its body leave or
+/// restart
+struct Loop {
+    // Locals declared after this are popped before jumping out of the body
+    local_count: usize,
+    // Where `continue` loops back to, if it is known before the body
+    continue_target: Option<usize>,
+    continue_jumps: Vec<usize>,
+    break_jumps: Vec<usize>,
+    // `try` blocks the loop is in, the ones opened in its body are closed
+    // before jumping out of it
+    open_tries: usize,
+}
+
+impl Loop {
+    fn new(local_count: usize, continue_target: Option<usize>, open_tries: usize) -> Loop {
+        Loop {
+            local_count,
+            continue_target,
+            open_tries,
+            continue_jumps: Vec::new(),
+            break_jumps: Vec::new(),
+        }
+    }
+}
+
+/// A try block, or a catch block that has a `finally` block, around the code
+/// being compiled
+struct OpenTry<'a> {
+    // Compiled before jumping out of the block
+    finally: Option<DeferredBlock<'a>>,
+}
+
+/// A local of an enclosing function used by a lambda or a local function, its
+/// value is copied into the function value when that is made
+struct Upvalue {
+    name: String,
+    squat_type: SquatType,
+    // Where the enclosing function finds the value
+    source: UpvalueSource,
+}
+
+enum UpvalueSource {
+    Local(usize),
+    Upvalue(usize),
+    // The local function itself, see `local_function`
+    Callee,
+}
+
+/// A function whose body is compiled around the function being compiled
+struct EnclosingFunction {
+    locals: Vec<CompilerLocal>,
+    upvalues: Vec<Upvalue>,
+    local_function: Option<(String, SquatType)>,
+}
+
+/// An argument compiled by `argument_list`
+struct Argument {
+    squat_type: SquatType,
+    // The string when the argument is a string literal
+    literal: Option<Rc<str>>,
+}
+
 #[derive(Clone, Copy)]
 enum ScopeType {
     Global,
@@ -83,15 +176,56 @@ pub struct Compiler<'a> {
     constants: &'a mut ValueArray,
 
     locals: Vec<CompilerLocal>,
+    upvalues: Vec<Upvalue>,
+    // Name and type of the function being compiled if it is declared in a
+    // block, its body calls it through the callee of its frame
+    local_function: Option<(String, SquatType)>,
+    // Innermost last, the global scope first
+    enclosing_functions: Vec<EnclosingFunction>,
     scope_depth: u32,
     scope_type: ScopeType,
     function_return_type: SquatType,
+    deferred_blocks: Vec<DeferredBlock<'a>>,
+    loops: Vec<Loop>,
+    // `try` blocks of the current function around the code being compiled
+    open_tries: Vec<OpenTry<'a>>,
+    in_deferred_block: bool,
 
     had_error: bool,
     panic_mode: bool,
 
     main_start: usize,
     found_main: bool,
+    function_ranges: Vec<FunctionRange>,
+    function_name: String,
+    // Calls to functions that are not native, they can change any global
+    user_function_calls: usize,
+    // Parameters of the function being compiled, they come first in `locals`
+    parameter_count: usize,
+    // Hidden global of the `deinit` function of each struct that has one
+    struct_deinits: HashMap<String, usize>,
+    // Names of the local slots of each function, used to label stack dumps
+    local_names: HashMap<String, Vec<String>>,
+
+    // Only recorded when a parse tree is requested
+    parse_tree: Option<Vec<ParseNode>>,
+    // Type of every local as `function.name`, only recorded when the symbols
+    // are requested
+    local_symbols: Option<Vec<(String, SquatType)>>,
+    doc_comments: Vec<DocComment>,
+    strict_types: bool,
+    no_implicit_default: bool,
+    legacy_print: bool,
+    warn_unused: bool,
+    warn_recursion: bool,
+    keep_unused_functions: bool,
+    // Index of every `Call` of the function being compiled to itself
+    self_calls: Vec<usize>,
+    warnings: Vec<String>,
+    errors: Vec<String>,
+    // Set right before an expression whose stack holds no temporaries, a
+    // block expression can only open such an expression
+    block_expression_allowed: bool,
 }
 
 impl<'a> Compiler<'a> {
@@ -109,7 +243,7 @@ impl<'a> Compiler<'a> {
         natives: &'a Vec<CompilerNative>,
     ) -> Compiler<'a> {
         Compiler {
-            lexer: Lexer::new(source),
+            lexer: Lexer::new(source, false),
             previous_token: None,
             current_token: None,
 
@@ -121,21 +255,129 @@ impl<'a> Compiler<'a> {
             constants,
 
             locals: Vec::with_capacity(INITIAL_LOCALS_VECTOR_SIZE),
+            upvalues: Vec::new(),
+            local_function: None,
+            enclosing_functions: Vec::new(),
             scope_depth: 0,
             scope_type: ScopeType::Global,
             function_return_type: SquatType::Nil,
+            deferred_blocks: Vec::new(),
+            loops: Vec::new(),
+            open_tries: Vec::new(),
+            in_deferred_block: false,
 
             had_error: false,
             panic_mode: false,
 
             main_start: 0,
             found_main: false,
+            function_ranges: Vec::new(),
+            function_name: String::new(),
+            user_function_calls: 0,
+            parameter_count: 0,
+            struct_deinits: HashMap::new(),
+            local_names: HashMap::new(),
+
+            parse_tree: None,
+            local_symbols: None,
+            doc_comments: Vec::new(),
+            strict_types: false,
+            no_implicit_default: false,
+            legacy_print: false,
+            warn_unused: true,
+            warn_recursion: false,
+            keep_unused_functions: false,
+            self_calls: Vec::new(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            block_expression_allowed: false,
         }
     }
 
+    /// Stops the warnings about variables that are never read
+    pub fn allow_unused_variables(&mut self) {
+        self.warn_unused = false;
+    }
+
+    /// Warns about functions that look like they can recurse without a
+    /// bound, see `check_recursion`
+    pub fn enable_recursion_warnings(&mut self) {
+        self.warn_recursion = true;
+    }
+
+    /// Keeps the functions that are never called from the program, they can
+    /// still be called by name from the host
+    pub fn keep_unused_functions(&mut self) {
+        self.keep_unused_functions = true;
+    }
+
+    /// Rejects mixing ints and floats, `1 + 2.0` has to convert one of them
+    /// with `to_int` or `to_float`. So does arithmetic on values whose type is
+    /// only known at runtime, and `number` only stands for an int or a float
+    /// in the arguments of natives.
+    pub fn enable_strict_types(&mut self) {
+        self.strict_types = true;
+    }
+
+    /// Requires every variable declaration to give a value instead of
+    /// defaulting to zero, an empty string, false or nil
+    pub fn disable_implicit_defaults(&mut self) {
+        self.no_implicit_default = true;
+    }
+
+    /// Treats `print` at the start of a statement as the print statement of
+    /// older scripts, `print value;` prints the value and a new line
+    pub fn enable_legacy_print(&mut self) {
+        self.legacy_print = true;
+    }
+
+    /// Makes the compiler record the types of locals as well, see
+    /// `dump_symbols`
+    pub fn record_symbols(&mut self) {
+        self.local_symbols = Some(Vec::new());
+    }
+
+    /// Makes the compiler record a parse tree while compiling, see
+    /// `take_parse_tree`
+    pub fn record_parse_tree(&mut self) {
+        self.parse_tree = Some(Vec::new());
+    }
+
+    /// Returns the doc comments of the functions and structs in the order
+    /// they were declared
+    pub fn take_doc_comments(&mut self) -> Vec<DocComment> {
+        std::mem::take(&mut self.doc_comments)
+    }
+
+    /// Returns the names of the local slots of each function. A slot that is
+    /// reused by different scopes lists all of its names separated by '/'.
+    pub fn take_local_names(&mut self) -> HashMap<String, Vec<String>> {
+        std::mem::take(&mut self.local_names)
+    }
+
+    /// Names of the global variables, functions and structs declared in the
+    /// source, without the hidden ones like the methods of structs
+    pub fn global_names(&self) -> HashSet<String> {
+        self.globals
+            .keys()
+            .filter(|name| !name.contains('.'))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the top level nodes recorded during compilation
+    pub fn take_parse_tree(&mut self) -> Vec<ParseNode> {
+        self.parse_tree.take().unwrap_or_default()
+    }
+
     /// Starts the compilation process and returns the `CompilationStatus`
     pub fn compile(&mut self) -> CompileStatus {
         self.advance();
+        // Only whitespace and comments, the lexer gives `Eof` right away
+        let is_empty = matches!(
+            &self.current_token,
+            Some(token) if token.token_type == TokenType::Eof
+        );
 
         while !self.check_current(TokenType::Eof) {
             self.declaration_statement(None);
@@ -146,55 +388,91 @@ impl<'a> Compiler<'a> {
 
         if !self.found_main {
             compile_status = CompileStatus::Fail;
-            println!("[COMPILE ERROR] Function 'main' was not defined!");
+            let error = if is_empty {
+                "[COMPILE ERROR] Empty program: no 'main' function found"
+            } else {
+                "[COMPILE ERROR] Function 'main' was not defined!"
+            };
+            println!("{}", error);
+            self.errors.push(error.to_owned());
         }
         if self.had_error {
             compile_status = CompileStatus::Fail;
         }
-
-        #[cfg(debug_assertions)]
-        println!("Global variable indicies {:?}", self.globals);
-        #[cfg(debug_assertions)]
-        println!("Constants {:?}", self.constants);
+        self.warn_unused_globals();
+        if let CompileStatus::Success(_) = compile_status {
+            if !self.keep_unused_functions {
+                self.eliminate_dead_functions();
+            }
+            self.eliminate_double_negations();
+            if let Err(msg) = self.verify_functions() {
+                println!("[INTERNAL COMPILER ERROR] {}", msg);
+                compile_status = CompileStatus::Fail;
+            }
+        }
 
         compile_status
     }
 
+    /// Name and index of every global, ordered by index
+    pub fn dump_globals(&self) -> String {
+        let mut globals: Vec<(&String, &CompilerGlobal)> = self.globals.iter().collect();
+        globals.sort_by_key(|(_, global)| global.index);
+        globals
+            .iter()
+            .map(|(name, global)| format!("{:>4}: {}", global.index, name))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Every global with its inferred type, followed by the locals of each
+    /// function if they were recorded
+    pub fn dump_symbols(&self) -> String {
+        let mut globals: Vec<(&String, &CompilerGlobal)> = self.globals.iter().collect();
+        globals.sort_by_key(|(_, global)| global.index);
+        globals
+            .iter()
+            .map(|(name, global)| format!("{}: {}", name, global.get_type()))
+            .chain(
+                self.local_symbols
+                    .iter()
+                    .flatten()
+                    .map(|(name, squat_type)| format!("{}: {}", name, squat_type)),
+            )
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
     //////////////////////////////////////////////////////////////////////////
-    /// Statement rules
+    // Statement rules
     //////////////////////////////////////////////////////////////////////////
 
     fn try_var_declaration(&mut self) -> bool {
         if self.check_current(TokenType::Var) {
             self.var_declaration(None);
             return true;
-        } else if self.check_current(TokenType::BoolType) {
-            self.var_declaration(Some(SquatType::Bool));
-            return true;
-        } else if self.check_current(TokenType::IntType) {
-            self.var_declaration(Some(SquatType::Int));
-            return true;
-        } else if self.check_current(TokenType::FloatType) {
-            self.var_declaration(Some(SquatType::Float));
-            return true;
-        } else if self.check_current(TokenType::StringType) {
-            self.var_declaration(Some(SquatType::String));
-            return true;
-        } else if self
-            .structs
-            .get(&self.current_token.as_ref().unwrap().lexeme)
-            .is_some()
-        {
-            let struct_data = self
-                .structs
-                .get(&self.current_token.as_ref().unwrap().lexeme)
-                .unwrap()
-                .clone();
-            self.advance();
-            self.var_declaration(Some(struct_data.get_instance_type()));
-            return true;
         }
-        false
+        let declares_variable = match self.current_token.as_ref().unwrap().token_type {
+            TokenType::AnyType
+            | TokenType::BoolType
+            | TokenType::IntType
+            | TokenType::FloatType
+            | TokenType::StringType => true,
+            // `Point point` declares a variable, `Point(1, 2)` is an expression
+            _ => {
+                self.structs
+                    .contains_key(&self.current_token.as_ref().unwrap().lexeme)
+                    && matches!(
+                        self.peek_next_token().map(|token| token.token_type),
+                        Some(TokenType::Identifier | TokenType::Question)
+                    )
+            }
+        };
+        if declares_variable {
+            let squat_type = self.get_type();
+            self.var_declaration(squat_type);
+        }
+        declares_variable
     }
 
     fn parse_function_type(&mut self) -> SquatType {
@@ -233,6 +511,9 @@ impl<'a> Compiler<'a> {
     }
 
     fn declaration_statement(&mut self, expected_return_type: Option<SquatType>) {
+        if matches!(self.scope_type, ScopeType::Function) {
+            self.main_chunk.mark_statement(self.locals.len());
+        }
         if self.check_current(TokenType::Semicolon) {
             self.compile_warning("Unnecessary ';'");
         } else if self.check_current(TokenType::Func) {
@@ -240,19 +521,28 @@ impl<'a> Compiler<'a> {
                 self.function_var_declaration();
             } else {
                 match self.scope_type {
-                    ScopeType::Global => self.function_declaration(),
+                    ScopeType::Global | ScopeType::Function => self.function_declaration(false),
                     ScopeType::Struct => {
                         self.compile_error("Cannot declare a function inside a struct")
                     }
-                    _ => self.compile_error("Cannot declare a function in local scope"),
                 }
             }
         } else if self.try_var_declaration() {
         } else if self.check_current(TokenType::Return) {
             match self.scope_type {
+                ScopeType::Function if self.in_deferred_block => {
+                    self.compile_error("Cannot return from inside a defer block.")
+                }
                 ScopeType::Function => self.return_statement(expected_return_type.unwrap()),
                 _ => self.compile_error("Cannot return from outside a function."),
             }
+        } else if self.check_current(TokenType::Defer) {
+            match self.scope_type {
+                ScopeType::Function => self.defer_statement(),
+                _ => self.compile_error("Cannot defer from outside a function."),
+            }
+        } else if self.check_current(TokenType::Annotation) {
+            self.annotated_declaration();
         } else if self.check_current(TokenType::Struct) {
             match self.scope_type {
                 ScopeType::Global => self.struct_declaration(),
@@ -261,7 +551,11 @@ impl<'a> Compiler<'a> {
         } else {
             match self.scope_type {
                 ScopeType::Function => self.statement(),
-                _ => self.compile_error("Statements are not allowed outside of function blocks."),
+                _ => {
+                    // Skipped so the statement is not started again
+                    self.advance();
+                    self.compile_error("Statements are not allowed outside of function blocks.")
+                }
             }
         }
 
@@ -271,10 +565,19 @@ impl<'a> Compiler<'a> {
     }
 
     fn struct_declaration(&mut self) {
+        let doc = self.previous_token.as_ref().unwrap().doc.clone();
         let (index, name) = match self.parse_variable("Expect struct name") {
             Ok((index, name)) => (index, name),
-            Err(_) => return,
+            Err(_) => {
+                self.skip_declaration();
+                return;
+            }
         };
+        if let Some(doc) = doc {
+            self.doc_comments
+                .push(DocComment::new("struct", &name, &doc));
+        }
+        self.tree_leaf(&format!("struct {}", name));
 
         let mut struct_data = SquatStructTypeData::new(&name);
         self.initialize_object(&name);
@@ -283,14 +586,30 @@ impl<'a> Compiler<'a> {
         let old_scope_type = self.scope_type;
         self.scope_type = ScopeType::Struct;
 
-        self.struct_block(&mut struct_data);
+        let deinit = self.struct_block(&mut struct_data);
 
         self.patch_jump(jump);
         self.patch_struct(&name, struct_data.clone());
 
-        self.structs.insert(name.clone(), struct_data);
+        if let Some(deinit) = deinit {
+            let deinit_index = self.globals.len();
+            self.globals.insert(
+                format!("{}.deinit", name),
+                CompilerGlobal::new(deinit_index, true, None),
+            );
+            let constant_index = self
+                .constants
+                .write(SquatValue::Object(SquatObject::Function(deinit)));
+            self.write_op_code(OpCode::Constant(constant_index));
+            self.write_op_code(OpCode::DefineGlobal(deinit_index));
+            self.struct_deinits.insert(name.clone(), deinit_index);
+        }
+
+        let field_names = struct_data.get_field_names();
+        self.structs.insert(name.clone(), struct_data.clone());
 
-        let struct_object = SquatObject::Struct(SquatStruct::new(&name));
+        let struct_object =
+            SquatObject::Struct(SquatStruct::new(&name, field_names).with_type_data(struct_data));
         let constant_index = self.constants.write(SquatValue::Object(struct_object));
         self.write_op_code(OpCode::Constant(constant_index));
         self.define_object(index);
@@ -298,39 +617,103 @@ impl<'a> Compiler<'a> {
         self.scope_type = old_scope_type;
     }
 
-    fn struct_block(&mut self, data: &mut SquatStructTypeData) {
+    /// Returns the `deinit` function if the struct has one
+    fn struct_block(&mut self, data: &mut SquatStructTypeData) -> Option<SquatFunction> {
+        let mut deinit = None;
         self.consume_current(TokenType::LeftBrace, "Expected '{' before struct body");
         while !self.check_current(TokenType::RightBrace) {
             if self.check_current(TokenType::Eof) {
                 self.compile_error("Expected closing '}' to end the struct body");
                 break;
             }
-            if self.check_current(TokenType::Var) {
+            if deinit.is_some() {
+                self.compile_error("'deinit' has to be the last thing in the struct body");
+                self.advance();
+            } else if self.current_token.as_ref().unwrap().token_type == TokenType::Identifier
+                && self.current_token.as_ref().unwrap().lexeme == "deinit"
+            {
+                self.advance();
+                deinit = self.struct_deinit(data);
+            } else if self.check_current(TokenType::Var) {
                 self.compile_error("Cannot use 'Var' to define struct field");
-            } else if self.check_current(TokenType::BoolType) {
-                self.define_struct_field(data, SquatType::Bool);
-            } else if self.check_current(TokenType::IntType) {
-                self.define_struct_field(data, SquatType::Int);
-            } else if self.check_current(TokenType::FloatType) {
-                self.define_struct_field(data, SquatType::Float);
-            } else if self.check_current(TokenType::StringType) {
-                self.define_struct_field(data, SquatType::String);
-            } else if self
+            } else if matches!(
+                self.current_token.as_ref().unwrap().token_type,
+                TokenType::BoolType
+                    | TokenType::IntType
+                    | TokenType::FloatType
+                    | TokenType::StringType
+            ) || self
                 .structs
-                .get(&self.current_token.as_ref().unwrap().lexeme)
-                .is_some()
+                .contains_key(&self.current_token.as_ref().unwrap().lexeme)
             {
-                let struct_data = self
-                    .structs
-                    .get(&self.current_token.as_ref().unwrap().lexeme)
-                    .unwrap()
-                    .clone();
-                self.advance();
-                self.define_struct_field(data, SquatType::Struct(struct_data));
+                let field_type = self.get_type().unwrap();
+                self.define_struct_field(data, field_type);
             } else {
                 todo!("Implement func");
             }
         }
+        deinit
+    }
+
+    /// ```
+    /// struct File {
+    ///     string path;
+    ///     deinit {
+    ///         println("closing " + self.path);
+    ///     }
+    /// }
+    /// ```
+    /// The body runs with the instance bound to `self` whenever a local
+    /// instance of the struct goes out of scope, see `end_scope`. It does not
+    /// run for globals, parameters, values that are not stored in a local, or
+    /// locals that are still alive when a `return` leaves the function. Instances
+    /// are copied when assigned, so every copy in a local runs it.
+    fn struct_deinit(&mut self, data: &SquatStructTypeData) -> Option<SquatFunction> {
+        if self.scope_depth > 0 {
+            self.compile_error("'deinit' is only allowed in structs declared at the top level");
+            return None;
+        }
+        // The fields are needed to access the properties of self
+        self.structs.insert(data.name.clone(), data.clone());
+
+        let deinit_name = format!("{}.deinit", data.name);
+        let old_scope_type = self.scope_type;
+        self.scope_type = ScopeType::Function;
+        let old_function_name = std::mem::replace(&mut self.function_name, deinit_name.clone());
+        let old_return_type = std::mem::replace(&mut self.function_return_type, SquatType::Nil);
+        let old_parameter_count = std::mem::replace(&mut self.parameter_count, 1);
+
+        self.begin_scope();
+        self.locals.push(CompilerLocal::new(
+            "self",
+            Some(self.scope_depth),
+            Some(data.get_instance_type()),
+        ));
+        self.record_local_name(0, "self");
+        self.record_local_symbol("self", &data.get_instance_type());
+
+        self.consume_current(TokenType::LeftBrace, "Expected '{' to define deinit body");
+        self.write_op_code(OpCode::Start);
+        let starting_index = self.main_chunk.get_size() - 1;
+
+        let deferred_blocks = std::mem::take(&mut self.deferred_blocks);
+        self.block(SquatType::Nil);
+        self.emit_deferred_blocks(false);
+        self.deferred_blocks = deferred_blocks;
+        self.end_scope();
+        self.write_op_code(OpCode::Nil);
+        self.write_op_code(OpCode::Return);
+
+        self.scope_type = old_scope_type;
+        self.function_name = old_function_name;
+        self.function_return_type = old_return_type;
+        self.parameter_count = old_parameter_count;
+
+        Some(SquatFunction::new(
+            &deinit_name,
+            starting_index,
+            SquatFunctionTypeData::new(vec![data.get_instance_type()], SquatType::Nil),
+        ))
     }
 
     fn define_struct_field(&mut self, data: &mut SquatStructTypeData, field_type: SquatType) {
@@ -339,68 +722,159 @@ impl<'a> Compiler<'a> {
             return;
         }
         let field_name = self.previous_token.as_ref().unwrap().lexeme.clone();
-        data.add_field(&field_name, field_type);
+        let mut default = None;
+        if self.check_current(TokenType::Equal) {
+            match self.try_const_eval(TokenType::Semicolon) {
+                Some(value) => {
+                    self.check_types(Some(field_type.clone()), &value.get_type());
+                    default = Some(value);
+                }
+                None => {
+                    self.compile_error(&format!(
+                        "Default value of field '{}' must be a constant",
+                        field_name
+                    ));
+                    while !matches!(
+                        self.current_token.as_ref().unwrap().token_type,
+                        TokenType::Semicolon | TokenType::Eof
+                    ) {
+                        self.advance();
+                    }
+                }
+            }
+        }
+        data.add_field(&field_name, field_type, default);
         self.consume_current(
             TokenType::Semicolon,
             "Expected ';' at the end of field declaraton",
         );
     }
 
-    fn function_declaration(&mut self) {
+    /// `@memoize func ...` makes the VM cache the results of the function by
+    /// its arguments. Only pure functions can be memoized: a cached call does
+    /// not run the body, so it does not change globals or print anything, and
+    /// arrays or structs given as arguments are compared by their contents at
+    /// the time of the call.
+    fn annotated_declaration(&mut self) {
+        let annotation = self.previous_token.as_ref().unwrap().lexeme.clone();
+        let memoize = annotation == "@memoize";
+        if !memoize {
+            self.compile_error(&format!("Unknown annotation '{}'", annotation));
+        }
+        if !self.check_current(TokenType::Func) {
+            self.compile_error(&format!(
+                "Expected a function declaration after '{}'",
+                annotation
+            ));
+            return;
+        }
+        match self.scope_type {
+            ScopeType::Global => self.function_declaration(memoize),
+            _ => self.compile_error("Only functions in the global scope can be memoized"),
+        }
+    }
+
+    fn function_declaration(&mut self, memoize: bool) {
+        let tree_mark = self.tree_mark();
+        let doc = self.previous_token.as_ref().unwrap().doc.clone();
         let (index, func_name) = match self.parse_variable("Expect function name") {
             Ok(value) => value,
             Err(()) => {
+                self.skip_declaration();
                 return;
             }
         };
+        let line = self.previous_token.as_ref().unwrap().line;
+        if let Some(doc) = doc {
+            self.doc_comments
+                .push(DocComment::new("func", &func_name, &doc));
+        }
 
         self.consume_current(
             TokenType::LeftParenthesis,
             "Expect '(' after function name.",
         );
-        let is_main: bool;
-
-        if func_name == "main" {
+        // Functions declared in a block are locals of the enclosing function,
+        // their body gets its own locals like any other function
+        let is_local = self.scope_depth > 0;
+        let is_main = func_name == "main" && !is_local;
+        if is_main {
             if self.found_main {
                 self.compile_error("Cannot have more then 1 main function");
             }
             self.found_main = true;
-            is_main = true;
+            if memoize {
+                self.compile_error("Cannot memoize the main function");
+            }
         } else {
-            is_main = false;
+            self.initialize_object(&func_name);
         }
-        let old_scope_type = self.scope_type;
-        self.scope_type = ScopeType::Function;
 
-        if !is_main {
-            self.initialize_object(&func_name);
+        let is_global = !is_main && !is_local;
+        if let Some((function_data, jump)) =
+            self.function(&func_name, line, is_main, memoize, is_global)
+        {
+            if is_local {
+                self.locals[index].set_type(SquatType::Function(function_data));
+            } else if !is_main {
+                self.define_object(index);
+                self.function_ranges.push(FunctionRange {
+                    global_index: index,
+                    start: jump,
+                    end: self.main_chunk.get_size() - 1,
+                });
+            }
         }
+        self.tree_branch(tree_mark, &format!("func {}", func_name));
+    }
+
+    /// A function used as a value, like `func(int x) int { return x * 2; }`.
+    /// The locals of the enclosing functions that its body uses are copied
+    /// into it when it is made, assigning to them is an error.
+    fn lambda(&mut self) -> SquatType {
+        let tree_mark = self.tree_mark();
+        let line = self.previous_token.as_ref().unwrap().line;
+        self.consume_current(TokenType::LeftParenthesis, "Expect '(' after 'func'.");
+        let function_type = match self.function("<lambda>", line, false, false, false) {
+            Some((function_data, _)) => SquatType::Function(function_data),
+            None => SquatType::Nil,
+        };
+        self.tree_branch(tree_mark, "lambda");
+        function_type
+    }
+
+    /// Compiles the parameters and body of a function after its '(', then
+    /// pushes the function unless it is main. The function is jumped over,
+    /// returns its type and where the jump is. The type of a global function
+    /// is set before its body so the body can call it.
+    fn function(
+        &mut self,
+        func_name: &str,
+        line: u32,
+        is_main: bool,
+        memoize: bool,
+        is_global: bool,
+    ) -> Option<(SquatFunctionTypeData, usize)> {
+        let old_scope_type = self.scope_type;
+        self.scope_type = ScopeType::Function;
+        let old_function_name = std::mem::replace(&mut self.function_name, func_name.to_owned());
+        let old_parameter_count = self.parameter_count;
+        self.enclosing_functions.push(EnclosingFunction {
+            locals: std::mem::take(&mut self.locals),
+            upvalues: std::mem::take(&mut self.upvalues),
+            local_function: self.local_function.take(),
+        });
+        let outer_scope_depth = std::mem::replace(&mut self.scope_depth, 0);
+        let outer_return_type = self.function_return_type.clone();
         self.begin_scope();
 
         let jump = self.emit_jump(OpCode::Jump(usize::MAX));
         let mut param_types: Vec<SquatType> = Vec::with_capacity(255);
         if !is_main {
             if !self.check_current(TokenType::RightParenthesis) {
-                param_types.push(match self.get_parameter_type() {
-                    Ok(value) => value,
-                    Err(()) => return,
-                });
-                let (constant, var_name) = match self.parse_variable("Expect parameter name") {
-                    Ok((constant, var_name)) => (constant, var_name),
-                    Err(_) => return,
-                };
-                self.define_variable(constant, &var_name, param_types.last().unwrap().clone());
-
+                param_types.push(self.parameter()?);
                 while self.check_current(TokenType::Comma) {
-                    param_types.push(match self.get_parameter_type() {
-                        Ok(value) => value,
-                        Err(()) => return,
-                    });
-                    let (constant, var_name) = match self.parse_variable("Expect parameter name") {
-                        Ok((constant, var_name)) => (constant, var_name),
-                        Err(_) => return,
-                    };
-                    self.define_variable(constant, &var_name, param_types.last().unwrap().clone());
+                    param_types.push(self.parameter()?);
                 }
                 self.consume_current(TokenType::RightParenthesis, "Expect closing ')'.");
             }
@@ -408,15 +882,13 @@ impl<'a> Compiler<'a> {
             self.consume_current(TokenType::RightParenthesis, "Expect closing ')'");
         }
 
-        let return_type: SquatType;
-        if !is_main {
-            return_type = match self.get_return_type() {
-                Some(value) => value,
-                None => SquatType::Nil,
-            };
+        self.parameter_count = param_types.len();
+
+        let return_type = if !is_main {
+            self.get_return_type().unwrap_or(SquatType::Nil)
         } else {
-            return_type = SquatType::Int;
-        }
+            SquatType::Int
+        };
         self.function_return_type = return_type.clone();
 
         self.consume_current(TokenType::LeftBrace, "Expected '{' to define function body");
@@ -427,35 +899,120 @@ impl<'a> Compiler<'a> {
         }
         let starting_index = self.main_chunk.get_size() - 1;
 
-        if !is_main {
-            self.patch_function(
-                &func_name,
-                SquatFunctionTypeData::new(param_types, return_type.clone()),
-            );
+        let function_data = SquatFunctionTypeData::new(param_types, return_type.clone());
+        if is_global {
+            self.patch_function(func_name, function_data.clone());
+        } else if !is_main && func_name != "<lambda>" {
+            self.local_function = Some((
+                func_name.to_owned(),
+                SquatType::Function(function_data.clone()),
+            ));
         }
 
+        let deferred_blocks = std::mem::take(&mut self.deferred_blocks);
+        let self_calls = std::mem::take(&mut self.self_calls);
+        let loops = std::mem::take(&mut self.loops);
+        let open_tries = std::mem::take(&mut self.open_tries);
         self.block(return_type.clone());
+        self.loops = loops;
+        self.open_tries = open_tries;
+        let body_end = self.main_chunk.get_size();
+        if self.warn_recursion {
+            self.check_recursion(func_name, line, starting_index, body_end);
+        }
+        self.self_calls = self_calls;
+        self.emit_deferred_blocks(false);
+        self.deferred_blocks = deferred_blocks;
         self.end_scope();
         if is_main {
             self.write_op_code(OpCode::Stop);
-        } else {
+        } else if self.had_error
+            || self.can_reach_end(starting_index, body_end)
+            // Pops of a scope that returned in every path are never reached
+            // either, but the function still has to end with a return
+            || self.main_chunk.get_instruction(body_end - 1) != OpCode::Return
+        {
             self.write_op_code(OpCode::Nil);
             self.write_op_code(OpCode::Return);
+        } else {
+            // Every path returns, the code after the body would never run and
+            // neither would the jumps to it, like the one over an else branch
+            self.main_chunk.truncate(body_end);
+            for op_index in starting_index..body_end {
+                if let OpCode::Jump(offset)
+                | OpCode::JumpIfFalse(offset)
+                | OpCode::JumpIfTrue(offset) = self.main_chunk.get_instruction(op_index)
+                {
+                    if op_index + 1 + offset == body_end {
+                        self.main_chunk.replace_with_nop(op_index);
+                    }
+                }
+            }
         }
 
+        let enclosing = self.enclosing_functions.pop().unwrap();
+        self.locals = enclosing.locals;
+        let upvalues = std::mem::replace(&mut self.upvalues, enclosing.upvalues);
+        self.local_function = enclosing.local_function;
+        self.scope_depth = outer_scope_depth;
+        self.function_return_type = outer_return_type;
+        self.scope_type = old_scope_type;
+        self.function_name = old_function_name;
+        self.parameter_count = old_parameter_count;
+
         self.patch_jump(jump);
         if !is_main {
-            let function_obj =
-                SquatObject::Function(SquatFunction::new(&func_name, starting_index));
+            let mut function = SquatFunction::new(func_name, starting_index, function_data.clone());
+            if memoize {
+                function = function.memoized();
+            }
+            let function_obj = SquatObject::Function(function);
             let constant_index = self.constants.write(SquatValue::Object(function_obj));
             self.write_op_code(OpCode::Constant(constant_index));
-            self.define_object(index);
+            if !upvalues.is_empty() {
+                for upvalue in &upvalues {
+                    self.write_op_code(match upvalue.source {
+                        UpvalueSource::Local(index) => OpCode::GetLocal(index),
+                        UpvalueSource::Upvalue(index) => OpCode::GetUpvalue(index),
+                        UpvalueSource::Callee => OpCode::GetCallee,
+                    });
+                }
+                self.write_op_code(OpCode::Closure(upvalues.len()));
+            }
+        }
+        Some((function_data, jump))
+    }
+
+    fn record_local_symbol(&mut self, name: &str, squat_type: &SquatType) {
+        if let Some(local_symbols) = self.local_symbols.as_mut() {
+            if !name.is_empty() {
+                local_symbols.push((
+                    format!("{}.{}", self.function_name, name),
+                    squat_type.clone(),
+                ));
+            }
         }
+    }
 
-        self.scope_type = old_scope_type;
+    fn record_local_name(&mut self, index: usize, name: &str) {
+        let slots = self
+            .local_names
+            .entry(self.function_name.clone())
+            .or_default();
+        if slots.len() <= index {
+            slots.resize(index + 1, String::new());
+        }
+        let slot = &mut slots[index];
+        if slot.is_empty() {
+            *slot = name.to_owned();
+        } else if !slot.split('/').any(|existing| existing == name) {
+            slot.push('/');
+            slot.push_str(name);
+        }
     }
 
     fn var_declaration(&mut self, squat_type: Option<SquatType>) {
+        let tree_mark = self.tree_mark();
         let (index, name) = match self.parse_variable("Expect variable name") {
             Ok(value) => value,
             Err(()) => {
@@ -466,13 +1023,37 @@ impl<'a> Compiler<'a> {
         let var_type: SquatType;
 
         if self.check_current(TokenType::Equal) {
-            var_type = self.expression_with_type(squat_type);
+            let expression_type = match self.try_const_eval(TokenType::Semicolon) {
+                Some(value) => {
+                    let value_type = value.get_type();
+                    self.check_types(squat_type.clone(), &value_type);
+                    let index = self.constants.write(value);
+                    self.write_op_code(OpCode::Constant(index));
+                    value_type
+                }
+                None => {
+                    self.block_expression_allowed = true;
+                    self.expression_with_type(squat_type.clone())
+                }
+            };
+            // Keep the declared type so optionals stay optional after assignment
+            var_type = match squat_type {
+                Some(squat_type) => squat_type,
+                // Later uses can't be checked against a value that failed
+                None if self.panic_mode => SquatType::Error,
+                None => expression_type,
+            };
         } else {
             if squat_type.is_none() {
+                self.compile_error("Cannot define variable using 'var' without giving it a value");
+                self.define_variable(index, &name, SquatType::Error);
+                return;
+            }
+            if self.no_implicit_default {
                 self.compile_error(&format!(
-                    "Cannot define variable using 'var' without giving it a value"
+                    "Variable '{}' has to be given a value when implicit defaults are disabled",
+                    name
                 ));
-                return;
             }
             let index = match squat_type.unwrap() {
                 SquatType::Int => {
@@ -485,7 +1066,7 @@ impl<'a> Compiler<'a> {
                 }
                 SquatType::String => {
                     var_type = SquatType::String;
-                    Some(self.constants.write(SquatValue::String("".to_owned())))
+                    Some(self.constants.write(SquatValue::String("".into())))
                 }
                 SquatType::Bool => {
                     var_type = SquatType::Bool;
@@ -493,17 +1074,25 @@ impl<'a> Compiler<'a> {
                 }
                 SquatType::Function(data) => {
                     var_type = SquatType::Function(data);
-                    self.compile_error(&format!("Must define function"));
+                    self.compile_error("Must define function");
                     None
                 }
                 SquatType::NativeFunction(data) => {
                     var_type = SquatType::NativeFunction(data);
-                    self.compile_error(&format!("Cannot declare native function"));
+                    self.compile_error("Cannot declare native function");
                     None
                 }
                 SquatType::Struct(data) => {
                     var_type = SquatType::Struct(data);
-                    self.compile_error(&format!("Must define struct"));
+                    self.compile_error("Must define struct");
+                    None
+                }
+                SquatType::Optional(data) => {
+                    var_type = SquatType::Optional(data);
+                    None
+                }
+                SquatType::Any => {
+                    var_type = SquatType::Any;
                     None
                 }
                 _ => unreachable!("var_declaration"),
@@ -520,10 +1109,25 @@ impl<'a> Compiler<'a> {
         );
 
         self.define_variable(index, &name, var_type);
+        self.tree_branch(tree_mark, &format!("var {}", name));
     }
 
+    /// The type at the current token, wrapped into an optional if it is
+    /// followed by a '?'
     fn get_type(&mut self) -> Option<SquatType> {
+        let squat_type = self.get_base_type()?;
+        if self.check_current(TokenType::Question) {
+            return Some(SquatType::Optional(Box::new(squat_type)));
+        }
+        Some(squat_type)
+    }
+
+    fn get_base_type(&mut self) -> Option<SquatType> {
         match self.current_token.as_ref().unwrap().token_type {
+            TokenType::AnyType => {
+                self.advance();
+                Some(SquatType::Any)
+            }
             TokenType::BoolType => {
                 self.advance();
                 Some(SquatType::Bool)
@@ -564,6 +1168,17 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    /// Declares the next parameter as a local, `const` before its type makes
+    /// assigning to it an error
+    fn parameter(&mut self) -> Option<SquatType> {
+        let is_const = self.check_current(TokenType::Const);
+        let param_type = self.get_parameter_type().ok()?;
+        let (constant, var_name) = self.parse_variable("Expect parameter name").ok()?;
+        self.define_variable(constant, &var_name, param_type.clone());
+        self.locals.last_mut().unwrap().is_const = is_const;
+        Some(param_type)
+    }
+
     fn get_parameter_type(&mut self) -> Result<SquatType, ()> {
         match self.get_type() {
             Some(paramter_type) => Ok(paramter_type),
@@ -575,8 +1190,7 @@ impl<'a> Compiler<'a> {
     }
 
     fn get_return_type(&mut self) -> Option<SquatType> {
-        let meh = self.get_type();
-        meh
+        self.get_type()
     }
 
     /// Return value:
@@ -591,7 +1205,7 @@ impl<'a> Compiler<'a> {
 
         let name = self.previous_token.as_ref().unwrap().lexeme.clone();
 
-        if let Some(_) = self.resolve_native(&name) {
+        if self.resolve_native(&name).is_some() {
             self.compile_error(&format!("'{}' is a native object", name));
             return Err(());
         }
@@ -612,20 +1226,23 @@ impl<'a> Compiler<'a> {
                     return Err(());
                 }
             }
-            let local = CompilerLocal::new(&name, None, None);
+            let mut local = CompilerLocal::new(&name, None, None);
+            local.line = self.previous_token.as_ref().unwrap().line;
             let index = self.locals.len();
             self.locals.push(local);
+            self.record_local_name(index, &name);
             return Ok((index, name));
         }
 
         let var_name = self.previous_token.as_ref().unwrap().lexeme.clone();
-        if self.globals.get(&var_name).is_some() {
+        if self.globals.contains_key(&var_name) {
             self.compile_error(&format!("{} is allready defined", var_name));
             return Err(());
         }
 
         let index = self.globals.len();
-        let global = CompilerGlobal::new(index, false, None);
+        let mut global = CompilerGlobal::new(index, false, None);
+        global.line = self.previous_token.as_ref().unwrap().line;
         self.globals.insert(var_name, global);
         Ok((index, name))
     }
@@ -668,6 +1285,7 @@ impl<'a> Compiler<'a> {
 
     fn define_variable(&mut self, index: usize, name: &str, squat_type: SquatType) {
         if self.scope_depth > 0 {
+            self.record_local_symbol(name, &squat_type);
             self.locals.last_mut().unwrap().depth = Some(self.scope_depth);
             self.locals.last_mut().unwrap().set_type(squat_type);
             return;
@@ -678,7 +1296,10 @@ impl<'a> Compiler<'a> {
     }
 
     fn return_statement(&mut self, _expected_return_type: SquatType) {
+        let tree_mark = self.tree_mark();
+        self.block_expression_allowed = true;
         let expression_type = self.expression();
+        self.tree_branch(tree_mark, "return");
         if self.function_return_type != expression_type {
             self.compile_error(&format!(
                 "Function has return type '{}' but '{}' was given",
@@ -686,70 +1307,520 @@ impl<'a> Compiler<'a> {
             ));
         }
         self.consume_current(TokenType::Semicolon, "Expected ';' after return value");
+        if !self.open_tries.is_empty() {
+            // Keeps the return value below the locals of the finally blocks
+            self.locals
+                .push(CompilerLocal::new("", Some(self.scope_depth), None));
+            self.close_tries(0);
+            self.locals.pop();
+        }
+        self.emit_deferred_blocks(true);
         self.write_op_code(OpCode::Return);
     }
 
-    fn statement(&mut self) {
-        if self.check_current(TokenType::If) {
-            self.if_statement();
-        } else if self.check_current(TokenType::While) {
-            self.while_statement();
-        } else if self.check_current(TokenType::For) {
-            self.for_statement();
-        } else if self.check_current(TokenType::LeftBrace) {
-            self.begin_scope();
-            self.block(SquatType::Nil);
-            self.end_scope();
-        } else {
-            self.expression_statement();
+    /// The block is skipped here and compiled before every return of the
+    /// function instead, see `emit_deferred_blocks`.
+    fn defer_statement(&mut self) {
+        if self.in_deferred_block {
+            self.compile_error("Cannot defer inside a defer block.");
+            return;
+        }
+        if self.scope_depth != 1 {
+            self.compile_error("Can only defer in the outermost scope of a function.");
+            return;
         }
-    }
-
-    fn if_statement(&mut self) {
-        self.consume_current(TokenType::LeftParenthesis, "Expected '(' after 'if'");
-        self.expression(); // This expression can have any type, no type check required
-        self.consume_current(TokenType::RightParenthesis, "Expected closing ')'");
-
-        let then_jump = self.emit_jump(OpCode::JumpIfFalse(usize::MAX));
-        self.write_op_code(OpCode::Pop);
-        self.statement();
 
-        let else_jump = self.emit_jump(OpCode::Jump(usize::MAX));
-        self.patch_jump(then_jump);
-        self.write_op_code(OpCode::Pop);
+        self.deferred_blocks.push(DeferredBlock {
+            lexer: self.lexer.clone(),
+            previous_token: self.previous_token.clone(),
+            current_token: self.current_token.clone(),
+            local_count: self.locals.len(),
+        });
+        self.tree_leaf("defer");
+        self.skip_block("defer");
+    }
 
-        if self.check_current(TokenType::Else) {
-            self.statement();
+    /// Skips over a block that is compiled somewhere else
+    ///
+    /// # Arguments
+    /// * `keyword` - The keyword in front of the block
+    fn skip_block(&mut self, keyword: &str) {
+        self.consume_current(
+            TokenType::LeftBrace,
+            &format!("Expected '{{' after '{}'", keyword),
+        );
+        let mut depth = 1;
+        while depth > 0 {
+            if self.check_current(TokenType::Eof) {
+                self.compile_error(&format!(
+                    "Expected closing '}}' to end the {} block",
+                    keyword
+                ));
+                return;
+            }
+            if self.check_current(TokenType::LeftBrace) {
+                depth += 1;
+            } else if self.check_current(TokenType::RightBrace) {
+                depth -= 1;
+            } else {
+                self.advance();
+            }
         }
-
-        self.patch_jump(else_jump);
     }
 
-    fn while_statement(&mut self) {
-        let loop_start = self.main_chunk.get_size();
+    /// Skips the rest of a function or struct declaration that can't be
+    /// compiled, its body included. `synchronize` would stop at the
+    /// parameters and compile the body as statements of the enclosing scope.
+    fn skip_declaration(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.current_token.as_ref().unwrap().token_type {
+                TokenType::Eof => return,
+                TokenType::LeftParenthesis | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParenthesis => depth -= 1,
+                TokenType::RightBrace => {
+                    depth -= 1;
+                    if depth <= 0 {
+                        self.advance();
+                        return;
+                    }
+                }
+                TokenType::Semicolon if depth <= 0 => {
+                    self.advance();
+                    return;
+                }
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    /// Skips the rest of an expression after an illegal token, up to the token
+    /// that ends it. The statement around the expression carries on, so a
+    /// `for` keeps its loop variable and compiles its body in its own scope.
+    fn skip_expression(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.current_token.as_ref().unwrap().token_type {
+                TokenType::Eof
+                | TokenType::Semicolon
+                | TokenType::LeftBrace
+                | TokenType::RightBrace => return,
+                TokenType::Comma if depth == 0 => return,
+                TokenType::RightParenthesis | TokenType::RightBracket if depth == 0 => return,
+                TokenType::LeftParenthesis | TokenType::LeftBracket => depth += 1,
+                TokenType::RightParenthesis | TokenType::RightBracket => depth -= 1,
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    /// Compiles the deferred blocks of the current function in reverse order.
+    ///
+    /// # Arguments
+    /// * `has_return_value` - Whether a return value on the stack has to be kept
+    fn emit_deferred_blocks(&mut self, has_return_value: bool) {
+        if self.deferred_blocks.is_empty() {
+            return;
+        }
+
+        let lexer = self.lexer.clone();
+        let previous_token = self.previous_token.clone();
+        let current_token = self.current_token.clone();
+        if has_return_value {
+            self.locals
+                .push(CompilerLocal::new("", Some(self.scope_depth), None));
+        }
+
+        let deferred_blocks = std::mem::take(&mut self.deferred_blocks);
+        // Already recorded where the blocks were declared
+        let parse_tree = self.parse_tree.take();
+        self.in_deferred_block = true;
+        for deferred_block in deferred_blocks.iter().rev() {
+            self.saved_block(deferred_block, "defer");
+        }
+        self.in_deferred_block = false;
+        self.parse_tree = parse_tree;
+        self.deferred_blocks = deferred_blocks;
+
+        if has_return_value {
+            self.locals.pop();
+        }
+        self.lexer = lexer;
+        self.previous_token = previous_token;
+        self.current_token = current_token;
+    }
+
+    /// Compiles a block from where its lexer was saved, the locals declared
+    /// after it are hidden from it. The caller restores the lexer.
+    fn saved_block(&mut self, saved_block: &DeferredBlock<'a>, keyword: &str) {
+        self.lexer = saved_block.lexer.clone();
+        self.previous_token = saved_block.previous_token.clone();
+        self.current_token = saved_block.current_token.clone();
+
+        let hidden_names: Vec<String> = self.locals[saved_block.local_count..]
+            .iter_mut()
+            .map(|local| std::mem::take(&mut local.name))
+            .collect();
+
+        self.consume_current(
+            TokenType::LeftBrace,
+            &format!("Expected '{{' after '{}'", keyword),
+        );
+        self.begin_scope();
+        self.block(SquatType::Nil);
+        self.end_scope();
+
+        for (local, name) in self.locals[saved_block.local_count..]
+            .iter_mut()
+            .zip(hidden_names)
+        {
+            local.name = name;
+        }
+    }
+
+    /// Compiles a `finally` block at one of the exits of its try statement
+    fn emit_finally_block(&mut self, finally: &DeferredBlock<'a>) {
+        let lexer = self.lexer.clone();
+        let previous_token = self.previous_token.clone();
+        let current_token = self.current_token.clone();
+        // Already recorded after the catch block
+        let parse_tree = self.parse_tree.take();
+        self.saved_block(finally, "finally");
+        self.parse_tree = parse_tree;
+        self.lexer = lexer;
+        self.previous_token = previous_token;
+        self.current_token = current_token;
+    }
+
+    fn statement(&mut self) {
+        let tree_mark = self.tree_mark();
+        let label = if self.check_current(TokenType::If) {
+            self.if_statement();
+            "if"
+        } else if self.check_current(TokenType::While) {
+            self.while_statement();
+            "while"
+        } else if self.check_current(TokenType::For) {
+            self.for_statement();
+            "for"
+        } else if self.check_current(TokenType::Match) {
+            self.match_statement();
+            "match"
+        } else if self.check_current(TokenType::Break) {
+            self.break_statement();
+            "break"
+        } else if self.check_current(TokenType::Continue) {
+            self.continue_statement();
+            "continue"
+        } else if self.check_current(TokenType::Try) {
+            self.try_statement();
+            "try"
+        } else if self.check_current(TokenType::Throw) {
+            self.throw_statement();
+            "throw"
+        } else if self.check_current(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block(SquatType::Nil);
+            self.end_scope();
+            "block"
+        } else if self.legacy_print
+            && self.current_token.as_ref().unwrap().token_type == TokenType::Identifier
+            && self.current_token.as_ref().unwrap().lexeme == "print"
+        {
+            self.advance();
+            self.print_statement();
+            "print"
+        } else {
+            self.expression_statement();
+            "expr"
+        };
+        self.tree_branch(tree_mark, label);
+    }
+
+    /// An `else if` chain is compiled as a single statement, every branch
+    /// jumps straight to the end of the chain.
+    fn if_statement(&mut self) {
+        let mut end_jumps = Vec::new();
+        loop {
+            self.consume_current(TokenType::LeftParenthesis, "Expected '(' after 'if'");
+            self.expression(); // This expression can have any type, no type check required
+            self.consume_current(TokenType::RightParenthesis, "Expected closing ')'");
+
+            let then_jump = self.emit_jump(OpCode::JumpIfFalse(usize::MAX));
+            self.write_op_code(OpCode::Pop);
+            self.statement();
+
+            end_jumps.push(self.emit_jump(OpCode::Jump(usize::MAX)));
+            self.patch_jump(then_jump);
+            self.write_op_code(OpCode::Pop);
+
+            if !self.check_current(TokenType::Else) {
+                break;
+            }
+            if !self.check_current(TokenType::If) {
+                self.statement();
+                break;
+            }
+        }
+
+        for end_jump in end_jumps {
+            self.patch_jump(end_jump);
+        }
+    }
+
+    /// Pops the locals declared in the body of the loop so far and jumps past
+    /// the end of the loop
+    fn break_statement(&mut self) {
+        let Some(local_count) = self.enclosing_loop_locals("break") else {
+            return;
+        };
+        self.consume_current(TokenType::Semicolon, "Expect ';' after 'break'");
+        self.close_loop_tries();
+        self.pop_loop_locals(local_count);
+        let jump = self.emit_jump(OpCode::Jump(usize::MAX));
+        self.loops.last_mut().unwrap().break_jumps.push(jump);
+    }
+
+    /// Pops the locals declared in the body of the loop so far and starts its
+    /// next iteration
+    fn continue_statement(&mut self) {
+        let Some(local_count) = self.enclosing_loop_locals("continue") else {
+            return;
+        };
+        self.consume_current(TokenType::Semicolon, "Expect ';' after 'continue'");
+        self.close_loop_tries();
+        self.pop_loop_locals(local_count);
+        match self.loops.last().unwrap().continue_target {
+            Some(target) => self.emit_loop(target),
+            None => {
+                let jump = self.emit_jump(OpCode::Jump(usize::MAX));
+                self.loops.last_mut().unwrap().continue_jumps.push(jump);
+            }
+        }
+    }
+
+    fn enclosing_loop_locals(&mut self, keyword: &str) -> Option<usize> {
+        if self.in_deferred_block {
+            self.compile_error(&format!("Cannot {} out of a defer block.", keyword));
+            return None;
+        }
+        let local_count = self.loops.last().map(|enclosing| enclosing.local_count);
+        if local_count.is_none() {
+            self.compile_error(&format!("Cannot {} outside of a loop.", keyword));
+        }
+        local_count
+    }
+
+    /// Only the locals that are initialized are on the stack, a declaration
+    /// pushes its value after the body of a block expression
+    fn pop_loop_locals(&mut self, local_count: usize) {
+        for local_index in (local_count..self.locals.len()).rev() {
+            if self.locals[local_index].depth.is_some() {
+                self.emit_deinit(local_index);
+                self.write_op_code(OpCode::Pop);
+            }
+        }
+    }
+
+    /// Stops catching errors in the `try` blocks that `break` or `continue`
+    /// jumps out of
+    fn close_loop_tries(&mut self) {
+        let open_tries = self.loops.last().unwrap().open_tries;
+        self.close_tries(open_tries);
+    }
+
+    /// Stops catching errors in the open `try` blocks past the given count,
+    /// innermost first, and compiles their `finally` blocks. A `finally` block
+    /// still has the `try` blocks around it open.
+    fn close_tries(&mut self, open_tries: usize) {
+        let mut closed = Vec::new();
+        while self.open_tries.len() > open_tries {
+            let open_try = self.open_tries.pop().unwrap();
+            self.write_op_code(OpCode::EndTry);
+            if let Some(finally) = &open_try.finally {
+                self.emit_finally_block(finally);
+            }
+            closed.push(open_try);
+        }
+        // The code after the jump is still in the blocks
+        self.open_tries.extend(closed.into_iter().rev());
+    }
+
+    /// Looks past the try and catch blocks for a `finally` block, it is
+    /// compiled again at every exit of them. The current token is the '{' of
+    /// the try block.
+    fn find_finally_block(&self) -> Option<DeferredBlock<'a>> {
+        let mut lexer = self.lexer.clone();
+        let mut depth = 1;
+        let mut closed_blocks = 0;
+        loop {
+            let Ok(token) = lexer.scan_token() else {
+                continue;
+            };
+            match token.token_type {
+                TokenType::Eof => return None,
+                _ if depth == 0 && closed_blocks == 2 => {
+                    if token.token_type != TokenType::Finally {
+                        return None;
+                    }
+                    let current_token = lexer.scan_token().ok();
+                    return Some(DeferredBlock {
+                        lexer,
+                        previous_token: Some(token),
+                        current_token,
+                        local_count: self.locals.len(),
+                    });
+                }
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        closed_blocks += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Runtime errors in the try block jump to the catch block, with the
+    /// stacks as they were before the try block and the message as its
+    /// string variable. The `finally` block is compiled at the end of both,
+    /// before every jump out of them and before passing on an error of the
+    /// catch block.
+    fn try_statement(&mut self) {
+        let finally = self.find_finally_block();
+        let try_jump = self.emit_jump(OpCode::Try(usize::MAX));
+        self.consume_current(TokenType::LeftBrace, "Expected '{' after 'try'");
+        self.open_tries.push(OpenTry {
+            finally: finally.clone(),
+        });
+        self.begin_scope();
+        self.block(SquatType::Nil);
+        self.end_scope();
+        self.open_tries.pop();
+        self.write_op_code(OpCode::EndTry);
+        if let Some(finally) = &finally {
+            self.emit_finally_block(finally);
+        }
+        let end_jump = self.emit_jump(OpCode::Jump(usize::MAX));
+
+        self.patch_jump(try_jump);
+        self.consume_current(TokenType::Catch, "Expected 'catch' after the try block");
+        self.consume_current(TokenType::LeftParenthesis, "Expected '(' after 'catch'");
+        self.begin_scope();
+        if let Ok((index, name)) = self.parse_variable("Expected a name for the error") {
+            self.define_variable(index, &name, SquatType::String);
+        }
+        self.consume_current(TokenType::RightParenthesis, "Expected closing ')'");
+        self.consume_current(TokenType::LeftBrace, "Expected '{' after 'catch'");
+        let Some(finally) = finally else {
+            self.block(SquatType::Nil);
+            self.end_scope();
+            self.patch_jump(end_jump);
+            return;
+        };
+
+        let rethrow_jump = self.emit_jump(OpCode::Try(usize::MAX));
+        self.open_tries.push(OpenTry {
+            finally: Some(finally.clone()),
+        });
+        self.block(SquatType::Nil);
+        self.open_tries.pop();
+        self.write_op_code(OpCode::EndTry);
+        self.end_scope();
+        self.emit_finally_block(&finally);
+        let catch_end_jump = self.emit_jump(OpCode::Jump(usize::MAX));
+
+        // An error in the catch block is thrown again after the finally block,
+        // the error variable and the message are on the stack
+        self.patch_jump(rethrow_jump);
+        for _ in 0..2 {
+            self.locals
+                .push(CompilerLocal::new("", Some(self.scope_depth), None));
+        }
+        self.emit_finally_block(&finally);
+        let message_index = self.locals.len() - 1;
+        self.write_op_code(OpCode::GetLocal(message_index));
+        self.write_op_code(OpCode::Throw);
+        self.locals.truncate(message_index - 1);
+
+        self.patch_jump(catch_end_jump);
+        self.patch_jump(end_jump);
+        self.check_current(TokenType::Finally);
+        self.tree_leaf("finally");
+        self.skip_block("finally");
+    }
+
+    /// The value is the message of the runtime error, a catch block gets it
+    /// as a string
+    fn throw_statement(&mut self) {
+        let line = self.previous_token.as_ref().unwrap().line;
+        self.expression();
+        self.consume_current(TokenType::Semicolon, "Expected ';' after the thrown value");
+        self.write_op_code_at_line(OpCode::Throw, line);
+    }
+
+    /// Points the `break`s of the innermost loop at the current instruction
+    fn end_loop(&mut self) {
+        let enclosing = self.loops.pop().unwrap();
+        for jump in enclosing.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.main_chunk.get_size();
+        let local_count = self.locals.len();
+        let user_function_calls = self.user_function_calls;
         self.consume_current(TokenType::LeftParenthesis, "Expected '(' after 'while'");
         self.expression(); // This expression can have any type, no type check required
         self.consume_current(TokenType::RightParenthesis, "Expected closing ')'");
 
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse(usize::MAX));
         self.write_op_code(OpCode::Pop);
+        self.loops.push(Loop::new(
+            local_count,
+            Some(loop_start),
+            self.open_tries.len(),
+        ));
         self.statement();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.write_op_code(OpCode::Pop);
+        self.end_loop();
+
+        if self.user_function_calls == user_function_calls {
+            self.hoist_loop_invariants(loop_start, local_count);
+        }
     }
 
     fn for_statement(&mut self) {
         self.begin_scope();
 
         self.consume_current(TokenType::LeftParenthesis, "Expected '(' after 'for'");
+        let mut lexer = self.lexer.clone();
+        if matches!(
+            (lexer.scan_token(), lexer.scan_token()),
+            (Ok(name), Ok(colon))
+                if name.token_type == TokenType::Identifier && colon.token_type == TokenType::Colon
+        ) {
+            self.for_each_statement();
+            self.end_scope();
+            return;
+        }
         if self.try_var_declaration() {
         } else if !self.check_current(TokenType::Semicolon) {
             self.expression_statement();
         }
 
         let mut loop_start = self.main_chunk.get_size();
+        let condition_start = loop_start;
+        let local_count = self.locals.len();
+        let user_function_calls = self.user_function_calls;
         let mut exit_jump: Option<usize> = None;
         if !self.check_current(TokenType::Semicolon) {
             self.expression(); // This expression can have any type, no type check required
@@ -763,13 +1834,18 @@ impl<'a> Compiler<'a> {
             let body_jump = self.emit_jump(OpCode::Jump(usize::MAX));
             let increment_start = self.main_chunk.get_size();
             self.expression();
-            self.write_op_code(OpCode::Pop);
+            self.pop_expression_value(increment_start);
             self.consume_current(TokenType::RightParenthesis, "Expect closing ')'");
             self.emit_loop(loop_start);
             loop_start = increment_start;
             self.patch_jump(body_jump);
         }
 
+        self.loops.push(Loop::new(
+            local_count,
+            Some(loop_start),
+            self.open_tries.len(),
+        ));
         self.statement();
         self.emit_loop(loop_start);
 
@@ -777,7 +1853,182 @@ impl<'a> Compiler<'a> {
             self.patch_jump(exit_jump);
             self.write_op_code(OpCode::Pop);
         }
+        self.end_loop();
+
+        if self.user_function_calls == user_function_calls {
+            self.hoist_loop_invariants(condition_start, local_count);
+        }
+        self.end_scope();
+    }
+
+    /// `for (int i : range(0, 5))` and `for (var name : names)` pull the
+    /// elements of a range or an array one at a time. The iterated value and
+    /// a cursor into it are kept in hidden locals.
+    fn for_each_statement(&mut self) {
+        let declared_type = if self.check_current(TokenType::Var) {
+            None
+        } else {
+            match self.get_type() {
+                Some(squat_type) => Some(squat_type),
+                None => {
+                    self.compile_error("Expected variable type for the loop variable");
+                    return;
+                }
+            }
+        };
+        self.consume_current(TokenType::Identifier, "Expect loop variable name");
+        let name = self.previous_token.as_ref().unwrap().lexeme.clone();
+        let line = self.previous_token.as_ref().unwrap().line;
+        if self.resolve_native(&name).is_some() {
+            self.compile_error(&format!("'{}' is a native object", name));
+            return;
+        }
+        self.consume_current(TokenType::Colon, "Expected ':' after loop variable");
+
+        let iterable_type = self.expression();
+        self.consume_current(TokenType::RightParenthesis, "Expect closing ')'");
+        let element_type = match &iterable_type {
+            SquatType::Range => SquatType::Int,
+            SquatType::Array(element_type) => *element_type.clone(),
+            SquatType::Any => SquatType::Any,
+            _ => {
+                self.compile_error(&format!(
+                    "Can only iterate over arrays and ranges but '{}' was given",
+                    iterable_type
+                ));
+                return;
+            }
+        };
+        self.check_types(declared_type.clone(), &element_type);
+
+        let iterable_index = self.locals.len();
+        self.locals.push(CompilerLocal::new(
+            "",
+            Some(self.scope_depth),
+            Some(iterable_type),
+        ));
+        let cursor_index = self.locals.len();
+        let zero = self.constants.write(SquatValue::Int(0));
+        self.write_op_code(OpCode::Constant(zero));
+        self.locals.push(CompilerLocal::new(
+            "",
+            Some(self.scope_depth),
+            Some(SquatType::Int),
+        ));
+
+        let loop_start = self.main_chunk.get_size();
+        let local_count = self.locals.len();
+        let user_function_calls = self.user_function_calls;
+        self.write_op_code(OpCode::GetLocal(iterable_index));
+        self.write_op_code(OpCode::GetLocal(cursor_index));
+        self.write_op_code(OpCode::IterNext);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(usize::MAX));
+        self.write_op_code(OpCode::Pop);
+
+        // The element left by `IterNext` is the loop variable
+        self.begin_scope();
+        let element_index = self.locals.len();
+        let element_type = declared_type.unwrap_or(element_type);
+        self.record_local_symbol(&name, &element_type);
+        let mut element = CompilerLocal::new(&name, Some(self.scope_depth), Some(element_type));
+        element.line = line;
+        self.locals.push(element);
+        self.record_local_name(element_index, &name);
+        self.loops
+            .push(Loop::new(local_count, None, self.open_tries.len()));
+        self.statement();
+        self.end_scope();
+
+        // `continue` skips to moving the cursor
+        for jump in std::mem::take(&mut self.loops.last_mut().unwrap().continue_jumps) {
+            self.patch_jump(jump);
+        }
+        let one = self.constants.write(SquatValue::Int(1));
+        self.write_op_code(OpCode::GetLocal(cursor_index));
+        self.write_op_code(OpCode::Constant(one));
+        self.write_op_code(OpCode::Add);
+        self.write_op_code(OpCode::SetLocal(cursor_index));
+        self.write_op_code(OpCode::Pop);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.write_op_code(OpCode::Pop);
+        self.write_op_code(OpCode::Pop);
+        self.end_loop();
+
+        if self.user_function_calls == user_function_calls {
+            self.hoist_loop_invariants(loop_start, local_count);
+        }
+    }
+
+    /// ```
+    /// match value {
+    ///     int i: println(i + 1);
+    ///     string s: println(s + "!");
+    ///     else: println("something else");
+    /// }
+    /// ```
+    /// Runs the first arm whose type matches the runtime type of the value and
+    /// binds the value to the arm's name.
+    fn match_statement(&mut self) {
+        self.begin_scope();
+        self.expression();
+        // The value stays on the stack while the arms are checked
+        self.locals
+            .push(CompilerLocal::new("", Some(self.scope_depth), None));
+        let value_index = self.locals.len() - 1;
+
+        self.consume_current(TokenType::LeftBrace, "Expected '{' after match value");
+        let mut end_jumps = Vec::new();
+        while !self.check_current(TokenType::RightBrace) {
+            if self.check_current(TokenType::Eof) {
+                self.compile_error("Expected closing '}' to end the match body");
+                break;
+            }
+
+            if self.check_current(TokenType::Else) {
+                self.consume_current(TokenType::Colon, "Expected ':' after 'else'");
+                self.statement();
+                end_jumps.push(self.emit_jump(OpCode::Jump(usize::MAX)));
+                continue;
+            }
+
+            let arm_type = match self.get_type() {
+                Some(SquatType::Function(_)) => {
+                    self.compile_error("Cannot match on function types");
+                    return;
+                }
+                Some(arm_type) => arm_type,
+                None => {
+                    self.compile_error("Expected a type for the match arm");
+                    return;
+                }
+            };
+            let type_index = self.constants.write(SquatValue::Type(arm_type.clone()));
+            self.write_op_code(OpCode::GetLocal(value_index));
+            self.write_op_code(OpCode::IsType(type_index));
+            let next_arm_jump = self.emit_jump(OpCode::JumpIfFalse(usize::MAX));
+            self.write_op_code(OpCode::Pop);
+
+            self.begin_scope();
+            let (index, name) = match self.parse_variable("Expected name for the match arm") {
+                Ok(value) => value,
+                Err(()) => return,
+            };
+            self.consume_current(TokenType::Colon, "Expected ':' after match arm name");
+            self.write_op_code(OpCode::GetLocal(value_index));
+            self.define_variable(index, &name, arm_type);
+            self.statement();
+            self.end_scope();
+
+            end_jumps.push(self.emit_jump(OpCode::Jump(usize::MAX)));
+            self.patch_jump(next_arm_jump);
+            self.write_op_code(OpCode::Pop);
+        }
 
+        for end_jump in end_jumps {
+            self.patch_jump(end_jump);
+        }
         self.end_scope();
     }
 
@@ -791,14 +2042,45 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume_current(TokenType::Semicolon, "Expect ';' after value");
+        self.write_op_code(OpCode::Print);
+    }
+
     fn expression_statement(&mut self) {
+        let expression_start = self.main_chunk.get_size();
         self.expression();
         self.consume_current(TokenType::Semicolon, "Expect ';' after expression");
+        self.pop_expression_value(expression_start);
+    }
+
+    /// Pops the value of an expression whose code starts at
+    /// `expression_start`. When the expression is exactly `i = i + 1` on an
+    /// int local, it is folded into an `IncLocal` that pushes nothing.
+    fn pop_expression_value(&mut self, expression_start: usize) {
+        if self.main_chunk.get_size() == expression_start + 4 {
+            let code: Vec<OpCode> = (expression_start..expression_start + 4)
+                .map(|op_index| self.main_chunk.get_instruction(op_index))
+                .collect();
+            if let [OpCode::GetLocal(index), OpCode::Constant(constant), OpCode::Add, OpCode::SetLocal(set_index)] =
+                code[..]
+            {
+                if let SquatValue::Int(amount) = self.constants.get(constant) {
+                    if index == set_index && self.locals[index].get_type() == SquatType::Int {
+                        let amount = *amount;
+                        self.main_chunk.truncate(expression_start);
+                        self.write_op_code(OpCode::IncLocal(index, amount));
+                        return;
+                    }
+                }
+            }
+        }
         self.write_op_code(OpCode::Pop);
     }
 
     //////////////////////////////////////////////////////////////////////////
-    /// Expression rules
+    // Expression rules
     //////////////////////////////////////////////////////////////////////////
 
     fn parse_precedence(
@@ -806,41 +2088,72 @@ impl<'a> Compiler<'a> {
         precedence: Precedence,
         expected_type: Option<SquatType>,
     ) -> SquatType {
+        // The condition of a ternary and the operands of a comparison can have
+        // any type, only the value of the whole expression has to match the
+        // expected type
+        // Arithmetic can mix ints and floats unless the types are strict, its
+        // operands don't have to be of the expected number type either
+        let mixes_numbers =
+            !self.strict_types && matches!(expected_type, Some(SquatType::Int | SquatType::Float));
+        let is_operand =
+            expected_type.is_some() && self.typed_operator_follows(precedence, mixes_numbers);
         self.advance();
+        let tree_mark = self.tree_mark();
+        let prefix_token = self.parse_tree.as_ref().and(self.previous_token.clone());
         let prefix_type = self.call_prefix(
             self.previous_token.as_ref().unwrap().token_type,
-            expected_type.clone(),
+            if is_operand {
+                None
+            } else {
+                expected_type.clone()
+            },
         );
-        if !self.check_types(expected_type.clone(), &prefix_type) {
+        if let Some(prefix_token) = prefix_token {
+            self.tree_prefix(tree_mark, prefix_token);
+        }
+        if !is_operand && !self.check_types(expected_type.clone(), &prefix_type) {
             return expected_type.unwrap();
         }
 
+        let mut expression_type = prefix_type;
         while precedence <= self.get_precedence(self.current_token.as_ref().unwrap().token_type) {
             self.advance();
 
+            // The left operand is already recorded
+            let tree_mark = self.tree_mark().saturating_sub(1);
+            let operator = self.previous_token.as_ref().unwrap().lexeme.clone();
             if self.check_previous(TokenType::Question) {
-                return self.ternary(expected_type);
+                let ternary_type = self.ternary(expected_type);
+                self.tree_branch(tree_mark, &operator);
+                return ternary_type;
             }
-            self.call_infix(
+            expression_type = self.call_infix(
                 self.previous_token.as_ref().unwrap().token_type,
-                Some(prefix_type.clone()),
+                Some(expression_type),
             );
+            self.tree_branch(tree_mark, &operator);
         }
 
-        prefix_type
+        if is_operand {
+            self.check_types(expected_type, &expression_type);
+        }
+        expression_type
     }
 
+    /// Both branches are parsed at ternary precedence, so ternaries nest to
+    /// the right: `a ? b : c ? d : e` is `a ? b : (c ? d : e)` and
+    /// `a ? b ? c : d : e` is `a ? (b ? c : d) : e`
     fn ternary(&mut self, expected_type: Option<SquatType>) -> SquatType {
         let else_jump = self.emit_jump(OpCode::JumpIfFalse(usize::MAX));
         self.write_op_code(OpCode::Pop);
-        let expression_type = self.parse_precedence(Precedence::Ternary + 1, expected_type.clone());
+        let expression_type = self.parse_precedence(Precedence::Ternary, expected_type.clone());
 
         let end_jump = self.emit_jump(OpCode::Jump(usize::MAX));
         self.patch_jump(else_jump);
         self.write_op_code(OpCode::Pop);
         self.consume_current(TokenType::Colon, "Expect ':' after true ternary block");
 
-        self.parse_precedence(Precedence::Ternary + 1, Some(expression_type.clone()));
+        self.parse_precedence(Precedence::Ternary, Some(expression_type.clone()));
         self.patch_jump(end_jump);
 
         expression_type
@@ -863,47 +2176,223 @@ impl<'a> Compiler<'a> {
     }
 
     fn binary(&mut self, expected_type: Option<SquatType>) -> SquatType {
-        let token_type = self.previous_token.as_ref().unwrap().clone().token_type;
+        // Operations are attributed to the line of the operator, the operand
+        // can be on a later line
+        let operator = self.previous_token.clone().unwrap();
+        let token_type = operator.token_type;
+        if matches!(
+            token_type,
+            TokenType::GreaterGreater | TokenType::GreaterGreaterGreater
+        ) {
+            return self.shift(token_type, operator.line, expected_type);
+        }
+
+        let is_arithmetic = matches!(
+            token_type,
+            TokenType::Plus
+                | TokenType::Minus
+                | TokenType::Star
+                | TokenType::Slash
+                | TokenType::Percent
+        );
+        let lhs_type = expected_type.clone().unwrap_or_default();
+        // `==` and `!=` are how optionals are checked for nil
+        let takes_optionals = matches!(token_type, TokenType::EqualEqual | TokenType::BangEqual);
+        let lhs_unwrapped = takes_optionals || self.check_unwrapped(&operator, &lhs_type);
+        // Ints and floats can be mixed, `1 + 2.0` is `3.0` and `1 == 1.0` is
+        // true. Strict mode only lets numbers of the same type meet.
+        let expected_type = match expected_type {
+            Some(SquatType::Int | SquatType::Float) if !self.strict_types => {
+                Some(SquatType::Number)
+            }
+            expected_type => expected_type,
+        };
 
         let precedence = self.get_precedence(token_type);
         let rhs_type = self.parse_precedence(precedence + 1, expected_type.clone());
-        self.check_types(expected_type, &rhs_type);
+        if self.check_types(expected_type, &rhs_type) && lhs_unwrapped && !takes_optionals {
+            self.check_unwrapped(&operator, &rhs_type);
+        }
 
-        match token_type {
-            TokenType::Plus => self.write_op_code(OpCode::Add),
-            TokenType::Minus => self.write_op_code(OpCode::Subtract),
-            TokenType::Star => self.write_op_code(OpCode::Multiply),
-            TokenType::Slash => self.write_op_code(OpCode::Divide),
-            TokenType::Percent => self.write_op_code(OpCode::Mod),
-
-            TokenType::BangEqual => self.write_op_code(OpCode::NotEqual),
-            TokenType::EqualEqual => self.write_op_code(OpCode::Equal),
-            TokenType::Greater => self.write_op_code(OpCode::Greater),
-            TokenType::GreaterEqual => self.write_op_code(OpCode::GreaterEqual),
-            TokenType::Less => self.write_op_code(OpCode::Less),
-            TokenType::LessEqual => self.write_op_code(OpCode::LessEqual),
+        if self.strict_types
+            && is_arithmetic
+            && (matches!(lhs_type, SquatType::Any) || matches!(rhs_type, SquatType::Any))
+        {
+            self.compile_error_at_line(
+                operator.line,
+                &format!(
+                    "Operands of '{}' must have known types in strict mode but found {} and {}",
+                    operator.lexeme, lhs_type, rhs_type
+                ),
+            );
+        }
+
+        let op_code = match token_type {
+            TokenType::Plus => OpCode::Add,
+            TokenType::Minus => OpCode::Subtract,
+            TokenType::Star => OpCode::Multiply,
+            TokenType::Slash => OpCode::Divide,
+            TokenType::Percent => OpCode::Mod,
+
+            TokenType::BangEqual => OpCode::NotEqual,
+            TokenType::EqualEqual => OpCode::Equal,
+            TokenType::Greater => OpCode::Greater,
+            TokenType::GreaterEqual => OpCode::GreaterEqual,
+            TokenType::Less => OpCode::Less,
+            TokenType::LessEqual => OpCode::LessEqual,
 
             _ => unreachable!(),
-        }
+        };
+        self.write_op_code_at_line(op_code, operator.line);
 
-        rhs_type
+        match (lhs_type, rhs_type) {
+            _ if !is_arithmetic => SquatType::Bool,
+            (SquatType::Float, SquatType::Int) | (SquatType::Int, SquatType::Float) => {
+                SquatType::Float
+            }
+            (_, rhs_type) => rhs_type,
+        }
     }
 
-    fn call(&mut self, object_data: SquatType) -> SquatType {
-        let return_type = match object_data {
-            SquatType::Function(data) | SquatType::NativeFunction(data) => {
-                let mut arg_count = 0;
-                if !self.check_current(TokenType::RightParenthesis) {
-                    while !self.check_current(TokenType::RightParenthesis)
-                        && arg_count <= data.get_arity()
-                    {
-                        let expression_type = self.expression();
-                        self.check_types(Some(data.get_param_type(arg_count)), &expression_type);
-                        arg_count += 1;
-                        self.check_current(TokenType::Comma);
-                    }
+    /// `>>` is an arithmetic shift that keeps the sign, so `-8 >> 1` is `-4`.
+    /// `>>>` is a logical shift that fills with zeros, so shifting a negative
+    /// number gives a large positive one. Only the lowest 6 bits of the shift
+    /// amount are used.
+    fn shift(
+        &mut self,
+        token_type: TokenType,
+        line: u32,
+        lhs_type: Option<SquatType>,
+    ) -> SquatType {
+        if let Some(lhs_type) = lhs_type {
+            self.check_types(Some(SquatType::Int), &lhs_type);
+        }
+        let precedence = self.get_precedence(token_type);
+        self.parse_precedence(precedence + 1, Some(SquatType::Int));
+
+        let op_code = match token_type {
+            TokenType::GreaterGreater => OpCode::ShiftRight,
+            TokenType::GreaterGreaterGreater => OpCode::ShiftRightLogical,
+            _ => unreachable!(),
+        };
+        self.write_op_code_at_line(op_code, line);
+
+        SquatType::Int
+    }
+
+    /// Compiles the arguments up to the closing ')' and returns how many there
+    /// were. A trailing comma is allowed. `callee` names the function in type
+    /// errors.
+    fn argument_list(&mut self, param_types: &[SquatType], callee: &str) -> Vec<Argument> {
+        let mut args = Vec::new();
+        while !self.check_current(TokenType::RightParenthesis) {
+            let arg_count = args.len();
+            let arg_start = self.main_chunk.get_size();
+            let expression_type = self.expression();
+            if let Some(param_type) = param_types.get(arg_count) {
+                if !types_match(param_type, &expression_type) {
+                    self.coded_compile_error(
+                        error_code::TYPE_MISMATCH,
+                        &format!(
+                            "Argument {} of '{}': expected {} but found {}",
+                            arg_count + 1,
+                            callee,
+                            param_type,
+                            expression_type
+                        ),
+                    );
+                }
+            }
+            // An argument that failed to compile can leave no code behind
+            let literal = if self.main_chunk.get_size() == arg_start + 1 {
+                match self.main_chunk.get_instruction(arg_start) {
+                    OpCode::Constant(index) => match self.constants.get(index) {
+                        SquatValue::String(value) => Some(value.clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            args.push(Argument {
+                squat_type: expression_type,
+                literal,
+            });
+
+            if !self.check_current(TokenType::Comma) {
+                self.consume_current(
+                    TokenType::RightParenthesis,
+                    "Expected ',' or ')' after argument",
+                );
+                break;
+            }
+        }
+        args
+    }
+
+    /// A format string given as a literal to `format` or `assert_that` has to
+    /// have a `{}` for every value after it
+    fn check_format_string(&mut self, callee: &str, args: &[Argument]) {
+        let format_index = match callee {
+            "format" => 0,
+            "assert_that" => 1,
+            _ => return,
+        };
+        let Some(format) = args.get(format_index).and_then(|arg| arg.literal.clone()) else {
+            return;
+        };
+        let placeholder_count = format.matches("{}").count();
+        let value_count = args.len() - format_index - 1;
+        if placeholder_count != value_count {
+            self.compile_error(&format!(
+                "Format string has {} placeholders but {} values were given",
+                placeholder_count, value_count
+            ));
+        }
+    }
+
+    /// `abs(x)` takes the absolute value in place with `Abs` rather than
+    /// calling the native, an int or float keeps its type
+    fn abs_call(&mut self) -> SquatType {
+        let line = self.previous_token.as_ref().unwrap().line;
+        let args = self.argument_list(&[SquatType::Number], "abs");
+        if args.len() != 1 {
+            self.compile_error(&format!("Expected 1 arguments but got {}.", args.len()));
+        }
+        self.write_op_code_at_line(OpCode::Abs, line);
+        match args.first().map(|arg| &arg.squat_type) {
+            Some(arg_type @ (SquatType::Int | SquatType::Float)) => arg_type.clone(),
+            _ => SquatType::Number,
+        }
+    }
+
+    fn call(&mut self, object_data: SquatType, callee: &str) -> SquatType {
+        // Line of the '(' rather than of the closing ')'
+        let line = self.previous_token.as_ref().unwrap().line;
+        let calls_user_functions = match object_data {
+            SquatType::Function(_) => true,
+            SquatType::NativeFunction(_) => self.native_calls_back(callee),
+            _ => false,
+        };
+        if calls_user_functions {
+            self.user_function_calls += 1;
+        }
+        let is_native = matches!(object_data, SquatType::NativeFunction(_));
+        let return_type = match object_data {
+            SquatType::Function(data) | SquatType::NativeFunction(data) => {
+                let args = self.argument_list(&data.param_types, callee);
+                if is_native {
+                    self.check_format_string(callee, &args);
                 }
-                if arg_count != data.get_arity() {
+                let arg_count = args.len();
+                if data.is_variadic() && arg_count < data.get_arity() {
+                    self.compile_error(&format!(
+                        "Expected at least {} arguments but got {}.",
+                        data.get_arity(),
+                        arg_count
+                    ));
+                } else if !data.is_variadic() && arg_count != data.get_arity() {
                     self.compile_error(&format!(
                         "Expected {} arguments but got {}.",
                         data.get_arity(),
@@ -911,22 +2400,23 @@ impl<'a> Compiler<'a> {
                     ));
                 }
 
-                self.write_op_code(OpCode::Call(arg_count));
+                self.write_op_code_at_line(OpCode::Call(arg_count), line);
                 data.get_return_type()
             }
             SquatType::Struct(data) => {
-                let mut arg_count = 0;
-                if !self.check_current(TokenType::RightParenthesis) {
-                    while !self.check_current(TokenType::RightParenthesis)
-                        && arg_count <= data.get_field_count()
-                    {
-                        let expression_type = self.expression();
-                        self.check_types(
-                            Some(data.get_field_type_by_index(arg_count)),
-                            &expression_type,
-                        );
+                let field_types: Vec<SquatType> = (0..data.get_field_count())
+                    .map(|field_index| data.get_field_type_by_index(field_index))
+                    .collect();
+                let mut arg_count = self.argument_list(&field_types, callee).len();
+                // Trailing fields that are left out take their default values
+                let defaults: Option<Vec<SquatValue>> = (arg_count..data.get_field_count())
+                    .map(|field_index| data.get_field_default(field_index))
+                    .collect();
+                if let Some(defaults) = defaults {
+                    for default in defaults {
+                        let constant_index = self.constants.write(default);
+                        self.write_op_code_at_line(OpCode::Constant(constant_index), line);
                         arg_count += 1;
-                        self.check_current(TokenType::Comma);
                     }
                 }
                 if arg_count != data.get_field_count() {
@@ -936,14 +2426,14 @@ impl<'a> Compiler<'a> {
                         arg_count
                     ));
                 }
-                self.write_op_code(OpCode::CreateInstance(arg_count));
+                self.write_op_code_at_line(OpCode::CreateInstance(arg_count), line);
                 data.get_instance_type()
             }
             _ => unreachable!("call"),
         };
 
         if self.check_current(TokenType::LeftParenthesis) {
-            return self.call(return_type);
+            return self.call(return_type, &format!("{}(...)", callee));
         } else if self.check_current(TokenType::Dot) {
             return self.property(return_type, None);
         }
@@ -967,7 +2457,7 @@ impl<'a> Compiler<'a> {
                     .get_field_type_and_index_by_name(&property_name)
                     .clone()
                 {
-                    Ok((field_type, property_index)) => {
+                    Some((field_type, property_index)) => {
                         if self.check_current(TokenType::Equal) {
                             self.expression_with_type(Some(field_type.clone()));
                             match get_op_code {
@@ -994,7 +2484,7 @@ impl<'a> Compiler<'a> {
                             field_type
                         }
                     }
-                    Err(_) => {
+                    None => {
                         self.compile_error(&format!(
                             "{} does not have a property called {}",
                             struct_name, property_name
@@ -1010,16 +2500,93 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    /// The type of the indexed value alone decides the type of the result, the
+    /// index only has to be an int. Its sign is a runtime concern.
     fn index(&mut self, squat_type: SquatType) -> SquatType {
-        self.expression_with_type(Some(SquatType::Int));
+        let indexed_location = self.main_chunk.get_size() - 1;
+        let index_type = self.expression_with_type(None);
+        if matches!(squat_type, SquatType::Map) {
+            if !matches!(
+                index_type,
+                SquatType::Int | SquatType::String | SquatType::Bool | SquatType::Any
+            ) {
+                self.compile_error(&format!(
+                    "Map keys have to be of {}, {} or {} but found {}",
+                    SquatType::Int,
+                    SquatType::String,
+                    SquatType::Bool,
+                    index_type
+                ));
+            }
+            self.consume_current(TokenType::RightBracket, "Expected closing ']'.");
+            self.write_op_code(OpCode::Index);
+            return SquatType::Any;
+        }
+        if index_type != SquatType::Int {
+            self.compile_error(&format!(
+                "Indices have to be of {} but found {}",
+                SquatType::Int,
+                index_type
+            ));
+        }
+        self.check_constant_index(indexed_location);
         self.consume_current(TokenType::RightBracket, "Expected closing ']'.");
         self.write_op_code(OpCode::Index);
         match squat_type {
             SquatType::String => SquatType::String,
+            SquatType::Array(element_type) => *element_type,
             _ => unreachable!(),
         }
     }
 
+    /// Reports out of range indices at compile time when both the string and
+    /// the index are constants. Anything else is checked by the VM.
+    fn check_constant_index(&mut self, indexed_location: usize) {
+        if self.main_chunk.get_size() != indexed_location + 2 {
+            return;
+        }
+        let (OpCode::Constant(string_index), OpCode::Constant(index_index)) = (
+            self.main_chunk.get_instruction(indexed_location),
+            self.main_chunk.get_instruction(indexed_location + 1),
+        ) else {
+            return;
+        };
+        if let (SquatValue::String(value), SquatValue::Int(index)) = (
+            self.constants.get(string_index),
+            self.constants.get(index_index),
+        ) {
+            let length = value.chars().count() as i64;
+            if length <= *index {
+                self.compile_error(&format!(
+                    "Index out of range, max possible index is {} but {} was given",
+                    length - 1,
+                    index
+                ));
+            }
+        }
+    }
+
+    /// Evaluates the expression at compile time if it only contains literals
+    /// and is followed by `terminator`. Nothing is consumed otherwise.
+    fn try_const_eval(&mut self, terminator: TokenType) -> Option<SquatValue> {
+        // The parse tree should still show the expression
+        if self.parse_tree.is_some() {
+            return None;
+        }
+
+        let mut evaluator = ConstEvaluator::new(self.lexer.clone(), self.current_token.clone()?);
+        let value = evaluator.expression()?;
+        if evaluator.current_token_type() != terminator {
+            return None;
+        }
+
+        let (lexer, previous_token, current_token) = evaluator.finish();
+        self.lexer = lexer;
+        self.previous_token = previous_token;
+        self.current_token = Some(current_token);
+        Some(value)
+    }
+
     fn expression_with_type(&mut self, expected_type: Option<SquatType>) -> SquatType {
         self.parse_precedence(Precedence::Assignment, expected_type)
     }
@@ -1034,6 +2601,112 @@ impl<'a> Compiler<'a> {
         t
     }
 
+    /// `{ int t = compute(); t * 2 }` runs the statements of the block and
+    /// evaluates to its final expression, which takes the slot of the first
+    /// local of the block when the scope is cleaned up.
+    fn block_expression(&mut self) -> SquatType {
+        if !matches!(self.scope_type, ScopeType::Function) {
+            self.compile_error("Block expressions are only allowed inside functions");
+            return SquatType::Nil;
+        }
+
+        // The variable being declared has no stack slot until the block is
+        // done, so it cannot take one in between the locals of the block
+        let uninitialized_count = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none())
+            .count();
+        let uninitialized = self
+            .locals
+            .split_off(self.locals.len() - uninitialized_count);
+
+        self.begin_scope();
+        let block_start = self.locals.len();
+        let mut value_type = None;
+        while value_type.is_none() {
+            if self.check_current(TokenType::RightBrace) {
+                self.compile_error("Block expression must end with an expression");
+                break;
+            }
+            if self.check_current(TokenType::Eof) {
+                self.compile_error("Expected closing '}' to end the block");
+                break;
+            }
+            if self.starts_declaration_or_statement() {
+                let return_type = self.function_return_type.clone();
+                self.declaration_statement(Some(return_type));
+                continue;
+            }
+            let expression_type = self.expression();
+            if self.check_current(TokenType::Semicolon) {
+                self.write_op_code(OpCode::Pop);
+            } else {
+                self.consume_current(
+                    TokenType::RightBrace,
+                    "Expected closing '}' after the value of the block",
+                );
+                value_type = Some(expression_type);
+            }
+        }
+        self.scope_depth -= 1;
+
+        let local_count = self.locals.len() - block_start;
+        for local_index in (block_start..self.locals.len()).rev() {
+            self.warn_if_unused(local_index);
+            self.emit_deinit(local_index);
+        }
+        if local_count > 0 {
+            self.write_op_code(OpCode::SetLocal(block_start));
+            self.write_op_code(OpCode::Pop);
+            for _ in 1..local_count {
+                self.write_op_code(OpCode::Pop);
+            }
+        }
+        self.locals.truncate(block_start);
+        self.locals.extend(uninitialized);
+
+        value_type.unwrap_or(SquatType::Nil)
+    }
+
+    /// Whether the current token starts something that `declaration_statement`
+    /// compiles differently than an expression statement
+    fn starts_declaration_or_statement(&self) -> bool {
+        let token = self.current_token.as_ref().unwrap();
+        match token.token_type {
+            TokenType::Semicolon
+            | TokenType::Func
+            | TokenType::Var
+            | TokenType::AnyType
+            | TokenType::BoolType
+            | TokenType::IntType
+            | TokenType::FloatType
+            | TokenType::StringType
+            | TokenType::Return
+            | TokenType::Defer
+            | TokenType::Struct
+            | TokenType::If
+            | TokenType::While
+            | TokenType::For
+            | TokenType::Match
+            | TokenType::Break
+            | TokenType::Continue
+            | TokenType::Try
+            | TokenType::Throw
+            | TokenType::LeftBrace => true,
+            TokenType::Identifier if self.legacy_print && token.lexeme == "print" => true,
+            TokenType::Identifier => {
+                self.structs.contains_key(&token.lexeme)
+                    && matches!(
+                        self.peek_next_token().map(|token| token.token_type),
+                        Some(TokenType::Identifier | TokenType::Question)
+                    )
+            }
+            _ => false,
+        }
+    }
+
     fn literal(&mut self) -> SquatType {
         let token_type = self.previous_token.as_ref().unwrap().token_type;
 
@@ -1050,6 +2723,15 @@ impl<'a> Compiler<'a> {
                 self.write_op_code(OpCode::True);
                 SquatType::Bool
             }
+            TokenType::Nan | TokenType::Inf => {
+                let value = match token_type {
+                    TokenType::Nan => f64::NAN,
+                    _ => f64::INFINITY,
+                };
+                let index = self.constants.write(SquatValue::Float(value));
+                self.write_op_code(OpCode::Constant(index));
+                SquatType::Float
+            }
             _ => unreachable!(),
         }
     }
@@ -1058,12 +2740,34 @@ impl<'a> Compiler<'a> {
         let lexeme = &self.previous_token.as_ref().unwrap().lexeme;
         let index;
         let number_type: SquatType;
-        if lexeme.contains(".") {
-            let value: f64 = lexeme.parse().unwrap();
+        if const_eval::is_hex_float(lexeme) {
+            let value = match const_eval::parse_hex_float(lexeme) {
+                Ok(value) => value,
+                Err(msg) => {
+                    self.compile_error(&msg);
+                    return SquatType::Float;
+                }
+            };
+            index = self.constants.write(SquatValue::Float(value));
+            number_type = SquatType::Float;
+        } else if lexeme.contains(".") {
+            let value: f64 = match lexeme.parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    self.compile_error(&format!("Invalid float literal '{}'", lexeme));
+                    return SquatType::Float;
+                }
+            };
             index = self.constants.write(SquatValue::Float(value));
             number_type = SquatType::Float;
         } else {
-            let value: i64 = lexeme.parse().unwrap();
+            let value: i64 = match lexeme.parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    self.compile_error(&format!("Integer literal '{}' is too large", lexeme));
+                    return SquatType::Int;
+                }
+            };
             index = self.constants.write(SquatValue::Int(value));
             number_type = SquatType::Int;
         }
@@ -1075,24 +2779,31 @@ impl<'a> Compiler<'a> {
     fn string(&mut self) -> SquatType {
         let value: String = self.previous_token.as_ref().unwrap().lexeme.clone();
 
-        let index = self.constants.write(SquatValue::String(value));
+        let index = self.constants.write(SquatValue::String(value.into()));
         self.write_op_code(OpCode::Constant(index));
+        if self.check_current(TokenType::LeftBracket) {
+            return self.index(SquatType::String);
+        }
         SquatType::String
     }
 
     fn unary(&mut self, expected_type: Option<SquatType>) -> SquatType {
-        let token_type = self.previous_token.as_ref().unwrap().token_type;
+        let operator = self.previous_token.clone().unwrap();
+        let token_type = operator.token_type;
+        let line = operator.line;
 
         let expression_type = self.parse_precedence(Precedence::Unary, expected_type.clone());
-        self.check_types(expected_type, &expression_type);
+        if self.check_types(expected_type, &expression_type) {
+            self.check_unwrapped(&operator, &expression_type);
+        }
 
         match token_type {
             TokenType::Bang => {
-                self.write_op_code(OpCode::Not);
+                self.write_op_code_at_line(OpCode::Not, line);
                 SquatType::Bool
             }
             TokenType::Minus => {
-                self.write_op_code(OpCode::Negate);
+                self.write_op_code_at_line(OpCode::Negate, line);
                 expression_type
             }
             _ => unreachable!(),
@@ -1102,13 +2813,16 @@ impl<'a> Compiler<'a> {
     fn variable(&mut self) -> SquatType {
         let var_name = self.previous_token.as_ref().unwrap().lexeme.clone();
 
-        let set_op_code: OpCode;
+        // Natives and upvalues cannot be assigned to, they have no set op code
+        let set_op_code: Option<OpCode>;
         let get_op_code: OpCode;
         let variable_type: SquatType;
         let object_type: ObjectType;
+        let mut is_const = false;
 
         if let Some((index, t)) = self.resolve_local(&var_name) {
-            set_op_code = OpCode::SetLocal(index);
+            is_const = self.locals[index].is_const;
+            set_op_code = Some(OpCode::SetLocal(index));
             get_op_code = OpCode::GetLocal(index);
             variable_type = t;
             match variable_type {
@@ -1116,8 +2830,22 @@ impl<'a> Compiler<'a> {
                 SquatType::Instance(_) => object_type = ObjectType::Instance,
                 _ => object_type = ObjectType::NotObject,
             }
+        } else if let Some(t) = self.resolve_callee(self.enclosing_functions.len(), &var_name) {
+            set_op_code = None;
+            get_op_code = OpCode::GetCallee;
+            variable_type = t;
+            object_type = ObjectType::Function;
+        } else if let Some((index, t)) = self.resolve_upvalue(&var_name) {
+            set_op_code = None;
+            get_op_code = OpCode::GetUpvalue(index);
+            variable_type = t;
+            match variable_type {
+                SquatType::Function(_) => object_type = ObjectType::Function,
+                SquatType::Instance(_) => object_type = ObjectType::Instance,
+                _ => object_type = ObjectType::NotObject,
+            }
         } else if let Some((index, t)) = self.resolve_global(&var_name) {
-            set_op_code = OpCode::SetGlobal(index);
+            set_op_code = Some(OpCode::SetGlobal(index));
             get_op_code = OpCode::GetGlobal(index);
             variable_type = t;
             match variable_type {
@@ -1127,7 +2855,7 @@ impl<'a> Compiler<'a> {
                 _ => object_type = ObjectType::NotObject,
             };
         } else if let Some((index, t)) = self.resolve_native(&var_name) {
-            set_op_code = OpCode::Nil; // Just to keep the compiler happy
+            set_op_code = None;
             get_op_code = OpCode::GetNative(index);
             variable_type = t;
             if let SquatType::NativeFunction(_) = variable_type {
@@ -1136,11 +2864,31 @@ impl<'a> Compiler<'a> {
                 object_type = ObjectType::NotObject;
             }
         } else {
-            self.compile_error(&format!("{} is not defined.", var_name));
+            self.coded_compile_error(
+                error_code::UNDEFINED_VARIABLE,
+                &format!("{} is not defined.", var_name),
+            );
             return SquatType::Nil;
         }
 
         if self.check_current(TokenType::Equal) {
+            let Some(set_op_code) = set_op_code else {
+                let reason = match get_op_code {
+                    OpCode::GetUpvalue(_) => "it is captured from an enclosing function",
+                    OpCode::GetCallee => "it is the function being declared",
+                    _ => "it is a native object",
+                };
+                self.compile_error(&format!("Cannot assign to '{}', {}", var_name, reason));
+                // The value is still parsed so it is not reported as well
+                self.expression_with_type(None);
+                return SquatType::Nil;
+            };
+            if is_const {
+                self.compile_error(&format!(
+                    "Cannot assign to '{}', it is a const parameter",
+                    var_name
+                ));
+            }
             if object_type == ObjectType::Class || object_type == ObjectType::Function {
                 self.compile_error(&format!(
                     "Cannot change assignment of an object of type '{:?}': {}",
@@ -1148,18 +2896,39 @@ impl<'a> Compiler<'a> {
                 ));
                 return SquatType::Nil;
             }
+            let tree_mark = self.tree_mark();
+            self.tree_leaf(&var_name);
             self.expression_with_type(Some(variable_type.clone()));
+            self.tree_branch(tree_mark, "=");
             self.write_op_code(set_op_code);
         } else {
+            self.mark_used(&var_name, get_op_code);
             match object_type {
                 ObjectType::Class | ObjectType::Function => {
+                    if matches!(get_op_code, OpCode::GetNative(_))
+                        && var_name == "abs"
+                        && self.check_current(TokenType::LeftParenthesis)
+                    {
+                        return self.abs_call();
+                    }
                     self.write_op_code(get_op_code);
                     if self.check_current(TokenType::LeftParenthesis) {
-                        return self.call(variable_type);
+                        let return_type = self.call(variable_type, &var_name);
+                        if matches!(get_op_code, OpCode::GetGlobal(_))
+                            && var_name == self.function_name
+                        {
+                            self.self_calls.push(self.main_chunk.get_size() - 1);
+                        }
+                        return return_type;
                     }
                 }
                 ObjectType::Instance => {
                     if self.check_current(TokenType::Dot) {
+                        // Properties of upvalues are read from a copy of them
+                        if let OpCode::GetUpvalue(_) = get_op_code {
+                            self.write_op_code(get_op_code);
+                            return self.property(variable_type, None);
+                        }
                         return self.property(variable_type, Some(get_op_code));
                     }
                     self.write_op_code(get_op_code)
@@ -1170,7 +2939,12 @@ impl<'a> Compiler<'a> {
             };
         }
 
-        if variable_type == SquatType::String && self.check_current(TokenType::LeftBracket) {
+        // Not `==`, it would also match variables of type any
+        let is_indexable = matches!(
+            variable_type,
+            SquatType::String | SquatType::Array(_) | SquatType::Map
+        );
+        if is_indexable && self.check_current(TokenType::LeftBracket) {
             return self.index(variable_type);
         }
 
@@ -1178,9 +2952,127 @@ impl<'a> Compiler<'a> {
     }
 
     //////////////////////////////////////////////////////////////////////////
-    /// Helper functions
+    // Helper functions
     //////////////////////////////////////////////////////////////////////////
 
+    fn mark_used(&mut self, name: &str, get_op_code: OpCode) {
+        match get_op_code {
+            OpCode::GetLocal(index) => self.locals[index].used = true,
+            OpCode::GetGlobal(_) => self.globals.get_mut(name).unwrap().used = true,
+            _ => {}
+        }
+    }
+
+    /// Parameters, hidden locals and names starting with '_' are never
+    /// reported. Neither is anything after an error, the variable may only
+    /// be unused because the code reading it failed to compile.
+    fn warn_if_unused(&mut self, local_index: usize) {
+        let local = &self.locals[local_index];
+        if !self.warn_unused
+            || self.had_error
+            || local.used
+            || local_index < self.parameter_count
+            || local.name.is_empty()
+            || local.name.starts_with('_')
+        {
+            return;
+        }
+        let (line, message) = (local.line, format!("Unused variable '{}'", local.name));
+        self.compile_warning_at_line(line, &message);
+    }
+
+    /// Functions and structs are not variables, unused ones are removed
+    /// with the dead functions
+    fn warn_unused_globals(&mut self) {
+        if !self.warn_unused || self.had_error {
+            return;
+        }
+        let mut unused: Vec<(usize, u32, String)> = self
+            .globals
+            .iter()
+            .filter(|(name, global)| {
+                !global.used
+                    && name.as_str() != "main"
+                    && !name.starts_with('_')
+                    && !name.contains('.')
+                    && !matches!(
+                        global.get_type(),
+                        SquatType::Function(_) | SquatType::Struct(_)
+                    )
+            })
+            .map(|(name, global)| (global.index, global.line, name.clone()))
+            .collect();
+        unused.sort();
+        for (_, line, name) in unused {
+            self.compile_warning_at_line(line, &format!("Unused variable '{}'", name));
+        }
+    }
+
+    /// The token after the current one, scanned by a copy of the lexer so
+    /// nothing is consumed. `None` if that token could not be scanned.
+    fn peek_next_token(&self) -> Option<Token> {
+        self.lexer.clone().scan_token().ok()
+    }
+
+    /// Whether a '?' or a comparison follows before the expression that
+    /// starts at the current token ends, scanned by a copy of the lexer. So
+    /// does arithmetic if `arithmetic` is set. Only operators that bind
+    /// looser than `precedence` are part of the expression.
+    fn typed_operator_follows(&self, precedence: Precedence, arithmetic: bool) -> bool {
+        let mut lexer = self.lexer.clone();
+        let mut token_type = self.current_token.as_ref().unwrap().token_type;
+        let mut depth = 0;
+        loop {
+            match token_type {
+                TokenType::LeftParenthesis | TokenType::LeftBracket | TokenType::LeftBrace => {
+                    depth += 1
+                }
+                TokenType::RightParenthesis | TokenType::RightBracket | TokenType::RightBrace => {
+                    if depth == 0 {
+                        return false;
+                    }
+                    depth -= 1;
+                }
+                TokenType::Question if depth == 0 && precedence <= Precedence::Ternary => {
+                    return true
+                }
+                TokenType::EqualEqual | TokenType::BangEqual
+                    if depth == 0 && precedence <= Precedence::Equality =>
+                {
+                    return true
+                }
+                TokenType::Greater
+                | TokenType::GreaterEqual
+                | TokenType::Less
+                | TokenType::LessEqual
+                    if depth == 0 && precedence <= Precedence::Comparison =>
+                {
+                    return true
+                }
+                TokenType::Plus
+                | TokenType::Minus
+                | TokenType::Star
+                | TokenType::Slash
+                | TokenType::Percent
+                    if arithmetic
+                        && depth == 0
+                        && precedence <= self.get_precedence(token_type) =>
+                {
+                    return true
+                }
+                TokenType::Semicolon | TokenType::Comma | TokenType::Colon if depth == 0 => {
+                    return false
+                }
+                TokenType::Eof => return false,
+                _ => {}
+            }
+            token_type = match lexer.scan_token() {
+                Ok(token) => token.token_type,
+                Err(_) => return false,
+            };
+        }
+    }
+
     fn advance(&mut self) {
         if self.current_token.is_some() {
             self.previous_token = Some(self.current_token.clone().unwrap());
@@ -1202,6 +3094,9 @@ impl<'a> Compiler<'a> {
                         LexerError::IncompleteString { line } => {
                             self.compile_error_at_line(line, "incomplete string")
                         }
+                        LexerError::IncompleteIdentifier { line } => {
+                            self.compile_error_at_line(line, "incomplete escaped identifier")
+                        }
                         LexerError::InternalError { msg, line } => {
                             self.compile_error_at_line(line, &msg)
                         }
@@ -1217,13 +3112,31 @@ impl<'a> Compiler<'a> {
                 self.advance();
                 return;
             }
-            let lexeme = &self.previous_token.as_ref().unwrap().lexeme;
-            self.compile_error(&format!("Error at '{}': {}", lexeme, message));
+            let error = self.unexpected_token_error(expected_type, message);
+            match expected_type {
+                TokenType::Semicolon => {
+                    self.coded_compile_error(error_code::MISSING_SEMICOLON, &error)
+                }
+                _ => self.compile_error(&error),
+            }
             return;
         }
         unreachable!();
     }
 
+    fn unexpected_token_error(&self, expected_type: TokenType, message: &str) -> String {
+        let lexeme = &self.previous_token.as_ref().unwrap().lexeme;
+        let current_token = self.current_token.as_ref().unwrap();
+        let found = match current_token.token_type {
+            TokenType::Eof => current_token.token_type.to_string(),
+            _ => format!("'{}'", current_token.lexeme),
+        };
+        format!(
+            "Error at '{}': {}, expected {} but found {}",
+            lexeme, message, expected_type, found
+        )
+    }
+
     fn check_current(&mut self, expected_type: TokenType) -> bool {
         if let Some(token) = &self.current_token {
             if token.token_type == expected_type {
@@ -1240,31 +3153,62 @@ impl<'a> Compiler<'a> {
                 return true;
             }
         }
-        return false;
+        false
     }
 
+    /// Skips the rest of the statement that had an error. Stops after its
+    /// ';', or before the '}' of the block it is in or the start of the next
+    /// statement, so the errors after it are not caused by the first one.
     fn synchronize(&mut self) {
-        // TODO this function needs more work to function properly
         self.panic_mode = false;
-        while self.current_token.as_ref().unwrap().token_type != TokenType::Eof {
+        loop {
+            if self.previous_token.as_ref().unwrap().token_type == TokenType::Semicolon {
+                return;
+            }
             match self.current_token.as_ref().unwrap().token_type {
-                TokenType::RightBrace | TokenType::Semicolon => {
-                    self.advance();
-                    break;
-                }
+                TokenType::Eof => return,
+                TokenType::RightBrace if self.scope_depth > 0 => return,
+                TokenType::Semicolon => {}
+                _ if self.starts_declaration_or_statement() => return,
                 _ => {}
             }
             self.advance();
         }
     }
 
+    /// Operators need the value of an optional, which may be nil. Returns
+    /// whether the operand can be used.
+    fn check_unwrapped(&mut self, operator: &Token, operand_type: &SquatType) -> bool {
+        let SquatType::Optional(value_type) = operand_type else {
+            return true;
+        };
+        if types_match(value_type, operand_type) {
+            return true;
+        }
+        self.coded_compile_error(
+            error_code::TYPE_MISMATCH,
+            &format!(
+                "Operand of '{}' is {} and may be nil, check it for nil first",
+                operator.lexeme, operand_type
+            ),
+        );
+        false
+    }
+
     fn check_types(&mut self, expected_type: Option<SquatType>, type_to_check: &SquatType) -> bool {
         if let Some(expected_type) = expected_type {
-            if *type_to_check != expected_type {
-                self.compile_error(&format!(
-                    "Expected {} but found {}",
-                    expected_type, type_to_check
-                ));
+            // Arguments of natives are checked by `argument_list`, the only
+            // place `number` matches ints and floats in strict mode
+            let number_wildcard = matches!(
+                (&expected_type, type_to_check),
+                (SquatType::Number, SquatType::Int | SquatType::Float)
+                    | (SquatType::Int | SquatType::Float, SquatType::Number)
+            );
+            if !types_match(&expected_type, type_to_check) || self.strict_types && number_wildcard {
+                self.coded_compile_error(
+                    error_code::TYPE_MISMATCH,
+                    &format!("Expected {} but found {}", expected_type, type_to_check),
+                );
                 return false;
             }
         }
@@ -1272,7 +3216,7 @@ impl<'a> Compiler<'a> {
     }
 
     //////////////////////////////////////////////////////////////////////////
-    /// Scope functions
+    // Scope functions
     //////////////////////////////////////////////////////////////////////////
 
     fn begin_scope(&mut self) {
@@ -1283,14 +3227,50 @@ impl<'a> Compiler<'a> {
         self.scope_depth -= 1;
 
         // Remove the local variables from the stack
-        while self.locals.len() > 0
+        while !self.locals.is_empty()
             && self.locals[self.locals.len() - 1].depth.unwrap_or(0) > self.scope_depth
         {
+            self.warn_if_unused(self.locals.len() - 1);
+            self.emit_deinit(self.locals.len() - 1);
             self.write_op_code(OpCode::Pop);
             self.locals.pop();
         }
     }
 
+    /// Calls the `deinit` of the struct if the local is an instance of one
+    /// that has it
+    fn emit_deinit(&mut self, local_index: usize) {
+        if local_index < self.parameter_count {
+            return;
+        }
+        let SquatType::Instance(data) = self.locals[local_index].get_type() else {
+            return;
+        };
+        let Some(&deinit_index) = self.struct_deinits.get(&data.struct_name) else {
+            return;
+        };
+        self.write_op_code(OpCode::GetGlobal(deinit_index));
+        self.write_op_code(OpCode::GetLocal(local_index));
+        self.write_op_code(OpCode::Call(1));
+        self.write_op_code(OpCode::Pop);
+        self.user_function_calls += 1;
+    }
+
+    /// Whether the native can call squat functions it is given. A callee that
+    /// is not a native by its name, like the result of a call, could be any
+    /// of them.
+    fn native_calls_back(&self, callee: &str) -> bool {
+        self.natives
+            .iter()
+            .find_map(|native| match native.get_value() {
+                SquatValue::Object(SquatObject::NativeFunction(func)) if func.name == callee => {
+                    Some(func.calls_back())
+                }
+                _ => None,
+            })
+            .unwrap_or(true)
+    }
+
     fn resolve_native(&mut self, name: &str) -> Option<(usize, SquatType)> {
         if let Some(native_index) = self.natives.iter().position(|x| match x.get_value() {
             SquatValue::Object(SquatObject::NativeFunction(func)) => func.name == name,
@@ -1322,8 +3302,69 @@ impl<'a> Compiler<'a> {
         None
     }
 
+    /// Finds a local of an enclosing function, it becomes an upvalue of every
+    /// function from the one it is declared in to the current one
+    fn resolve_upvalue(&mut self, name: &str) -> Option<(usize, SquatType)> {
+        self.resolve_upvalue_at(self.enclosing_functions.len(), name)
+    }
+
+    /// # Arguments
+    /// * `level` - Index of the function in `enclosing_functions`, their
+    ///   length for the current function
+    fn resolve_upvalue_at(&mut self, level: usize, name: &str) -> Option<(usize, SquatType)> {
+        let upvalues = self.upvalues_at(level);
+        if let Some(index) = upvalues.iter().position(|upvalue| upvalue.name == name) {
+            return Some((index, upvalues[index].squat_type.clone()));
+        }
+
+        let enclosing_level = level.checked_sub(1)?;
+        let enclosing_locals = &mut self.enclosing_functions[enclosing_level].locals;
+        let local_index = enclosing_locals
+            .iter()
+            .rposition(|local| local.name == name && local.depth.is_some());
+        let (source, squat_type) = if let Some(index) = local_index {
+            enclosing_locals[index].used = true;
+            (
+                UpvalueSource::Local(index),
+                enclosing_locals[index].get_type(),
+            )
+        } else if let Some(callee_type) = self.resolve_callee(enclosing_level, name) {
+            (UpvalueSource::Callee, callee_type)
+        } else {
+            let (index, squat_type) = self.resolve_upvalue_at(enclosing_level, name)?;
+            (UpvalueSource::Upvalue(index), squat_type)
+        };
+        let upvalues = self.upvalues_at(level);
+        upvalues.push(Upvalue {
+            name: name.to_owned(),
+            squat_type: squat_type.clone(),
+            source,
+        });
+        Some((upvalues.len() - 1, squat_type))
+    }
+
+    /// The type of the local function at the level if it has the name, its
+    /// own local in the enclosing function has no value yet
+    fn resolve_callee(&self, level: usize, name: &str) -> Option<SquatType> {
+        let local_function = match self.enclosing_functions.get(level) {
+            Some(enclosing) => &enclosing.local_function,
+            None => &self.local_function,
+        };
+        match local_function {
+            Some((function_name, squat_type)) if function_name == name => Some(squat_type.clone()),
+            _ => None,
+        }
+    }
+
+    fn upvalues_at(&mut self, level: usize) -> &mut Vec<Upvalue> {
+        match self.enclosing_functions.get_mut(level) {
+            Some(enclosing) => &mut enclosing.upvalues,
+            None => &mut self.upvalues,
+        }
+    }
+
     //////////////////////////////////////////////////////////////////////////
-    /// Token Linkers
+    // Token Linkers
     //////////////////////////////////////////////////////////////////////////
 
     fn call_prefix(
@@ -1331,17 +3372,50 @@ impl<'a> Compiler<'a> {
         token_type: TokenType,
         expected_type: Option<SquatType>,
     ) -> SquatType {
+        let block_expression_allowed = std::mem::take(&mut self.block_expression_allowed);
         match token_type {
+            TokenType::LeftBrace if block_expression_allowed => self.block_expression(),
+            TokenType::LeftBrace => {
+                self.compile_error(
+                    "Block expressions can only start the value of a declaration or a return",
+                );
+                // Its '}' would otherwise be taken for the end of the
+                // enclosing block
+                let mut depth = 1;
+                while depth > 0 && !self.check_current(TokenType::Eof) {
+                    if self.check_current(TokenType::LeftBrace) {
+                        depth += 1;
+                    } else if self.check_current(TokenType::RightBrace) {
+                        depth -= 1;
+                    } else {
+                        self.advance();
+                    }
+                }
+                SquatType::Nil
+            }
             TokenType::LeftParenthesis => self.grouping(expected_type),
             TokenType::Bang | TokenType::Minus => self.unary(expected_type),
             TokenType::Number => self.number(),
-            TokenType::False | TokenType::Nil | TokenType::True => self.literal(),
+            TokenType::False
+            | TokenType::Nil
+            | TokenType::True
+            | TokenType::Nan
+            | TokenType::Inf => self.literal(),
             TokenType::String => self.string(),
             TokenType::Identifier => self.variable(),
+            // The `any` native shares its name with the type
+            TokenType::AnyType
+                if self.current_token.as_ref().unwrap().token_type
+                    == TokenType::LeftParenthesis =>
+            {
+                self.variable()
+            }
+            TokenType::Func => self.lambda(),
             TokenType::Eof => SquatType::Nil,
             _ => {
                 self.compile_error("Illegal expression");
-                SquatType::Nil
+                self.skip_expression();
+                SquatType::Error
             }
         }
     }
@@ -1358,9 +3432,16 @@ impl<'a> Compiler<'a> {
             | TokenType::Greater
             | TokenType::GreaterEqual
             | TokenType::Less
-            | TokenType::LessEqual => self.binary(expected_type),
+            | TokenType::LessEqual
+            | TokenType::GreaterGreater
+            | TokenType::GreaterGreaterGreater => self.binary(expected_type),
             TokenType::And => self.and(),
             TokenType::Or => self.or(),
+            // Calls of variables are compiled by `variable`
+            TokenType::LeftParenthesis => {
+                self.compile_error("Only functions and structs can be called");
+                SquatType::Nil
+            }
             _ => {
                 dbg!(&self.previous_token);
                 dbg!(&self.current_token);
@@ -1378,6 +3459,7 @@ impl<'a> Compiler<'a> {
             | TokenType::GreaterEqual
             | TokenType::Less
             | TokenType::LessEqual => Precedence::Comparison,
+            TokenType::GreaterGreater | TokenType::GreaterGreaterGreater => Precedence::Shift,
             TokenType::And => Precedence::And,
             TokenType::Or => Precedence::Or,
             TokenType::Question => Precedence::Ternary,
@@ -1387,7 +3469,50 @@ impl<'a> Compiler<'a> {
     }
 
     //////////////////////////////////////////////////////////////////////////
-    /// Jumps
+    // Parse tree
+    //////////////////////////////////////////////////////////////////////////
+
+    fn tree_mark(&self) -> usize {
+        self.parse_tree.as_ref().map_or(0, |nodes| nodes.len())
+    }
+
+    fn tree_leaf(&mut self, lexeme: &str) {
+        if let Some(nodes) = self.parse_tree.as_mut() {
+            nodes.push(ParseNode::Leaf(lexeme.to_owned()));
+        }
+    }
+
+    /// Replaces the nodes recorded since `mark` with a single branch
+    fn tree_branch(&mut self, mark: usize, label: &str) {
+        if let Some(nodes) = self.parse_tree.as_mut() {
+            let children = nodes.split_off(mark.min(nodes.len()));
+            nodes.push(ParseNode::Branch(label.to_owned(), children));
+        }
+    }
+
+    /// Records the prefix expression that was just parsed. Groupings are left
+    /// out since the tree already shows the precedence, assignments record
+    /// themselves.
+    fn tree_prefix(&mut self, mark: usize, token: Token) {
+        if token.token_type == TokenType::LeftParenthesis {
+            return;
+        }
+        let is_assignment = matches!(
+            self.parse_tree.as_ref().and_then(|nodes| nodes.get(mark)),
+            Some(ParseNode::Branch(label, _)) if label == "="
+        );
+        if is_assignment {
+            return;
+        }
+        if self.tree_mark() == mark {
+            self.tree_leaf(&token.lexeme);
+        } else {
+            self.tree_branch(mark, &token.lexeme);
+        }
+    }
+
+    //////////////////////////////////////////////////////////////////////////
+    // Jumps
     //////////////////////////////////////////////////////////////////////////
 
     fn emit_jump(&mut self, op_code: OpCode) -> usize {
@@ -1401,21 +3526,254 @@ impl<'a> Compiler<'a> {
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
+        debug_assert!(loop_start < self.main_chunk.get_size());
         self.write_op_code(OpCode::Loop(loop_start));
     }
 
     //////////////////////////////////////////////////////////////////////////
-    /// Write instruction
+    // Write instruction
     //////////////////////////////////////////////////////////////////////////
 
     fn write_op_code(&mut self, op_code: OpCode) {
         let line = self.previous_token.as_ref().unwrap().line;
         self.main_chunk.write(op_code, line);
-        return;
+    }
+
+    fn write_op_code_at_line(&mut self, op_code: OpCode, line: u32) {
+        self.main_chunk.write(op_code, line);
+    }
+
+    //////////////////////////////////////////////////////////////////////////
+    // Optimizations
+    //////////////////////////////////////////////////////////////////////////
+
+    /// Removes the code of every function that can not be reached from `main`
+    /// or from the global scope.
+    fn eliminate_dead_functions(&mut self) {
+        let size = self.main_chunk.get_size();
+        let mut owner: Vec<Option<usize>> = vec![None; size];
+        for (function, range) in self.function_ranges.iter().enumerate() {
+            owner[range.start..=range.end].fill(Some(function));
+        }
+
+        // Everything outside of a function body is always executed, so scanning
+        // starts from there and follows the functions that are loaded.
+        let mut reachable = vec![false; self.function_ranges.len()];
+        let mut to_scan: Vec<usize> = (0..size).filter(|i| owner[*i].is_none()).collect();
+        while let Some(op_index) = to_scan.pop() {
+            if let OpCode::GetGlobal(global_index) = self.main_chunk.get_instruction(op_index) {
+                if let Some(function) = self
+                    .function_ranges
+                    .iter()
+                    .position(|range| range.global_index == global_index)
+                {
+                    if !reachable[function] {
+                        reachable[function] = true;
+                        let range = &self.function_ranges[function];
+                        to_scan.extend(range.start..=range.end);
+                    }
+                }
+            }
+        }
+
+        let keep: Vec<bool> = owner
+            .iter()
+            .map(|function| function.is_none_or(|function| reachable[function]))
+            .collect();
+        if keep.iter().all(|keep| *keep) {
+            return;
+        }
+
+        let new_indices = self.main_chunk.remove_instructions(&keep);
+        self.constants.relocate_functions(&new_indices);
+        self.function_ranges = std::mem::take(&mut self.function_ranges)
+            .into_iter()
+            .zip(reachable)
+            .filter(|(_, reachable)| *reachable)
+            .map(|(range, _)| FunctionRange {
+                global_index: range.global_index,
+                start: new_indices[range.start],
+                end: new_indices[range.end + 1] - 1,
+            })
+            .collect();
+    }
+
+    /// Warns when the function in `start..end` calls itself outside of a tail
+    /// position and only ever compares with `==` or `!=`, like a factorial
+    /// that stops at `n == 0`. Such a base case is easy to step over, a
+    /// negative argument recurses until the stack overflows.
+    fn check_recursion(&mut self, func_name: &str, line: u32, start: usize, end: usize) {
+        let has_non_tail_call = self.self_calls.iter().any(|&call| {
+            call + 1 >= end || self.main_chunk.get_instruction(call + 1) != OpCode::Return
+        });
+        if !has_non_tail_call {
+            return;
+        }
+
+        let mut has_equality = false;
+        for op_index in start..end {
+            match self.main_chunk.get_instruction(op_index) {
+                OpCode::Equal | OpCode::NotEqual => has_equality = true,
+                OpCode::Less | OpCode::LessEqual | OpCode::Greater | OpCode::GreaterEqual => return,
+                _ => {}
+            }
+        }
+        if has_equality {
+            self.compile_warning_at_line(
+                line,
+                &format!(
+                    "Function '{}' calls itself outside of a tail position and its only base case is an equality check, it may recurse until the stack overflows",
+                    func_name
+                ),
+            );
+        }
+    }
+
+    /// Whether the code in `start..end` can fall through or jump to `end`
+    fn can_reach_end(&self, start: usize, end: usize) -> bool {
+        let mut reached = vec![false; end - start + 1];
+        let mut to_scan = vec![start];
+        while let Some(op_index) = to_scan.pop() {
+            if op_index > end || reached[op_index - start] {
+                continue;
+            }
+            reached[op_index - start] = true;
+            if op_index == end {
+                return true;
+            }
+            match self.main_chunk.get_instruction(op_index) {
+                OpCode::Return | OpCode::Stop => {}
+                OpCode::Jump(offset) => to_scan.push(op_index + 1 + offset),
+                OpCode::JumpIfFalse(offset) | OpCode::JumpIfTrue(offset) | OpCode::Try(offset) => {
+                    to_scan.push(op_index + 1);
+                    to_scan.push(op_index + 1 + offset);
+                }
+                OpCode::JumpTo(target) | OpCode::Loop(target) => to_scan.push(target),
+                _ => to_scan.push(op_index + 1),
+            }
+        }
+        false
+    }
+
+    /// Checks that no function, main included, can fall through into the code
+    /// after it
+    fn verify_functions(&self) -> Result<(), String> {
+        // A function starts with the jump over its body, which ends right
+        // before the jump lands
+        let jumps = std::iter::once(self.main_chunk.get_main_start() - 1)
+            .chain(self.function_ranges.iter().map(|range| range.start));
+        for jump in jumps {
+            let OpCode::Jump(offset) = self.main_chunk.get_instruction(jump) else {
+                return Err(format!(
+                    "Function at {} does not start with a jump over its body",
+                    jump
+                ));
+            };
+            self.main_chunk
+                .verify_function(jump + 1, jump + offset)
+                .map_err(|msg| format!("Function at {}: {}", jump + 1, msg))?;
+        }
+        Ok(())
+    }
+
+    /// Loads the globals that are read but never written in the loop that
+    /// starts at `loop_start` once before the loop, into hidden locals that
+    /// are popped when the loop exits. The loop has to be the last code that
+    /// was written and `local_count` the number of locals before it.
+    ///
+    /// Loops that call functions which are not native, or natives that call
+    /// back into squat functions, are skipped, because those can write to any
+    /// global. So are loops that declare a function,
+    /// its body uses the locals of its own frame.
+    fn hoist_loop_invariants(&mut self, loop_start: usize, local_count: usize) {
+        if self.had_error {
+            return;
+        }
+
+        let loop_end = self.main_chunk.get_size();
+        let mut read: Vec<usize> = Vec::new();
+        let mut written: Vec<usize> = Vec::new();
+        for op_index in loop_start..loop_end {
+            match self.main_chunk.get_instruction(op_index) {
+                OpCode::Start => return,
+                OpCode::GetGlobal(index) if !read.contains(&index) => read.push(index),
+                OpCode::SetGlobal(index) | OpCode::SetGlobalProperty(index, _) => {
+                    written.push(index)
+                }
+                _ => {}
+            }
+        }
+        read.retain(|index| !written.contains(index));
+        if read.is_empty() {
+            return;
+        }
+
+        // The hidden locals take the first slots after the locals that existed
+        // before the loop, so the locals declared in the loop move up
+        let hoisted = read.len();
+        let shift = |index: usize| match index >= local_count {
+            true => index + hoisted,
+            false => index,
+        };
+        for op_index in loop_start..loop_end {
+            let op_code = match self.main_chunk.get_instruction(op_index) {
+                OpCode::GetGlobal(index) => match read.iter().position(|read| *read == index) {
+                    Some(position) => OpCode::GetLocal(local_count + position),
+                    None => continue,
+                },
+                OpCode::GetLocal(index) => OpCode::GetLocal(shift(index)),
+                OpCode::SetLocal(index) => OpCode::SetLocal(shift(index)),
+                OpCode::IncLocal(index, amount) => OpCode::IncLocal(shift(index), amount),
+                OpCode::GetLocalProperty(index, property) => {
+                    OpCode::GetLocalProperty(shift(index), property)
+                }
+                OpCode::SetLocalProperty(index, property) => {
+                    OpCode::SetLocalProperty(shift(index), property)
+                }
+                _ => continue,
+            };
+            self.main_chunk.set_instruction(op_index, op_code);
+        }
+
+        let loads: Vec<OpCode> = read.iter().map(|index| OpCode::GetGlobal(*index)).collect();
+        self.main_chunk.insert_before_loop(loop_start, &loads);
+        self.main_chunk
+            .deepen_statements(loop_start + hoisted, loop_end + hoisted, hoisted);
+        for _ in 0..hoisted {
+            self.write_op_code(OpCode::Pop);
+        }
+    }
+
+    /// Removes `Negate` pairs, as in `-(-x)`, unless something jumps between
+    /// them.
+    fn eliminate_double_negations(&mut self) {
+        let size = self.main_chunk.get_size();
+        let mut jump_targets = vec![false; size + 1];
+        for op_index in 0..size {
+            let target = match self.main_chunk.get_instruction(op_index) {
+                OpCode::Jump(offset)
+                | OpCode::JumpIfFalse(offset)
+                | OpCode::JumpIfTrue(offset)
+                | OpCode::Try(offset) => op_index + 1 + offset,
+                OpCode::Loop(target) | OpCode::JumpTo(target) => target,
+                _ => continue,
+            };
+            jump_targets[target] = true;
+        }
+
+        for (op_index, is_jump_target) in jump_targets.iter().enumerate().take(size).skip(1) {
+            if self.main_chunk.get_instruction(op_index - 1) == OpCode::Negate
+                && self.main_chunk.get_instruction(op_index) == OpCode::Negate
+                && !is_jump_target
+            {
+                self.main_chunk.replace_with_nop(op_index - 1);
+                self.main_chunk.replace_with_nop(op_index);
+            }
+        }
     }
 
     //////////////////////////////////////////////////////////////////////////
-    /// Logging
+    // Logging
     //////////////////////////////////////////////////////////////////////////
 
     fn compile_error(&mut self, message: &str) {
@@ -1424,13 +3782,1179 @@ impl<'a> Compiler<'a> {
     }
 
     fn compile_error_at_line(&mut self, line: u32, message: &str) {
-        println!("[ERROR] (Line {}) {}", line, message);
+        let error = format!("[ERROR] ({}) {}", self.main_chunk.location(line), message);
+        self.report_error(error);
+    }
+
+    /// Errors with a code can be looked up with `--explain`
+    fn coded_compile_error(&mut self, error_code: ErrorCode, message: &str) {
+        let line = self.previous_token.as_ref().unwrap().line;
+        let error = format!(
+            "[ERROR {}] ({}) {}",
+            error_code.code,
+            self.main_chunk.location(line),
+            message
+        );
+        self.report_error(error);
+    }
+
+    fn report_error(&mut self, error: String) {
+        // The rest of the statement is skipped, errors in it would only be
+        // caused by the first one
+        if self.panic_mode {
+            return;
+        }
+        println!("{}", error);
+        self.errors.push(error);
         self.had_error = true;
         self.panic_mode = true;
     }
 
     fn compile_warning(&mut self, message: &str) {
         let line = self.previous_token.as_ref().unwrap().line;
-        println!("[WARNING] (Line {}) {}", line, message);
+        self.compile_warning_at_line(line, message);
+    }
+
+    fn compile_warning_at_line(&mut self, line: u32, message: &str) {
+        let warning = format!("[WARNING] ({}) {}", self.main_chunk.location(line), message);
+        println!("{}", warning);
+        self.warnings.push(warning);
+    }
+}
+
+/// Whether a value of type `found` can be used where `expected` is required.
+/// Optionals have to be unwrapped first, unless `expected` allows them.
+fn types_match(expected: &SquatType, found: &SquatType) -> bool {
+    let unwraps_optional = matches!(found, SquatType::Optional(_))
+        && !matches!(
+            expected,
+            SquatType::Optional(_) | SquatType::Any | SquatType::Error
+        );
+    !unwraps_optional && *found == *expected
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Compiles the source without natives, along with the code and the
+    /// constants it returns the errors in the order they were reported
+    fn compile_source(source: &str) -> (CompileStatus, Chunk, ValueArray, Vec<String>) {
+        compile_source_with(
+            source,
+            Vec::new(),
+            |_| {},
+            |compiler| compiler.errors.clone(),
+        )
+    }
+
+    /// `configure` sets up the compiler before compiling, what `inspect`
+    /// reads from it afterwards is returned with the code and the constants
+    fn compile_source_with<T>(
+        source: &str,
+        natives: Vec<CompilerNative>,
+        configure: impl FnOnce(&mut Compiler),
+        inspect: impl FnOnce(&mut Compiler) -> T,
+    ) -> (CompileStatus, Chunk, ValueArray, T) {
+        let source = source.to_owned();
+        let mut chunk = Chunk::new("Test", true);
+        let mut constants = ValueArray::new("Constants");
+
+        let mut compiler = Compiler::new(&source, &mut chunk, &mut constants, &natives);
+        configure(&mut compiler);
+        let status = compiler.compile();
+        let inspected = inspect(&mut compiler);
+        (status, chunk, constants, inspected)
+    }
+
+    fn native(
+        name: &str,
+        function: crate::native::NativeFunc,
+        type_data: SquatFunctionTypeData,
+    ) -> CompilerNative {
+        CompilerNative::new(
+            SquatValue::Object(SquatObject::NativeFunction(
+                crate::object::SquatNativeFunction::new(name, function, type_data.clone()),
+            )),
+            SquatType::NativeFunction(type_data),
+        )
+    }
+
+    fn compiled_size(source: &str) -> usize {
+        let (status, chunk, _, _) = compile_source(source);
+        assert!(matches!(status, CompileStatus::Success(_)));
+        chunk.get_size()
+    }
+
+    #[test]
+    fn large_loop_body_jumps_to_correct_targets() {
+        let source = format!(
+            "
+            func main() {{
+                int i = 0;
+                while (i < 10) {{
+                    {}
+                    i = i + 1;
+                }}
+            }}
+            ",
+            "i = i * 1;\n".repeat(200)
+        );
+        let (status, chunk, _, _) = compile_source(&source);
+        assert!(matches!(status, CompileStatus::Success(_)));
+
+        let exit_jump = (0..chunk.get_size())
+            .find(|&i| matches!(chunk.get_instruction(i), OpCode::JumpIfFalse(_)))
+            .unwrap();
+        let loop_index = (0..chunk.get_size())
+            .find(|&i| matches!(chunk.get_instruction(i), OpCode::Loop(_)))
+            .unwrap();
+
+        let OpCode::JumpIfFalse(offset) = chunk.get_instruction(exit_jump) else {
+            unreachable!()
+        };
+        assert!(offset > 200 * 4);
+        assert_eq!(exit_jump + 1 + offset, loop_index + 1);
+        assert!(matches!(chunk.get_instruction(loop_index + 1), OpCode::Pop));
+
+        // The loop goes back to the start of the condition, which is followed by the exit jump
+        let OpCode::Loop(loop_start) = chunk.get_instruction(loop_index) else {
+            unreachable!()
+        };
+        assert!(loop_start < exit_jump);
+        assert!(matches!(
+            chunk.get_instruction(loop_start),
+            OpCode::GetLocal(_)
+        ));
+        assert!(matches!(chunk.get_instruction(exit_jump - 1), OpCode::Less));
+    }
+
+    #[test]
+    fn operation_is_on_the_line_of_its_operator() {
+        let source = "
+            func main() {
+                int first = 1;
+                int second = 2;
+                int total = first +
+                    second;
+            }
+            ";
+        let (status, chunk, _, _) = compile_source(source);
+        assert!(matches!(status, CompileStatus::Success(_)));
+
+        let add = (0..chunk.get_size())
+            .find(|op_index| chunk.get_instruction(*op_index) == OpCode::Add)
+            .unwrap();
+        assert_eq!(chunk.get_instruction_line(add), 5);
+        assert_eq!(chunk.get_instruction_line(add - 1), 6);
+    }
+
+    #[test]
+    fn legacy_print_statement() {
+        let source = "
+            func main() {
+                print 5;
+            }
+            ";
+        let (status, chunk, _, _) = compile_source_with(
+            source,
+            Vec::new(),
+            |compiler| compiler.enable_legacy_print(),
+            |_| {},
+        );
+        assert!(matches!(status, CompileStatus::Success(_)));
+        assert!(
+            (0..chunk.get_size()).any(|op_index| chunk.get_instruction(op_index) == OpCode::Print)
+        );
+
+        assert!(!compiles(source));
+    }
+
+    #[test]
+    fn struct_name_starts_declaration_or_expression() {
+        assert!(compiles(
+            "
+            struct Point {
+                int x;
+                int y;
+            }
+
+            func main() {
+                Point point = Point(1, 2);
+                Point? missing = nil;
+                Point(3, 4);
+                Point(5, 6).x;
+            }
+            "
+        ));
+    }
+
+    #[test]
+    fn double_negation_is_removed() {
+        let source = "
+            func main() {
+                int a = 5;
+                int b = -(-a);
+            }
+            ";
+        let (status, chunk, _, _) = compile_source(source);
+        assert!(matches!(status, CompileStatus::Success(_)));
+
+        let code: Vec<OpCode> = (0..chunk.get_size())
+            .map(|op_index| chunk.get_instruction(op_index))
+            .collect();
+        assert!(!code.contains(&OpCode::Negate));
+        assert_eq!(code.iter().filter(|op| **op == OpCode::Nop).count(), 2);
+    }
+
+    #[test]
+    fn implicit_return_is_omitted_after_a_return() {
+        let function_code = |body: &str| {
+            let source = format!(
+                "func pick(int x) int {{ {} }} func main() {{ int a = pick(1); }}",
+                body
+            );
+            let (status, chunk, _, _) = compile_source(&source);
+            assert!(matches!(status, CompileStatus::Success(_)));
+
+            // The function is the first code, right after the jump over it
+            let OpCode::Jump(body_size) = chunk.get_instruction(0) else {
+                unreachable!();
+            };
+            (1..=body_size)
+                .map(|op_index| chunk.get_instruction(op_index))
+                .collect::<Vec<OpCode>>()
+        };
+
+        assert_eq!(
+            function_code("int y = x; return y;"),
+            vec![
+                OpCode::Start,
+                OpCode::GetLocal(0),
+                OpCode::GetLocal(1),
+                OpCode::Return
+            ]
+        );
+        assert!(
+            !function_code("if (x > 0) { return 1; } else { return 2; }")
+                .ends_with(&[OpCode::Nil, OpCode::Return])
+        );
+        assert!(function_code("if (x > 0) { return 1; }").ends_with(&[OpCode::Nil, OpCode::Return]));
+    }
+
+    #[test]
+    fn local_increments_are_folded() {
+        let source = "
+            func main() {
+                int total = 0;
+                for (int i = 0; i < 10; i = i + 1) {
+                    total = total + 2;
+                }
+                int copy = total = total + 1;
+                float f = 0.0;
+                f = f + 1.0;
+            }
+            ";
+        let (status, chunk, _, _) = compile_source(source);
+        assert!(matches!(status, CompileStatus::Success(_)));
+
+        let code: Vec<OpCode> = (0..chunk.get_size())
+            .map(|op_index| chunk.get_instruction(op_index))
+            .collect();
+        assert!(code.contains(&OpCode::IncLocal(1, 1)));
+        assert!(code.contains(&OpCode::IncLocal(0, 2)));
+        // Only statements are folded, `copy` needs the value and `f` is a float
+        assert_eq!(
+            code.iter()
+                .filter(|op| matches!(op, OpCode::IncLocal(_, _)))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn malformed_hex_floats_are_errors() {
+        let source = |literal: &str| format!("func main() {{ float f = {}; }}", literal);
+        assert!(compiles(&source("0x1.8p3")));
+        assert!(compiles(&source("0x.8p-1")));
+        assert!(!compiles(&source("0x1.8")));
+        assert!(!compiles(&source("0x1.8p")));
+        assert!(!compiles(&source("0xp3")));
+        assert!(!compiles(&source("0x1.8.1p3")));
+        assert!(!compiles(&source("0x11111111111111111p0")));
+    }
+
+    #[test]
+    fn argument_type_errors_name_the_argument() {
+        let source = "
+            func greet(string name, int times) {
+            }
+
+            func main() {
+                greet(\"squat\", \"twice\");
+            }
+        ";
+        let (status, _, _, errors) = compile_source(source);
+        assert!(matches!(status, CompileStatus::Fail));
+        assert_eq!(
+            errors[0],
+            "[ERROR E0001] (<repl>:6) Argument 2 of 'greet': expected <type Int> but found <type String>"
+        );
+    }
+
+    #[test]
+    fn empty_programs_have_no_main() {
+        for source in ["", "  \n\t\n", "// nothing here\n/* or here */"] {
+            let (status, _, _, errors) = compile_source(source);
+            assert!(matches!(status, CompileStatus::Fail));
+            assert_eq!(
+                errors,
+                vec!["[COMPILE ERROR] Empty program: no 'main' function found"]
+            );
+        }
+        assert_eq!(
+            compile_source("int count = 0;").3,
+            vec!["[COMPILE ERROR] Function 'main' was not defined!"]
+        );
+    }
+
+    #[test]
+    fn common_errors_report_their_code() {
+        let first_error = |source: &str| compile_source(source).3[0].clone();
+        assert_eq!(
+            first_error("func main() { int count = \"three\"; }"),
+            "[ERROR E0001] (<repl>:1) Expected <type Int> but found <type String>"
+        );
+        assert!(first_error("func main() { count = 3; }").starts_with("[ERROR E0002]"));
+        assert!(first_error("func main() { int count = 3 }").starts_with("[ERROR E0003]"));
+        assert!(error_code::explain("E0001")
+            .unwrap()
+            .starts_with("A value of one type was used"));
+    }
+
+    #[test]
+    fn instances_are_typed_by_their_struct() {
+        let source = "
+            struct Point { int x; int y; }
+            struct Size { int x; int y; }
+
+            func main() {
+                DECLARATION
+            }
+        ";
+        let compiles_with = |declaration| compiles(&source.replace("DECLARATION", declaration));
+        assert!(compiles_with("Point point = Point(1, 2);"));
+        assert!(compiles_with("int x = Point(1, 2).x;"));
+        assert!(!compiles_with("Size size = Point(1, 2);"));
+        assert!(!compiles_with("string x = Point(1, 2).x;"));
+        assert!(!compiles_with("Point point = Point(1, \"2\");"));
+    }
+
+    #[test]
+    fn only_global_functions_can_be_memoized() {
+        assert!(compiles(
+            "@memoize func square(int n) int { return n * n; } func main() { square(2); }"
+        ));
+        assert!(!compiles(
+            "@cache func square(int n) int { return n * n; } func main() { square(2); }"
+        ));
+        assert!(!compiles("@memoize int n = 2; func main() {}"));
+        assert!(!compiles("@memoize func main() {}"));
+        assert!(!compiles(
+            "func main() { @memoize func square(int n) int { return n * n; } }"
+        ));
+    }
+
+    #[test]
+    fn functions_can_be_declared_in_blocks() {
+        assert!(compiles(
+            "func main() { func twice(int n) int { return n * 2; } int x = twice(2); }"
+        ));
+        assert!(!compiles(
+            "func main() { { func twice(int n) int { return n * 2; } } int x = twice(2); }"
+        ));
+        assert!(compiles(
+            "func main() { func down(int n) int { if (n > 0) { return down(n - 1); } return n; } }"
+        ));
+        assert!(!compiles(
+            "func main() { func twice(int n) int { twice = twice; return n; } }"
+        ));
+        // Like lambdas, they capture the locals of the enclosing function
+        assert!(compiles(
+            "func main() { int y = 3; func add(int n) int { return n + y; } int x = add(2); }"
+        ));
+        assert!(!compiles(
+            "func main() { func twice(int n) int { return n * 2; } string x = twice(2); }"
+        ));
+    }
+
+    #[test]
+    fn lambdas_are_typed_by_their_signature() {
+        assert!(compiles(
+            "func main() { func(int) int twice = func(int x) int { return x * 2; }; }"
+        ));
+        assert!(!compiles(
+            "func main() { func(int) int twice = func(float x) int { return 2; }; }"
+        ));
+        assert!(compiles(
+            "func main() { int y = 2; func(int) int add = func(int x) int { return x + y; }; }"
+        ));
+        assert!(!compiles(
+            "func main() { int y = 2; func(int) int add = func(int x) int { y = x; return y; }; }"
+        ));
+    }
+
+    #[test]
+    fn compiling_continues_after_a_return_outside_a_function() {
+        let errors = |source: &str| {
+            let type_data = SquatFunctionTypeData::new(vec![SquatType::Number], SquatType::Number);
+            let natives = vec![native("abs", crate::native::number::abs, type_data)];
+            compile_source_with(source, natives, |_| {}, |compiler| compiler.errors.clone()).3
+        };
+        let source = "
+            int x = 1;
+            return x + 2;
+            func main() {
+                int y = x + 1;
+            }
+        ";
+        assert_eq!(
+            errors(source),
+            vec!["[ERROR] (<repl>:3) Cannot return from outside a function.".to_owned()]
+        );
+
+        // The errors after it are still found
+        let type_errors = errors(&source.replace("x + 1", "x + \"1\""));
+        assert_eq!(type_errors.len(), 2);
+        assert!(type_errors[1].starts_with("[ERROR E0001] (<repl>:5)"));
+
+        // A declaration that can't be compiled is skipped as a whole
+        assert_eq!(
+            errors(
+                "
+                func abs(int x) int { return 100; }
+                struct abs { int x; }
+                func main() {
+                    int y = abs(1);
+                }
+                "
+            ),
+            vec![
+                "[ERROR] (<repl>:2) 'abs' is a native object".to_owned(),
+                "[ERROR] (<repl>:3) 'abs' is a native object".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn misplaced_block_expressions_are_skipped() {
+        let source = "
+            func main() {
+                int b = 1 + { 2 };
+                int c = b;
+            }
+        ";
+        assert_eq!(
+            compile_source(source).3,
+            vec![
+                "[ERROR] (<repl>:3) Block expressions can only start the value of a declaration or a return"
+                    .to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn break_and_continue_have_to_be_in_a_loop() {
+        assert!(compiles(
+            "func main() { while (true) { if (true) { continue; } break; } }"
+        ));
+        assert!(!compiles("func main() { break; }"));
+        assert!(!compiles("func main() { if (true) { continue; } }"));
+        assert!(!compiles(
+            "func main() { while (true) { func(int) int f = func(int x) int { break; }; } }"
+        ));
+        assert!(!compiles(
+            "func main() { defer { break; } while (true) { return 0; } }"
+        ));
+    }
+
+    #[test]
+    fn dead_functions_are_removed() {
+        let without_dead_function = compiled_size(
+            "
+            func square(int a) int {
+                return a * a;
+            }
+
+            func main() {
+                int value = square(4);
+            }
+            ",
+        );
+        let with_dead_function = compiled_size(
+            "
+            func unused(int a) int {
+                if (a > 0) {
+                    return unused(a - 1);
+                }
+                return a;
+            }
+
+            func square(int a) int {
+                return a * a;
+            }
+
+            func main() {
+                int value = square(4);
+            }
+            ",
+        );
+        assert_eq!(without_dead_function, with_dead_function);
+    }
+
+    fn parse_tree(source: &str) -> Vec<String> {
+        let (status, _, _, tree) = compile_source_with(
+            source,
+            Vec::new(),
+            |compiler| compiler.record_parse_tree(),
+            |compiler| compiler.take_parse_tree(),
+        );
+        assert!(matches!(status, CompileStatus::Success(_)));
+        tree.iter().map(|node| node.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_tree_follows_precedence() {
+        let tree = parse_tree(
+            "
+            func main() {
+                int value = 1 + 2 * 3;
+            }
+            ",
+        );
+        assert_eq!(tree, vec!["(func main (var value (+ 1 (* 2 3))))"]);
+    }
+
+    fn compiles(source: &str) -> bool {
+        matches!(compile_source(source).0, CompileStatus::Success(_))
+    }
+
+    #[test]
+    fn chained_assignment_checks_every_target() {
+        let source = "
+            func main() {
+                int a = 0;
+                TARGET_TYPE b = TARGET_VALUE;
+                a = b = 5;
+            }
+        ";
+        assert!(compiles(
+            &source
+                .replace("TARGET_TYPE", "int")
+                .replace("TARGET_VALUE", "0")
+        ));
+        assert!(!compiles(
+            &source
+                .replace("TARGET_TYPE", "string")
+                .replace("TARGET_VALUE", "\"\"")
+        ));
+    }
+
+    fn compiles_with_lines_native(source: &str) -> bool {
+        let type_data = SquatFunctionTypeData::new(
+            vec![SquatType::String],
+            SquatType::Array(Box::new(SquatType::String)),
+        );
+        let natives = vec![native("lines", crate::native::fs::read_lines, type_data)];
+        let (status, _, _, _) = compile_source_with(source, natives, |_| {}, |_| {});
+        matches!(status, CompileStatus::Success(_))
+    }
+
+    #[test]
+    fn index_type_does_not_depend_on_the_index_sign() {
+        for index in ["0", "-1", "i", "-i", "i - 2"] {
+            let array_source = format!(
+                "func main() {{ int i = 1; var a = lines(\"\"); TYPE x = a[{}]; }}",
+                index
+            );
+            assert!(compiles_with_lines_native(
+                &array_source.replace("TYPE", "string")
+            ));
+            assert!(!compiles_with_lines_native(
+                &array_source.replace("TYPE", "int")
+            ));
+
+            let string_source = format!(
+                "func main() {{ int i = 1; string s = \"abc\"; TYPE x = s[{}]; }}",
+                index
+            );
+            assert!(compiles(&string_source.replace("TYPE", "string")));
+            assert!(!compiles(&string_source.replace("TYPE", "int")));
+        }
+    }
+
+    #[test]
+    fn globals_cannot_be_read_before_their_declaration() {
+        assert!(compiles(
+            "int first = 1; func read() int { return first; } int second = read(); func main() { int _x = second; }"
+        ));
+        assert!(!compiles(
+            "func read() int { return later; } int later = 1; func main() { int _x = read(); }"
+        ));
+        assert!(!compiles(
+            "int first = first; func main() { int _x = first; }"
+        ));
+    }
+
+    #[test]
+    fn symbols_list_the_inferred_types() {
+        let source = "
+            float ratio = 0.5;
+
+            func half(float value) float {
+                var result = value * ratio;
+                return result;
+            }
+
+            func main() {
+                float _half = half(3.0);
+            }
+        ";
+        let (status, _, _, symbols) = compile_source_with(
+            source,
+            Vec::new(),
+            |compiler| compiler.record_symbols(),
+            |compiler| compiler.dump_symbols(),
+        );
+        assert!(matches!(status, CompileStatus::Success(_)));
+        let symbols: Vec<&str> = symbols.lines().collect();
+        assert_eq!(symbols[0], "ratio: <type Float>");
+        assert!(symbols.contains(&"half.value: <type Float>"));
+        assert!(symbols.contains(&"half.result: <type Float>"));
+        assert!(symbols.contains(&"main._half: <type Float>"));
+    }
+
+    #[test]
+    fn natives_cannot_be_assigned_to() {
+        assert!(compiles_with_lines_native(
+            "func main() { var _read = lines; }"
+        ));
+        assert!(!compiles_with_lines_native("func main() { lines = 5; }"));
+        assert!(!compiles_with_lines_native(
+            "func main() { var read = lines; lines = read; }"
+        ));
+    }
+
+    #[test]
+    fn indices_have_to_be_ints() {
+        for index in ["1.5", "-1.5", "\"1\"", "true"] {
+            assert!(!compiles(&format!(
+                "func main() {{ string s = \"abc\"; string x = s[{}]; }}",
+                index
+            )));
+        }
+    }
+
+    #[test]
+    fn comparisons_are_bools() {
+        for comparison in [
+            "x == 3",
+            "x + 1 != 3",
+            "x < 3",
+            "x >= 1 and x <= 2",
+            "\"a\" < \"b\"",
+        ] {
+            assert!(compiles(&format!(
+                "func main() {{ int x = 1; bool _b = {}; var _v = {}; }}",
+                comparison, comparison
+            )));
+            assert!(!compiles(&format!(
+                "func main() {{ int x = 1; int _b = {}; }}",
+                comparison
+            )));
+        }
+        assert!(compiles(
+            "func main() { int x = 1; int _y = x + 1; bool _b = x + 1 > 1; }"
+        ));
+    }
+
+    #[test]
+    fn dangling_ternary_colon_is_an_error() {
+        assert!(compiles(
+            "func main() { bool a = true; int x = a ? 1 : a ? 2 : 3; }"
+        ));
+        assert!(!compiles(
+            "func main() { bool a = true; int x = a ? 1 : 2 : 3; }"
+        ));
+    }
+
+    #[test]
+    fn unused_variables_are_reported() {
+        let source = "
+            int counter = 0;
+            int total = 0;
+
+            func add(int unused_param) {
+                int used = 1;
+                int unused = 2;
+                int _ignored = 3;
+                total = total + used;
+            }
+
+            func each(any values) {
+                for (var value : values) {}
+            }
+
+            func main() {
+                add(1);
+                each(1);
+            }
+        ";
+        let warnings = |source: &str, configure: fn(&mut Compiler)| {
+            compile_source_with(source, Vec::new(), configure, |compiler| {
+                compiler.warnings.clone()
+            })
+        };
+        let (status, _, _, unused) = warnings(source, |_| {});
+        assert!(matches!(status, CompileStatus::Success(_)));
+        assert_eq!(
+            unused,
+            vec![
+                "[WARNING] (<repl>:7) Unused variable 'unused'",
+                "[WARNING] (<repl>:13) Unused variable 'value'",
+                "[WARNING] (<repl>:2) Unused variable 'counter'",
+            ]
+        );
+
+        // Variables may only be unused because the code reading them failed
+        // to compile
+        let failing_source = source.replace("total + used", "total + used + \"1\"");
+        let (status, _, _, unused) = warnings(&failing_source, |_| {});
+        assert!(matches!(status, CompileStatus::Fail));
+        assert!(unused.is_empty());
+
+        let (status, _, _, unused) = warnings(source, |compiler| compiler.allow_unused_variables());
+        assert!(matches!(status, CompileStatus::Success(_)));
+        assert!(unused.is_empty());
+    }
+
+    #[test]
+    fn unbounded_recursion_is_reported() {
+        let source = "
+            func factorial(int n) int {
+                if (n == 0) {
+                    return 1;
+                }
+                return n * factorial(n - 1);
+            }
+
+            func countdown(int n) int {
+                if (n <= 0) {
+                    return 0;
+                }
+                return n + countdown(n - 1);
+            }
+
+            func find(int n) int {
+                if (n == 10) {
+                    return n;
+                }
+                return find(n + 1);
+            }
+
+            func main() {
+                int total = factorial(5) + countdown(5) + find(0);
+            }
+        ";
+        let warnings = |source: &str, configure: fn(&mut Compiler)| {
+            compile_source_with(source, Vec::new(), configure, |compiler| {
+                compiler.warnings.clone()
+            })
+        };
+        let (status, _, _, recursion) = warnings(source, |compiler| {
+            compiler.allow_unused_variables();
+            compiler.enable_recursion_warnings();
+        });
+        assert!(matches!(status, CompileStatus::Success(_)));
+        assert_eq!(
+            recursion,
+            vec!["[WARNING] (<repl>:2) Function 'factorial' calls itself outside of a tail position and its only base case is an equality check, it may recurse until the stack overflows"]
+        );
+
+        let (status, _, _, recursion) =
+            warnings(source, |compiler| compiler.allow_unused_variables());
+        assert!(matches!(status, CompileStatus::Success(_)));
+        assert!(recursion.is_empty());
+    }
+
+    #[test]
+    fn only_fields_with_defaults_can_be_omitted() {
+        let source = "
+            struct Point {
+                int x;
+                int y = 0;
+            }
+
+            func main() {
+                Point point = POINT_CALL;
+            }
+        ";
+        assert!(compiles(&source.replace("POINT_CALL", "Point(1)")));
+        assert!(!compiles(&source.replace("POINT_CALL", "Point()")));
+        assert!(!compiles(
+            &source
+                .replace("POINT_CALL", "Point(1, 2)")
+                .replace("int y = 0;", "int y = x;")
+        ));
+    }
+
+    #[test]
+    fn functions_do_not_fall_through() {
+        let source = "
+            func double(int n) int {
+                if (n > 10) {
+                    return n;
+                }
+                return n * 2;
+            }
+
+            func main() {
+                int i = 0;
+                while (i < 3) {
+                    i = double(i) + 1;
+                }
+            }
+        ";
+        let (status, _, _, (function_count, verified)) = compile_source_with(
+            source,
+            Vec::new(),
+            |_| {},
+            |compiler| (compiler.function_ranges.len(), compiler.verify_functions()),
+        );
+        assert!(matches!(status, CompileStatus::Success(_)));
+        assert_eq!(function_count, 1);
+        assert_eq!(verified, Ok(()));
+    }
+
+    #[test]
+    fn missing_semicolon_error_shows_found_token() {
+        let source = "int x = 1 }".to_owned();
+        let mut chunk = Chunk::new("Test", true);
+        let mut constants = ValueArray::new("Constants");
+        let natives = Vec::new();
+
+        let mut compiler = Compiler::new(&source, &mut chunk, &mut constants, &natives);
+        for _ in 0..5 {
+            compiler.advance();
+        }
+        assert_eq!(
+            compiler.unexpected_token_error(
+                TokenType::Semicolon,
+                "Expect ';' after variable declaration"
+            ),
+            "Error at '1': Expect ';' after variable declaration, expected ';' but found '}'"
+        );
+
+        compiler.advance();
+        assert_eq!(
+            compiler.unexpected_token_error(TokenType::RightParenthesis, "Expect closing ')'"),
+            "Error at '}': Expect closing ')', expected ')' but found end of file"
+        );
+    }
+
+    #[test]
+    fn constant_string_index_out_of_range() {
+        assert!(!compiles(
+            "
+            func main() {
+                string letter = \"hello\"[10];
+            }
+            "
+        ));
+        assert!(!compiles(
+            "
+            func main() {
+                string letter = \"héllo\"[5];
+            }
+            "
+        ));
+        assert!(compiles(
+            "
+            func main() {
+                string letter = \"hello\"[4];
+                letter = \"héllo\"[4];
+                int index = 10;
+                letter = \"hello\"[index];
+            }
+            "
+        ));
+    }
+
+    #[test]
+    fn doc_comment_is_attached_to_function() {
+        let source = "
+            /// Adds two numbers
+            /// and returns the sum
+            func add(int a, int b) int {
+                return a + b;
+            }
+
+            // Not a doc comment
+            func main() {
+                int sum = add(1, 2);
+            }
+            ";
+        let (status, _, _, doc_comments) = compile_source_with(
+            source,
+            Vec::new(),
+            |_| {},
+            |compiler| compiler.take_doc_comments(),
+        );
+        assert!(matches!(status, CompileStatus::Success(_)));
+        assert_eq!(
+            doc_comments,
+            vec![DocComment::new(
+                "func",
+                "add",
+                "Adds two numbers\nand returns the sum"
+            )]
+        );
+    }
+
+    #[test]
+    fn strict_types_reject_mixing_ints_and_floats() {
+        let strict_compiles = |source: &str| {
+            let type_data = SquatFunctionTypeData::new(vec![SquatType::Number], SquatType::Number);
+            let natives = vec![native("sqrt", crate::native::number::abs, type_data)];
+            let (status, _, _, _) = compile_source_with(
+                source,
+                natives,
+                |compiler| compiler.enable_strict_types(),
+                |_| {},
+            );
+            matches!(status, CompileStatus::Success(_))
+        };
+
+        for source in [
+            "func main() { float x = 1 + 2.0; }",
+            "func main() { float x = 2.0 * 3; }",
+            "func main() { bool x = 1 < 2.5; }",
+            "func main() { bool x = 1 == 1.0; }",
+        ] {
+            assert!(compiles(source), "{}", source);
+            assert!(!strict_compiles(source), "{}", source);
+        }
+        assert!(!compiles("func main() { int x = 1 + 2.0; }"));
+        assert!(strict_compiles("func main() { float x = 1.0 + 2.0; }"));
+
+        // `number` still takes ints and floats as an argument of a native,
+        // but what the native returns has to be converted
+        assert!(strict_compiles("func main() { any x = sqrt(2); }"));
+        assert!(!strict_compiles("func main() { int x = sqrt(2); }"));
+    }
+
+    #[test]
+    fn optionals_have_to_be_checked_before_operators_use_them() {
+        let source = "
+            func main() {
+                int? n;
+                any x = EXPRESSION;
+            }
+            ";
+        let (status, _, _, errors) = compile_source(&source.replace("EXPRESSION", "n + 1"));
+        assert!(matches!(status, CompileStatus::Fail));
+        assert_eq!(
+            errors,
+            vec!["[ERROR E0001] (<repl>:4) Operand of '+' is <type Optional of <type Int>> and may be nil, check it for nil first"]
+        );
+
+        for expression in ["1 + n", "n * 2", "n < 1", "-n", "!n"] {
+            assert!(
+                !compiles(&source.replace("EXPRESSION", expression)),
+                "{}",
+                expression
+            );
+        }
+        assert!(compiles(&source.replace("EXPRESSION", "n == nil")));
+        assert!(compiles(&source.replace("EXPRESSION", "n != nil")));
+
+        // The '?' belongs to the type, it can't be repeated after it
+        assert!(!compiles("func main() { int?? n; }"));
+    }
+
+    #[test]
+    fn failed_declaration_is_reported_once() {
+        let (_, _, _, errors) = compile_source(
+            "
+            var h;
+            func main() {
+                var x;
+                int y = x + h;
+                x = y;
+            }
+            ",
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "[ERROR] (<repl>:2) Cannot define variable using 'var' without giving it a value",
+                "[ERROR] (<repl>:4) Cannot define variable using 'var' without giving it a value",
+            ]
+        );
+
+        let (_, _, _, errors) = compile_source("func main() { var x = * 2; int y = x + 1; }");
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+    }
+
+    #[test]
+    fn illegal_token_is_reported_once() {
+        let (_, _, _, errors) = compile_source(
+            "
+            func main() {
+                int total = 0;
+                for (int i = 0; i < * 3; i = i + 1) {
+                    total = total + i;
+                }
+                if (total + * 2 > 3) {
+                    total = 1;
+                } else {
+                    total = 2;
+                }
+            }
+            ",
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "[ERROR] (<repl>:4) Illegal expression",
+                "[ERROR] (<repl>:7) Illegal expression",
+            ]
+        );
+    }
+
+    #[test]
+    fn strict_types_reject_mixing_through_any() {
+        let source = "
+            func main() {
+                any number = 1;
+                float sum = number + 2.0;
+            }
+            ";
+        assert!(compiles(source));
+
+        let (status, _, _, _) = compile_source_with(
+            source,
+            Vec::new(),
+            |compiler| compiler.enable_strict_types(),
+            |_| {},
+        );
+        assert!(matches!(status, CompileStatus::Fail));
+    }
+
+    #[test]
+    fn implicit_defaults_can_be_disabled() {
+        let source = "
+            func main() {
+                int x;
+                int y = x + 1;
+            }
+            ";
+        assert!(compiles(source));
+
+        let (status, _, _, errors) = compile_source_with(
+            source,
+            Vec::new(),
+            |compiler| compiler.disable_implicit_defaults(),
+            |compiler| compiler.errors.clone(),
+        );
+        assert!(matches!(status, CompileStatus::Fail));
+        assert_eq!(
+            errors,
+            vec!["[ERROR] (<repl>:3) Variable 'x' has to be given a value when implicit defaults are disabled"]
+        );
+    }
+
+    #[test]
+    fn const_parameters_cannot_be_assigned() {
+        assert!(compiles(
+            "
+            func double(const int x) int {
+                return x * 2;
+            }
+
+            func main() {
+                int four = double(2);
+            }
+            "
+        ));
+
+        let source = "
+            func clamp(const int x, int max) int {
+                if (x > max) {
+                    x = max;
+                }
+                return x;
+            }
+
+            func main() {
+                int two = clamp(3, 2);
+            }
+            ";
+        let (status, _, _, errors) = compile_source(source);
+        assert!(matches!(status, CompileStatus::Fail));
+        assert_eq!(
+            errors,
+            vec!["[ERROR] (<repl>:4) Cannot assign to 'x', it is a const parameter"]
+        );
+    }
+
+    #[test]
+    fn arguments_need_commas() {
+        assert!(!compiles(
+            "
+            func add(int a, int b) int {
+                return a + b;
+            }
+
+            func main() {
+                int sum = add(1 2);
+            }
+            "
+        ));
+    }
+
+    #[test]
+    fn oversized_integer_literal() {
+        assert!(!compiles(
+            "
+            func main() {
+                int big = 99999999999999999999;
+            }
+            "
+        ));
+        assert!(compiles(
+            "
+            func main() {
+                int big = 9223372036854775807;
+            }
+            "
+        ));
+    }
+
+    #[test]
+    fn constant_expression_is_folded() {
+        let source = "
+            func main() {
+                int value = 2 * (3 + 4);
+            }
+            ";
+        let (status, chunk, constants, _) = compile_source(source);
+        assert!(matches!(status, CompileStatus::Success(_)));
+        assert_eq!(constants.get_size(), 1);
+        assert_eq!(constants.get(0), &SquatValue::Int(14));
+        assert!((0..chunk.get_size()).all(|op_index| !matches!(
+            chunk.get_instruction(op_index),
+            OpCode::Add | OpCode::Multiply
+        )));
     }
 }
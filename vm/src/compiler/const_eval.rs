@@ -0,0 +1,156 @@
+use crate::{
+    lexer::Lexer,
+    token::{Token, TokenType},
+    value::squat_value::SquatValue,
+};
+
+pub fn is_hex_float(lexeme: &str) -> bool {
+    lexeme.starts_with("0x") || lexeme.starts_with("0X")
+}
+
+/// Value of a hex float literal like `0x1.8p3`, which is `1.5 * 2^3`. The
+/// `p` exponent is required.
+pub fn parse_hex_float(lexeme: &str) -> Result<f64, String> {
+    let invalid = || format!("Invalid hex float literal '{}'", lexeme);
+    let digits = lexeme
+        .strip_prefix("0x")
+        .or_else(|| lexeme.strip_prefix("0X"))
+        .ok_or_else(invalid)?;
+    let (mantissa, exponent) = digits.split_once(['p', 'P']).ok_or_else(invalid)?;
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return Err(invalid());
+    }
+    let mut exponent: i32 = exponent.parse().map_err(|_| invalid())?;
+
+    let mut value: u64 = 0;
+    for digit in whole.chars().chain(fraction.chars()) {
+        let digit = digit.to_digit(16).ok_or_else(invalid)?;
+        value = value
+            .checked_mul(16)
+            .and_then(|value| value.checked_add(digit as u64))
+            .ok_or_else(|| format!("Hex float literal '{}' has too many digits", lexeme))?;
+    }
+    exponent -= 4 * fraction.len() as i32;
+    Ok(value as f64 * 2f64.powi(exponent))
+}
+
+/// Folds an expression made only of literals, groupings and arithmetic into a
+/// single value. Works on its own copy of the lexer so nothing is consumed or
+/// reported unless the whole expression is constant.
+pub struct ConstEvaluator<'a> {
+    lexer: Lexer<'a>,
+    previous_token: Option<Token>,
+    current_token: Token,
+}
+
+impl<'a> ConstEvaluator<'a> {
+    pub fn new(lexer: Lexer<'a>, current_token: Token) -> ConstEvaluator<'a> {
+        ConstEvaluator {
+            lexer,
+            previous_token: None,
+            current_token,
+        }
+    }
+
+    pub fn current_token_type(&self) -> TokenType {
+        self.current_token.token_type
+    }
+
+    /// Returns the lexer and the tokens to continue compiling after the
+    /// evaluated expression
+    pub fn finish(self) -> (Lexer<'a>, Option<Token>, Token) {
+        (self.lexer, self.previous_token, self.current_token)
+    }
+
+    /// Returns `None` if the expression is not constant or cannot be folded
+    /// without changing its meaning, like a division by zero.
+    pub fn expression(&mut self) -> Option<SquatValue> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, min_binding_power: u8) -> Option<SquatValue> {
+        let mut value = self.prefix()?;
+        loop {
+            let operator = self.current_token_type();
+            let binding_power = match operator {
+                TokenType::Plus | TokenType::Minus => 1,
+                TokenType::Star | TokenType::Slash | TokenType::Percent => 2,
+                _ => break,
+            };
+            if binding_power <= min_binding_power {
+                break;
+            }
+            self.advance()?;
+            let rhs = self.binary(binding_power)?;
+            value = Self::fold(operator, value, rhs)?;
+        }
+        Some(value)
+    }
+
+    fn prefix(&mut self) -> Option<SquatValue> {
+        self.advance()?;
+        let token = self.previous_token.as_ref()?;
+        match token.token_type {
+            TokenType::Number if is_hex_float(&token.lexeme) => {
+                parse_hex_float(&token.lexeme).ok().map(SquatValue::Float)
+            }
+            TokenType::Number if token.lexeme.contains('.') => {
+                token.lexeme.parse().ok().map(SquatValue::Float)
+            }
+            TokenType::Number => token.lexeme.parse().ok().map(SquatValue::Int),
+            TokenType::String => Some(SquatValue::String(token.lexeme.as_str().into())),
+            TokenType::True => Some(SquatValue::Bool(true)),
+            TokenType::False => Some(SquatValue::Bool(false)),
+            TokenType::Nan => Some(SquatValue::Float(f64::NAN)),
+            TokenType::Inf => Some(SquatValue::Float(f64::INFINITY)),
+            TokenType::LeftParenthesis => {
+                let value = self.binary(0)?;
+                if self.current_token_type() != TokenType::RightParenthesis {
+                    return None;
+                }
+                self.advance()?;
+                Some(value)
+            }
+            TokenType::Minus => match self.binary(2)? {
+                SquatValue::Int(value) => value.checked_neg().map(SquatValue::Int),
+                SquatValue::Float(value) => Some(SquatValue::Float(-value)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn fold(operator: TokenType, left: SquatValue, right: SquatValue) -> Option<SquatValue> {
+        match (&left, &right) {
+            // Checked so overflows and divisions by zero are left to the VM
+            (SquatValue::Int(l), SquatValue::Int(r)) => match operator {
+                TokenType::Plus => l.checked_add(*r),
+                TokenType::Minus => l.checked_sub(*r),
+                TokenType::Star => l.checked_mul(*r),
+                TokenType::Slash => l.checked_div(*r),
+                TokenType::Percent => l.checked_rem(*r),
+                _ => None,
+            }
+            .map(SquatValue::Int),
+            (SquatValue::Float(_), SquatValue::Float(_)) => match operator {
+                TokenType::Plus => Some(left + right),
+                TokenType::Minus => Some(left - right),
+                TokenType::Star => Some(left * right),
+                TokenType::Slash => Some(left / right),
+                TokenType::Percent => Some(left % right),
+                _ => None,
+            },
+            (SquatValue::String(_), SquatValue::String(_)) if operator == TokenType::Plus => {
+                Some(left + right)
+            }
+            _ => None,
+        }
+    }
+
+    fn advance(&mut self) -> Option<()> {
+        let next_token = self.lexer.scan_token().ok()?;
+        self.previous_token = Some(std::mem::replace(&mut self.current_token, next_token));
+        Some(())
+    }
+}
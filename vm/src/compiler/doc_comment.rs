@@ -0,0 +1,30 @@
+use std::fmt;
+
+/// `///` comment attached to a function or struct declaration, printed by
+/// `--extract-docs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocComment {
+    pub kind: String,
+    pub name: String,
+    pub text: String,
+}
+
+impl DocComment {
+    pub fn new(kind: &str, name: &str, text: &str) -> DocComment {
+        DocComment {
+            kind: kind.to_owned(),
+            name: name.to_owned(),
+            text: text.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for DocComment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.name)?;
+        for line in self.text.lines() {
+            write!(f, "\n    {}", line)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+/// Stable code of a kind of compile error, printed with the error and looked
+/// up by `--explain`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorCode {
+    pub code: &'static str,
+    explanation: &'static str,
+}
+
+pub const TYPE_MISMATCH: ErrorCode = ErrorCode {
+    code: "E0001",
+    explanation: "\
+A value of one type was used where another type is required.
+
+Variables, parameters and return values have a type, the value given to them
+has to be of that type. Ints and floats are not converted implicitly, use
+to_int or to_float.
+
+Example:
+
+    func main() {
+        int count = \"three\"; // Expected <type Int> but found <type String>
+    }
+
+Give a value of the expected type, or change the type of the variable.",
+};
+
+pub const UNDEFINED_VARIABLE: ErrorCode = ErrorCode {
+    code: "E0002",
+    explanation: "\
+A name was used that is not defined at this point of the program.
+
+Locals can only be used after their declaration and inside the block that
+declares them. Globals and functions can only be used after their
+declaration.
+
+Example:
+
+    func main() {
+        print(total); // total is not defined.
+    }
+
+Check the spelling of the name, or declare it before using it.",
+};
+
+pub const MISSING_SEMICOLON: ErrorCode = ErrorCode {
+    code: "E0003",
+    explanation: "\
+A statement was not ended with ';'.
+
+Every declaration and expression statement ends with a semicolon.
+
+Example:
+
+    func main() {
+        int count = 3 // Expect ';' after variable declaration
+    }
+
+Add the ';' at the end of the statement.",
+};
+
+const ERROR_CODES: [ErrorCode; 3] = [TYPE_MISMATCH, UNDEFINED_VARIABLE, MISSING_SEMICOLON];
+
+/// Longer explanation of the error code, with an example
+pub fn explain(code: &str) -> Option<&'static str> {
+    ERROR_CODES
+        .iter()
+        .find(|error_code| error_code.code.eq_ignore_ascii_case(code))
+        .map(|error_code| error_code.explanation)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn codes_are_explained() {
+        assert!(explain("E0001").unwrap().contains("Example:"));
+        assert!(explain("e0003").unwrap().contains("';'"));
+        assert_eq!(explain("E9999"), None);
+    }
+}
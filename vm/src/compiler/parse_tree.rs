@@ -0,0 +1,25 @@
+use std::fmt;
+
+/// Node of the tree printed by `--parse-tree`. The compiler builds it next
+/// to the byte code, a leaf is a single token and a branch is an operator or
+/// statement with its operands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseNode {
+    Leaf(String),
+    Branch(String, Vec<ParseNode>),
+}
+
+impl fmt::Display for ParseNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseNode::Leaf(lexeme) => write!(f, "{}", lexeme),
+            ParseNode::Branch(label, children) => {
+                write!(f, "({}", label)?;
+                for child in children {
+                    write!(f, " {}", child)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
@@ -6,6 +6,12 @@ pub struct CompilerLocal {
     // If this value is missing, the variable is not initialized yet.
     pub depth: Option<u32>,
     squat_type: Option<SquatType>,
+    // Line of the declaration and whether the value is ever read, used to
+    // warn about unused variables
+    pub line: u32,
+    pub used: bool,
+    // Parameters declared with `const` cannot be assigned to
+    pub is_const: bool,
 }
 
 impl CompilerLocal {
@@ -14,6 +20,9 @@ impl CompilerLocal {
             name: name.to_string(),
             depth,
             squat_type,
+            line: 0,
+            used: false,
+            is_const: false,
         }
     }
 
@@ -54,6 +63,9 @@ pub struct CompilerGlobal {
     pub index: usize,
     pub initialized: bool,
     squat_type: Option<SquatType>,
+    // See `CompilerLocal`
+    pub line: u32,
+    pub used: bool,
 }
 
 impl CompilerGlobal {
@@ -62,6 +74,8 @@ impl CompilerGlobal {
             index,
             initialized,
             squat_type,
+            line: 0,
+            used: false,
         }
     }
 
@@ -7,25 +7,33 @@ pub enum LexerError {
     UndefinedToken { line: u32, lexeme: String },
     IncompleteComment { line: u32 },
     IncompleteString { line: u32 },
+    IncompleteIdentifier { line: u32 },
     InternalError { msg: String, line: u32 },
 }
 
+#[derive(Clone)]
 pub struct Lexer<'a> {
     source: &'a str,
     start: usize,
     current_index: usize,
     source_iterator: Peekable<Chars<'a>>,
     line: u32,
+    doc_lines: Vec<String>,
+    // Comments are returned as `TokenType::Comment` tokens instead of being
+    // skipped, for tools that need to keep them
+    emit_comments: bool,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(source: &'a String) -> Lexer<'a> {
+    pub fn new(source: &'a String, emit_comments: bool) -> Lexer<'a> {
         Lexer {
             source,
             start: 0,
             current_index: 0,
             source_iterator: source.chars().peekable(),
             line: 1,
+            doc_lines: Vec::new(),
+            emit_comments,
         }
     }
 
@@ -44,7 +52,7 @@ impl<'a> Lexer<'a> {
         self.start = self.current_index;
 
         if let Some(c) = self.source_iterator.next() {
-            self.current_index += 1;
+            self.current_index += c.len_utf8();
 
             if let Some(token) = self.identifier(&c) {
                 return Ok(token);
@@ -123,6 +131,14 @@ impl<'a> Lexer<'a> {
                         if *c == '=' {
                             self.advance();
                             Ok(self.make_token(TokenType::GreaterEqual))
+                        } else if *c == '>' {
+                            self.advance();
+                            if self.source_iterator.peek() == Some(&'>') {
+                                self.advance();
+                                Ok(self.make_token(TokenType::GreaterGreaterGreater))
+                            } else {
+                                Ok(self.make_token(TokenType::GreaterGreater))
+                            }
                         } else {
                             Ok(self.make_token(TokenType::Greater))
                         }
@@ -155,6 +171,45 @@ impl<'a> Lexer<'a> {
                     self.advance();
                     Ok(token)
                 }
+                '@' => {
+                    while let Some(c) = self.source_iterator.peek() {
+                        if c.is_ascii_alphanumeric() || *c == '_' {
+                            self.advance();
+                            continue;
+                        }
+                        break;
+                    }
+                    if self.current_index - self.start == 1 {
+                        return Err(LexerError::UndefinedToken {
+                            line: self.line,
+                            lexeme: "@".to_owned(),
+                        });
+                    }
+                    Ok(self.make_token(TokenType::Annotation))
+                }
+                // Escaped identifiers like `match` can be used where a keyword would
+                // not be allowed
+                '`' => {
+                    while let Some(c) = self.source_iterator.peek() {
+                        if *c == '`' || *c == '\n' {
+                            break;
+                        }
+                        self.advance();
+                    }
+
+                    if self.source_iterator.peek() != Some(&'`') {
+                        return Err(LexerError::IncompleteIdentifier { line: self.line });
+                    }
+
+                    // Omit surrounding backticks
+                    self.start += 1;
+                    let token = self.make_token(TokenType::Identifier);
+                    self.advance();
+                    if token.lexeme.is_empty() {
+                        return Err(LexerError::IncompleteIdentifier { line: self.line });
+                    }
+                    Ok(token)
+                }
                 _ => Err(LexerError::UndefinedToken {
                     line: self.line,
                     lexeme: (self.source[self.start..self.current_index]).to_owned(),
@@ -180,16 +235,29 @@ impl<'a> Lexer<'a> {
                 }
                 // Comments
                 '/' => {
+                    let comment_line = self.line;
+                    let comment_token_start = self.current_index;
                     if self.peek_next("/") {
                         // Single line
+                        let comment_start = self.current_index + 1;
                         while let Some(c) = self.source_iterator.peek() {
                             if *c == '\n' {
-                                self.line += 1;
-                                self.current_index += 1;
                                 break;
                             }
                             self.advance();
                         }
+                        let comment = self.source.get(comment_start..self.current_index);
+                        if let Some(doc) = comment.and_then(|comment| comment.strip_prefix('/')) {
+                            self.doc_lines.push(doc.trim().to_owned());
+                        }
+                        if self.emit_comments {
+                            // The new line is left for the next token
+                            return Ok(Some(self.comment_token(comment_token_start, comment_line)));
+                        }
+                        if self.source_iterator.peek().is_some() {
+                            self.line += 1;
+                            self.current_index += 1;
+                        }
                     } else if self.peek_next("*") {
                         // Multi line
                         self.advance(); // Skip '*'
@@ -210,6 +278,10 @@ impl<'a> Lexer<'a> {
                         if !complete_comment {
                             return Err(LexerError::IncompleteComment { line: self.line });
                         }
+                        if self.emit_comments {
+                            self.source_iterator.next(); // Skip the closing '/'
+                            return Ok(Some(self.comment_token(comment_token_start, comment_line)));
+                        }
                     } else {
                         break; // Break here to let it be handled as a Slash token
                     }
@@ -234,21 +306,33 @@ impl<'a> Lexer<'a> {
             let lexeme = self.source.get(self.start..self.current_index).unwrap();
             return match lexeme {
                 "and" => Some(self.make_token(TokenType::And)),
+                "break" => Some(self.make_token(TokenType::Break)),
+                "catch" => Some(self.make_token(TokenType::Catch)),
+                "const" => Some(self.make_token(TokenType::Const)),
+                "continue" => Some(self.make_token(TokenType::Continue)),
                 "struct" => Some(self.make_token(TokenType::Struct)),
+                "defer" => Some(self.make_token(TokenType::Defer)),
                 "else" => Some(self.make_token(TokenType::Else)),
                 "extends" => Some(self.make_token(TokenType::Extends)),
                 "false" => Some(self.make_token(TokenType::False)),
+                "finally" => Some(self.make_token(TokenType::Finally)),
                 "for" => Some(self.make_token(TokenType::For)),
                 "func" => Some(self.make_token(TokenType::Func)),
                 "if" => Some(self.make_token(TokenType::If)),
+                "inf" => Some(self.make_token(TokenType::Inf)),
+                "match" => Some(self.make_token(TokenType::Match)),
+                "nan" => Some(self.make_token(TokenType::Nan)),
                 "nil" => Some(self.make_token(TokenType::Nil)),
                 "or" => Some(self.make_token(TokenType::Or)),
                 "return" => Some(self.make_token(TokenType::Return)),
                 "super" => Some(self.make_token(TokenType::Super)),
+                "throw" => Some(self.make_token(TokenType::Throw)),
                 "true" => Some(self.make_token(TokenType::True)),
+                "try" => Some(self.make_token(TokenType::Try)),
                 "var" => Some(self.make_token(TokenType::Var)),
                 "while" => Some(self.make_token(TokenType::While)),
 
+                "any" => Some(self.make_token(TokenType::AnyType)),
                 "bool" => Some(self.make_token(TokenType::BoolType)),
                 "float" => Some(self.make_token(TokenType::FloatType)),
                 "int" => Some(self.make_token(TokenType::IntType)),
@@ -261,6 +345,9 @@ impl<'a> Lexer<'a> {
     }
 
     fn number(&mut self, c: &char) -> Option<Token> {
+        if *c == '0' && matches!(self.source_iterator.peek(), Some('x' | 'X')) {
+            return Some(self.hex_float());
+        }
         if c.is_numeric() {
             while let Some(d) = self.source_iterator.peek() {
                 if d.is_numeric() {
@@ -286,19 +373,68 @@ impl<'a> Lexer<'a> {
         None
     }
 
+    /// `0x1.8p3`, the digits are hexadecimal and the exponent after `p` is a
+    /// power of two. Malformed literals are reported by the compiler.
+    fn hex_float(&mut self) -> Token {
+        self.advance();
+        while let Some(d) = self.source_iterator.peek() {
+            if d.is_ascii_hexdigit() || *d == '.' {
+                self.advance();
+                continue;
+            }
+            break;
+        }
+
+        if let Some('p' | 'P') = self.source_iterator.peek() {
+            self.advance();
+            if let Some('+' | '-') = self.source_iterator.peek() {
+                self.advance();
+            }
+            while let Some(d) = self.source_iterator.peek() {
+                if d.is_ascii_digit() {
+                    self.advance();
+                    continue;
+                }
+                break;
+            }
+        }
+
+        self.make_token(TokenType::Number)
+    }
+
+    /// `current_index` is a byte index into the source, so it moves by the
+    /// length of the skipped character
     fn advance(&mut self) {
-        self.source_iterator.next();
-        self.current_index += 1;
+        if let Some(c) = self.source_iterator.next() {
+            self.current_index += c.len_utf8();
+        }
     }
 
-    fn make_token(&self, token_type: TokenType) -> Token {
+    fn make_token(&mut self, token_type: TokenType) -> Token {
+        let doc = match self.doc_lines.is_empty() {
+            true => None,
+            false => Some(std::mem::take(&mut self.doc_lines).join("\n")),
+        };
         Token {
+            doc,
             token_type,
             lexeme: (self.source[self.start..self.current_index]).to_owned(),
             line: self.line,
         }
     }
 
+    /// Comments are not documentation of the token after them when they are
+    /// tokens themselves, so the doc lines are dropped
+    fn comment_token(&mut self, start: usize, line: u32) -> Token {
+        self.doc_lines.clear();
+        Token {
+            doc: None,
+            token_type: TokenType::Comment,
+            lexeme: self.source[start..self.current_index].to_owned(),
+            line,
+        }
+    }
+
     fn peek_next(&mut self, character: &str) -> bool {
         if let Some(substr) = self
             .source
@@ -318,3 +454,116 @@ impl<'a> Lexer<'a> {
         self.current_index >= self.source.len()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn token_types(source: &str, emit_comments: bool) -> Vec<(TokenType, String, u32)> {
+        let source = source.to_owned();
+        let mut lexer = Lexer::new(&source, emit_comments);
+        let mut tokens = Vec::new();
+        loop {
+            let token = lexer.scan_token().unwrap();
+            if token.token_type == TokenType::Eof {
+                return tokens;
+            }
+            tokens.push((token.token_type, token.lexeme, token.line));
+        }
+    }
+
+    const SOURCE: &str = "int a; // the count\n/* multi\nline */ a = 1;";
+
+    #[test]
+    fn comments_are_tokens_when_emitted() {
+        let tokens = token_types(SOURCE, true);
+        assert_eq!(
+            tokens[3],
+            (TokenType::Comment, "// the count".to_owned(), 1)
+        );
+        assert_eq!(
+            tokens[4],
+            (TokenType::Comment, "/* multi\nline */".to_owned(), 2)
+        );
+        assert_eq!(tokens[5], (TokenType::Identifier, "a".to_owned(), 3));
+        assert_eq!(tokens.len(), 9);
+    }
+
+    #[test]
+    fn comments_are_skipped_by_default() {
+        let tokens = token_types(SOURCE, false);
+        assert!(tokens
+            .iter()
+            .all(|(token_type, _, _)| *token_type != TokenType::Comment));
+        assert_eq!(tokens[3], (TokenType::Identifier, "a".to_owned(), 3));
+        assert_eq!(tokens.len(), 7);
+    }
+
+    #[test]
+    fn hex_floats_are_one_number() {
+        assert_eq!(
+            token_types("0x1.8p3 0xAp-2;", false),
+            vec![
+                (TokenType::Number, "0x1.8p3".to_owned(), 1),
+                (TokenType::Number, "0xAp-2".to_owned(), 1),
+                (TokenType::Semicolon, ";".to_owned(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_can_end_the_file() {
+        for emit_comments in [false, true] {
+            let comment =
+                |lexeme: &str| emit_comments.then(|| (TokenType::Comment, lexeme.to_owned(), 1));
+            let expected = |lexeme: &str| {
+                let mut tokens = vec![
+                    (TokenType::Identifier, "a".to_owned(), 1),
+                    (TokenType::Semicolon, ";".to_owned(), 1),
+                ];
+                tokens.extend(comment(lexeme));
+                tokens
+            };
+            assert_eq!(token_types("a; // end", emit_comments), expected("// end"));
+            assert_eq!(token_types("a; //", emit_comments), expected("//"));
+            assert_eq!(
+                token_types("a; /* end */", emit_comments),
+                expected("/* end */")
+            );
+            assert_eq!(token_types("a; /**/", emit_comments), expected("/**/"));
+        }
+    }
+
+    #[test]
+    fn empty_sources_end_right_away() {
+        for emit_comments in [false, true] {
+            assert_eq!(token_types("", emit_comments), vec![]);
+            assert_eq!(token_types(" \n\t\r\n", emit_comments), vec![]);
+        }
+        assert_eq!(token_types("// a\n/* b */\n", false), vec![]);
+    }
+
+    #[test]
+    fn unclosed_comment_at_the_end_is_an_error() {
+        for source in ["a; /* end *", "a; /* end", "a; /*"] {
+            let source = source.to_owned();
+            let mut lexer = Lexer::new(&source, false);
+            lexer.scan_token().unwrap();
+            lexer.scan_token().unwrap();
+            assert!(lexer.scan_token().is_err());
+        }
+    }
+
+    #[test]
+    fn annotations_keep_their_at_sign() {
+        assert_eq!(
+            token_types("@memoize func", false),
+            vec![
+                (TokenType::Annotation, "@memoize".to_owned(), 1),
+                (TokenType::Func, "func".to_owned(), 1),
+            ]
+        );
+        let source = "@ func".to_owned();
+        assert!(Lexer::new(&source, false).scan_token().is_err());
+    }
+}
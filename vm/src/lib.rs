@@ -0,0 +1,15 @@
+//! Compiler and virtual machine of Squat. The `squat` binary runs files and
+//! projects with it, hosts can also load a program and call its functions,
+//! see `VM::call_function`.
+
+mod chunk;
+pub mod compiler;
+mod lexer;
+mod native;
+mod object;
+mod op_code;
+pub mod options;
+pub mod project;
+mod token;
+pub mod value;
+pub mod vm;
@@ -1,17 +1,13 @@
-mod chunk;
-mod compiler;
-mod lexer;
-mod native;
-mod object;
-mod op_code;
-mod options;
-mod token;
-mod value;
-mod vm;
-
-use options::Options;
+use squat::compiler::error_code;
+use squat::options::Options;
+use squat::project::Project;
+use squat::vm::{InterpretResult, VM};
 use std::fs;
-use vm::{InterpretResult, VM};
+
+// Above the codes a script usually exits with, so failures can be told apart
+// from a main that returns an error code
+const COMPILE_ERROR_EXIT_CODE: i64 = 101;
+const RUNTIME_ERROR_EXIT_CODE: i64 = 102;
 
 fn run_file(opts: &Options) -> Result<i64, i64> {
     let mut vm = VM::new();
@@ -22,12 +18,37 @@ fn run_file(opts: &Options) -> Result<i64, i64> {
     };
     println!("Compiling and running file: {}", opts.file);
 
-    let result = vm.interpret_source(source, opts);
+    exit_code(vm.interpret_source(source, opts))
+}
+
+fn run_project(opts: &Options) -> Result<i64, i64> {
+    let mut vm = VM::new();
+
+    let project = match Project::load(&opts.project) {
+        Ok(project) => project,
+        Err(msg) => {
+            println!("[ERROR] {}", msg);
+            return Err(-1);
+        }
+    };
+    println!("Compiling and running project: {}", opts.project);
+
+    let (source, source_files) = project.source();
+    vm.set_source_files(source_files);
+    exit_code(vm.interpret_source(source, opts))
+}
 
+fn exit_code(result: InterpretResult) -> Result<i64, i64> {
     match result {
         InterpretResult::InterpretOk(exit_code) => Ok(exit_code),
-        InterpretResult::InterpretCompileError => Err(-1),
-        InterpretResult::InterpretRuntimeError => Err(-1),
+        InterpretResult::InterpretCompileError => {
+            eprintln!("[ERROR] Compilation failed");
+            Err(COMPILE_ERROR_EXIT_CODE)
+        }
+        InterpretResult::InterpretRuntimeError => {
+            eprintln!("[ERROR] Execution failed with a runtime error");
+            Err(RUNTIME_ERROR_EXIT_CODE)
+        }
     }
 }
 
@@ -35,7 +56,28 @@ fn cmain() -> i32 {
     env_logger::init();
     let opts = Options::parse();
 
-    match run_file(&opts) {
+    if !opts.explain.is_empty() {
+        return match error_code::explain(&opts.explain) {
+            Some(explanation) => {
+                println!("{}", explanation);
+                0
+            }
+            None => {
+                println!("[ERROR] Unknown error code '{}'", opts.explain);
+                -1
+            }
+        };
+    }
+
+    let result = match (opts.file.is_empty(), opts.project.is_empty()) {
+        (false, true) => run_file(&opts),
+        (true, false) => run_project(&opts),
+        _ => {
+            println!("[ERROR] Either --file or --project has to be given");
+            Err(-1)
+        }
+    };
+    match result {
         Ok(i) | Err(i) => i as i32,
     }
 }
@@ -1,10 +1,46 @@
+use std::collections::HashSet;
+
 use crate::value::squat_value::SquatValue;
 
+use self::{introspection::CallContext, random::RandomState};
+
+pub mod array;
+pub mod encoding;
+pub mod fs;
+pub mod introspection;
 pub mod io;
+pub mod map;
 pub mod misc;
 pub mod number;
+pub mod priority_queue;
+pub mod random;
 pub mod string;
+pub mod test;
 
 pub type NativeFuncArgs = Vec<SquatValue>;
 pub type NativeFuncReturnType = Result<SquatValue, String>;
 pub type NativeFunc = fn(NativeFuncArgs) -> NativeFuncReturnType;
+
+/// Calls a squat function with the given arguments, see `VM::call_value`
+pub type NativeCallback<'a> =
+    &'a mut dyn FnMut(&SquatValue, NativeFuncArgs) -> NativeFuncReturnType;
+/// Native that calls back into squat functions it was given as arguments
+pub type NativeFuncWithCallback = fn(NativeFuncArgs, NativeCallback) -> NativeFuncReturnType;
+/// Native that reads or changes the state the VM keeps for natives
+pub type NativeFuncWithState = fn(NativeFuncArgs, &mut NativeState) -> NativeFuncReturnType;
+
+/// What natives keep between calls or need to know about the running
+/// program. Every VM has its own, set up when it starts running a program.
+#[derive(Debug, Default)]
+pub struct NativeState {
+    // Set by the VM right before calling a native that uses it, see
+    // `SquatNativeFunction::with_call_context`
+    pub call_context: CallContext,
+    pub global_names: HashSet<String>,
+    // Set by `set_exit_code`, used by the VM once main is done
+    pub exit_code: Option<i64>,
+    // Digits after the decimal point when a float is turned into text, see
+    // `SquatValue::to_string_with_precision`
+    pub float_precision: Option<usize>,
+    pub random: RandomState,
+}
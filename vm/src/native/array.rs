@@ -0,0 +1,296 @@
+use super::*;
+use crate::{
+    object::{SquatObject, SquatRange},
+    value::squat_value::SquatValue,
+};
+
+/// `false` for an empty array
+pub fn any(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let values = array_arg(&args[0])?;
+    Ok(SquatValue::Bool(
+        values.iter().any(|value| value.is_truthy()),
+    ))
+}
+
+/// `true` for an empty array
+pub fn all(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let values = array_arg(&args[0])?;
+    Ok(SquatValue::Bool(
+        values.iter().all(|value| value.is_truthy()),
+    ))
+}
+
+/// Elements are compared with `==`, instances and nested arrays are equal
+/// when all of their values are.
+pub fn array_contains(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let values = array_arg(&args[0])?;
+    Ok(SquatValue::Bool(values.contains(&args[1])))
+}
+
+/// Index of the first element equal to the value or -1, see `array_contains`
+pub fn array_index_of(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let values = array_arg(&args[0])?;
+    Ok(SquatValue::Int(
+        values
+            .iter()
+            .position(|value| *value == args[1])
+            .map_or(-1, |index| index as i64),
+    ))
+}
+
+/// Lazy sequence of the integers from `start` up to but not including `end`
+pub fn range(args: NativeFuncArgs) -> NativeFuncReturnType {
+    match (&args[0], &args[1]) {
+        (SquatValue::Int(start), SquatValue::Int(end)) => Ok(SquatValue::Object(
+            SquatObject::Range(SquatRange::new(*start, *end)),
+        )),
+        _ => Err("Range bounds must be of type int".to_owned()),
+    }
+}
+
+/// Elements from `start` up to but not including `end`
+pub fn slice(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let values = array_arg(&args[0])?;
+    let (start, end) = match (&args[1], &args[2]) {
+        (SquatValue::Int(start), SquatValue::Int(end)) => (*start, *end),
+        _ => return Err("Slice indices must be of type int".to_owned()),
+    };
+
+    if start < 0 || end < 0 {
+        return Err(format!(
+            "Slice indices cannot be negative but {} and {} were given",
+            start, end
+        ));
+    }
+    if start > end {
+        return Err(format!(
+            "Slice start {} is greater than its end {}",
+            start, end
+        ));
+    }
+    if end as usize > values.len() {
+        return Err(format!(
+            "Slice end {} is out of range for an array of length {}",
+            end,
+            values.len()
+        ));
+    }
+
+    Ok(SquatValue::Object(SquatObject::Array(
+        values[start as usize..end as usize].to_vec().into(),
+    )))
+}
+
+pub fn join(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let values = array_arg(&args[0])?;
+    let separator = match &args[1] {
+        SquatValue::String(separator) => separator,
+        value => return Err(format!("'{}' is not of type string", value)),
+    };
+
+    let mut strings = Vec::with_capacity(values.len());
+    for value in values {
+        match value {
+            SquatValue::String(value) => strings.push(&**value),
+            _ => return Err(format!("Cannot join '{}', it is not of type string", value)),
+        }
+    }
+    Ok(SquatValue::String(strings.join(&**separator).into()))
+}
+
+/// Randomly permuted copy of the array, shuffled with Fisher-Yates
+pub fn shuffle(args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+    let mut values = array_arg(&args[0])?.clone();
+    for index in (1..values.len()).rev() {
+        values.swap(index, state.random.next_index(index + 1));
+    }
+    Ok(SquatValue::Object(SquatObject::Array(values.into())))
+}
+
+/// Stable sort by the values the key function returns for the elements, the
+/// key function is called once per element
+pub fn sort_by_key(args: NativeFuncArgs, call: NativeCallback) -> NativeFuncReturnType {
+    let values = array_arg(&args[0])?;
+    let mut keyed = Vec::with_capacity(values.len());
+    for value in values {
+        keyed.push((call(&args[1], vec![value.clone()])?, value.clone()));
+    }
+
+    let mut incomparable = None;
+    keyed.sort_by(|(key1, _), (key2, _)| {
+        key1.partial_cmp(key2).unwrap_or_else(|| {
+            incomparable.get_or_insert_with(|| (key1.clone(), key2.clone()));
+            std::cmp::Ordering::Equal
+        })
+    });
+    if let Some((key1, key2)) = incomparable {
+        return Err(format!(
+            "Cannot compare sort keys '{}' and '{}'",
+            key1, key2
+        ));
+    }
+    Ok(SquatValue::Object(SquatObject::Array(
+        keyed.into_iter().map(|(_, value)| value).collect(),
+    )))
+}
+
+fn array_arg(value: &SquatValue) -> Result<&Vec<SquatValue>, String> {
+    match value {
+        SquatValue::Object(SquatObject::Array(values)) => Ok(values),
+        _ => Err(format!("'{}' is not of type array", value)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn array(values: Vec<SquatValue>) -> SquatValue {
+        SquatValue::Object(SquatObject::Array(values.into()))
+    }
+
+    #[test]
+    fn shuffle_keeps_the_elements() {
+        assert_eq!(
+            shuffle(
+                vec![array(vec![SquatValue::Int(7)])],
+                &mut NativeState::default()
+            ),
+            Ok(array(vec![SquatValue::Int(7)]))
+        );
+
+        let values: Vec<SquatValue> = (0..20).map(SquatValue::Int).collect();
+        let Ok(SquatValue::Object(SquatObject::Array(mut shuffled))) =
+            shuffle(vec![array(values.clone())], &mut NativeState::default())
+        else {
+            panic!("shuffle did not return an array");
+        };
+        shuffled.sort_by_key(|value| match value {
+            SquatValue::Int(value) => *value,
+            _ => unreachable!(),
+        });
+        assert_eq!(shuffled.into_inner(), values);
+    }
+
+    #[test]
+    fn any_and_all() {
+        let mixed = array(vec![SquatValue::Bool(false), SquatValue::Int(0)]);
+        let all_true = array(vec![SquatValue::Bool(true), SquatValue::Int(1)]);
+        let all_false = array(vec![SquatValue::Bool(false), SquatValue::Nil]);
+        let empty = array(vec![]);
+
+        assert_eq!(any(vec![mixed.clone()]), Ok(SquatValue::Bool(true)));
+        assert_eq!(all(vec![mixed]), Ok(SquatValue::Bool(false)));
+        assert_eq!(any(vec![all_true.clone()]), Ok(SquatValue::Bool(true)));
+        assert_eq!(all(vec![all_true]), Ok(SquatValue::Bool(true)));
+        assert_eq!(any(vec![all_false.clone()]), Ok(SquatValue::Bool(false)));
+        assert_eq!(all(vec![all_false]), Ok(SquatValue::Bool(false)));
+        assert_eq!(any(vec![empty.clone()]), Ok(SquatValue::Bool(false)));
+        assert_eq!(all(vec![empty]), Ok(SquatValue::Bool(true)));
+    }
+
+    #[test]
+    fn contains_and_index_of() {
+        let values = array(vec![
+            SquatValue::Int(3),
+            SquatValue::Int(5),
+            SquatValue::Int(5),
+        ]);
+
+        assert_eq!(
+            array_contains(vec![values.clone(), SquatValue::Int(5)]),
+            Ok(SquatValue::Bool(true))
+        );
+        assert_eq!(
+            array_index_of(vec![values.clone(), SquatValue::Int(5)]),
+            Ok(SquatValue::Int(1))
+        );
+        assert_eq!(
+            array_contains(vec![values.clone(), SquatValue::Int(4)]),
+            Ok(SquatValue::Bool(false))
+        );
+        assert_eq!(
+            array_index_of(vec![values, SquatValue::Int(4)]),
+            Ok(SquatValue::Int(-1))
+        );
+    }
+
+    #[test]
+    fn slice_ranges() {
+        let values = array(vec![
+            SquatValue::Int(1),
+            SquatValue::Int(2),
+            SquatValue::Int(3),
+        ]);
+
+        assert_eq!(
+            slice(vec![values.clone(), SquatValue::Int(1), SquatValue::Int(3)]),
+            Ok(array(vec![SquatValue::Int(2), SquatValue::Int(3)]))
+        );
+        assert_eq!(
+            slice(vec![values.clone(), SquatValue::Int(2), SquatValue::Int(2)]),
+            Ok(array(vec![]))
+        );
+        assert!(slice(vec![
+            values.clone(),
+            SquatValue::Int(-1),
+            SquatValue::Int(2)
+        ])
+        .is_err());
+        assert!(slice(vec![values.clone(), SquatValue::Int(2), SquatValue::Int(1)]).is_err());
+        assert!(slice(vec![values, SquatValue::Int(0), SquatValue::Int(4)]).is_err());
+    }
+
+    #[test]
+    fn join_strings() {
+        let separator = SquatValue::String(",".into());
+        let values = array(vec![
+            SquatValue::String("a".into()),
+            SquatValue::String("b".into()),
+            SquatValue::String("c".into()),
+        ]);
+
+        assert_eq!(
+            join(vec![values, separator.clone()]),
+            Ok(SquatValue::String("a,b,c".into()))
+        );
+        assert_eq!(
+            join(vec![array(vec![]), separator.clone()]),
+            Ok(SquatValue::String("".into()))
+        );
+        assert!(join(vec![array(vec![SquatValue::Int(1)]), separator]).is_err());
+    }
+
+    #[test]
+    fn sort_by_key_is_stable() {
+        let words = array(
+            ["bb", "a", "cc", "d"]
+                .map(|word| SquatValue::String(word.into()))
+                .to_vec(),
+        );
+        let mut length = |_: &SquatValue, args: NativeFuncArgs| match &args[0] {
+            SquatValue::String(word) => Ok(SquatValue::Int(word.len() as i64)),
+            _ => unreachable!(),
+        };
+
+        assert_eq!(
+            sort_by_key(vec![words, SquatValue::Nil], &mut length),
+            Ok(array(
+                ["a", "d", "bb", "cc"]
+                    .map(|word| SquatValue::String(word.into()))
+                    .to_vec()
+            ))
+        );
+    }
+
+    #[test]
+    fn sort_by_key_rejects_incomparable_keys() {
+        let values = array(vec![SquatValue::Int(1), SquatValue::Bool(true)]);
+        assert_eq!(
+            sort_by_key(vec![values, SquatValue::Nil], &mut |_, args| Ok(
+                args[0].clone()
+            )),
+            Err("Cannot compare sort keys 'true' and '1'".to_owned())
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use super::*;
+use crate::value::squat_value::SquatValue;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The UTF-8 bytes of a string in standard base64, padded with '='
+pub fn base64_encode(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::String(value) = &args[0] else {
+        return Err(format!("'{}' is not of type string", args[0]));
+    };
+    let mut encoded = String::with_capacity(value.len().div_ceil(3) * 4);
+    for group in value.as_bytes().chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (index, byte)| {
+            bits | (*byte as u32) << (16 - 8 * index)
+        });
+        // A group of n bytes fills n + 1 characters, the rest is padding
+        for index in 0..4 {
+            if index <= group.len() {
+                let sextet = (bits >> (18 - 6 * index)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[sextet as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    Ok(SquatValue::String(encoded.into()))
+}
+
+/// The string encoded by `base64_encode`, the decoded bytes have to be
+/// valid UTF-8
+pub fn base64_decode(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::String(value) = &args[0] else {
+        return Err(format!("'{}' is not of type string", args[0]));
+    };
+    let invalid = || format!("'{}' is not valid base64", value);
+    if value.len() % 4 != 0 {
+        return Err(invalid());
+    }
+    let data = value.trim_end_matches('=');
+    if value.len() - data.len() > 2 {
+        return Err(invalid());
+    }
+
+    let mut bytes = Vec::with_capacity(data.len() / 4 * 3);
+    for group in data.as_bytes().chunks(4) {
+        if group.len() == 1 {
+            return Err(invalid());
+        }
+        let mut bits = 0u32;
+        for (index, c) in group.iter().enumerate() {
+            let sextet = BASE64_ALPHABET
+                .iter()
+                .position(|letter| letter == c)
+                .ok_or_else(invalid)?;
+            bits |= (sextet as u32) << (18 - 6 * index);
+        }
+        // n characters hold n - 1 whole bytes
+        for index in 0..group.len() - 1 {
+            bytes.push((bits >> (16 - 8 * index)) as u8);
+        }
+    }
+    match String::from_utf8(bytes) {
+        Ok(decoded) => Ok(SquatValue::String(decoded.into())),
+        Err(error) => Err(format!("The decoded bytes are not valid UTF-8: {}", error)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(value: &str) -> NativeFuncReturnType {
+        base64_encode(vec![SquatValue::String(value.into())])
+    }
+
+    fn decode(value: &str) -> NativeFuncReturnType {
+        base64_decode(vec![SquatValue::String(value.into())])
+    }
+
+    #[test]
+    fn strings_round_trip_through_base64() {
+        let string = |value: &str| Ok(SquatValue::String(value.into()));
+        for (value, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foobar", "Zm9vYmFy"),
+            ("héllo?>", "aMOpbGxvPz4="),
+        ] {
+            assert_eq!(encode(value), string(encoded));
+            assert_eq!(decode(encoded), string(value));
+        }
+    }
+
+    #[test]
+    fn malformed_base64_is_rejected() {
+        assert_eq!(decode("Zm9"), Err("'Zm9' is not valid base64".to_owned()));
+        assert!(decode("Zm9v!A==").is_err());
+        assert!(decode("Z===").is_err());
+        assert!(decode("Zg=v").is_err());
+        assert!(decode("=Zg=").is_err());
+        // Valid base64, but not of a UTF-8 string
+        assert!(decode("/w==").is_err());
+    }
+}
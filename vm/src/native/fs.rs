@@ -0,0 +1,111 @@
+use super::*;
+use crate::{object::SquatObject, value::squat_value::SquatValue};
+
+pub fn read_lines(args: NativeFuncArgs) -> NativeFuncReturnType {
+    match &args[0] {
+        SquatValue::String(path) => match std::fs::read_to_string(&**path) {
+            Ok(contents) => Ok(SquatValue::Object(SquatObject::Array(
+                contents
+                    .lines()
+                    .map(|line| SquatValue::String(line.into()))
+                    .collect(),
+            ))),
+            Err(err) => Err(format!("Failed to read file '{}': {}", path, err)),
+        },
+        _ => Err(format!("'{}' is not of type string", args[0])),
+    }
+}
+
+/// Names of the entries in the directory, sorted since `read_dir` gives no
+/// order guarantees
+pub fn list_dir(args: NativeFuncArgs) -> NativeFuncReturnType {
+    match &args[0] {
+        SquatValue::String(path) => {
+            let error =
+                |err: std::io::Error| format!("Failed to list directory '{}': {}", path, err);
+            let mut names = std::fs::read_dir(&**path)
+                .map_err(error)?
+                .map(|entry| {
+                    entry
+                        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                        .map_err(error)
+                })
+                .collect::<Result<Vec<String>, String>>()?;
+            names.sort();
+            Ok(SquatValue::Object(SquatObject::Array(
+                names
+                    .into_iter()
+                    .map(|name| SquatValue::String(name.into()))
+                    .collect(),
+            )))
+        }
+        _ => Err(format!("'{}' is not of type string", args[0])),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_lines_splits_on_both_line_endings() {
+        let path = std::env::temp_dir().join("squat_read_lines_test.txt");
+        std::fs::write(&path, "first\nsecond\r\nthird\n").unwrap();
+
+        let lines = read_lines(vec![SquatValue::String(path.to_str().unwrap().into())]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            lines,
+            Ok(SquatValue::Object(SquatObject::Array(
+                vec![
+                    SquatValue::String("first".into()),
+                    SquatValue::String("second".into()),
+                    SquatValue::String("third".into()),
+                ]
+                .into()
+            )))
+        );
+    }
+
+    #[test]
+    fn read_lines_missing_file() {
+        let lines = read_lines(vec![SquatValue::String(
+            "/this/file/does/not/exist.txt".into(),
+        )]);
+        assert!(lines.is_err());
+    }
+
+    #[test]
+    fn list_dir_returns_entry_names() {
+        let path = std::env::temp_dir().join("squat_list_dir_test");
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("b.txt"), "").unwrap();
+        std::fs::write(path.join("a.txt"), "").unwrap();
+        std::fs::create_dir(path.join("c")).unwrap();
+
+        let names = list_dir(vec![SquatValue::String(path.to_str().unwrap().into())]);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(
+            names,
+            Ok(SquatValue::Object(SquatObject::Array(
+                vec![
+                    SquatValue::String("a.txt".into()),
+                    SquatValue::String("b.txt".into()),
+                    SquatValue::String("c".into()),
+                ]
+                .into()
+            )))
+        );
+    }
+
+    #[test]
+    fn list_dir_missing_directory() {
+        let names = list_dir(vec![SquatValue::String(
+            "/this/directory/does/not/exist".into(),
+        )]);
+        assert!(names.is_err());
+    }
+}
@@ -0,0 +1,44 @@
+use super::*;
+
+/// Where the running squat function was called from, and how much the
+/// program holds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CallContext {
+    caller_line: i64,
+    stack_depth: i64,
+    object_count: i64,
+}
+
+impl CallContext {
+    pub fn new(caller_line: i64, stack_depth: i64, object_count: i64) -> CallContext {
+        CallContext {
+            caller_line,
+            stack_depth,
+            object_count,
+        }
+    }
+}
+
+/// Line the current function was called at, or the current line in main
+pub fn caller_line(_args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+    Ok(SquatValue::Int(state.call_context.caller_line))
+}
+
+/// Number of squat functions on the call stack, main included
+pub fn stack_depth(_args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+    Ok(SquatValue::Int(state.call_context.stack_depth))
+}
+
+/// Objects held by the program, see `ObjectToken`
+pub fn mem_usage(_args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+    Ok(SquatValue::Int(state.call_context.object_count))
+}
+
+/// Whether the program declares a global variable, function or struct with
+/// the name
+pub fn defined(args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+    let SquatValue::String(name) = &args[0] else {
+        return Err(format!("'{}' is not of type string", args[0]));
+    };
+    Ok(SquatValue::Bool(state.global_names.contains(&**name)))
+}
@@ -1,20 +1,22 @@
 use super::*;
 use crate::value::squat_value::SquatValue;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
 
-pub fn print(args: NativeFuncArgs) -> NativeFuncReturnType {
+pub fn print(args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
     let output = args
         .iter()
-        .map(|x| x.to_string())
+        .map(|x| x.to_string_with_precision(state.float_precision))
         .collect::<Vec<String>>()
         .join(" ");
     print!("{}", output);
     Ok(SquatValue::Nil)
 }
 
-pub fn println(args: NativeFuncArgs) -> NativeFuncReturnType {
+pub fn println(args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
     let output = args
         .iter()
-        .map(|x| x.to_string())
+        .map(|x| x.to_string_with_precision(state.float_precision))
         .collect::<Vec<String>>()
         .join(" ");
     println!("{}", output);
@@ -31,8 +33,149 @@ pub fn input(_args: NativeFuncArgs) -> NativeFuncReturnType {
                     value.pop();
                 }
             }
-            Ok(SquatValue::String(value))
+            Ok(SquatValue::String(value.into()))
         }
         Err(msg) => Err(msg.to_string()),
     }
 }
+
+pub fn prompt(args: NativeFuncArgs) -> NativeFuncReturnType {
+    prompt_with(
+        &args[0].to_string(),
+        &mut std::io::stdin().lock(),
+        &mut std::io::stdout(),
+    )
+}
+
+/// Writes the message before reading, flushed so it shows up even though it
+/// has no newline
+fn prompt_with(
+    message: &str,
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> NativeFuncReturnType {
+    write!(writer, "{}", message).map_err(|msg| msg.to_string())?;
+    writer.flush().map_err(|msg| msg.to_string())?;
+    let mut value = String::new();
+    match reader.read_line(&mut value) {
+        Ok(_) => Ok(SquatValue::String(value.trim().into())),
+        Err(msg) => Err(msg.to_string()),
+    }
+}
+
+pub fn read_all(_args: NativeFuncArgs) -> NativeFuncReturnType {
+    read_all_from(&mut std::io::stdin())
+}
+
+fn read_all_from(reader: &mut impl Read) -> NativeFuncReturnType {
+    let mut value = String::new();
+    match reader.read_to_string(&mut value) {
+        Ok(_) => Ok(SquatValue::String(value.into())),
+        Err(msg) => Err(msg.to_string()),
+    }
+}
+
+/// Reads one key press without waiting for enter. The terminal is switched
+/// to raw mode with `stty` while reading, so it needs a terminal with `stty`.
+pub fn read_key(_args: NativeFuncArgs) -> NativeFuncReturnType {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err("Cannot read a key, stdin is not a terminal".to_owned());
+    }
+    set_raw_mode(true)?;
+    let key = read_key_from(&mut stdin.lock());
+    set_raw_mode(false)?;
+    key
+}
+
+fn set_raw_mode(raw: bool) -> Result<(), String> {
+    let mode: &[&str] = match raw {
+        true => &["raw", "-echo"],
+        false => &["-raw", "echo"],
+    };
+    // stty changes the terminal it gets as its stdin
+    let status = Command::new("stty")
+        .args(mode)
+        .stdin(Stdio::inherit())
+        .status()
+        .map_err(|msg| format!("Cannot switch the terminal mode: {}", msg))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err("Cannot switch the terminal mode".to_owned()),
+    }
+}
+
+/// Reads the bytes of one UTF-8 character
+fn read_key_from(reader: &mut impl Read) -> NativeFuncReturnType {
+    let mut bytes = [0; 4];
+    reader
+        .read_exact(&mut bytes[..1])
+        .map_err(|msg| msg.to_string())?;
+    let length = match bytes[0] {
+        0xf0.. => 4,
+        0xe0.. => 3,
+        0xc0.. => 2,
+        _ => 1,
+    };
+    reader
+        .read_exact(&mut bytes[1..length])
+        .map_err(|msg| msg.to_string())?;
+    match std::str::from_utf8(&bytes[..length]) {
+        Ok(key) => Ok(SquatValue::String(key.into())),
+        Err(_) => Err("The key is not valid UTF-8".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_all_reads_until_eof() {
+        let mut reader = Cursor::new("first line\nsecond line\n\nlast");
+        assert_eq!(
+            read_all_from(&mut reader),
+            Ok(SquatValue::String("first line\nsecond line\n\nlast".into()))
+        );
+    }
+
+    #[test]
+    fn prompt_writes_the_message_and_trims_the_answer() {
+        let mut reader = Cursor::new("  squat \r\nnext line\n");
+        let mut writer: Vec<u8> = Vec::new();
+        assert_eq!(
+            prompt_with("Name: ", &mut reader, &mut writer),
+            Ok(SquatValue::String("squat".into()))
+        );
+        assert_eq!(writer, b"Name: ");
+        assert_eq!(
+            prompt_with("Again: ", &mut reader, &mut writer),
+            Ok(SquatValue::String("next line".into()))
+        );
+        assert_eq!(writer, b"Name: Again: ");
+    }
+
+    #[test]
+    fn keys_are_read_one_character_at_a_time() {
+        let mut reader = Cursor::new("aé\n");
+        assert_eq!(
+            read_key_from(&mut reader),
+            Ok(SquatValue::String("a".into()))
+        );
+        assert_eq!(
+            read_key_from(&mut reader),
+            Ok(SquatValue::String("é".into()))
+        );
+        assert_eq!(
+            read_key_from(&mut reader),
+            Ok(SquatValue::String("\n".into()))
+        );
+        assert!(read_key_from(&mut reader).is_err());
+
+        // Waits for a key press in a terminal
+        if !std::io::stdin().is_terminal() {
+            assert!(read_key(vec![]).is_err());
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use super::*;
+use crate::{
+    object::{SquatInstance, SquatMap, SquatObject},
+    value::squat_value::SquatValue,
+};
+
+/// Field names of a struct instance mapped to their values
+pub fn to_map(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::Object(SquatObject::Instance(instance)) = &args[0] else {
+        return Err(format!("'{}' is not an instance of a struct", args[0]));
+    };
+    let Some(fields) = instance.fields() else {
+        return Err(format!(
+            "The fields of '{}' are not known",
+            instance.instance_of
+        ));
+    };
+
+    let mut map = SquatMap::new();
+    for (name, value) in fields {
+        map.insert(SquatValue::String(name.into()), value.clone());
+    }
+    Ok(SquatValue::Object(SquatObject::Map(map)))
+}
+
+/// Instance of a struct with its fields taken from the map by name. A field
+/// that is not in the map takes its default value, other keys are an error.
+pub fn from_map(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::Object(SquatObject::Struct(squat_struct)) = &args[0] else {
+        return Err(format!("'{}' is not a struct", args[0]));
+    };
+    let SquatValue::Object(SquatObject::Map(map)) = &args[1] else {
+        return Err(format!("'{}' is not of type map", args[1]));
+    };
+    let type_data = &squat_struct.type_data;
+    if type_data.get_field_count() != squat_struct.field_names.len() {
+        return Err(format!(
+            "The fields of '{}' are not known",
+            squat_struct.name
+        ));
+    }
+
+    for key in map.keys() {
+        let is_field = match key {
+            SquatValue::String(name) => squat_struct
+                .field_names
+                .iter()
+                .any(|field| **field == **name),
+            _ => false,
+        };
+        if !is_field {
+            return Err(format!(
+                "Struct '{}' has no field {}",
+                squat_struct.name,
+                key.to_nested_string()
+            ));
+        }
+    }
+
+    let mut properties = Vec::with_capacity(squat_struct.field_names.len());
+    for (field_index, field_name) in squat_struct.field_names.iter().enumerate() {
+        let field_type = type_data.get_field_type_by_index(field_index);
+        let value = match map.get(&SquatValue::String(field_name.as_str().into())) {
+            Some(value) => value.clone(),
+            None => type_data.get_field_default(field_index).ok_or_else(|| {
+                format!(
+                    "Field '{}' of struct '{}' is missing from the map",
+                    field_name, squat_struct.name
+                )
+            })?,
+        };
+        if value.get_type() != field_type {
+            return Err(format!(
+                "Field '{}' of struct '{}' expects {} but the map has {}",
+                field_name,
+                squat_struct.name,
+                field_type,
+                value.get_type()
+            ));
+        }
+        properties.push(value);
+    }
+    let instance = SquatInstance::new(&squat_struct.name, properties)
+        .with_field_names(squat_struct.field_names.clone());
+    Ok(SquatValue::Object(SquatObject::Instance(instance)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::object::SquatStruct;
+    use crate::value::squat_type::{SquatStructTypeData, SquatType};
+
+    #[test]
+    fn instances_map_field_names_to_values() {
+        let instance = SquatInstance::new("Point", vec![SquatValue::Int(1), SquatValue::Int(2)])
+            .with_field_names(vec!["x".to_owned(), "y".to_owned()].into());
+        let map = to_map(vec![SquatValue::Object(SquatObject::Instance(instance))]).unwrap();
+        assert_eq!(map.to_string(), r#"{"x": 1, "y": 2}"#);
+        let SquatValue::Object(SquatObject::Map(map)) = map else {
+            panic!("Expected a map");
+        };
+        assert_eq!(
+            map.get(&SquatValue::String("y".into())),
+            Some(&SquatValue::Int(2))
+        );
+    }
+
+    #[test]
+    fn only_instances_can_be_mapped() {
+        assert_eq!(
+            to_map(vec![SquatValue::Int(1)]),
+            Err("'1' is not an instance of a struct".to_owned())
+        );
+    }
+
+    #[test]
+    fn instances_are_built_from_maps() {
+        let mut type_data = SquatStructTypeData::new("Config");
+        type_data.add_field("name", SquatType::String, None);
+        type_data.add_field("retries", SquatType::Int, Some(SquatValue::Int(3)));
+        let config = SquatValue::Object(SquatObject::Struct(
+            SquatStruct::new("Config", type_data.get_field_names()).with_type_data(type_data),
+        ));
+        let build = |entries: Vec<(&str, SquatValue)>| {
+            let mut map = SquatMap::new();
+            for (key, value) in entries {
+                map.insert(SquatValue::String(key.into()), value);
+            }
+            from_map(vec![
+                config.clone(),
+                SquatValue::Object(SquatObject::Map(map)),
+            ])
+        };
+
+        let instance = build(vec![
+            ("retries", SquatValue::Int(5)),
+            ("name", SquatValue::String("server".into())),
+        ])
+        .unwrap();
+        assert_eq!(
+            to_map(vec![instance]).unwrap().to_string(),
+            r#"{"name": "server", "retries": 5}"#
+        );
+        let instance = build(vec![("name", SquatValue::String("server".into()))]).unwrap();
+        assert_eq!(
+            to_map(vec![instance]).unwrap().to_string(),
+            r#"{"name": "server", "retries": 3}"#
+        );
+
+        assert_eq!(
+            build(vec![("name", SquatValue::Int(1))]),
+            Err(
+                "Field 'name' of struct 'Config' expects <type String> but the map has <type Int>"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            build(vec![("retries", SquatValue::Int(1))]),
+            Err("Field 'name' of struct 'Config' is missing from the map".to_owned())
+        );
+        assert_eq!(
+            build(vec![
+                ("name", SquatValue::String("server".into())),
+                ("port", SquatValue::Int(80)),
+            ]),
+            Err("Struct 'Config' has no field \"port\"".to_owned())
+        );
+    }
+}
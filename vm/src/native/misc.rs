@@ -1,6 +1,13 @@
 use super::*;
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{object::SquatObject, value::squat_type::SquatFunctionTypeData};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+static CLOCK_START: OnceLock<Instant> = OnceLock::new();
+
+/// Exits right away without returning to the VM, see `set_exit_code` for a
+/// clean exit
 pub fn exit(args: NativeFuncArgs) -> NativeFuncReturnType {
     let exit_code: SquatValue = args[0].clone();
     if let SquatValue::Int(exit_code) = exit_code {
@@ -11,6 +18,17 @@ pub fn exit(args: NativeFuncArgs) -> NativeFuncReturnType {
     }
 }
 
+/// Keeps running and exits with the given code once main is done
+pub fn set_exit_code(args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+    match args[0] {
+        SquatValue::Int(exit_code) => {
+            state.exit_code = Some(exit_code);
+            Ok(SquatValue::Nil)
+        }
+        _ => Err(format!("'{}' is not of type int", args[0])),
+    }
+}
+
 pub fn time(_args: NativeFuncArgs) -> NativeFuncReturnType {
     let now = SystemTime::now();
     let value = now
@@ -20,6 +38,381 @@ pub fn time(_args: NativeFuncArgs) -> NativeFuncReturnType {
     Ok(SquatValue::Float(value))
 }
 
+pub fn squat_version(_args: NativeFuncArgs) -> NativeFuncReturnType {
+    Ok(SquatValue::String(env!("CARGO_PKG_VERSION").into()))
+}
+
+/// Whether the interpreter version is the given one or newer, parts left out
+/// of "major.minor.patch" count as 0
+pub fn version_at_least(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::String(version) = &args[0] else {
+        return Err(format!("'{}' is not of type string", args[0]));
+    };
+    let required = parse_version(version)?;
+    let current = parse_version(env!("CARGO_PKG_VERSION"))?;
+    Ok(SquatValue::Bool(current >= required))
+}
+
+fn parse_version(version: &str) -> Result<[u64; 3], String> {
+    let parts: Vec<&str> = version.split('.').collect();
+    if parts.len() > 3 {
+        return Err(format!("'{}' is not a version number", version));
+    }
+    let mut numbers = [0; 3];
+    for (number, part) in numbers.iter_mut().zip(parts) {
+        *number = part
+            .parse()
+            .map_err(|_| format!("'{}' is not a version number", version))?;
+    }
+    Ok(numbers)
+}
+
+/// Whole seconds since the unix epoch
+pub fn now(_args: NativeFuncArgs) -> NativeFuncReturnType {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    Ok(SquatValue::Int(now.as_secs() as i64))
+}
+
+/// Nanoseconds since the first call, unlike `time` it never goes backwards
+pub fn clock(_args: NativeFuncArgs) -> NativeFuncReturnType {
+    let start = CLOCK_START.get_or_init(Instant::now);
+    Ok(SquatValue::Int(start.elapsed().as_nanos() as i64))
+}
+
+/// Seconds as hours, minutes and seconds, like "1h 2m 3s". Units above the
+/// largest non zero one are left out and seconds keep up to 3 decimals.
+pub fn format_duration(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let total = match &args[0] {
+        SquatValue::Float(value) => *value,
+        SquatValue::Int(value) => *value as f64,
+        _ => return Err(format!("'{}' is not of type number", args[0])),
+    };
+    if total < 0. || !total.is_finite() {
+        return Err(format!("Can't format a duration of {} seconds", total));
+    }
+    // Rounded first so 59.9999 becomes "1m 0s" rather than "60s"
+    let total = (total * 1000.).round() / 1000.;
+
+    let hours = (total / 3600.).floor();
+    let minutes = ((total - hours * 3600.) / 60.).floor();
+    let seconds = total - hours * 3600. - minutes * 60.;
+    let seconds = format!("{:.3}", seconds);
+    let seconds = seconds.trim_end_matches('0').trim_end_matches('.');
+
+    let duration = if hours > 0. {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0. {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    };
+    Ok(SquatValue::String(duration.into()))
+}
+
+/// Seconds since the unix epoch as a UTC date and time. `%Y`, `%m`, `%d`,
+/// `%H`, `%M` and `%S` are replaced by the year, month, day, hour, minute and
+/// second and `%%` by a '%'.
+pub fn format_time(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let seconds = match &args[0] {
+        SquatValue::Int(value) => *value,
+        SquatValue::Float(value) if value.is_finite() => value.floor() as i64,
+        _ => return Err(format!("Can't format a time of {} seconds", args[0])),
+    };
+    let SquatValue::String(format) = &args[1] else {
+        return Err(format!("'{}' is not of type string", args[1]));
+    };
+
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let seconds_of_day = seconds.rem_euclid(86400);
+    let mut formatted = String::with_capacity(format.len());
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => formatted.push_str(&format!("{:04}", year)),
+            Some('m') => formatted.push_str(&format!("{:02}", month)),
+            Some('d') => formatted.push_str(&format!("{:02}", day)),
+            Some('H') => formatted.push_str(&format!("{:02}", seconds_of_day / 3600)),
+            Some('M') => formatted.push_str(&format!("{:02}", seconds_of_day % 3600 / 60)),
+            Some('S') => formatted.push_str(&format!("{:02}", seconds_of_day % 60)),
+            Some('%') => formatted.push('%'),
+            Some(other) => return Err(format!("Unknown time format '%{}'", other)),
+            None => return Err("Time format ends with a lone '%'".to_owned()),
+        }
+    }
+    Ok(SquatValue::String(formatted.into()))
+}
+
+/// Year, month and day of the days since 1970-01-01 in the proleptic
+/// Gregorian calendar, counted in 400 year eras that start on March 1st
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months start from March so the leap day is the last day of the year
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
 pub fn get_type(args: NativeFuncArgs) -> NativeFuncReturnType {
     Ok(SquatValue::Type(args[0].get_type()))
 }
+
+pub fn type_name(args: NativeFuncArgs) -> NativeFuncReturnType {
+    Ok(SquatValue::String(args[0].get_type().name().into()))
+}
+
+pub fn arity(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let type_data = function_type_data(&args[0], "arity")?;
+    Ok(SquatValue::Int(type_data.get_arity() as i64))
+}
+
+pub fn param_types(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let type_data = function_type_data(&args[0], "param_types")?;
+    let types = type_data
+        .param_types
+        .iter()
+        .map(|param_type| SquatValue::Type(param_type.clone()))
+        .collect();
+    Ok(SquatValue::Object(SquatObject::Array(types)))
+}
+
+/// Strings are reversed by characters
+pub fn reverse(args: NativeFuncArgs) -> NativeFuncReturnType {
+    match &args[0] {
+        SquatValue::String(value) => Ok(SquatValue::String(
+            value.chars().rev().collect::<String>().into(),
+        )),
+        SquatValue::Object(SquatObject::Array(values)) => Ok(SquatValue::Object(
+            SquatObject::Array(values.iter().rev().cloned().collect()),
+        )),
+        value => Err(format!("Cannot reverse a value of {}", value.get_type())),
+    }
+}
+
+/// Characters of a string, elements of an array or entries of a map
+pub fn size(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let size = match &args[0] {
+        SquatValue::String(value) => value.chars().count(),
+        SquatValue::Object(SquatObject::Array(values)) => values.len(),
+        SquatValue::Object(SquatObject::Map(map)) => map.len(),
+        value => {
+            return Err(format!(
+                "Cannot get the size of a value of {}",
+                value.get_type()
+            ))
+        }
+    };
+    Ok(SquatValue::Int(size as i64))
+}
+
+/// Hash of an int, string or bool, equal values have the same hash
+pub fn hash(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let Some(key) = args[0].hash_key() else {
+        return Err(format!("Cannot hash a value of {}", args[0].get_type()));
+    };
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    Ok(SquatValue::Int(hasher.finish() as i64))
+}
+
+/// Name of the operating system, like "linux", "macos" or "windows"
+pub fn os_name(_args: NativeFuncArgs) -> NativeFuncReturnType {
+    Ok(SquatValue::String(std::env::consts::OS.into()))
+}
+
+/// Name of the CPU architecture, like "x86_64" or "aarch64"
+pub fn arch(_args: NativeFuncArgs) -> NativeFuncReturnType {
+    Ok(SquatValue::String(std::env::consts::ARCH.into()))
+}
+
+fn function_type_data<'a>(
+    value: &'a SquatValue,
+    native_name: &str,
+) -> Result<&'a SquatFunctionTypeData, String> {
+    match value {
+        SquatValue::Object(SquatObject::Function(func)) => Ok(&func.type_data),
+        SquatValue::Object(SquatObject::NativeFunction(func)) => Ok(&func.type_data),
+        _ => Err(format!(
+            "{} expects a function but got {}",
+            native_name,
+            value.get_type()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::object::{SquatFunction, SquatInstance, SquatMap};
+
+    #[test]
+    fn type_names() {
+        let name = |value: SquatValue| type_name(vec![value]);
+        let string = |value: &str| Ok(SquatValue::String(value.into()));
+        assert_eq!(name(SquatValue::Int(1)), string("int"));
+        assert_eq!(name(SquatValue::Float(1.5)), string("float"));
+        assert_eq!(name(SquatValue::String("a".into())), string("string"));
+        assert_eq!(name(SquatValue::Bool(true)), string("bool"));
+        assert_eq!(name(SquatValue::Nil), string("nil"));
+        assert_eq!(
+            name(SquatValue::Object(SquatObject::Instance(
+                SquatInstance::new("Point", vec![])
+            ))),
+            string("Point")
+        );
+        assert_eq!(
+            name(SquatValue::Object(SquatObject::Function(
+                SquatFunction::new("add", 0, SquatFunctionTypeData::default())
+            ))),
+            string("function")
+        );
+    }
+
+    #[test]
+    fn clock_does_not_go_backwards() {
+        let first = clock(vec![]).unwrap();
+        let second = clock(vec![]).unwrap();
+        assert!(second >= first);
+    }
+
+    #[test]
+    fn format_durations() {
+        let format = |seconds: f64| format_duration(vec![SquatValue::Float(seconds)]);
+        assert_eq!(format(3723.0), Ok(SquatValue::String("1h 2m 3s".into())));
+        assert_eq!(format(0.0), Ok(SquatValue::String("0s".into())));
+        assert_eq!(format(61.5), Ok(SquatValue::String("1m 1.5s".into())));
+        assert_eq!(
+            format(3600.25),
+            Ok(SquatValue::String("1h 0m 0.25s".into()))
+        );
+        assert_eq!(format(59.9999), Ok(SquatValue::String("1m 0s".into())));
+        assert!(format(-1.0).is_err());
+    }
+
+    #[test]
+    fn reverse_string_and_array() {
+        assert_eq!(
+            reverse(vec![SquatValue::String("abc".into())]),
+            Ok(SquatValue::String("cba".into()))
+        );
+        assert_eq!(
+            reverse(vec![SquatValue::Object(SquatObject::Array(
+                vec![SquatValue::Int(1), SquatValue::Int(2), SquatValue::Int(3),].into()
+            ))]),
+            Ok(SquatValue::Object(SquatObject::Array(
+                vec![SquatValue::Int(3), SquatValue::Int(2), SquatValue::Int(1),].into()
+            )))
+        );
+        assert!(reverse(vec![SquatValue::Int(1)]).is_err());
+    }
+
+    #[test]
+    fn sizes_of_strings_arrays_and_maps() {
+        let size_of = |value: SquatValue| size(vec![value]);
+        assert_eq!(
+            size_of(SquatValue::String("héllo".into())),
+            Ok(SquatValue::Int(5))
+        );
+        assert_eq!(
+            size_of(SquatValue::Object(SquatObject::Array(
+                vec![SquatValue::Int(1), SquatValue::Int(2),].into()
+            ))),
+            Ok(SquatValue::Int(2))
+        );
+        let mut map = SquatMap::new();
+        map.insert(SquatValue::String("x".into()), SquatValue::Int(1));
+        assert_eq!(
+            size_of(SquatValue::Object(SquatObject::Map(map))),
+            Ok(SquatValue::Int(1))
+        );
+        assert!(size_of(SquatValue::Int(3)).is_err());
+    }
+
+    #[test]
+    fn equal_values_hash_equally() {
+        let hash_of = |value: SquatValue| hash(vec![value]);
+        assert_eq!(
+            hash_of(SquatValue::String("key".into())),
+            hash_of(SquatValue::String("key".into()))
+        );
+        assert_eq!(hash_of(SquatValue::Int(7)), hash_of(SquatValue::Int(7)));
+        assert_ne!(hash_of(SquatValue::Int(7)), hash_of(SquatValue::Int(8)));
+        assert_ne!(hash_of(SquatValue::Int(1)), hash_of(SquatValue::Bool(true)));
+        assert_eq!(
+            hash_of(SquatValue::Float(1.5)),
+            Err("Cannot hash a value of <type Float>".to_owned())
+        );
+        assert!(hash_of(SquatValue::Nil).is_err());
+        assert!(hash_of(SquatValue::Object(SquatObject::Array(vec![].into()))).is_err());
+    }
+
+    #[test]
+    fn os_name_is_a_known_platform() {
+        let Ok(SquatValue::String(name)) = os_name(vec![]) else {
+            panic!("os_name did not return a string");
+        };
+        assert!(["linux", "macos", "windows", "freebsd", "android", "ios"].contains(&&*name));
+        assert!(matches!(arch(vec![]), Ok(SquatValue::String(arch)) if !arch.is_empty()));
+    }
+
+    #[test]
+    fn times_are_formatted_in_utc() {
+        let formatted = |seconds: SquatValue, format: &str| {
+            format_time(vec![seconds, SquatValue::String(format.into())])
+        };
+        let string = |value: &str| Ok(SquatValue::String(value.into()));
+        assert_eq!(
+            formatted(SquatValue::Int(0), "%Y-%m-%d %H:%M:%S"),
+            string("1970-01-01 00:00:00")
+        );
+        assert_eq!(
+            formatted(SquatValue::Int(1709210096), "%d/%m/%Y %H:%M:%S"),
+            string("29/02/2024 12:34:56")
+        );
+        assert_eq!(
+            formatted(SquatValue::Float(951782400.75), "%Y%m%d 100%%"),
+            string("20000229 100%")
+        );
+        assert_eq!(
+            formatted(SquatValue::Int(-1), "%Y-%m-%d %H:%M:%S"),
+            string("1969-12-31 23:59:59")
+        );
+        assert!(formatted(SquatValue::Int(0), "%y").is_err());
+        assert!(formatted(SquatValue::Int(0), "100%").is_err());
+    }
+
+    #[test]
+    fn versions_are_compared_by_their_parts() {
+        let at_least = |version: &str| version_at_least(vec![SquatValue::String(version.into())]);
+        assert!(
+            matches!(squat_version(vec![]), Ok(SquatValue::String(version)) if !version.is_empty())
+        );
+        assert_eq!(at_least("0.0.1"), Ok(SquatValue::Bool(true)));
+        assert_eq!(
+            at_least(env!("CARGO_PKG_VERSION")),
+            Ok(SquatValue::Bool(true))
+        );
+        assert_eq!(at_least("0"), Ok(SquatValue::Bool(true)));
+        assert_eq!(at_least("1000.0"), Ok(SquatValue::Bool(false)));
+        assert_eq!(parse_version("1.10"), Ok([1, 10, 0]));
+        assert!(parse_version("1.2.3.4").is_err());
+        assert!(parse_version("1.x").is_err());
+        assert!(parse_version("").is_err());
+    }
+}
@@ -62,3 +62,193 @@ pub fn to_float(args: NativeFuncArgs) -> NativeFuncReturnType {
         _ => Err(format!("Can't cast '{}' to a number", args[0])),
     }
 }
+
+pub fn try_to_int(args: NativeFuncArgs) -> NativeFuncReturnType {
+    match &args[0] {
+        SquatValue::String(value) => Ok(value
+            .trim()
+            .parse::<i64>()
+            .map_or(SquatValue::Nil, SquatValue::Int)),
+        _ => Err(format!("'{}' is not of type string", args[0])),
+    }
+}
+
+pub fn try_to_float(args: NativeFuncArgs) -> NativeFuncReturnType {
+    match &args[0] {
+        SquatValue::String(value) => Ok(value
+            .trim()
+            .parse::<f64>()
+            .map_or(SquatValue::Nil, SquatValue::Float)),
+        _ => Err(format!("'{}' is not of type string", args[0])),
+    }
+}
+
+pub fn format_float(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let value = match &args[0] {
+        SquatValue::Float(value) => *value,
+        SquatValue::Int(value) => *value as f64,
+        _ => return Err(format!("'{}' is not of type number", args[0])),
+    };
+    match &args[1] {
+        SquatValue::Int(precision) if *precision >= 0 => Ok(SquatValue::String(
+            format!("{:.*}", *precision as usize, value).into(),
+        )),
+        SquatValue::Int(precision) => Err(format!(
+            "Precision cannot be negative but {} was given",
+            precision
+        )),
+        _ => Err(format!("'{}' is not of type int", args[1])),
+    }
+}
+
+/// Whether `low <= value <= high`, ints are compared as floats when mixed
+/// with floats
+pub fn between(args: NativeFuncArgs) -> NativeFuncReturnType {
+    if let Some(value) = args
+        .iter()
+        .find(|value| !matches!(value, SquatValue::Int(_) | SquatValue::Float(_)))
+    {
+        return Err(format!("'{}' is not of type number", value));
+    }
+    let has_float = args
+        .iter()
+        .any(|value| matches!(value, SquatValue::Float(_)));
+    let values: Vec<SquatValue> = args
+        .into_iter()
+        .map(|value| match value {
+            SquatValue::Int(i) if has_float => SquatValue::Float(i as f64),
+            value => value,
+        })
+        .collect();
+    let (value, low, high) = (&values[0], &values[1], &values[2]);
+    Ok(SquatValue::Bool(low <= value && value <= high))
+}
+
+/// Keeps ints as ints, `abs(x)` calls are compiled to `OpCode::Abs` instead
+pub fn abs(args: NativeFuncArgs) -> NativeFuncReturnType {
+    absolute(&args[0])
+}
+
+/// Shared with `OpCode::Abs`
+pub fn absolute(value: &SquatValue) -> NativeFuncReturnType {
+    match value {
+        SquatValue::Float(value) => Ok(SquatValue::Float(value.abs())),
+        SquatValue::Int(value) => match value.checked_abs() {
+            Some(value) => Ok(SquatValue::Int(value)),
+            None => Err(format!(
+                "The absolute value of {} does not fit an int",
+                value
+            )),
+        },
+        _ => Err(format!("'{}' is not of type number", value)),
+    }
+}
+
+/// The int with a ',' between every group of 3 digits, like "-1,234,567"
+pub fn format_number(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::Int(value) = args[0] else {
+        return Err(format!("'{}' is not of type int", args[0]));
+    };
+    let digits = value.unsigned_abs().to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3 + 1);
+    if value < 0 {
+        formatted.push('-');
+    }
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index) % 3 == 0 {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    Ok(SquatValue::String(formatted.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn between_includes_the_bounds() {
+        let is_between =
+            |value: SquatValue, low: SquatValue, high: SquatValue| between(vec![value, low, high]);
+        let int = SquatValue::Int;
+        let float = SquatValue::Float;
+        assert_eq!(
+            is_between(int(5), int(1), int(10)),
+            Ok(SquatValue::Bool(true))
+        );
+        assert_eq!(
+            is_between(int(1), int(1), int(10)),
+            Ok(SquatValue::Bool(true))
+        );
+        assert_eq!(
+            is_between(int(10), int(1), int(10)),
+            Ok(SquatValue::Bool(true))
+        );
+        assert_eq!(
+            is_between(int(11), int(1), int(10)),
+            Ok(SquatValue::Bool(false))
+        );
+        assert_eq!(
+            is_between(int(0), int(1), int(10)),
+            Ok(SquatValue::Bool(false))
+        );
+        assert_eq!(
+            is_between(float(0.5), int(0), int(1)),
+            Ok(SquatValue::Bool(true))
+        );
+        assert_eq!(
+            is_between(float(f64::NAN), int(0), int(1)),
+            Ok(SquatValue::Bool(false))
+        );
+        assert!(is_between(SquatValue::String("a".into()), int(0), int(1)).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
+    fn format_float_precision() {
+        assert_eq!(
+            format_float(vec![SquatValue::Float(3.14159), SquatValue::Int(2)]),
+            Ok(SquatValue::String("3.14".into()))
+        );
+        assert_eq!(
+            format_float(vec![SquatValue::Int(2), SquatValue::Int(1)]),
+            Ok(SquatValue::String("2.0".into()))
+        );
+        assert!(format_float(vec![SquatValue::Float(1.5), SquatValue::Int(-1)]).is_err());
+    }
+
+    #[test]
+    fn try_parse_returns_nil_on_failure() {
+        assert_eq!(
+            try_to_int(vec![SquatValue::String("42".into())]),
+            Ok(SquatValue::Int(42))
+        );
+        assert_eq!(
+            try_to_int(vec![SquatValue::String("4x2".into())]),
+            Ok(SquatValue::Nil)
+        );
+        assert_eq!(
+            try_to_float(vec![SquatValue::String("2.5".into())]),
+            Ok(SquatValue::Float(2.5))
+        );
+        assert_eq!(
+            try_to_float(vec![SquatValue::String("two".into())]),
+            Ok(SquatValue::Nil)
+        );
+    }
+
+    #[test]
+    fn numbers_are_formatted_with_separators() {
+        let formatted = |value: i64| format_number(vec![SquatValue::Int(value)]);
+        let string = |value: &str| Ok(SquatValue::String(value.into()));
+        assert_eq!(formatted(1234567), string("1,234,567"));
+        assert_eq!(formatted(123456), string("123,456"));
+        assert_eq!(formatted(-1234), string("-1,234"));
+        assert_eq!(formatted(-123), string("-123"));
+        assert_eq!(formatted(999), string("999"));
+        assert_eq!(formatted(0), string("0"));
+        assert_eq!(formatted(i64::MIN), string("-9,223,372,036,854,775,808"));
+        assert!(format_number(vec![SquatValue::Float(1.5)]).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use super::*;
+use crate::object::{QueueEntry, SquatObject};
+use std::{cell::RefCell, collections::BinaryHeap, rc::Rc};
+
+/// Pushing and popping take logarithmic time, the smallest value is popped
+/// first
+pub fn pq_new(_args: NativeFuncArgs) -> NativeFuncReturnType {
+    Ok(SquatValue::Object(SquatObject::PriorityQueue(Rc::new(
+        RefCell::new(BinaryHeap::new()).into(),
+    ))))
+}
+
+/// Only takes values that can be ordered with the ones already in the queue,
+/// like ints with ints or strings with strings
+pub fn pq_push(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::Object(SquatObject::PriorityQueue(queue)) = &args[0] else {
+        return Err(format!("'{}' is not a priority queue", args[0]));
+    };
+    let value = &args[1];
+    let mut queue = queue.borrow_mut();
+    let compared_to = queue.peek().map_or(value, |top| &top.0);
+    if value.partial_cmp(compared_to).is_none() {
+        return Err(format!(
+            "Cannot order {} in a priority queue of {}",
+            value.to_nested_string(),
+            compared_to.get_type()
+        ));
+    }
+    queue.push(QueueEntry(value.clone()));
+    Ok(SquatValue::Nil)
+}
+
+pub fn pq_pop(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::Object(SquatObject::PriorityQueue(queue)) = &args[0] else {
+        return Err(format!("'{}' is not a priority queue", args[0]));
+    };
+    match queue.borrow_mut().pop() {
+        Some(QueueEntry(value)) => Ok(value),
+        None => Err("Cannot pop from an empty priority queue".to_owned()),
+    }
+}
+
+pub fn pq_len(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::Object(SquatObject::PriorityQueue(queue)) = &args[0] else {
+        return Err(format!("'{}' is not a priority queue", args[0]));
+    };
+    Ok(SquatValue::Int(queue.borrow().len() as i64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn values_are_popped_smallest_first() {
+        let queue = pq_new(vec![]).unwrap();
+        for value in [5, 1, 4, 1, 3] {
+            assert_eq!(
+                pq_push(vec![queue.clone(), SquatValue::Int(value)]),
+                Ok(SquatValue::Nil)
+            );
+        }
+        assert_eq!(pq_len(vec![queue.clone()]), Ok(SquatValue::Int(5)));
+
+        let popped: Vec<SquatValue> = (0..5)
+            .map(|_| pq_pop(vec![queue.clone()]).unwrap())
+            .collect();
+        assert_eq!(popped, [1, 1, 3, 4, 5].map(SquatValue::Int));
+        assert!(pq_pop(vec![queue.clone()]).is_err());
+    }
+
+    #[test]
+    fn only_ordered_values_are_pushed() {
+        let queue = pq_new(vec![]).unwrap();
+        assert!(pq_push(vec![queue.clone(), SquatValue::Float(f64::NAN)]).is_err());
+        assert!(pq_push(vec![queue.clone(), SquatValue::Int(1)]).is_ok());
+        assert_eq!(
+            pq_push(vec![queue.clone(), SquatValue::String("a".into())]),
+            Err("Cannot order \"a\" in a priority queue of <type Int>".to_owned())
+        );
+        assert_eq!(pq_len(vec![queue]), Ok(SquatValue::Int(1)));
+    }
+}
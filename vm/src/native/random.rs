@@ -0,0 +1,40 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// State of the xorshift generator, zero until it is seeded
+#[derive(Debug, Default)]
+pub struct RandomState(u64);
+
+impl RandomState {
+    /// Makes the generator return the same numbers for the same seed, zero
+    /// seeds it from the time again on next use
+    pub fn seed(&mut self, seed: u64) {
+        self.0 = match seed {
+            0 => 0,
+            // Close seeds should not start close to each other
+            seed => seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+        };
+    }
+
+    /// Next number of the xorshift64* generator, seeded from the time on
+    /// first use
+    fn next_u64(&mut self) -> u64 {
+        let mut state = self.0;
+        if state == 0 {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_nanos() as u64);
+            // Zero would make the generator return zeros forever
+            state = nanos | 1;
+        }
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        self.0 = state;
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Random index in `0..bound`, `bound` has to be greater than zero
+    pub fn next_index(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
@@ -1,14 +1,286 @@
 use super::*;
-use crate::value::squat_value::SquatValue;
+use crate::{object::SquatObject, value::squat_value::SquatValue};
+use std::{cell::RefCell, rc::Rc};
 
-pub fn to_str(args: NativeFuncArgs) -> NativeFuncReturnType {
+pub fn to_str(args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
     Ok(match &args[0] {
-        SquatValue::Nil => SquatValue::String("Nil".to_owned()),
-        SquatValue::Int(value) => SquatValue::String(value.to_string()),
-        SquatValue::Float(value) => SquatValue::String(value.to_string()),
-        SquatValue::String(value) => SquatValue::String(value.to_string()),
-        SquatValue::Bool(value) => SquatValue::String(value.to_string()),
-        SquatValue::Object(value) => SquatValue::String(value.to_string()),
-        SquatValue::Type(value) => SquatValue::String(value.to_string()),
+        SquatValue::Nil => SquatValue::String("Nil".into()),
+        SquatValue::Int(value) => SquatValue::String(value.to_string().into()),
+        SquatValue::Float(_) | SquatValue::Object(_) => SquatValue::String(
+            args[0]
+                .to_string_with_precision(state.float_precision)
+                .into(),
+        ),
+        SquatValue::String(value) => SquatValue::String(value.clone()),
+        SquatValue::Bool(value) => SquatValue::String(value.to_string().into()),
+        SquatValue::Type(value) => SquatValue::String(value.to_string().into()),
     })
 }
+
+/// Replaces each `{}` of the format string with the next value, there has to
+/// be exactly one value per placeholder. Floats are shown with the given
+/// precision, see `SquatValue::to_string_with_precision`.
+pub fn format_values(
+    format: &str,
+    values: &[SquatValue],
+    float_precision: Option<usize>,
+) -> Result<String, String> {
+    let placeholder_count = format.matches("{}").count();
+    if placeholder_count != values.len() {
+        return Err(format!(
+            "Format string has {} placeholders but {} values were given",
+            placeholder_count,
+            values.len()
+        ));
+    }
+
+    let mut formatted = String::with_capacity(format.len());
+    let mut parts = format.split("{}");
+    formatted.push_str(parts.next().unwrap());
+    for (part, value) in parts.zip(values) {
+        formatted.push_str(&value.to_string_with_precision(float_precision));
+        formatted.push_str(part);
+    }
+    Ok(formatted)
+}
+
+/// The format string with its `{}` replaced by the values, see `format_values`
+pub fn format(args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+    let SquatValue::String(value) = &args[0] else {
+        return Err(format!("'{}' is not of type string", args[0]));
+    };
+    let formatted = format_values(value, &args[1..], state.float_precision)?;
+    Ok(SquatValue::String(formatted.into()))
+}
+
+/// One single character string per character
+pub fn chars(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::String(value) = &args[0] else {
+        return Err(format!("'{}' is not of type string", args[0]));
+    };
+    let chars = value
+        .chars()
+        .map(|c| SquatValue::String(c.to_string().into()))
+        .collect();
+    Ok(SquatValue::Object(SquatObject::Array(chars)))
+}
+
+/// The UTF-8 bytes of a string as ints
+pub fn to_bytes(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::String(value) = &args[0] else {
+        return Err(format!("'{}' is not of type string", args[0]));
+    };
+    let bytes = value
+        .bytes()
+        .map(|byte| SquatValue::Int(byte as i64))
+        .collect();
+    Ok(SquatValue::Object(SquatObject::Array(bytes)))
+}
+
+/// The string of an array of UTF-8 bytes, the reverse of `to_bytes`
+pub fn from_bytes(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::Object(SquatObject::Array(values)) = &args[0] else {
+        return Err(format!("'{}' is not of type array", args[0]));
+    };
+    let bytes = values
+        .iter()
+        .map(|value| match value {
+            SquatValue::Int(byte @ 0..=255) => Ok(*byte as u8),
+            _ => Err(format!("'{}' is not a byte", value.to_nested_string())),
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    match String::from_utf8(bytes) {
+        Ok(value) => Ok(SquatValue::String(value.into())),
+        Err(error) => Err(format!("The bytes are not valid UTF-8: {}", error)),
+    }
+}
+
+/// Appending to a builder does not copy what is already in it, unlike adding
+/// strings, so a string built from many pieces takes linear time
+pub fn string_builder(_args: NativeFuncArgs) -> NativeFuncReturnType {
+    Ok(SquatValue::Object(SquatObject::StringBuilder(Rc::new(
+        RefCell::new(String::new()).into(),
+    ))))
+}
+
+/// Appends the value as `to_str` would convert it
+pub fn append(args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+    let SquatValue::Object(SquatObject::StringBuilder(builder)) = &args[0] else {
+        return Err(format!("'{}' is not a string builder", args[0]));
+    };
+    builder
+        .borrow_mut()
+        .push_str(&args[1].to_string_with_precision(state.float_precision));
+    Ok(SquatValue::Nil)
+}
+
+pub fn build(args: NativeFuncArgs) -> NativeFuncReturnType {
+    let SquatValue::Object(SquatObject::StringBuilder(builder)) = &args[0] else {
+        return Err(format!("'{}' is not a string builder", args[0]));
+    };
+    Ok(SquatValue::String(builder.borrow().as_str().into()))
+}
+
+pub fn pad_left(args: NativeFuncArgs) -> NativeFuncReturnType {
+    pad(&args, true)
+}
+
+pub fn pad_right(args: NativeFuncArgs) -> NativeFuncReturnType {
+    pad(&args, false)
+}
+
+/// Fills the string up to the width, counted in characters
+fn pad(args: &[SquatValue], left: bool) -> NativeFuncReturnType {
+    let (SquatValue::String(value), SquatValue::Int(width), SquatValue::String(fill)) =
+        (&args[0], &args[1], &args[2])
+    else {
+        return Err("Expected a string, an int and a fill string".to_owned());
+    };
+    let mut fill_chars = fill.chars();
+    let (Some(fill), None) = (fill_chars.next(), fill_chars.next()) else {
+        return Err(format!(
+            "The fill has to be a single character but '{}' was given",
+            fill
+        ));
+    };
+
+    let missing = (*width).max(0) as usize;
+    let missing = missing.saturating_sub(value.chars().count());
+    if missing == 0 {
+        return Ok(SquatValue::String(value.clone()));
+    }
+    let padding: String = std::iter::repeat_n(fill, missing).collect();
+    Ok(match left {
+        true => [&*padding, &**value].concat().into(),
+        false => [&**value, &*padding].concat().into(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pad_args(value: &str, width: i64, fill: &str) -> NativeFuncArgs {
+        vec![
+            SquatValue::String(value.into()),
+            SquatValue::Int(width),
+            SquatValue::String(fill.into()),
+        ]
+    }
+
+    #[test]
+    fn strings_are_padded_by_characters() {
+        assert_eq!(
+            pad_left(pad_args("7", 3, "0")),
+            Ok(SquatValue::String("007".into()))
+        );
+        assert_eq!(
+            pad_right(pad_args("né", 4, ".")),
+            Ok(SquatValue::String("né..".into()))
+        );
+        assert_eq!(
+            pad_left(pad_args("ab", 4, "é")),
+            Ok(SquatValue::String("ééab".into()))
+        );
+    }
+
+    #[test]
+    fn wide_strings_are_not_padded() {
+        assert_eq!(
+            pad_left(pad_args("long", 2, " ")),
+            Ok(SquatValue::String("long".into()))
+        );
+        assert_eq!(
+            pad_right(pad_args("four", 4, " ")),
+            Ok(SquatValue::String("four".into()))
+        );
+        assert_eq!(
+            pad_right(pad_args("x", -1, " ")),
+            Ok(SquatValue::String("x".into()))
+        );
+    }
+
+    #[test]
+    fn fill_has_to_be_one_character() {
+        assert!(pad_left(pad_args("x", 3, "")).is_err());
+        assert!(pad_right(pad_args("x", 3, "ab")).is_err());
+    }
+
+    #[test]
+    fn format_values_fills_placeholders_in_order() {
+        assert_eq!(
+            format_values(
+                "{} + {} = {}",
+                &[
+                    SquatValue::Int(1),
+                    SquatValue::Float(1.5),
+                    SquatValue::Float(2.5)
+                ],
+                None
+            ),
+            Ok("1 + 1.5 = 2.5".to_owned())
+        );
+        assert_eq!(
+            format_values("{}", &[SquatValue::Float(2.5)], Some(2)),
+            Ok("2.50".to_owned())
+        );
+        assert_eq!(
+            format_values("no values", &[], None),
+            Ok("no values".to_owned())
+        );
+        assert!(format_values("{} {}", &[SquatValue::Nil], None).is_err());
+    }
+
+    #[test]
+    fn strings_split_into_characters() {
+        let split = |value: &str| chars(vec![SquatValue::String(value.into())]);
+        let array = |values: &[&str]| {
+            Ok(SquatValue::Object(SquatObject::Array(
+                values
+                    .iter()
+                    .map(|value| SquatValue::String((*value).into()))
+                    .collect(),
+            )))
+        };
+        assert_eq!(split("ab"), array(&["a", "b"]));
+        assert_eq!(split("né"), array(&["n", "é"]));
+        assert_eq!(split(""), array(&[]));
+        assert!(chars(vec![SquatValue::Int(1)]).is_err());
+    }
+
+    #[test]
+    fn builders_are_shared_between_copies() {
+        let builder = string_builder(vec![]).unwrap();
+        let copy = builder.clone();
+        let state = &mut NativeState::default();
+        append(vec![builder.clone(), SquatValue::String("a".into())], state).unwrap();
+        append(vec![copy.clone(), SquatValue::Int(1)], state).unwrap();
+        assert_eq!(build(vec![builder]), Ok(SquatValue::String("a1".into())));
+        assert!(append(vec![SquatValue::Nil, SquatValue::Int(1)], state).is_err());
+    }
+
+    #[test]
+    fn strings_round_trip_through_bytes() {
+        let bytes = |values: Vec<i64>| {
+            SquatValue::Object(SquatObject::Array(
+                values.into_iter().map(SquatValue::Int).collect(),
+            ))
+        };
+        assert_eq!(
+            to_bytes(vec![SquatValue::String("Hi!".into())]),
+            Ok(bytes(vec![72, 105, 33]))
+        );
+        assert_eq!(
+            from_bytes(vec![bytes(vec![72, 105, 33])]),
+            Ok(SquatValue::String("Hi!".into()))
+        );
+        assert_eq!(
+            to_bytes(vec![SquatValue::String("é".into())]),
+            Ok(bytes(vec![0xc3, 0xa9]))
+        );
+
+        assert!(from_bytes(vec![bytes(vec![0xc3])]).is_err());
+        assert!(from_bytes(vec![bytes(vec![72, 256])]).is_err());
+        assert!(from_bytes(vec![bytes(vec![-1])]).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use super::*;
+use crate::{native::string::format_values, value::squat_value::SquatValue};
+
+/// Stops with the message formatted with the remaining arguments if the
+/// condition is false, see `string::format_values`
+pub fn assert_that(args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+    let message = match &args[1] {
+        SquatValue::String(message) => message,
+        value => return Err(format!("'{}' is not of type string", value)),
+    };
+    match &args[0] {
+        SquatValue::Bool(true) => Ok(SquatValue::Nil),
+        SquatValue::Bool(false) => Err(format!(
+            "Assertion failed: {}",
+            format_values(message, &args[2..], state.float_precision)?
+        )),
+        value => Err(format!("'{}' is not of type bool", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> SquatValue {
+        SquatValue::String(value.into())
+    }
+
+    #[test]
+    fn passing_assertion_returns_nil() {
+        assert_eq!(
+            assert_that(
+                vec![
+                    SquatValue::Bool(true),
+                    string("unused {}"),
+                    SquatValue::Int(1)
+                ],
+                &mut NativeState::default()
+            ),
+            Ok(SquatValue::Nil)
+        );
+    }
+
+    #[test]
+    fn failing_assertion_formats_the_values() {
+        assert_eq!(
+            assert_that(
+                vec![
+                    SquatValue::Bool(false),
+                    string("expected {} but got {}"),
+                    SquatValue::Int(3),
+                    string("three"),
+                ],
+                &mut NativeState::default()
+            ),
+            Err("Assertion failed: expected 3 but got three".to_owned())
+        );
+    }
+}
@@ -1,31 +1,84 @@
+use std::{
+    cell::{Cell, OnceCell, RefCell},
+    cmp::Ordering,
+    collections::BinaryHeap,
+    fmt,
+    ops::{Deref, DerefMut},
+    rc::Rc,
+};
+
 use crate::{
-    native::{NativeFunc, NativeFuncArgs, NativeFuncReturnType},
-    value::{squat_type::SquatType, squat_value::SquatValue},
+    native::{
+        NativeCallback, NativeFunc, NativeFuncArgs, NativeFuncReturnType, NativeFuncWithCallback,
+        NativeFuncWithState, NativeState,
+    },
+    value::{
+        squat_type::{
+            SquatFunctionTypeData, SquatInstanceTypeData, SquatStructTypeData, SquatType,
+        },
+        squat_value::SquatValue,
+    },
 };
 
 #[derive(Debug, Clone, Default)]
 pub struct SquatStruct {
     pub name: String,
+    // Shared with every instance, in the order of the properties
+    pub field_names: Rc<[String]>,
+    // Field types and defaults known by the compiler, `from_map` checks the
+    // values against them
+    pub type_data: Rc<SquatStructTypeData>,
 }
 impl SquatStruct {
-    pub fn new(name: &str) -> SquatStruct {
+    pub fn new(name: &str, field_names: Vec<String>) -> SquatStruct {
         SquatStruct {
             name: name.to_string(),
+            field_names: field_names.into(),
+            type_data: Rc::new(SquatStructTypeData::new(name)),
         }
     }
+
+    pub fn with_type_data(mut self, type_data: SquatStructTypeData) -> SquatStruct {
+        self.type_data = Rc::new(type_data);
+        self
+    }
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct SquatInstance {
     pub instance_of: String,
+    field_names: Rc<[String]>,
     properties: Vec<SquatValue>,
+    token: ObjectToken,
 }
 impl SquatInstance {
     pub fn new(instance_of: &str, properties: Vec<SquatValue>) -> SquatInstance {
         SquatInstance {
             instance_of: instance_of.to_string(),
+            field_names: Rc::new([]),
             properties,
+            token: ObjectToken::default(),
+        }
+    }
+
+    pub fn with_field_names(mut self, field_names: Rc<[String]>) -> SquatInstance {
+        self.field_names = field_names;
+        self
+    }
+
+    /// Name and value of every field, `None` if the instance was created
+    /// without its field names
+    pub fn fields(&self) -> Option<Vec<(&str, &SquatValue)>> {
+        if self.field_names.len() != self.properties.len() {
+            return None;
         }
+        Some(
+            self.field_names
+                .iter()
+                .map(|name| name.as_str())
+                .zip(&self.properties)
+                .collect(),
+        )
     }
 
     pub fn get_property(&self, index: usize) -> SquatValue {
@@ -50,33 +103,323 @@ impl PartialEq for SquatInstance {
 pub struct SquatFunction {
     pub name: String,
     pub start_instruction_index: usize,
+    pub type_data: SquatFunctionTypeData,
+    // Results are cached by the arguments, see `@memoize`
+    pub memoize: bool,
+    // Values of the enclosing locals the function uses, copied when the
+    // function value is made, see `OpCode::Closure`
+    pub upvalues: Rc<[SquatValue]>,
 }
 
 impl SquatFunction {
-    pub fn new(name: &str, start_instruction_index: usize) -> SquatFunction {
+    /// Functions without a name are called `<anonymous>` so stack traces
+    /// always name every frame
+    pub fn new(
+        name: &str,
+        start_instruction_index: usize,
+        type_data: SquatFunctionTypeData,
+    ) -> SquatFunction {
         SquatFunction {
-            name: name.to_owned(),
+            name: match name {
+                "" => "<anonymous>".to_owned(),
+                name => name.to_owned(),
+            },
             start_instruction_index,
+            type_data,
+            memoize: false,
+            upvalues: Rc::new([]),
         }
     }
+
+    pub fn memoized(mut self) -> SquatFunction {
+        self.memoize = true;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NativeFuncKind {
+    Plain(NativeFunc),
+    WithCallback(NativeFuncWithCallback),
+    WithState(NativeFuncWithState),
 }
 
 #[derive(Debug, Clone)]
 pub struct SquatNativeFunction {
     pub name: String,
-    function: NativeFunc,
+    function: NativeFuncKind,
+    pub type_data: SquatFunctionTypeData,
+    // The VM sets the call context of the native state before calling the
+    // function, see `native::introspection`
+    pub uses_call_context: bool,
 }
 
 impl SquatNativeFunction {
-    pub fn new(name: &str, function: NativeFunc) -> SquatNativeFunction {
+    pub fn new(
+        name: &str,
+        function: NativeFunc,
+        type_data: SquatFunctionTypeData,
+    ) -> SquatNativeFunction {
+        SquatNativeFunction {
+            name: name.to_string(),
+            function: NativeFuncKind::Plain(function),
+            type_data,
+            uses_call_context: false,
+        }
+    }
+
+    pub fn with_callback(
+        name: &str,
+        function: NativeFuncWithCallback,
+        type_data: SquatFunctionTypeData,
+    ) -> SquatNativeFunction {
+        SquatNativeFunction {
+            name: name.to_string(),
+            function: NativeFuncKind::WithCallback(function),
+            type_data,
+            uses_call_context: false,
+        }
+    }
+
+    pub fn with_state(
+        name: &str,
+        function: NativeFuncWithState,
+        type_data: SquatFunctionTypeData,
+    ) -> SquatNativeFunction {
         SquatNativeFunction {
             name: name.to_string(),
-            function,
+            function: NativeFuncKind::WithState(function),
+            type_data,
+            uses_call_context: false,
+        }
+    }
+
+    pub fn with_call_context(mut self) -> SquatNativeFunction {
+        self.uses_call_context = true;
+        self
+    }
+
+    /// Whether the native can call squat functions, see `with_callback`
+    pub fn calls_back(&self) -> bool {
+        matches!(self.function, NativeFuncKind::WithCallback(_))
+    }
+
+    /// Calls natives that do not call back, see `call_back` for the others
+    pub fn call(&self, args: NativeFuncArgs, state: &mut NativeState) -> NativeFuncReturnType {
+        match self.function {
+            NativeFuncKind::Plain(function) => function(args),
+            NativeFuncKind::WithState(function) => function(args, state),
+            NativeFuncKind::WithCallback(_) => {
+                unreachable!("Natives that call back are called with `call_back`")
+            }
+        }
+    }
+
+    /// Calls natives created with `with_callback`. The callback needs the VM,
+    /// so these natives can't be given the native state as well.
+    pub fn call_back(
+        &self,
+        args: NativeFuncArgs,
+        callback: NativeCallback,
+    ) -> NativeFuncReturnType {
+        match self.function {
+            NativeFuncKind::WithCallback(function) => function(args, callback),
+            _ => unreachable!("Only natives created with `with_callback` call back"),
+        }
+    }
+}
+
+/// Keys and values in the order they were inserted. Keys are hashable
+/// values, see `SquatValue::is_hashable`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SquatMap {
+    entries: Vec<(SquatValue, SquatValue)>,
+    token: ObjectToken,
+}
+
+impl SquatMap {
+    pub fn new() -> SquatMap {
+        SquatMap {
+            entries: Vec::new(),
+            token: ObjectToken::default(),
+        }
+    }
+
+    /// Replaces the value if the key is already in the map
+    pub fn insert(&mut self, key: SquatValue, value: SquatValue) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|(entry_key, _)| *entry_key == key)
+        {
+            Some((_, entry_value)) => *entry_value = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    pub fn get(&self, key: &SquatValue) -> Option<&SquatValue> {
+        self.entries
+            .iter()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &SquatValue> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Integers from `start` up to but not including `end`. The for-each loop
+/// pulls them one at a time, so the range is never stored as an array.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SquatRange {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl SquatRange {
+    pub fn new(start: i64, end: i64) -> SquatRange {
+        SquatRange { start, end }
+    }
+
+    /// Value at `index` steps from the start, `None` once the end is reached
+    pub fn get(&self, index: i64) -> Option<i64> {
+        let value = self.start.checked_add(index)?;
+        if value < self.end {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+/// Element of a priority queue. `BinaryHeap` needs a total order and pops the
+/// largest element, so the order of the values is reversed. The queue only
+/// takes values that can be compared with the ones in it, see `pq_push`.
+#[derive(Debug, Clone)]
+pub struct QueueEntry(pub SquatValue);
+
+impl PartialEq for QueueEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueueEntry {}
+
+impl PartialOrd for QueueEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueueEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.partial_cmp(&self.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Objects of a VM that are alive, see `ObjectToken`
+#[derive(Debug, Clone, Default)]
+pub struct ObjectCounter(Rc<Cell<usize>>);
+
+impl ObjectCounter {
+    pub fn get(&self) -> usize {
+        self.0.get()
+    }
+}
+
+/// Part of every array, instance, map, string builder and priority queue.
+/// Once the VM counts the object, see `SquatObject::count`, the token keeps
+/// the counter of the VM up to date: a copy adds one and dropping removes
+/// one. Builders and queues are shared by their copies, so they count once.
+#[derive(Debug, Default)]
+pub struct ObjectToken(OnceCell<ObjectCounter>);
+
+impl ObjectToken {
+    /// Adds the object to the counter, false if it already was counted
+    fn count(&self, counter: &ObjectCounter) -> bool {
+        if self.0.get().is_some() {
+            return false;
+        }
+        counter.0.set(counter.0.get() + 1);
+        self.0.get_or_init(|| counter.clone());
+        true
+    }
+}
+
+impl Clone for ObjectToken {
+    fn clone(&self) -> Self {
+        let token = ObjectToken::default();
+        if let Some(counter) = self.0.get() {
+            token.count(counter);
+        }
+        token
+    }
+}
+
+impl Drop for ObjectToken {
+    fn drop(&mut self) {
+        if let Some(counter) = self.0.get() {
+            counter.0.set(counter.0.get() - 1);
         }
     }
+}
 
-    pub fn call(&self, args: NativeFuncArgs) -> NativeFuncReturnType {
-        (self.function)(args)
+// Tokens don't take part in comparing objects
+impl PartialEq for ObjectToken {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+/// Value of an object that has no fields of its own to keep its token in
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Counted<T> {
+    value: T,
+    token: ObjectToken,
+}
+
+impl<T> Counted<T> {
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> From<T> for Counted<T> {
+    fn from(value: T) -> Self {
+        Counted {
+            value,
+            token: ObjectToken::default(),
+        }
+    }
+}
+
+impl<T> FromIterator<T> for Counted<Vec<T>> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Vec::from_iter(iter).into()
+    }
+}
+
+impl<T> Deref for Counted<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Counted<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
     }
 }
 
@@ -86,29 +429,111 @@ pub enum SquatObject {
     NativeFunction(SquatNativeFunction),
     Struct(SquatStruct),
     Instance(SquatInstance),
+    Array(Counted<Vec<SquatValue>>),
+    Range(SquatRange),
+    Map(SquatMap),
+    // Shared by every copy, appending to one appends to all of them
+    StringBuilder(Rc<Counted<RefCell<String>>>),
+    // Pops the smallest value first, shared by every copy like a builder
+    PriorityQueue(Rc<Counted<RefCell<BinaryHeap<QueueEntry>>>>),
 }
 
 impl SquatObject {
+    /// Adds the object and the objects in it that were not counted yet to
+    /// the counter. Objects are made without a counter, natives don't have
+    /// one, so the VM counts them when they reach the stack. Copies of
+    /// counted objects are counted as they are made.
+    pub fn count(&self, counter: &ObjectCounter) {
+        // What is in an object that was already counted was counted with it
+        match self {
+            SquatObject::Instance(instance) if instance.token.count(counter) => {
+                for value in &instance.properties {
+                    value.count_objects(counter);
+                }
+            }
+            SquatObject::Array(values) if values.token.count(counter) => {
+                for value in values.iter() {
+                    value.count_objects(counter);
+                }
+            }
+            SquatObject::Map(map) if map.token.count(counter) => {
+                for (key, value) in &map.entries {
+                    key.count_objects(counter);
+                    value.count_objects(counter);
+                }
+            }
+            SquatObject::StringBuilder(builder) => {
+                builder.token.count(counter);
+            }
+            SquatObject::PriorityQueue(queue) if queue.token.count(counter) => {
+                for entry in queue.borrow().iter() {
+                    entry.0.count_objects(counter);
+                }
+            }
+            _ => {}
+        }
+    }
+
     pub fn get_type(&self) -> SquatType {
         match self {
-            SquatObject::Function(_) => SquatType::Function(Default::default()),
-            SquatObject::NativeFunction(_) => SquatType::NativeFunction(Default::default()),
+            SquatObject::Function(func) => SquatType::Function(func.type_data.clone()),
+            SquatObject::NativeFunction(func) => SquatType::NativeFunction(func.type_data.clone()),
             SquatObject::Struct(_) => SquatType::Struct(Default::default()),
-            SquatObject::Instance(_) => SquatType::Instance(Default::default()),
+            SquatObject::Instance(instance) => {
+                SquatType::Instance(SquatInstanceTypeData::new(&instance.instance_of))
+            }
+            SquatObject::Array(values) => SquatType::Array(Box::new(match values.first() {
+                Some(value) => value.get_type(),
+                None => SquatType::Any,
+            })),
+            SquatObject::Range(_) => SquatType::Range,
+            SquatObject::Map(_) => SquatType::Map,
+            SquatObject::StringBuilder(_) => SquatType::StringBuilder,
+            SquatObject::PriorityQueue(_) => SquatType::PriorityQueue,
         }
     }
 }
 
-impl ToString for SquatObject {
-    fn to_string(&self) -> String {
+impl fmt::Display for SquatObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            SquatObject::Function(func) => format!("<func {}>", func.name),
-            SquatObject::NativeFunction(func) => format!("<native func {}>", func.name),
-            SquatObject::Struct(class) => format!("<class {}>", class.name),
-            SquatObject::Instance(instance) => format!(
+            SquatObject::Function(func) => write!(f, "<func {}>", func.name),
+            SquatObject::NativeFunction(func) => write!(f, "<native func {}>", func.name),
+            SquatObject::Struct(class) => write!(f, "<class {}>", class.name),
+            SquatObject::Instance(instance) => write!(
+                f,
                 "<instance of {} {:?}>",
                 instance.instance_of, instance.properties
             ),
+            SquatObject::Array(values) => write!(
+                f,
+                "[{}]",
+                values
+                    .iter()
+                    .map(|x| x.to_nested_string_with_precision(f.precision()))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            SquatObject::Range(range) => write!(f, "range({}, {})", range.start, range.end),
+            SquatObject::Map(map) => write!(
+                f,
+                "{{{}}}",
+                map.entries
+                    .iter()
+                    .map(|(key, value)| format!(
+                        "{}: {}",
+                        key.to_nested_string(),
+                        value.to_nested_string_with_precision(f.precision())
+                    ))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            SquatObject::StringBuilder(builder) => {
+                write!(f, "<string builder of {} bytes>", builder.borrow().len())
+            }
+            SquatObject::PriorityQueue(queue) => {
+                write!(f, "<priority queue of {} values>", queue.borrow().len())
+            }
         }
     }
 }
@@ -118,6 +543,7 @@ impl PartialEq for SquatObject {
         match (self, other) {
             (SquatObject::Function(func1), SquatObject::Function(func2)) => {
                 func1.start_instruction_index == func2.start_instruction_index
+                    && func1.upvalues == func2.upvalues
             }
             (SquatObject::NativeFunction(func1), SquatObject::NativeFunction(func2)) => {
                 func1.name == func2.name
@@ -128,6 +554,15 @@ impl PartialEq for SquatObject {
             (SquatObject::Instance(instance1), SquatObject::Instance(instance2)) => {
                 instance1 == instance2
             }
+            (SquatObject::Array(values1), SquatObject::Array(values2)) => values1 == values2,
+            (SquatObject::Range(range1), SquatObject::Range(range2)) => range1 == range2,
+            (SquatObject::Map(map1), SquatObject::Map(map2)) => map1 == map2,
+            (SquatObject::StringBuilder(builder1), SquatObject::StringBuilder(builder2)) => {
+                Rc::ptr_eq(builder1, builder2)
+            }
+            (SquatObject::PriorityQueue(queue1), SquatObject::PriorityQueue(queue2)) => {
+                Rc::ptr_eq(queue1, queue2)
+            }
             _ => false,
         }
     }
@@ -12,6 +12,8 @@ pub enum OpCode {
     Multiply,
     Divide,
     Mod,
+    ShiftRight,
+    ShiftRightLogical,
 
     Equal,
     NotEqual,
@@ -22,6 +24,8 @@ pub enum OpCode {
 
     Not,
     Negate,
+    // The absolute value of the number on top of the stack, for `abs(x)`
+    Abs,
 
     Pop,
 
@@ -30,6 +34,12 @@ pub enum OpCode {
     SetGlobal(usize),
     GetLocal(usize),
     SetLocal(usize),
+    // Pushes a value the running function captured from an enclosing one
+    GetUpvalue(usize),
+    // Pushes the running function, a local function calls itself with it
+    GetCallee,
+    // Adds the amount to an int local in place, for `i = i + 1;`
+    IncLocal(usize, i64),
     GetNative(usize),
     GetGlobalProperty(usize, usize),
     GetLocalProperty(usize, usize),
@@ -38,17 +48,35 @@ pub enum OpCode {
     SetLocalProperty(usize, usize),
 
     Index,
+    // Pops a cursor and an array or range, pushes the element at the cursor
+    // and `true`, or `nil` and `false` once there are no elements left
+    IterNext,
+    IsType(usize),
 
     JumpTo(usize),
     JumpIfFalse(usize),
     Jump(usize),
     JumpIfTrue(usize),
     Loop(usize),
+    // Runtime errors until the matching `EndTry` unwind the stacks to where
+    // they were here and jump by the offset, with the message pushed
+    Try(usize),
+    EndTry,
+    // Pops a value and raises a runtime error with it as the message
+    Throw,
 
     Call(usize),
     CreateInstance(usize),
+    // Pops that many captured values and gives them to the function below
+    Closure(usize),
     Return,
 
     Start,
     Stop,
+
+    // Only emitted for the `print` statement of `--legacy-print`
+    Print,
+
+    // Left in place of removed instructions so that jump targets stay valid
+    Nop,
 }
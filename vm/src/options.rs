@@ -3,13 +3,15 @@ use arg_parser::CmdArgs;
 #[derive(CmdArgs, Debug, Default)]
 #[metadata(description = "Squat virtual machine.")]
 pub struct Options {
+    #[arg(short = "-f", long = "--file", description = "The file to compile")]
+    pub file: String,
+
     #[arg(
-        short = "-f",
-        long = "--file",
-        description = "The file to compile",
-        required = true
+        short = "-r",
+        long = "--project",
+        description = "Compile every .squat file of the directory together and run main"
     )]
-    pub file: String,
+    pub project: String,
 
     #[arg(
         short = "-c",
@@ -34,4 +36,137 @@ pub struct Options {
         description = "Log the stack of the program before each instruction"
     )]
     pub log_stack: bool,
+
+    #[arg(
+        short = "-p",
+        long = "--parse-tree",
+        description = "Print the parse tree of the program instead of running it"
+    )]
+    pub print_parse_tree: bool,
+
+    #[arg(
+        short = "-P",
+        long = "--profile",
+        description = "Print how many times each function was called"
+    )]
+    pub profile: bool,
+
+    #[arg(
+        short = "-o",
+        long = "--profile-out",
+        description = "Write samples of the call stack to this file in the folded format of flamegraphs"
+    )]
+    pub profile_out: String,
+
+    #[arg(
+        short = "-S",
+        long = "--stats",
+        description = "Print the size of the program and the max stack depth after running it"
+    )]
+    pub stats: bool,
+
+    #[arg(
+        short = "-d",
+        long = "--extract-docs",
+        description = "Print the doc comments of functions and structs instead of running"
+    )]
+    pub extract_docs: bool,
+
+    #[arg(
+        short = "-t",
+        long = "--strict-types",
+        description = "Reject mixing ints and floats without converting with to_int or to_float"
+    )]
+    pub strict_types: bool,
+
+    #[arg(
+        short = "-n",
+        long = "--no-implicit-default",
+        description = "Require every variable declaration to give the variable a value"
+    )]
+    pub no_implicit_default: bool,
+
+    #[arg(
+        short = "-C",
+        long = "--coverage",
+        description = "Print the source lines that were never executed"
+    )]
+    pub coverage: bool,
+
+    #[arg(
+        short = "-k",
+        long = "--constants",
+        description = "Log the constants after compilation"
+    )]
+    pub log_constants: bool,
+
+    #[arg(
+        short = "-l",
+        long = "--legacy-print",
+        description = "Allow the 'print value;' statement of older scripts"
+    )]
+    pub legacy_print: bool,
+
+    #[arg(
+        short = "-m",
+        long = "--max-string-length",
+        description = "Stop with an error when a string gets longer than this many bytes"
+    )]
+    pub max_string_length: usize,
+
+    #[arg(
+        short = "-M",
+        long = "--max-objects",
+        description = "Stop with an error when the program holds more than this many objects"
+    )]
+    pub max_objects: usize,
+
+    #[arg(
+        short = "-F",
+        long = "--float-precision",
+        description = "Print floats with this many digits after the decimal point"
+    )]
+    pub float_precision: Option<usize>,
+
+    #[arg(
+        short = "-u",
+        long = "--allow-unused",
+        description = "Do not warn about variables that are never read"
+    )]
+    pub allow_unused: bool,
+
+    #[arg(
+        short = "-y",
+        long = "--symbols",
+        description = "Print every global and local with its type after compilation"
+    )]
+    pub print_symbols: bool,
+
+    #[arg(
+        short = "-w",
+        long = "--warn-recursion",
+        description = "Warn about functions that may recurse without a bound"
+    )]
+    pub warn_recursion: bool,
+
+    #[arg(
+        short = "-e",
+        long = "--explain",
+        description = "Print the explanation of an error code, like E0001, and exit"
+    )]
+    pub explain: String,
+
+    #[arg(
+        short = "-R",
+        long = "--seed",
+        description = "Seed of the random numbers, the same seed shuffles the same way; 0 seeds from the clock"
+    )]
+    pub seed: u64,
+
+    #[arg(
+        short = "-V",
+        long = "--verify-stack",
+        description = "Stop with an error when a statement starts with values left on the stack"
+    )]
+    pub verify_stack: bool,
 }
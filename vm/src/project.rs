@@ -0,0 +1,290 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::lexer::Lexer;
+use crate::token::TokenType;
+
+/// Source file of a project, with the top level names it declares and every
+/// identifier it uses
+struct ProjectFile {
+    path: String,
+    source: String,
+    declared: Vec<String>,
+    used: Vec<String>,
+}
+
+impl ProjectFile {
+    fn new(path: String, source: String) -> ProjectFile {
+        let mut file = ProjectFile {
+            path,
+            source,
+            declared: Vec::new(),
+            used: Vec::new(),
+        };
+        file.scan_names();
+        file
+    }
+
+    /// Top level functions, structs and globals are declared by the file.
+    /// Anything nested in braces belongs to a declaration.
+    fn scan_names(&mut self) {
+        let mut lexer = Lexer::new(&self.source, false);
+        let mut depth = 0;
+        let mut previous = TokenType::Eof;
+        let mut pending: Option<String> = None;
+        while let Ok(token) = lexer.scan_token() {
+            if let Some(name) = pending.take() {
+                if token.token_type == TokenType::Equal {
+                    self.declared.push(name);
+                }
+            }
+            match token.token_type {
+                TokenType::Eof => break,
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace => depth -= 1,
+                TokenType::Identifier => {
+                    if depth == 0 && matches!(previous, TokenType::Func | TokenType::Struct) {
+                        self.declared.push(token.lexeme.clone());
+                    } else if depth == 0
+                        && matches!(
+                            previous,
+                            TokenType::Var
+                                | TokenType::AnyType
+                                | TokenType::BoolType
+                                | TokenType::FloatType
+                                | TokenType::IntType
+                                | TokenType::StringType
+                                | TokenType::Identifier
+                                | TokenType::Question
+                        )
+                    {
+                        pending = Some(token.lexeme.clone());
+                    }
+                    self.used.push(token.lexeme);
+                }
+                _ => {}
+            }
+            previous = token.token_type;
+        }
+    }
+}
+
+/// Every `.squat` file of a directory, compiled together as one program.
+/// Globals are only visible after their declaration, so a file comes after
+/// the files that declare the names it uses.
+pub struct Project {
+    files: Vec<ProjectFile>,
+}
+
+impl Project {
+    pub fn load(directory: &str) -> Result<Project, String> {
+        let entries = fs::read_dir(directory)
+            .map_err(|err| format!("Failed to read project '{}': {}", directory, err))?;
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|err| format!("Failed to read project '{}': {}", directory, err))?
+                .path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "squat")
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            files.push(Project::read_file(&path)?);
+        }
+        Project::new(files)
+    }
+
+    fn read_file(path: &Path) -> Result<ProjectFile, String> {
+        let path = path.to_string_lossy().into_owned();
+        let source = fs::read_to_string(&path)
+            .map_err(|err| format!("Failed to read file '{}': {}", path, err))?;
+        Ok(ProjectFile::new(path, source))
+    }
+
+    fn new(files: Vec<ProjectFile>) -> Result<Project, String> {
+        if files.is_empty() {
+            return Err("The project has no .squat files".to_owned());
+        }
+        let mut project = Project { files };
+        project.sort_by_dependencies()?;
+        Ok(project)
+    }
+
+    /// Files in the order they are compiled
+    #[cfg(test)]
+    pub fn file_paths(&self) -> Vec<&str> {
+        self.files.iter().map(|file| file.path.as_str()).collect()
+    }
+
+    /// Sources of all files joined in order, with the first line of each
+    /// file in the joined source
+    pub fn source(&self) -> (String, Vec<(u32, String)>) {
+        let mut source = String::new();
+        let mut first_lines = Vec::with_capacity(self.files.len());
+        let mut line = 1;
+        for file in &self.files {
+            first_lines.push((line, file.path.clone()));
+            source.push_str(&file.source);
+            if !file.source.ends_with('\n') {
+                source.push('\n');
+            }
+            line += file.source.lines().count().max(1) as u32;
+        }
+        (source, first_lines)
+    }
+
+    fn sort_by_dependencies(&mut self) -> Result<(), String> {
+        let mut declared_in: HashMap<&str, usize> = HashMap::new();
+        for (index, file) in self.files.iter().enumerate() {
+            for name in &file.declared {
+                if let Some(other) = declared_in.insert(name, index) {
+                    return Err(format!(
+                        "'{}' is defined in both {} and {}",
+                        name, self.files[other].path, file.path
+                    ));
+                }
+            }
+        }
+
+        let dependencies: Vec<Vec<usize>> = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let mut dependencies: Vec<usize> = file
+                    .used
+                    .iter()
+                    .filter_map(|name| declared_in.get(name.as_str()).copied())
+                    .filter(|&dependency| dependency != index)
+                    .collect();
+                dependencies.sort();
+                dependencies.dedup();
+                dependencies
+            })
+            .collect();
+
+        // Files whose dependencies are all placed go first, in name order
+        let mut order = Vec::with_capacity(self.files.len());
+        let mut placed = vec![false; self.files.len()];
+        while order.len() < self.files.len() {
+            let next = (0..self.files.len()).find(|&index| {
+                !placed[index]
+                    && dependencies[index]
+                        .iter()
+                        .all(|&dependency| placed[dependency])
+            });
+            match next {
+                Some(index) => {
+                    placed[index] = true;
+                    order.push(index);
+                }
+                None => {
+                    let cycle: Vec<&str> = (0..self.files.len())
+                        .filter(|&index| !placed[index])
+                        .map(|index| self.files[index].path.as_str())
+                        .collect();
+                    return Err(format!(
+                        "Circular dependency between the files {}",
+                        cycle.join(", ")
+                    ));
+                }
+            }
+        }
+
+        let mut files: Vec<Option<ProjectFile>> = self.files.drain(..).map(Some).collect();
+        self.files = order
+            .into_iter()
+            .map(|index| files[index].take().unwrap())
+            .collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn project(files: &[(&str, &str)]) -> Result<Project, String> {
+        Project::new(
+            files
+                .iter()
+                .map(|(path, source)| ProjectFile::new(path.to_string(), source.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn files_come_after_their_dependencies() {
+        let project = project(&[
+            ("a.squat", "func main() { println(greeting(name)); }"),
+            (
+                "b.squat",
+                "func greeting(string who) string { return \"hi \" + who; }",
+            ),
+            (
+                "c.squat",
+                "string name = \"squat\";\nstruct Point { int x; }",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(project.file_paths(), vec!["b.squat", "c.squat", "a.squat"]);
+    }
+
+    #[test]
+    fn declarations_are_top_level_names() {
+        let file = ProjectFile::new(
+            "a.squat".to_owned(),
+            "int count = 0;\nPoint? origin = nil;\nstruct Point { int x; }\nfunc main() { int local = 1; }"
+                .to_owned(),
+        );
+        assert_eq!(file.declared, vec!["count", "origin", "Point", "main"]);
+    }
+
+    #[test]
+    fn duplicate_names_are_an_error() {
+        assert_eq!(
+            project(&[
+                ("a.squat", "func helper() int { return 1; }"),
+                ("b.squat", "int helper = 2;"),
+            ])
+            .err(),
+            Some("'helper' is defined in both a.squat and b.squat".to_owned())
+        );
+    }
+
+    #[test]
+    fn circular_dependencies_are_an_error() {
+        assert_eq!(
+            project(&[
+                ("a.squat", "int first = second;"),
+                ("b.squat", "int second = first;"),
+            ])
+            .err(),
+            Some("Circular dependency between the files a.squat, b.squat".to_owned())
+        );
+    }
+
+    #[test]
+    fn source_keeps_the_first_line_of_each_file() {
+        let project = project(&[
+            ("a.squat", "int one = 1;\nint two = 2;"),
+            ("b.squat", "int three = one + two;\n"),
+        ])
+        .unwrap();
+        let (source, first_lines) = project.source();
+        assert_eq!(
+            source,
+            "int one = 1;\nint two = 2;\nint three = one + two;\n"
+        );
+        assert_eq!(
+            first_lines,
+            vec![(1, "a.squat".to_owned()), (3, "b.squat".to_owned())]
+        );
+    }
+}
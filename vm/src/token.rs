@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 #[repr(u8)]
 pub enum TokenType {
@@ -26,6 +28,8 @@ pub enum TokenType {
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
+    GreaterGreaterGreater,
     Less,
     LessEqual,
 
@@ -36,33 +40,125 @@ pub enum TokenType {
 
     // Keywords
     And,
+    Break,
+    Catch,
+    Const,
+    Continue,
     Struct,
+    Defer,
     Else,
     Extends,
     False,
+    Finally,
     For,
     Func,
     If,
+    Inf,
+    Match,
+    Nan,
     Nil,
     Or,
     Return,
     Super,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
     // Type Keywords
+    AnyType,
     BoolType,
     FloatType,
     IntType,
     StringType,
 
+    // `@memoize`, the lexeme keeps the '@'
+    Annotation,
+
+    // Only produced by a lexer that keeps comments
+    Comment,
+
     Eof,
 }
 
+/// Source text of the token, or what kind of token it is for literals
+impl fmt::Display for TokenType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            TokenType::LeftParenthesis => "'('",
+            TokenType::RightParenthesis => "')'",
+            TokenType::LeftBrace => "'{'",
+            TokenType::RightBrace => "'}'",
+            TokenType::LeftBracket => "'['",
+            TokenType::RightBracket => "']'",
+            TokenType::Comma => "','",
+            TokenType::Dot => "'.'",
+            TokenType::Plus => "'+'",
+            TokenType::Minus => "'-'",
+            TokenType::Semicolon => "';'",
+            TokenType::Slash => "'/'",
+            TokenType::Star => "'*'",
+            TokenType::Percent => "'%'",
+            TokenType::Colon => "':'",
+            TokenType::Question => "'?'",
+            TokenType::Bang => "'!'",
+            TokenType::BangEqual => "'!='",
+            TokenType::Equal => "'='",
+            TokenType::EqualEqual => "'=='",
+            TokenType::Greater => "'>'",
+            TokenType::GreaterEqual => "'>='",
+            TokenType::GreaterGreater => "'>>'",
+            TokenType::GreaterGreaterGreater => "'>>>'",
+            TokenType::Less => "'<'",
+            TokenType::LessEqual => "'<='",
+            TokenType::Identifier => "identifier",
+            TokenType::String => "string",
+            TokenType::Number => "number",
+            TokenType::And => "'and'",
+            TokenType::Break => "'break'",
+            TokenType::Catch => "'catch'",
+            TokenType::Const => "'const'",
+            TokenType::Continue => "'continue'",
+            TokenType::Struct => "'struct'",
+            TokenType::Defer => "'defer'",
+            TokenType::Else => "'else'",
+            TokenType::Extends => "'extends'",
+            TokenType::False => "'false'",
+            TokenType::Finally => "'finally'",
+            TokenType::For => "'for'",
+            TokenType::Func => "'func'",
+            TokenType::If => "'if'",
+            TokenType::Inf => "'inf'",
+            TokenType::Match => "'match'",
+            TokenType::Nan => "'nan'",
+            TokenType::Nil => "'nil'",
+            TokenType::Or => "'or'",
+            TokenType::Return => "'return'",
+            TokenType::Super => "'super'",
+            TokenType::Throw => "'throw'",
+            TokenType::True => "'true'",
+            TokenType::Try => "'try'",
+            TokenType::Var => "'var'",
+            TokenType::While => "'while'",
+            TokenType::AnyType => "'any'",
+            TokenType::BoolType => "'bool'",
+            TokenType::FloatType => "'float'",
+            TokenType::IntType => "'int'",
+            TokenType::StringType => "'string'",
+            TokenType::Annotation => "annotation",
+            TokenType::Comment => "comment",
+            TokenType::Eof => "end of file",
+        };
+        write!(f, "{}", text)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: u32,
+    // Text of the `///` comments right before the token
+    pub doc: Option<String>,
 }
@@ -1,13 +1,16 @@
 pub mod squat_type;
 pub mod squat_value;
 
+use std::collections::HashMap;
+
 use crate::object::SquatObject;
-use squat_value::SquatValue;
+use squat_value::{HashKey, SquatValue};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ValueArray {
     name: String,
     values: Vec<SquatValue>,
+    hashed_indices: HashMap<HashKey, usize>,
 }
 
 impl ValueArray {
@@ -15,6 +18,7 @@ impl ValueArray {
         ValueArray {
             name: String::from(name),
             values: Vec::new(),
+            hashed_indices: HashMap::new(),
         }
     }
 
@@ -25,7 +29,20 @@ impl ValueArray {
         &self.values[index]
     }
 
+    pub fn get_size(&self) -> usize {
+        self.values.len()
+    }
+
     pub fn write(&mut self, value: SquatValue) -> usize {
+        if let Some(key) = value.hash_key() {
+            if let Some(index) = self.hashed_indices.get(&key) {
+                return *index;
+            }
+            self.values.push(value);
+            self.hashed_indices.insert(key, self.values.len() - 1);
+            return self.values.len() - 1;
+        }
+
         if let Some(index) = self.values.iter().position(|v| *v == value) {
             if let Some(SquatValue::Object(SquatObject::Function(func))) = self.values.get(index) {
                 println!("Found same squat function {}", func.name);
@@ -35,4 +52,59 @@ impl ValueArray {
         self.values.push(value);
         self.values.len() - 1
     }
+
+    /// Index, value and type of every constant, one per line
+    pub fn dump(&self) -> String {
+        let mut dump = format!("==== Begin: {} ====\n", self.name);
+        for (index, value) in self.values.iter().enumerate() {
+            dump += &format!("{:08}: {} {}\n", index, value, value.get_type());
+        }
+        dump += &format!("==== End:   {} ====", self.name);
+        dump
+    }
+
+    /// Points the function objects to their new start instructions after
+    /// instructions were removed from the chunk.
+    pub fn relocate_functions(&mut self, new_indices: &[usize]) {
+        for value in self.values.iter_mut() {
+            if let SquatValue::Object(SquatObject::Function(func)) = value {
+                func.start_instruction_index = new_indices[func.start_instruction_index];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dump_lists_constants() {
+        let mut constants = ValueArray::new("Constants");
+        constants.write(SquatValue::Int(42));
+        constants.write(SquatValue::String("hello".into()));
+        constants.write(SquatValue::Int(42));
+
+        assert_eq!(
+            constants.dump(),
+            "==== Begin: Constants ====\n\
+             00000000: 42 <type Int>\n\
+             00000001: hello <type String>\n\
+             ==== End:   Constants ===="
+        );
+    }
+
+    #[test]
+    fn only_hashable_constants_share_a_key() {
+        let mut constants = ValueArray::new("Constants");
+        assert_eq!(constants.write(SquatValue::Int(1)), 0);
+        assert_eq!(constants.write(SquatValue::Float(1.0)), 1);
+        assert_eq!(constants.write(SquatValue::Bool(true)), 2);
+        assert_eq!(constants.write(SquatValue::Int(1)), 0);
+        assert_eq!(constants.write(SquatValue::Float(1.0)), 1);
+
+        // NaN is not equal to itself, so it is never reused
+        assert_eq!(constants.write(SquatValue::Float(f64::NAN)), 3);
+        assert_eq!(constants.write(SquatValue::Float(f64::NAN)), 4);
+    }
 }
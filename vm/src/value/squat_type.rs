@@ -1,5 +1,7 @@
 use std::{collections::HashMap, fmt};
 
+use super::squat_value::SquatValue;
+
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct SquatInstanceTypeData {
     pub struct_name: String,
@@ -18,6 +20,8 @@ pub struct SquatStructTypeData {
     pub name: String,
     field_types: Vec<SquatType>,
     fields: HashMap<String, (SquatType, usize)>,
+    // Value of each field when it is left out of the constructor call
+    defaults: Vec<Option<SquatValue>>,
 }
 
 impl SquatStructTypeData {
@@ -26,6 +30,7 @@ impl SquatStructTypeData {
             name: name.to_string(),
             field_types: vec![],
             fields: HashMap::new(),
+            defaults: vec![],
         }
     }
 
@@ -42,17 +47,19 @@ impl SquatStructTypeData {
         }
     }
 
-    pub fn get_field_type_and_index_by_name(
-        &self,
-        field_name: &str,
-    ) -> Result<(SquatType, usize), ()> {
-        match self.fields.get(field_name) {
-            Some((field_type, index)) => Ok((field_type.clone(), *index)),
-            None => Err(()),
-        }
+    pub fn get_field_type_and_index_by_name(&self, field_name: &str) -> Option<(SquatType, usize)> {
+        self.fields
+            .get(field_name)
+            .map(|(field_type, index)| (field_type.clone(), *index))
     }
 
-    pub fn add_field(&mut self, field_name: &str, field_type: SquatType) {
+    pub fn add_field(
+        &mut self,
+        field_name: &str,
+        field_type: SquatType,
+        default: Option<SquatValue>,
+    ) {
+        self.defaults.push(default);
         self.field_types.push(field_type.clone());
         self.fields.insert(
             field_name.to_owned(),
@@ -60,6 +67,21 @@ impl SquatStructTypeData {
         );
     }
 
+    pub fn get_field_default(&self, field_index: usize) -> Option<SquatValue> {
+        self.defaults.get(field_index).cloned().flatten()
+    }
+
+    /// Names of the fields in the order of their indices
+    pub fn get_field_names(&self) -> Vec<String> {
+        let mut names: Vec<(&String, usize)> = self
+            .fields
+            .iter()
+            .map(|(name, (_, index))| (name, *index))
+            .collect();
+        names.sort_by_key(|(_, index)| *index);
+        names.into_iter().map(|(name, _)| name.clone()).collect()
+    }
+
     pub fn get_field_count(&self) -> usize {
         self.field_types.len()
     }
@@ -69,6 +91,8 @@ impl SquatStructTypeData {
 pub struct SquatFunctionTypeData {
     pub param_types: Vec<SquatType>,
     return_type: Box<SquatType>,
+    // Any number of arguments of any type can follow the parameters
+    variadic: bool,
 }
 
 impl SquatFunctionTypeData {
@@ -76,40 +100,55 @@ impl SquatFunctionTypeData {
         SquatFunctionTypeData {
             param_types,
             return_type: Box::new(return_type),
+            variadic: false,
         }
     }
 
+    pub fn variadic(mut self) -> SquatFunctionTypeData {
+        self.variadic = true;
+        self
+    }
+
+    pub fn is_variadic(&self) -> bool {
+        self.variadic
+    }
+
     pub fn get_return_type(&self) -> SquatType {
         *self.return_type.clone()
     }
 
     pub fn set_return_type(&mut self, return_type: SquatType) {
-        self.return_type = Box::new(return_type);
-    }
-
-    pub fn get_param_type(&self, arg_count: usize) -> SquatType {
-        match self.param_types.get(arg_count) {
-            Some(param_type) => param_type.clone(),
-            None => {
-                unreachable!("{} {:?}", arg_count, self.param_types)
-            }
-        }
+        *self.return_type = return_type;
     }
 
     pub fn get_arity(&self) -> usize {
         self.param_types.len()
     }
+
+    /// See `SquatType::exact_eq`
+    pub fn exact_eq(&self, other: &SquatFunctionTypeData) -> bool {
+        self.param_types.len() == other.param_types.len()
+            && self
+                .param_types
+                .iter()
+                .zip(&other.param_types)
+                .all(|(param_type, other_type)| param_type.exact_eq(other_type))
+            && self.return_type.exact_eq(&other.return_type)
+            && self.variadic == other.variadic
+    }
 }
 
 impl PartialEq for SquatFunctionTypeData {
     fn eq(&self, other: &Self) -> bool {
-        return self.param_types == other.param_types
-            && self.get_return_type() == other.get_return_type();
+        self.param_types == other.param_types
+            && self.get_return_type() == other.get_return_type()
+            && self.variadic == other.variadic
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum SquatType {
+    #[default]
     Nil,
     Int,
     Float,
@@ -119,14 +158,67 @@ pub enum SquatType {
     NativeFunction(SquatFunctionTypeData),
     Struct(SquatStructTypeData),
     Instance(SquatInstanceTypeData),
+    Array(Box<SquatType>),
+    Range,
+    // Keys and values of any type, see `SquatMap`
+    Map,
+    // See `SquatObject::StringBuilder`
+    StringBuilder,
+    // See `SquatObject::PriorityQueue`
+    PriorityQueue,
+    Optional(Box<SquatType>),
     Type,
     Number,
     Any,
+    // Type of a name whose declaration failed to compile, it matches every
+    // type so its uses don't report errors of their own
+    Error,
 }
 
-impl Default for SquatType {
-    fn default() -> Self {
-        SquatType::Nil
+impl SquatType {
+    /// Equality without the leniency of `==` used by the type checker, `any`
+    /// and `number` only equal themselves and optionals only equal optionals
+    pub fn exact_eq(&self, other: &SquatType) -> bool {
+        match (self, other) {
+            (SquatType::Function(data), SquatType::Function(data2))
+            | (SquatType::NativeFunction(data), SquatType::NativeFunction(data2)) => {
+                data.exact_eq(data2)
+            }
+            (SquatType::Struct(data), SquatType::Struct(data2)) => data.name == data2.name,
+            (SquatType::Instance(data), SquatType::Instance(data2)) => {
+                data.struct_name == data2.struct_name
+            }
+            (SquatType::Array(element_type), SquatType::Array(element_type2))
+            | (SquatType::Optional(element_type), SquatType::Optional(element_type2)) => {
+                element_type.exact_eq(element_type2)
+            }
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+
+    /// Plain lowercase name of the type, instances are named after their
+    /// struct
+    pub fn name(&self) -> String {
+        match self {
+            SquatType::Nil => "nil".to_owned(),
+            SquatType::Int => "int".to_owned(),
+            SquatType::Float => "float".to_owned(),
+            SquatType::String => "string".to_owned(),
+            SquatType::Bool => "bool".to_owned(),
+            SquatType::Function(_) | SquatType::NativeFunction(_) => "function".to_owned(),
+            SquatType::Struct(_) => "struct".to_owned(),
+            SquatType::Instance(data) => data.struct_name.clone(),
+            SquatType::Array(_) => "array".to_owned(),
+            SquatType::Range => "range".to_owned(),
+            SquatType::Map => "map".to_owned(),
+            SquatType::StringBuilder => "string_builder".to_owned(),
+            SquatType::PriorityQueue => "priority_queue".to_owned(),
+            SquatType::Optional(value_type) => format!("{}?", value_type.name()),
+            SquatType::Type => "type".to_owned(),
+            SquatType::Number => "number".to_owned(),
+            SquatType::Any => "any".to_owned(),
+            SquatType::Error => "error".to_owned(),
+        }
     }
 }
 
@@ -160,8 +252,15 @@ impl fmt::Display for SquatType {
             ),
             SquatType::Struct(data) => write!(f, "<type Struct {}>", data.name),
             SquatType::Instance(data) => write!(f, "<type Instance of {}>", data.struct_name),
+            SquatType::Array(element_type) => write!(f, "<type Array of {}>", element_type),
+            SquatType::Range => write!(f, "<type Range>"),
+            SquatType::Map => write!(f, "<type Map>"),
+            SquatType::StringBuilder => write!(f, "<type StringBuilder>"),
+            SquatType::PriorityQueue => write!(f, "<type PriorityQueue>"),
+            SquatType::Optional(value_type) => write!(f, "<type Optional of {}>", value_type),
             SquatType::Type => write!(f, "<type Type>"),
             SquatType::Any => write!(f, "<type Any>"),
+            SquatType::Error => write!(f, "<type Error>"),
             SquatType::Number => write!(f, "<type Number>"),
         }
     }
@@ -175,9 +274,15 @@ impl PartialEq for SquatType {
             | (SquatType::Float, SquatType::Float)
             | (SquatType::Bool, SquatType::Bool)
             | (SquatType::Type, SquatType::Type)
+            | (SquatType::Range, SquatType::Range)
+            | (SquatType::Map, SquatType::Map)
+            | (SquatType::StringBuilder, SquatType::StringBuilder)
+            | (SquatType::PriorityQueue, SquatType::PriorityQueue)
             | (SquatType::String, SquatType::String)
             | (SquatType::Any, _)
             | (_, SquatType::Any)
+            | (SquatType::Error, _)
+            | (_, SquatType::Error)
             | (SquatType::Number, SquatType::Number)
             | (SquatType::Number, SquatType::Int)
             | (SquatType::Number, SquatType::Float)
@@ -187,6 +292,18 @@ impl PartialEq for SquatType {
             | (SquatType::NativeFunction(data), SquatType::NativeFunction(data2)) => data == data2,
             (SquatType::Struct(data), SquatType::Struct(data2)) => data == data2,
             (SquatType::Instance(data), SquatType::Instance(data2)) => data == data2,
+            (SquatType::Array(element_type), SquatType::Array(element_type2)) => {
+                element_type == element_type2
+            }
+            (SquatType::Optional(value_type), SquatType::Optional(value_type2)) => {
+                value_type == value_type2
+            }
+            (SquatType::Optional(_), SquatType::Nil) | (SquatType::Nil, SquatType::Optional(_)) => {
+                true
+            }
+            (SquatType::Optional(value_type), other) | (other, SquatType::Optional(value_type)) => {
+                **value_type == *other
+            }
             (_, _) => false,
         }
     }
@@ -1,13 +1,16 @@
 use super::squat_type::SquatType;
-use crate::object::SquatObject;
-use std::fmt;
+use crate::object::{ObjectCounter, SquatObject};
+use std::{fmt, rc::Rc};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub enum SquatValue {
+    #[default]
     Nil,
     Int(i64),
     Float(f64),
-    String(String),
+    // Strings never change in place, copies share the text and operations
+    // that change a string create a new one
+    String(Rc<str>),
     Bool(bool),
     Object(SquatObject),
     Type(SquatType),
@@ -33,6 +36,99 @@ impl SquatValue {
             SquatValue::Type(_) => SquatType::Type,
         }
     }
+
+    /// See `SquatObject::count`
+    pub fn count_objects(&self, counter: &ObjectCounter) {
+        if let SquatValue::Object(obj) = self {
+            obj.count(counter);
+        }
+    }
+
+    /// Values that can be used as keys. Anything else has to be rejected
+    /// before it gets hashed.
+    pub fn is_hashable(&self) -> bool {
+        self.hash_key().is_some()
+    }
+
+    /// The value as a key of a hash map, `None` if it is not hashable
+    pub fn hash_key(&self) -> Option<HashKey> {
+        match self {
+            SquatValue::Int(value) => Some(HashKey::Int(*value)),
+            SquatValue::String(value) => Some(HashKey::String(value.clone())),
+            SquatValue::Bool(value) => Some(HashKey::Bool(*value)),
+            _ => None,
+        }
+    }
+
+    /// How the value is shown as an element of an array. Strings are quoted
+    /// there so `["1", 1]` tells them apart, at the top level they are shown
+    /// as they are. Values are copied on assignment, so an array can never
+    /// contain itself.
+    pub fn to_nested_string(&self) -> String {
+        self.to_nested_string_with_precision(None)
+    }
+
+    /// The value as text with floats shown with this many digits after the
+    /// decimal point, the ones in arrays and maps included. `None` shows
+    /// them with the shortest representation that reads back as the same
+    /// value. See `--float-precision`.
+    pub fn to_string_with_precision(&self, float_precision: Option<usize>) -> String {
+        match float_precision {
+            Some(precision) => format!("{:.*}", precision, self),
+            None => self.to_string(),
+        }
+    }
+
+    /// `to_nested_string` with floats shown like `to_string_with_precision`
+    /// shows them
+    pub fn to_nested_string_with_precision(&self, float_precision: Option<usize>) -> String {
+        match self {
+            SquatValue::String(value) => format!("{:?}", value),
+            value => value.to_string_with_precision(float_precision),
+        }
+    }
+
+    /// Equality of the `==` operator, unlike `PartialEq` an int and a float
+    /// with the same numeric value are equal and types have to match exactly
+    pub fn equals(&self, other: &SquatValue) -> bool {
+        match (self, other) {
+            (SquatValue::Int(int), SquatValue::Float(float))
+            | (SquatValue::Float(float), SquatValue::Int(int)) => *int as f64 == *float,
+            (SquatValue::Type(squat_type), SquatValue::Type(other_type)) => {
+                squat_type.exact_eq(other_type)
+            }
+            _ => self == other,
+        }
+    }
+
+    /// Ordering of the comparison operators, unlike `PartialOrd` an int and a
+    /// float are compared by their numeric value
+    pub fn compare(&self, other: &SquatValue) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (SquatValue::Int(int), SquatValue::Float(float)) => (*int as f64).partial_cmp(float),
+            (SquatValue::Float(float), SquatValue::Int(int)) => float.partial_cmp(&(*int as f64)),
+            _ => self.partial_cmp(other),
+        }
+    }
+
+    /// Shifts right filling with zeros instead of the sign bit.
+    pub fn shift_right_logical(self, rhs: SquatValue) -> SquatValue {
+        match (self, rhs) {
+            (SquatValue::Int(i1), SquatValue::Int(i2)) => {
+                SquatValue::Int((i1 as u64).wrapping_shr(i2 as u32) as i64)
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The hashable values, floats are left out so keys can be compared with
+/// `Eq`. See `SquatValue::hash_key`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HashKey {
+    Int(i64),
+    String(Rc<str>),
+    Bool(bool),
 }
 
 impl std::ops::Add<SquatValue> for SquatValue {
@@ -44,9 +140,15 @@ impl std::ops::Add<SquatValue> for SquatValue {
             (SquatValue::Float(f1), SquatValue::Float(f2)) => SquatValue::Float(f1 + f2),
             (SquatValue::Int(i), SquatValue::Float(f)) => SquatValue::Float((i as f64) + f),
             (SquatValue::Float(f), SquatValue::Int(i)) => SquatValue::Float(f + (i as f64)),
-            (SquatValue::String(s1), SquatValue::String(s2)) => SquatValue::String(s1 + &s2),
-            (SquatValue::String(s), value) => SquatValue::String(s + &value.to_string()),
-            (value, SquatValue::String(s)) => SquatValue::String(value.to_string() + &s),
+            (SquatValue::String(s1), SquatValue::String(s2)) => {
+                SquatValue::String([&*s1, &*s2].concat().into())
+            }
+            (SquatValue::String(s), value) => {
+                SquatValue::String([&*s, &value.to_string()].concat().into())
+            }
+            (value, SquatValue::String(s)) => {
+                SquatValue::String([&value.to_string(), &*s].concat().into())
+            }
             (value1, value2) => unreachable!("{} {}", value1, value2),
         }
     }
@@ -108,6 +210,19 @@ impl std::ops::Rem<SquatValue> for SquatValue {
     }
 }
 
+impl std::ops::Shr<SquatValue> for SquatValue {
+    type Output = SquatValue;
+
+    fn shr(self, rhs: SquatValue) -> Self::Output {
+        match (self, rhs) {
+            (SquatValue::Int(i1), SquatValue::Int(i2)) => {
+                SquatValue::Int(i1.wrapping_shr(i2 as u32))
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl PartialOrd for SquatValue {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
@@ -125,24 +240,106 @@ impl fmt::Display for SquatValue {
         match self {
             SquatValue::Nil => write!(f, "Nil"),
             SquatValue::Int(value) => write!(f, "{}", value),
-            SquatValue::Float(value) => write!(f, "{}", value),
+            // The precision of the formatter is the one of the floats, see
+            // `to_string_with_precision`
+            SquatValue::Float(value) => match f.precision() {
+                Some(precision) => write!(f, "{:.*}", precision, value),
+                None => write!(f, "{}", value),
+            },
             SquatValue::Bool(value) => write!(f, "{}", value),
             SquatValue::String(value) => write!(f, "{}", value),
-            SquatValue::Object(object) => write!(f, "{}", object.to_string()),
-            SquatValue::Type(t) => write!(f, "{}", t.to_string()),
+            SquatValue::Object(object) => fmt::Display::fmt(object, f),
+            SquatValue::Type(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+/// Conversions between Rust values and `SquatValue` for code that embeds the
+/// VM. Converting back fails with a message if the value has another type.
+macro_rules! impl_conversions {
+    ($rust_type:ty, $variant:ident, $type_name:literal) => {
+        impl From<$rust_type> for SquatValue {
+            fn from(value: $rust_type) -> Self {
+                SquatValue::$variant(value)
+            }
+        }
+
+        impl TryFrom<SquatValue> for $rust_type {
+            type Error = String;
+
+            fn try_from(value: SquatValue) -> Result<Self, Self::Error> {
+                match value {
+                    SquatValue::$variant(value) => Ok(value),
+                    _ => Err(format!("'{}' is not of type {}", value, $type_name)),
+                }
+            }
         }
+    };
+}
+
+impl_conversions!(i64, Int, "int");
+impl_conversions!(f64, Float, "float");
+impl_conversions!(bool, Bool, "bool");
+
+impl From<String> for SquatValue {
+    fn from(value: String) -> Self {
+        SquatValue::String(value.into())
     }
 }
 
-impl Default for SquatValue {
-    fn default() -> Self {
-        SquatValue::Nil
+impl TryFrom<SquatValue> for String {
+    type Error = String;
+
+    fn try_from(value: SquatValue) -> Result<Self, Self::Error> {
+        match value {
+            SquatValue::String(value) => Ok(value.to_string()),
+            _ => Err(format!("'{}' is not of type string", value)),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn hash_of(value: &SquatValue) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash_key().unwrap().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn nested_arrays_quote_their_strings() {
+        let array = |values: Vec<SquatValue>| SquatValue::Object(SquatObject::Array(values.into()));
+        let value = array(vec![
+            SquatValue::Int(1),
+            SquatValue::String("1".into()),
+            array(vec![
+                SquatValue::String("say \"hi\"".into()),
+                array(vec![]),
+                SquatValue::Nil,
+            ]),
+        ]);
+        assert_eq!(value.to_string(), r#"[1, "1", ["say \"hi\"", [], Nil]]"#);
+        assert_eq!(SquatValue::String("top".into()).to_string(), "top");
+    }
+
+    #[test]
+    fn type_values_are_equal_only_when_exact() {
+        let squat_type = |squat_type: SquatType| SquatValue::Type(squat_type);
+        assert!(squat_type(SquatType::Int).equals(&squat_type(SquatType::Int)));
+        assert!(!squat_type(SquatType::Int).equals(&squat_type(SquatType::Float)));
+        assert!(!squat_type(SquatType::Any).equals(&squat_type(SquatType::Int)));
+        assert!(!squat_type(SquatType::Number).equals(&squat_type(SquatType::Float)));
+        assert!(!squat_type(SquatType::Optional(Box::new(SquatType::Int)))
+            .equals(&squat_type(SquatType::Int)));
+        assert!(squat_type(SquatType::Array(Box::new(SquatType::String)))
+            .equals(&squat_type(SquatType::Array(Box::new(SquatType::String)))));
+        assert!(!squat_type(SquatType::Array(Box::new(SquatType::Any)))
+            .equals(&squat_type(SquatType::Array(Box::new(SquatType::String)))));
+    }
 
     #[test]
     fn int_int() {
@@ -159,6 +356,17 @@ mod test {
         assert_eq!(div, SquatValue::Int(5));
     }
 
+    #[test]
+    fn shift_right() {
+        let v1 = SquatValue::Int(-8);
+
+        assert_eq!(v1.clone() >> SquatValue::Int(1), SquatValue::Int(-4));
+        assert_eq!(
+            v1.shift_right_logical(SquatValue::Int(60)),
+            SquatValue::Int(15)
+        );
+    }
+
     #[test]
     fn float_float() {
         let v1 = SquatValue::Float(10.);
@@ -206,47 +414,95 @@ mod test {
 
     #[test]
     fn string_anything() {
-        let v1 = SquatValue::String("string".to_string());
+        let v1 = SquatValue::String("string".into());
 
         let v2 = SquatValue::Int(10);
         assert_eq!(
             v1.clone() + v2.clone(),
-            SquatValue::String("string10".to_string())
+            SquatValue::String("string10".into())
         );
 
         let v2 = SquatValue::Float(10.2);
         assert_eq!(
             v1.clone() + v2.clone(),
-            SquatValue::String("string10.2".to_string())
+            SquatValue::String("string10.2".into())
         );
 
         let v2 = SquatValue::Bool(false);
         assert_eq!(
             v1.clone() + v2.clone(),
-            SquatValue::String("stringfalse".to_string())
+            SquatValue::String("stringfalse".into())
         );
     }
 
     #[test]
     fn anything_string() {
-        let v1 = SquatValue::String("string".to_string());
+        let v1 = SquatValue::String("string".into());
 
         let v2 = SquatValue::Int(10);
         assert_eq!(
             v2.clone() + v1.clone(),
-            SquatValue::String("10string".to_string())
+            SquatValue::String("10string".into())
         );
 
         let v2 = SquatValue::Float(10.2);
         assert_eq!(
             v2.clone() + v1.clone(),
-            SquatValue::String("10.2string".to_string())
+            SquatValue::String("10.2string".into())
         );
 
         let v2 = SquatValue::Bool(false);
         assert_eq!(
             v2.clone() + v1.clone(),
-            SquatValue::String("falsestring".to_string())
+            SquatValue::String("falsestring".into())
+        );
+    }
+
+    #[test]
+    fn hash_equal_values() {
+        assert_eq!(hash_of(&SquatValue::Int(42)), hash_of(&SquatValue::Int(42)));
+        assert_eq!(
+            hash_of(&SquatValue::String("key".into())),
+            hash_of(&SquatValue::String("key".into()))
+        );
+        assert_ne!(
+            hash_of(&SquatValue::Int(1)),
+            hash_of(&SquatValue::Bool(true))
+        );
+    }
+
+    #[test]
+    fn hashable_values() {
+        assert!(SquatValue::Int(1).is_hashable());
+        assert!(SquatValue::String("key".into()).is_hashable());
+        assert!(SquatValue::Bool(false).is_hashable());
+        assert!(!SquatValue::Float(1.).is_hashable());
+        assert!(!SquatValue::Nil.is_hashable());
+    }
+
+    #[test]
+    fn numeric_equality() {
+        assert!(SquatValue::Int(1).equals(&SquatValue::Float(1.0)));
+        assert!(SquatValue::Float(1.0).equals(&SquatValue::Int(1)));
+        assert!(!SquatValue::Int(1).equals(&SquatValue::Float(2.0)));
+        assert!(!SquatValue::Int(1).equals(&SquatValue::String("1".into())));
+        assert_ne!(SquatValue::Int(1), SquatValue::Float(1.0));
+    }
+
+    #[test]
+    fn rust_conversions() {
+        assert_eq!(i64::try_from(SquatValue::from(42)), Ok(42));
+        assert_eq!(f64::try_from(SquatValue::from(1.5)), Ok(1.5));
+        assert_eq!(
+            String::try_from(SquatValue::from("squat".to_owned())),
+            Ok("squat".to_owned())
+        );
+        assert_eq!(bool::try_from(SquatValue::from(true)), Ok(true));
+
+        assert_eq!(
+            i64::try_from(SquatValue::Float(1.5)),
+            Err("'1.5' is not of type int".to_owned())
         );
+        assert!(bool::try_from(SquatValue::Nil).is_err());
     }
 }
@@ -1,8 +1,15 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
+    panic::{self, AssertUnwindSafe},
+};
+
 use crate::{
     chunk::Chunk,
     compiler::{variable::CompilerNative, CompileStatus, Compiler},
-    native,
-    object::{SquatInstance, SquatNativeFunction, SquatObject},
+    native::{self, introspection::CallContext, NativeFuncArgs, NativeFuncReturnType, NativeState},
+    object::{ObjectCounter, SquatFunction, SquatInstance, SquatNativeFunction, SquatObject},
     op_code::OpCode,
     options::Options,
     value::{
@@ -14,8 +21,11 @@ use crate::{
 
 const INITIAL_STACK_SIZE: usize = 256;
 const INITIAL_CALL_STACK_SIZE: usize = 256;
+// Instructions between two samples of the call stack for `--profile-out`
+const PROFILE_SAMPLE_INTERVAL: usize = 64;
 
 #[derive(PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum InterpretResult {
     InterpretOk(i64),
     InterpretCompileError,
@@ -25,19 +35,76 @@ pub enum InterpretResult {
 struct CallFrame {
     stack_index: usize,
     return_address: usize,
+    return_chunk: usize,
     func_name: String,
+    // Where the returned value is cached if the function is memoized
+    memo_key: Option<MemoKey>,
+}
+
+/// Where a runtime error in a `try` block continues, see `OpCode::Try`
+struct CatchHandler {
+    catch_address: usize,
+    chunk: usize,
+    stack_size: usize,
+    call_depth: usize,
 }
 
+/// Start of the memoized function and its arguments
+type MemoKey = (usize, String);
+
 impl CallFrame {
-    fn new(stack_index: usize, return_address: usize, func_name: String) -> CallFrame {
+    fn new(
+        stack_index: usize,
+        return_address: usize,
+        return_chunk: usize,
+        func_name: String,
+    ) -> CallFrame {
         CallFrame {
             stack_index,
             return_address,
+            return_chunk,
             func_name,
+            memo_key: None,
+        }
+    }
+}
+
+/// Byte code and constants of a compiled program, see `VM::compile`
+pub struct CompiledProgram {
+    chunk: Chunk,
+    constants: ValueArray,
+    global_count: usize,
+    local_names: HashMap<String, Vec<String>>,
+    global_names: HashSet<String>,
+}
+
+impl CompiledProgram {
+    /// Program from a hand built chunk, used to test opcodes without the
+    /// compiler. Like compiled code, the chunk has to end with a `JumpTo` to
+    /// one past the start of main.
+    #[cfg(test)]
+    pub fn new(chunk: Chunk, constants: ValueArray, global_count: usize) -> CompiledProgram {
+        CompiledProgram {
+            chunk,
+            constants,
+            global_count,
+            local_names: HashMap::new(),
+            global_names: HashSet::new(),
         }
     }
 }
 
+/// Summary printed by `--stats`
+#[derive(Debug, PartialEq)]
+pub struct ProgramStats {
+    instruction_count: usize,
+    constant_count: usize,
+    global_count: usize,
+    function_count: usize,
+    max_stack_depth: usize,
+    executed_instructions: usize,
+}
+
 pub struct VM {
     stack: Vec<SquatValue>,
     call_stack: Vec<CallFrame>,
@@ -47,6 +114,41 @@ pub struct VM {
     current_chunk: usize,
     chunks: Vec<Chunk>,
     had_error: bool,
+    // Only counted with the profile option
+    function_calls: HashMap<String, usize>,
+    // Samples of each call stack, its function names joined by ';', only
+    // taken with the profile output option
+    profile_samples: HashMap<String, usize>,
+    max_stack_depth: usize,
+    executed_instructions: usize,
+    // Executed instructions of each chunk, only recorded with the coverage option
+    coverage: Vec<Vec<bool>>,
+    local_names: HashMap<String, Vec<String>>,
+    // 0 if strings can be of any length
+    max_string_length: usize,
+    // 0 if the program can hold any number of objects
+    max_objects: usize,
+    // Arrays, instances, maps, builders and queues that are alive, see
+    // `ObjectToken`
+    objects: ObjectCounter,
+    // First line of each file when compiling a project, see `Project::source`
+    source_files: Vec<(u32, String)>,
+    // Set when the host calls functions by name, see `call_function`
+    keep_unused_functions: bool,
+    // Results of `@memoize` functions, kept for one run of a program
+    memo_cache: HashMap<MemoKey, SquatValue>,
+    // One for each `try` block that is running, innermost last
+    catch_handlers: Vec<CatchHandler>,
+    // Message of the runtime error that is going to be caught
+    caught_error: Option<String>,
+    // Passed to the natives that use it, set up for each run of a program
+    native_state: NativeState,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
 }
 
 impl VM {
@@ -54,59 +156,510 @@ impl VM {
         VM {
             stack: Vec::with_capacity(INITIAL_STACK_SIZE),
             call_stack: Vec::with_capacity(INITIAL_CALL_STACK_SIZE),
-            globals: vec![None; 1],
+            globals: Vec::new(),
             natives: Vec::with_capacity(255),
             constants: ValueArray::new("Constants"),
             current_chunk: 0,
             chunks: vec![Chunk::new("Main", true)],
             had_error: false,
+            function_calls: HashMap::new(),
+            profile_samples: HashMap::new(),
+            max_stack_depth: 0,
+            executed_instructions: 0,
+            coverage: Vec::new(),
+            local_names: HashMap::new(),
+            max_string_length: 0,
+            max_objects: 0,
+            objects: ObjectCounter::default(),
+            source_files: Vec::new(),
+            keep_unused_functions: false,
+            memo_cache: HashMap::new(),
+            catch_handlers: Vec::new(),
+            caught_error: None,
+            native_state: NativeState::default(),
         }
     }
 
+    /// Compiles every function even if the program never calls it, so the
+    /// host can call it with `call_function`
+    pub fn keep_unused_functions(&mut self) {
+        self.keep_unused_functions = true;
+    }
+
+    /// Compiled sources are made of these files from now on, see
+    /// `Project::source`
+    pub fn set_source_files(&mut self, source_files: Vec<(u32, String)>) {
+        self.source_files = source_files;
+    }
+
     pub fn interpret_source(&mut self, source: String, opts: &Options) -> InterpretResult {
-        self.define_native_functions();
-        let mut compiler = Compiler::new(
-            &source,
-            &mut self.chunks[0],
-            &mut self.constants,
-            &self.natives,
-        );
+        let program = self.compile(&source, opts);
+        if opts.print_parse_tree || opts.extract_docs {
+            return match program {
+                Some(_) => InterpretResult::InterpretOk(0),
+                None => InterpretResult::InterpretCompileError,
+            };
+        }
+
+        match program {
+            Some(program) => self.execute(&program, opts),
+            None => InterpretResult::InterpretCompileError,
+        }
+    }
+
+    /// Compiles the source into a program that can be executed any number of
+    /// times with `execute`. Returns `None` if compilation failed, the errors
+    /// are reported by the compiler.
+    pub fn compile(&mut self, source: &String, opts: &Options) -> Option<CompiledProgram> {
+        if self.natives.is_empty() {
+            self.define_native_functions();
+        }
+
+        let mut chunk = Chunk::new("Main", true);
+        if !opts.file.is_empty() {
+            chunk.set_file_name(&opts.file);
+        }
+        for (first_line, file_name) in &self.source_files {
+            chunk.add_source_file(file_name, *first_line);
+        }
+        let mut constants = ValueArray::new("Constants");
+        let mut compiler = Compiler::new(source, &mut chunk, &mut constants, &self.natives);
+        if opts.print_parse_tree {
+            compiler.record_parse_tree();
+        }
+        if opts.strict_types {
+            compiler.enable_strict_types();
+        }
+        if opts.no_implicit_default {
+            compiler.disable_implicit_defaults();
+        }
+        if opts.legacy_print {
+            compiler.enable_legacy_print();
+        }
+        if opts.allow_unused {
+            compiler.allow_unused_variables();
+        }
+        if opts.warn_recursion {
+            compiler.enable_recursion_warnings();
+        }
+        if opts.print_symbols {
+            compiler.record_symbols();
+        }
+        if self.keep_unused_functions {
+            compiler.keep_unused_functions();
+        }
         let compile_status = compiler.compile();
+        let parse_tree = compiler.take_parse_tree();
+        let doc_comments = compiler.take_doc_comments();
+        let local_names = compiler.take_local_names();
+        let global_names = compiler.global_names();
+        let globals = match opts.log_globals {
+            true => Some(compiler.dump_globals()),
+            false => None,
+        };
+        let symbols = match opts.print_symbols {
+            true => Some(compiler.dump_symbols()),
+            false => None,
+        };
 
         drop(compiler);
-        if opts.log_byte_code {
-            println!("---------------- INSTRUCTIONS ----------------");
-            self.chunks.iter().for_each(|chunk| chunk.disassemble());
-            println!("----------------------------------------------");
-        }
-
-        let interpret_result = match compile_status {
-            CompileStatus::Success(global_count) => {
-                self.globals = vec![None; global_count];
-                self.call_stack.push(CallFrame::new(
-                    0,
-                    self.chunks[0].get_main_start(),
-                    "main".to_owned(),
-                ));
+        if opts.print_parse_tree {
+            parse_tree.iter().for_each(|node| println!("{}", node));
+        } else if opts.extract_docs {
+            doc_comments.iter().for_each(|doc| println!("{}", doc));
+        } else {
+            if opts.log_byte_code {
+                println!("---------------- INSTRUCTIONS ----------------");
+                chunk.disassemble(&constants);
+                println!("----------------------------------------------");
+            }
+            if let Some(globals) = globals {
+                println!("------------------ GLOBALS -------------------");
+                println!("{}", globals);
+                println!("----------------------------------------------");
+            }
+            if let Some(symbols) = symbols {
+                println!("------------------ SYMBOLS -------------------");
+                println!("{}", symbols);
+                println!("----------------------------------------------");
+            }
+            if opts.log_constants {
+                println!("----------------- CONSTANTS ------------------");
+                println!("{}", constants.dump());
+                println!("----------------------------------------------");
+            }
+        }
+
+        match compile_status {
+            CompileStatus::Success(global_count) => Some(CompiledProgram {
+                chunk,
+                constants,
+                global_count,
+                local_names,
+                global_names,
+            }),
+            CompileStatus::Fail => None,
+        }
+    }
+
+    /// Runs a program from the start with a fresh stack and fresh globals
+    pub fn execute(&mut self, program: &CompiledProgram, opts: &Options) -> InterpretResult {
+        self.reset(program, opts);
+        let interpret_result = self.run(opts);
+
+        if opts.profile {
+            self.print_profile();
+        }
+        if !opts.profile_out.is_empty() {
+            self.write_profile_samples(&opts.profile_out);
+        }
+        if opts.stats {
+            self.print_stats(program);
+        }
+        if opts.coverage {
+            self.print_coverage();
+        }
+        interpret_result
+    }
+
+    /// Runs the top level definitions of a program but not its main function,
+    /// afterwards its functions can be called with `call_function`
+    pub fn load(&mut self, program: &CompiledProgram, opts: &Options) -> InterpretResult {
+        self.reset(program, opts);
+        // The top level code ends by jumping to main, stop there instead
+        let last = self.chunks[0].get_size() - 1;
+        let jump_to_main = self.chunks[0].get_instruction(last);
+        self.chunks[0].set_instruction(last, OpCode::Stop);
+        let interpret_result = self.run(opts);
+        self.chunks[0].set_instruction(last, jump_to_main);
+        interpret_result
+    }
+
+    /// Calls a function of the loaded program by name and returns what it
+    /// returned. The arguments are checked against the parameters of the
+    /// function first.
+    ///
+    /// Functions `main` never references are left out when compiling, call
+    /// `keep_unused_functions` before `compile` to be able to call them.
+    pub fn call_function(
+        &mut self,
+        name: &str,
+        args: Vec<SquatValue>,
+    ) -> Result<SquatValue, String> {
+        if self.call_stack.is_empty() {
+            return Err("No program is loaded".to_owned());
+        }
+        let function = self
+            .globals
+            .iter()
+            .flatten()
+            .find(|value| {
+                matches!(value, SquatValue::Object(SquatObject::Function(func_data)) if func_data.name == name)
+            })
+            .cloned()
+            .ok_or_else(|| format!("Function '{}' is not defined", name))?;
+
+        self.had_error = false;
+        let call_depth = self.call_stack.len();
+        let stack_size = self.stack.len();
+        let result = self.call_value(&function, args, &Options::default());
+        // A runtime error leaves the frames of the failed call behind
+        self.call_stack.truncate(call_depth);
+        self.stack.truncate(stack_size);
+        self.catch_handlers.clear();
+        self.caught_error = None;
+        self.had_error = false;
+        result
+    }
+
+    fn reset(&mut self, program: &CompiledProgram, opts: &Options) {
+        self.chunks = vec![program.chunk.clone()];
+        self.constants = program.constants.clone();
+        // Sized by the program, the globals of the last one are dropped even
+        // when it had more of them
+        self.globals.clear();
+        self.globals.resize(program.global_count, None);
+        self.local_names = program.local_names.clone();
+        self.native_state.global_names = program.global_names.clone();
+        self.rewind(opts);
+    }
+
+    /// Clears everything the last run left behind, the stacks and globals
+    /// included, but keeps the compiled code and constants
+    pub fn rewind(&mut self, opts: &Options) {
+        self.stack.clear();
+        self.call_stack.clear();
+        self.had_error = false;
+        self.current_chunk = 0;
+        for chunk in self.chunks.iter_mut() {
+            chunk.reset();
+        }
+        self.globals.fill(None);
+        self.max_string_length = opts.max_string_length;
+        self.max_objects = opts.max_objects;
+        self.native_state.float_precision = opts.float_precision;
+        self.catch_handlers.clear();
+        self.caught_error = None;
+        self.function_calls.clear();
+        self.profile_samples.clear();
+        self.memo_cache.clear();
+        self.native_state.random.seed(opts.seed);
+        self.max_stack_depth = 0;
+        self.executed_instructions = 0;
+        self.native_state.exit_code = None;
+        self.coverage = self
+            .chunks
+            .iter()
+            .map(|chunk| vec![false; chunk.get_size()])
+            .collect();
 
-                self.interpret_chunk(0, opts)
+        self.call_stack.push(CallFrame::new(
+            0,
+            self.chunks[0].get_main_start(),
+            0,
+            "main".to_owned(),
+        ));
+    }
+
+    /// Runs the loaded program from where it is, after `rewind` from the
+    /// start
+    pub fn run(&mut self, opts: &Options) -> InterpretResult {
+        // Internal errors still panic, say where in the program it happened
+        // before the panic continues
+        match panic::catch_unwind(AssertUnwindSafe(|| self.interpret_chunk(0, 0, opts))) {
+            Ok(interpret_result) => interpret_result,
+            Err(payload) => {
+                eprintln!("{}", self.panic_location());
+                panic::resume_unwind(payload);
             }
-            CompileStatus::Fail => InterpretResult::InterpretCompileError,
+        }
+    }
+
+    /// Values left on the stack by the previous statement mean the compiler
+    /// emitted code that does not clean up after itself
+    fn verify_statement_depth(&mut self) {
+        let chunk = &self.chunks[self.current_chunk];
+        let Some(local_count) = chunk.get_statement_depth(chunk.current_instruction) else {
+            return;
         };
+        let expected = self.call_stack.last().unwrap().stack_index + local_count;
+        if self.stack.len() != expected {
+            self.runtime_error(&format!(
+                "[INTERNAL ERROR] Statement at instruction {} starts with {} values on the stack but {} were expected",
+                chunk.current_instruction,
+                self.stack.len(),
+                expected
+            ));
+        }
+    }
 
-        self.chunks[self.current_chunk].clear_instructions();
-        interpret_result
+    /// The instruction that was executing, `next` already moved past it
+    fn panic_location(&self) -> String {
+        let chunk = &self.chunks[self.current_chunk];
+        let op_index = chunk.current_instruction.saturating_sub(1);
+        format!(
+            "[INTERNAL ERROR] VM panicked in {} at instruction {} {:?} ({})",
+            chunk.get_name(),
+            op_index,
+            chunk.get_instruction(op_index),
+            chunk.location(chunk.get_instruction_line(op_index))
+        )
+    }
+
+    /// Source lines that have instructions but none of them were executed
+    fn uncovered_lines(&self) -> Vec<u32> {
+        let mut covered_lines = HashSet::new();
+        let mut lines = BTreeSet::new();
+        for (chunk, hits) in self.chunks.iter().zip(self.coverage.iter()) {
+            for (op_index, hit) in hits.iter().enumerate() {
+                let line = chunk.get_instruction_line(op_index);
+                if *hit {
+                    covered_lines.insert(line);
+                }
+                lines.insert(line);
+            }
+        }
+        lines
+            .into_iter()
+            .filter(|line| *line != 0 && !covered_lines.contains(line))
+            .collect()
+    }
+
+    fn print_coverage(&self) {
+        println!("------------------ COVERAGE ------------------");
+        let uncovered_lines = self.uncovered_lines();
+        if uncovered_lines.is_empty() {
+            println!("All lines were executed");
+        }
+        for line in uncovered_lines {
+            println!("Line {} was never executed", line);
+        }
+        println!("----------------------------------------------");
+    }
+
+    /// Size of the program, the max stack depth and the number of executed
+    /// instructions of its last execution
+    pub fn stats(&self, program: &CompiledProgram) -> ProgramStats {
+        let function_count = (0..program.chunk.get_size())
+            .filter(|&op_index| program.chunk.get_instruction(op_index) == OpCode::Start)
+            .count();
+        ProgramStats {
+            instruction_count: program.chunk.get_size(),
+            constant_count: program.constants.get_size(),
+            global_count: program.global_count,
+            function_count,
+            max_stack_depth: self.max_stack_depth,
+            executed_instructions: self.executed_instructions,
+        }
+    }
+
+    fn print_stats(&self, program: &CompiledProgram) {
+        let stats = self.stats(program);
+        println!("------------------- STATS --------------------");
+        println!("Instructions:    {}", stats.instruction_count);
+        println!("Constants:       {}", stats.constant_count);
+        println!("Globals:         {}", stats.global_count);
+        println!("Functions:       {}", stats.function_count);
+        println!("Max stack depth: {}", stats.max_stack_depth);
+        println!("Executed:        {}", stats.executed_instructions);
+        println!("----------------------------------------------");
+    }
+
+    fn print_profile(&self) {
+        let mut function_calls: Vec<(&String, &usize)> = self.function_calls.iter().collect();
+        function_calls
+            .sort_by(|(name1, count1), (name2, count2)| count2.cmp(count1).then(name1.cmp(name2)));
+
+        println!("------------------ PROFILE -------------------");
+        for (name, count) in function_calls {
+            println!("{}: {} calls", name, count);
+        }
+        println!("----------------------------------------------");
+    }
+
+    fn sample_call_stack(&mut self) {
+        let stack = self
+            .call_stack
+            .iter()
+            .map(|frame| frame.func_name.as_str())
+            .collect::<Vec<&str>>()
+            .join(";");
+        *self.profile_samples.entry(stack).or_insert(0) += 1;
+    }
+
+    /// Writes the samples in the folded format of flamegraph tools, one
+    /// `main;caller;callee count` line per call stack
+    fn write_profile_samples(&self, path: &str) {
+        let mut samples: Vec<(&String, &usize)> = self.profile_samples.iter().collect();
+        samples.sort();
+        let folded: String = samples
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect();
+        if let Err(error) = fs::write(path, folded) {
+            println!(
+                "[ERROR] Failed to write the profile to '{}': {}",
+                path, error
+            );
+        }
+    }
+
+    /// Calls a native with the state it uses or a callback into the VM
+    fn call_native(
+        &mut self,
+        native: &SquatNativeFunction,
+        args: NativeFuncArgs,
+        opts: &Options,
+    ) -> NativeFuncReturnType {
+        if native.calls_back() {
+            return native.call_back(args, &mut |function, args| {
+                self.call_value(function, args, opts)
+            });
+        }
+        if native.uses_call_context {
+            self.native_state.call_context = self.call_context();
+        }
+        native.call(args, &mut self.native_state)
+    }
+
+    /// Calls a function value from a native and runs it until it returns
+    fn call_value(
+        &mut self,
+        function: &SquatValue,
+        args: NativeFuncArgs,
+        opts: &Options,
+    ) -> NativeFuncReturnType {
+        let func_data = match function {
+            SquatValue::Object(SquatObject::Function(func_data)) => func_data,
+            SquatValue::Object(SquatObject::NativeFunction(native)) => {
+                return self.call_native(native, args, opts);
+            }
+            _ => return Err(format!("'{}' is not a function", function)),
+        };
+        check_arguments(func_data, &args)?;
+        let param_count = args.len();
+
+        let return_depth = self.call_stack.len();
+        self.stack.push(function.clone());
+        self.stack.extend(args);
+        self.call_stack.push(CallFrame::new(
+            self.stack.len() - param_count,
+            self.chunks[self.current_chunk].current_instruction,
+            self.current_chunk,
+            func_data.name.clone(),
+        ));
+        match self.interpret_chunk(func_data.start_instruction_index, return_depth, opts) {
+            InterpretResult::InterpretOk(_) => Ok(self.stack.pop().unwrap()),
+            _ => Err(format!("Function '{}' failed", func_data.name)),
+        }
+    }
+
+    /// One line per stack value, labeled with the name of the local variable
+    /// in that slot if there is one
+    fn stack_dump(&self) -> String {
+        let mut dump = String::new();
+        for (index, value) in self.stack.iter().enumerate() {
+            dump.push_str(&format!("\t[{:?}]", value));
+            if let Some(name) = self.local_name(index) {
+                dump.push_str(&format!(" {}", name));
+            }
+            dump.push('\n');
+        }
+        dump
+    }
+
+    fn local_name(&self, stack_index: usize) -> Option<String> {
+        let frame = self
+            .call_stack
+            .iter()
+            .rev()
+            .find(|frame| frame.stack_index <= stack_index)?;
+        let name = self
+            .local_names
+            .get(&frame.func_name)?
+            .get(stack_index - frame.stack_index)?;
+        if name.is_empty() {
+            None
+        } else {
+            Some(format!("{}.{}", frame.func_name, name))
+        }
     }
 
-    fn interpret_chunk(&mut self, starting_instruction: usize, opts: &Options) -> InterpretResult {
+    /// Runs until main finishes, or until a return leaves `return_depth`
+    /// frames on the call stack when a function is called from a native
+    fn interpret_chunk(
+        &mut self,
+        starting_instruction: usize,
+        return_depth: usize,
+        opts: &Options,
+    ) -> InterpretResult {
         self.chunks[self.current_chunk].current_instruction = starting_instruction;
 
         loop {
+            self.max_stack_depth = self.max_stack_depth.max(self.stack.len());
             if opts.log_stack {
                 println!("STACK");
-                for value in self.stack.iter() {
-                    println!("\t[{:?}]", value);
-                }
+                print!("{}", self.stack_dump());
             }
             if opts.log_globals {
                 println!("GLOBALS:");
@@ -117,136 +670,357 @@ impl VM {
                 }
             }
 
+            if opts.coverage {
+                let current_instruction = self.chunks[self.current_chunk].current_instruction;
+                if let Some(hit) = self.coverage[self.current_chunk].get_mut(current_instruction) {
+                    *hit = true;
+                }
+            }
+
             if opts.log_insturctions {
-                self.chunks[self.current_chunk].disassemble_current_instruction();
+                self.chunks[self.current_chunk].disassemble_current_instruction(&self.constants);
+            }
+
+            // An instruction that makes an object leaves it on top of the stack
+            if let Some(value) = self.stack.last() {
+                value.count_objects(&self.objects);
+                if self.max_objects != 0 {
+                    self.check_object_count();
+                }
+            }
+
+            // The stack of a failed instruction is cleaned up by the catch
+            if opts.verify_stack && !self.had_error {
+                self.verify_statement_depth();
             }
 
             if self.had_error {
+                if self.catch_error(return_depth) {
+                    continue;
+                }
                 return InterpretResult::InterpretRuntimeError;
             }
 
+            if !opts.profile_out.is_empty()
+                && self
+                    .executed_instructions
+                    .is_multiple_of(PROFILE_SAMPLE_INTERVAL)
+            {
+                self.sample_call_stack();
+            }
+
             if let Some(instruction) = self.chunks[self.current_chunk].next() {
+                self.executed_instructions += 1;
+                // The arms are ordered by how often the opcodes run in loops
+                // and calls, hottest first. See `dispatch_throughput`.
                 match instruction {
                     OpCode::Constant(index) => {
                         let index = *index;
                         let constant: &SquatValue = self.constants.get(index);
                         self.stack.push(constant.clone());
                     }
-
-                    OpCode::False => self.stack.push(SquatValue::Bool(false)),
-                    OpCode::Nil => self.stack.push(SquatValue::Nil),
-                    OpCode::True => self.stack.push(SquatValue::Bool(true)),
-
-                    OpCode::Add => self.binary_op(|left, right| left + right),
-                    OpCode::Subtract => self.binary_op(|left, right| left - right),
-                    OpCode::Multiply => self.binary_op(|left, right| left * right),
-                    OpCode::Divide => self.binary_op(|left, right| left / right),
-                    OpCode::Mod => self.binary_op(|left, right| left % right),
-
-                    OpCode::Equal => self.binary_cmp(|left, right| left == right),
-                    OpCode::NotEqual => self.binary_cmp(|left, right| left != right),
-                    OpCode::Greater => self.binary_cmp(|left, right| left > right),
-                    OpCode::GreaterEqual => self.binary_cmp(|left, right| left >= right),
-                    OpCode::Less => self.binary_cmp(|left, right| left < right),
-                    OpCode::LessEqual => self.binary_cmp(|left, right| left <= right),
-
-                    OpCode::Not => {
-                        if let Some(value) = self.stack.pop() {
-                            self.stack.push(SquatValue::Bool(!value.is_truthy()));
+                    OpCode::GetLocal(index) => {
+                        let index = index + self.call_stack.last().unwrap().stack_index;
+                        self.stack.push(self.stack[index].clone());
+                    }
+                    OpCode::SetLocal(index) => {
+                        if let Some(value) = self.stack.last() {
+                            let index = index + self.call_stack.last().unwrap().stack_index;
+                            self.stack[index] = value.clone();
                         } else {
-                            unreachable!("'!' cannot be used alone");
+                            unreachable!("SetLocal OpCode expects a value to be on the stack");
                         }
                     }
-                    OpCode::Negate => match self.stack.pop() {
-                        Some(SquatValue::Float(value)) => {
-                            self.stack.push(SquatValue::Float(-value));
-                        }
-                        Some(SquatValue::Int(value)) => {
-                            self.stack.push(SquatValue::Int(-value));
+                    OpCode::GetUpvalue(index) => {
+                        // The called function is right below its arguments
+                        let function_index = self.call_stack.last().unwrap().stack_index - 1;
+                        let SquatValue::Object(SquatObject::Function(function)) =
+                            &self.stack[function_index]
+                        else {
+                            unreachable!("GetUpvalue OpCode expects a function below the frame");
+                        };
+                        self.stack.push(function.upvalues[*index].clone());
+                    }
+                    OpCode::GetCallee => {
+                        let function_index = self.call_stack.last().unwrap().stack_index - 1;
+                        self.stack.push(self.stack[function_index].clone());
+                    }
+                    OpCode::IncLocal(index, amount) => {
+                        let index = index + self.call_stack.last().unwrap().stack_index;
+                        if let SquatValue::Int(value) = &mut self.stack[index] {
+                            *value += amount;
+                        } else {
+                            unreachable!("IncLocal OpCode expects an int local");
                         }
-                        _ => unreachable!("Negate requires a number value"),
-                    },
-
+                    }
                     OpCode::Pop => {
                         self.stack.pop();
                     }
 
-                    OpCode::DefineGlobal(index) => {
-                        let index = *index;
-                        if let Some(value) = self.stack.pop() {
-                            self.globals[index] = Some(value);
-                        } else {
-                            unreachable!("DefineGlobal OpCode expects a value to be on the stack");
+                    OpCode::Add => {
+                        self.binary_op(|left, right| left + right);
+                        self.check_string_length();
+                    }
+                    OpCode::Subtract => self.binary_op(|left, right| left - right),
+                    OpCode::Multiply => self.binary_op(|left, right| left * right),
+                    OpCode::Divide => {
+                        if self.divides_int_by_zero() {
+                            self.runtime_error("Division by zero");
+                            continue;
                         }
+                        self.binary_op(|left, right| left / right)
                     }
-                    OpCode::GetGlobal(index) => {
-                        let index = *index;
-                        if let Some(Some(value)) = self.globals.get(index) {
-                            self.stack.push(value.clone());
-                        } else {
-                            self.runtime_error(&format!(
-                                "Variable with index {} is not defined",
-                                index
-                            ));
+                    OpCode::Mod => {
+                        if self.divides_int_by_zero() {
+                            self.runtime_error("Modulo by zero");
+                            continue;
                         }
+                        self.binary_op(|left, right| left % right)
                     }
-                    OpCode::SetGlobal(index) => {
-                        let index = *index;
+                    OpCode::ShiftRight => self.binary_op(|left, right| left >> right),
+                    OpCode::ShiftRightLogical => {
+                        self.binary_op(|left, right| left.shift_right_logical(right))
+                    }
+
+                    OpCode::Less => self.binary_cmp(|left, right| {
+                        left.compare(&right).is_some_and(Ordering::is_lt)
+                    }),
+                    OpCode::LessEqual => self.binary_cmp(|left, right| {
+                        left.compare(&right).is_some_and(Ordering::is_le)
+                    }),
+                    OpCode::Greater => self.binary_cmp(|left, right| {
+                        left.compare(&right).is_some_and(Ordering::is_gt)
+                    }),
+                    OpCode::GreaterEqual => self.binary_cmp(|left, right| {
+                        left.compare(&right).is_some_and(Ordering::is_ge)
+                    }),
+                    OpCode::Equal => self.binary_cmp(|left, right| left.equals(&right)),
+                    OpCode::NotEqual => self.binary_cmp(|left, right| !left.equals(&right)),
+
+                    OpCode::JumpIfFalse(offset) => {
                         if let Some(value) = self.stack.last() {
-                            if let Some(Some(_value)) = self.globals.get(index) {
-                                self.globals[index] = Some(value.clone());
-                            } else {
-                                self.runtime_error(&format!(
-                                    "You cannot set a global variable before defining it"
-                                ));
+                            if !value.is_truthy() {
+                                self.chunks[self.current_chunk].current_instruction += *offset;
                             }
                         } else {
-                            unreachable!("SetGlobal OpCode expects a value to be on the stack");
+                            unreachable!("JumpIfFalse OpCode expect a value to be on the stack");
                         }
                     }
-
-                    OpCode::GetLocal(index) => {
-                        let index = index + self.call_stack.last().unwrap().stack_index;
-                        self.stack.push(self.stack[index].clone());
+                    OpCode::Jump(offset) => {
+                        self.chunks[self.current_chunk].current_instruction += *offset;
                     }
-                    OpCode::SetLocal(index) => {
+                    OpCode::Loop(loop_start) => {
+                        self.chunks[self.current_chunk].current_instruction = *loop_start;
+                    }
+                    OpCode::JumpIfTrue(offset) => {
                         if let Some(value) = self.stack.last() {
-                            let index = index + self.call_stack.last().unwrap().stack_index;
-                            self.stack[index] = value.clone();
+                            if value.is_truthy() {
+                                self.chunks[self.current_chunk].current_instruction += *offset;
+                            }
                         } else {
-                            unreachable!("SetLocal OpCode expects a value to be on the stack");
+                            unreachable!("JumpIfTrue OpCode expect a value to be on the stack");
                         }
                     }
-
-                    OpCode::GetNative(index) => {
-                        self.stack.push(self.natives[*index].get_value().clone());
+                    OpCode::JumpTo(instruction_number) => {
+                        self.chunks[self.current_chunk].current_instruction = *instruction_number;
                     }
-
-                    OpCode::GetGlobalProperty(object_index, property_index) => {
-                        if let Some(Some(SquatValue::Object(SquatObject::Instance(
-                            instance_data,
-                        )))) = self.globals.get(*object_index)
-                        {
-                            self.stack.push(instance_data.get_property(*property_index));
-                        } else {
-                            unreachable!(
-                                "GetGlobalProperty expected a class instance at global position {}",
-                                object_index
-                            );
-                        }
+                    OpCode::Try(offset) => {
+                        let offset = *offset;
+                        let catch_address =
+                            self.chunks[self.current_chunk].current_instruction + offset;
+                        self.catch_handlers.push(CatchHandler {
+                            catch_address,
+                            chunk: self.current_chunk,
+                            stack_size: self.stack.len(),
+                            call_depth: self.call_stack.len(),
+                        });
                     }
-                    OpCode::GetLocalProperty(object_index, property_index) => {
-                        let stack_index =
-                            object_index + self.call_stack.last().unwrap().stack_index;
-                        if let Some(SquatValue::Object(SquatObject::Instance(instance_data))) =
-                            self.stack.get(stack_index)
-                        {
-                            self.stack.push(instance_data.get_property(*property_index));
-                        } else {
-                            unreachable!(
-                                "GetLocalProperty expected a class instance at stack index {}",
-                                stack_index
-                            );
+                    OpCode::EndTry => {
+                        self.catch_handlers.pop();
+                    }
+                    OpCode::Throw => {
+                        let value = self.stack.pop().unwrap();
+                        self.runtime_error(&value.to_string());
+                    }
+
+                    OpCode::Call(arg_count) => {
+                        let arg_count = *arg_count;
+                        let func_data_location = self.stack.len() - 1 - arg_count;
+                        // All this ugly code for the native stuff exists because of the
+                        // borrow checker.
+                        let native = match self.stack.get(func_data_location).unwrap() {
+                            SquatValue::Object(SquatObject::Function(func_data)) => {
+                                let memo_key = func_data.memoize.then(|| {
+                                    // Closures of the same function can capture other values
+                                    let args = &self.stack[func_data_location + 1..];
+                                    let key = format!("{:?}{:?}", func_data.upvalues, args);
+                                    (func_data.start_instruction_index, key)
+                                });
+                                if let Some(value) =
+                                    memo_key.as_ref().and_then(|key| self.memo_cache.get(key))
+                                {
+                                    let value = value.clone();
+                                    self.stack.truncate(func_data_location);
+                                    self.stack.push(value);
+                                    continue;
+                                }
+                                let return_address =
+                                    self.chunks[self.current_chunk].current_instruction;
+                                let mut call_frame = CallFrame::new(
+                                    self.stack.len() - arg_count,
+                                    return_address,
+                                    self.current_chunk,
+                                    func_data.name.clone(),
+                                );
+                                call_frame.memo_key = memo_key;
+                                self.call_stack.push(call_frame);
+                                self.chunks[self.current_chunk].current_instruction =
+                                    func_data.start_instruction_index;
+                                if opts.profile {
+                                    *self
+                                        .function_calls
+                                        .entry(func_data.name.clone())
+                                        .or_default() += 1;
+                                }
+                                continue;
+                            }
+                            SquatValue::Object(SquatObject::NativeFunction(func)) => func.clone(),
+                            _ => unreachable!("Call OpCode expects a FunctionObject on the stack"),
+                        };
+
+                        let mut args = Vec::new();
+                        for _i in 0..arg_count {
+                            args.push(self.stack.pop().unwrap())
+                        }
+                        self.stack.pop().unwrap();
+                        args.reverse();
+                        match self.call_native(&native, args, opts) {
+                            Ok(value) => {
+                                self.stack.push(value);
+                                self.check_string_length();
+                            }
+                            // Errors of called back functions are already reported
+                            Err(_) if self.had_error => {}
+                            Err(msg) => self.runtime_error(&msg),
+                        };
+                    }
+                    OpCode::Return => {
+                        let return_val = self.stack.pop().unwrap();
+                        // The frame of main has no caller to return to, the
+                        // returned value is the exit code
+                        if self.call_stack.len() == 1 {
+                            self.native_state.exit_code = None;
+                            if let SquatValue::Int(i) = return_val {
+                                return InterpretResult::InterpretOk(i);
+                            }
+                            return InterpretResult::InterpretOk(0);
+                        }
+                        let call_frame = self.call_stack.pop().unwrap();
+                        if let Some(memo_key) = call_frame.memo_key {
+                            self.memo_cache.insert(memo_key, return_val.clone());
+                        }
+                        while call_frame.stack_index < self.stack.len() {
+                            self.stack.pop(); // Pop local variables
+                        }
+                        self.stack.pop(); // Pop SquatFunc
+                        self.current_chunk = call_frame.return_chunk;
+                        self.chunks[self.current_chunk].current_instruction =
+                            call_frame.return_address;
+                        self.stack.push(return_val);
+                        if self.call_stack.len() == return_depth {
+                            return InterpretResult::InterpretOk(0);
+                        }
+                    }
+
+                    OpCode::GetGlobal(index) => {
+                        let index = *index;
+                        if let Some(Some(value)) = self.globals.get(index) {
+                            self.stack.push(value.clone());
+                        } else {
+                            self.runtime_error(&format!(
+                                "Variable with index {} is not defined",
+                                index
+                            ));
+                        }
+                    }
+                    OpCode::SetGlobal(index) => {
+                        let index = *index;
+                        if let Some(value) = self.stack.last() {
+                            if let Some(Some(_value)) = self.globals.get(index) {
+                                self.globals[index] = Some(value.clone());
+                            } else {
+                                self.runtime_error(
+                                    "You cannot set a global variable before defining it",
+                                );
+                            }
+                        } else {
+                            unreachable!("SetGlobal OpCode expects a value to be on the stack");
+                        }
+                    }
+                    OpCode::DefineGlobal(index) => {
+                        let index = *index;
+                        if let Some(value) = self.stack.pop() {
+                            self.globals[index] = Some(value);
+                        } else {
+                            unreachable!("DefineGlobal OpCode expects a value to be on the stack");
+                        }
+                    }
+                    OpCode::GetNative(index) => {
+                        self.stack.push(self.natives[*index].get_value().clone());
+                    }
+
+                    OpCode::False => self.stack.push(SquatValue::Bool(false)),
+                    OpCode::Nil => self.stack.push(SquatValue::Nil),
+                    OpCode::True => self.stack.push(SquatValue::Bool(true)),
+                    OpCode::Not => {
+                        if let Some(value) = self.stack.pop() {
+                            self.stack.push(SquatValue::Bool(!value.is_truthy()));
+                        } else {
+                            unreachable!("'!' cannot be used alone");
+                        }
+                    }
+                    OpCode::Negate => match self.stack.pop() {
+                        Some(SquatValue::Float(value)) => {
+                            self.stack.push(SquatValue::Float(-value));
+                        }
+                        Some(SquatValue::Int(value)) => {
+                            self.stack.push(SquatValue::Int(-value));
+                        }
+                        _ => unreachable!("Negate requires a number value"),
+                    },
+                    OpCode::Abs => {
+                        let value = self.stack.last_mut().unwrap();
+                        match native::number::absolute(value) {
+                            Ok(absolute) => *value = absolute,
+                            Err(msg) => self.runtime_error(&msg),
+                        }
+                    }
+
+                    OpCode::GetGlobalProperty(object_index, property_index) => {
+                        if let Some(Some(SquatValue::Object(SquatObject::Instance(
+                            instance_data,
+                        )))) = self.globals.get(*object_index)
+                        {
+                            self.stack.push(instance_data.get_property(*property_index));
+                        } else {
+                            unreachable!(
+                                "GetGlobalProperty expected a class instance at global position {}",
+                                object_index
+                            );
+                        }
+                    }
+                    OpCode::GetLocalProperty(object_index, property_index) => {
+                        let stack_index =
+                            object_index + self.call_stack.last().unwrap().stack_index;
+                        if let Some(SquatValue::Object(SquatObject::Instance(instance_data))) =
+                            self.stack.get(stack_index)
+                        {
+                            self.stack.push(instance_data.get_property(*property_index));
+                        } else {
+                            unreachable!(
+                                "GetLocalProperty expected a class instance at stack index {}",
+                                stack_index
+                            );
                         }
                     }
                     OpCode::GetProperty(property_index) => {
@@ -290,21 +1064,27 @@ impl VM {
                     }
 
                     OpCode::Index => {
-                        if let Some(SquatValue::Int(index)) = self.stack.pop() {
+                        if let Some(SquatValue::Object(SquatObject::Map(_))) =
+                            self.stack.iter().nth_back(1)
+                        {
+                            self.index_map();
+                        } else if let Some(SquatValue::Int(index)) = self.stack.pop() {
                             if let Some(indexed_value) = self.stack.pop() {
                                 match indexed_value {
-                                    SquatValue::String(value) => {
-                                        if value.len() as i64 <= index {
-                                            self.runtime_error(&format!("Index out of range, max possible index is {} but {} was given", value.len() - 1, index));
+                                    SquatValue::String(value) => match char_at(&value, index) {
+                                        Ok(character) => self.stack.push(character),
+                                        Err(msg) => self.runtime_error(&msg),
+                                    },
+                                    SquatValue::Object(SquatObject::Array(values)) => {
+                                        if values.len() as i64 <= index {
+                                            self.runtime_error(&format!("Index out of range, max possible index is {} but {} was given", values.len() as i64 - 1, index));
                                         } else if index < 0 {
                                             self.runtime_error(&format!(
                                                 "Given index {} is a negative number",
                                                 index
                                             ));
                                         } else {
-                                            self.stack.push(SquatValue::String(String::from(
-                                                value.as_bytes()[index as usize] as char,
-                                            )));
+                                            self.stack.push(values[index as usize].clone());
                                         }
                                     }
                                     _ => unreachable!(
@@ -319,111 +1099,98 @@ impl VM {
                             unreachable!("Index OpCode expects an Int on top of the stack")
                         }
                     }
-
-                    OpCode::JumpTo(instruction_number) => {
-                        self.chunks[self.current_chunk].current_instruction = *instruction_number;
-                    }
-                    OpCode::JumpIfFalse(offset) => {
-                        if let Some(value) = self.stack.last() {
-                            if !value.is_truthy() {
-                                self.chunks[self.current_chunk].current_instruction += *offset;
+                    OpCode::IterNext => {
+                        let cursor = match self.stack.pop() {
+                            Some(SquatValue::Int(cursor)) => cursor,
+                            _ => unreachable!("IterNext OpCode expects an Int cursor on the stack"),
+                        };
+                        let next = match self.stack.pop() {
+                            Some(SquatValue::Object(SquatObject::Array(values))) => {
+                                values.get(cursor as usize).cloned()
                             }
-                        } else {
-                            unreachable!("JumpIfFalse OpCode expect a value to be on the stack");
-                        }
-                    }
-                    OpCode::Jump(offset) => {
-                        self.chunks[self.current_chunk].current_instruction += *offset;
-                    }
-                    OpCode::JumpIfTrue(offset) => {
-                        if let Some(value) = self.stack.last() {
-                            if value.is_truthy() {
-                                self.chunks[self.current_chunk].current_instruction += *offset;
+                            Some(SquatValue::Object(SquatObject::Range(range))) => {
+                                range.get(cursor).map(SquatValue::Int)
                             }
-                        } else {
-                            unreachable!("JumpIfTrue OpCode expect a value to be on the stack");
-                        }
-                    }
-                    OpCode::Loop(loop_start) => {
-                        self.chunks[self.current_chunk].current_instruction = *loop_start;
-                    }
-
-                    OpCode::Call(arg_count) => {
-                        let arg_count = *arg_count;
-                        let func_data_location = self.stack.len() - 1 - arg_count;
-                        // All this ugly code for the native stuff exists because of the
-                        // borrow checker.
-                        let native = match self.stack.get(func_data_location).unwrap() {
-                            SquatValue::Object(SquatObject::Function(func_data)) => {
-                                let return_address =
-                                    self.chunks[self.current_chunk].current_instruction;
-                                self.call_stack.push(CallFrame::new(
-                                    self.stack.len() - arg_count,
-                                    return_address,
-                                    func_data.name.clone(),
+                            value => {
+                                self.runtime_error(&format!(
+                                    "Can only iterate over arrays and ranges but {} was given",
+                                    value.unwrap()
                                 ));
-                                self.chunks[self.current_chunk].current_instruction =
-                                    func_data.start_instruction_index;
-                                continue;
+                                None
                             }
-                            SquatValue::Object(SquatObject::NativeFunction(func)) => func.clone(),
-                            _ => unreachable!("Call OpCode expects a FunctionObject on the stack"),
                         };
-
-                        let mut args = Vec::new();
-                        for _i in 0..arg_count {
-                            args.push(self.stack.pop().unwrap())
+                        let has_next = next.is_some();
+                        self.stack.push(next.unwrap_or(SquatValue::Nil));
+                        self.stack.push(SquatValue::Bool(has_next));
+                    }
+                    OpCode::IsType(type_index) => {
+                        let value = self.stack.pop().unwrap();
+                        if let SquatValue::Type(squat_type) = self.constants.get(*type_index) {
+                            self.stack
+                                .push(SquatValue::Bool(value.get_type() == *squat_type));
+                        } else {
+                            unreachable!("IsType OpCode expects a type constant");
                         }
-                        self.stack.pop().unwrap();
-                        args.reverse();
-                        match native.call(args) {
-                            Ok(value) => self.stack.push(value),
-                            Err(msg) => self.runtime_error(&msg),
-                        };
                     }
                     OpCode::CreateInstance(arg_count) => {
                         let arg_count = *arg_count;
-                        let class_data_location = self.stack.len() - 1 - arg_count;
-                        match self.stack.get(class_data_location).unwrap() {
-                            SquatValue::Object(SquatObject::Struct(_)) => {
-                                let mut args = Vec::new();
-                                for _i in 0..arg_count {
-                                    args.push(self.stack.pop().unwrap())
-                                }
-                                match self.stack.pop() {
-                                    Some(SquatValue::Object(SquatObject::Struct(class_data))) => {
-                                        args.reverse();
-                                        self.stack.push(SquatValue::Object(SquatObject::Instance(
-                                            SquatInstance::new(&class_data.name, args),
-                                        )));
-                                    }
-                                    _ => unreachable!(),
-                                }
-                            }
-                            _ => unreachable!("CreateInstace OpCode expects a Class on the stack"),
-                        };
-                    }
-                    OpCode::Return => {
-                        let return_val = self.stack.pop().unwrap();
-                        if let Some(call_frame) = self.call_stack.pop() {
-                            while call_frame.stack_index < self.stack.len() {
-                                self.stack.pop(); // Pop local variables
+                        if arg_count >= self.stack.len() {
+                            self.runtime_error(&format!(
+                                "Cannot create an instance from {} values, the stack only has {}",
+                                arg_count,
+                                self.stack.len()
+                            ));
+                            continue;
+                        }
+                        let struct_location = self.stack.len() - 1 - arg_count;
+                        let struct_data = match &self.stack[struct_location] {
+                            SquatValue::Object(SquatObject::Struct(struct_data)) => {
+                                struct_data.clone()
                             }
-                            self.stack.pop(); // Pop SquatFunc
-                            self.chunks[self.current_chunk].current_instruction =
-                                call_frame.return_address;
-                            self.stack.push(return_val);
-                        } else {
-                            if let SquatValue::Int(i) = return_val {
-                                return InterpretResult::InterpretOk(i);
+                            value => {
+                                let message = format!("'{}' is not a struct", value);
+                                self.runtime_error(&message);
+                                continue;
                             }
-                            return InterpretResult::InterpretOk(0);
+                        };
+                        if struct_data.field_names.len() != arg_count {
+                            self.runtime_error(&format!(
+                                "Struct '{}' has {} fields but {} values were given",
+                                struct_data.name,
+                                struct_data.field_names.len(),
+                                arg_count
+                            ));
+                            continue;
                         }
+                        let args = self.stack.split_off(struct_location + 1);
+                        self.stack.pop(); // Pop the struct
+                        self.stack.push(SquatValue::Object(SquatObject::Instance(
+                            SquatInstance::new(&struct_data.name, args)
+                                .with_field_names(struct_data.field_names),
+                        )));
+                    }
+                    OpCode::Closure(upvalue_count) => {
+                        let upvalues = self.stack.split_off(self.stack.len() - upvalue_count);
+                        let Some(SquatValue::Object(SquatObject::Function(function))) =
+                            self.stack.last_mut()
+                        else {
+                            unreachable!("Closure OpCode expects a function below the upvalues");
+                        };
+                        function.upvalues = upvalues.into();
                     }
 
-                    OpCode::Start => {}
+                    OpCode::Print => {
+                        let value = self.stack.pop().unwrap();
+                        println!(
+                            "{}",
+                            value.to_string_with_precision(self.native_state.float_precision)
+                        );
+                    }
+                    OpCode::Start | OpCode::Nop => {}
                     OpCode::Stop => {
-                        return InterpretResult::InterpretOk(0);
+                        return InterpretResult::InterpretOk(
+                            self.native_state.exit_code.take().unwrap_or(0),
+                        );
                     }
                 }
             } else {
@@ -434,6 +1201,34 @@ impl VM {
         InterpretResult::InterpretOk(0)
     }
 
+    /// Whether the two values on top of the stack are ints and the divisor
+    /// is 0, which has no result unlike dividing floats
+    fn divides_int_by_zero(&self) -> bool {
+        matches!(
+            self.stack.as_slice(),
+            [.., SquatValue::Int(_), SquatValue::Int(0)]
+        )
+    }
+
+    /// Continues with the catch block of the innermost `try` if the error
+    /// happened in it, unless the `try` is outside of the function a native
+    /// called back that only runs until `return_depth`
+    fn catch_error(&mut self, return_depth: usize) -> bool {
+        match self.catch_handlers.last() {
+            Some(handler) if handler.call_depth > return_depth => {}
+            _ => return false,
+        }
+        let handler = self.catch_handlers.pop().unwrap();
+        let message = self.caught_error.take().unwrap_or_default();
+        self.call_stack.truncate(handler.call_depth);
+        self.stack.truncate(handler.stack_size);
+        self.stack.push(SquatValue::String(message.into()));
+        self.current_chunk = handler.chunk;
+        self.chunks[self.current_chunk].current_instruction = handler.catch_address;
+        self.had_error = false;
+        true
+    }
+
     fn binary_op<F>(&mut self, op: F)
     where
         F: FnOnce(SquatValue, SquatValue) -> SquatValue,
@@ -441,13 +1236,60 @@ impl VM {
         let right = self.stack.pop();
         let left = self.stack.pop();
 
-        if left.is_some() && right.is_some() {
-            self.stack.push(op(left.unwrap(), right.unwrap()));
+        if let (Some(left), Some(right)) = (left, right) {
+            self.stack.push(op(left, right));
         } else {
             unreachable!("Binary operations require 2 values in the stack");
         }
     }
 
+    /// Replaces the key on top of the stack and the map below it with the
+    /// value of the key
+    fn index_map(&mut self) {
+        let key = self.stack.pop().unwrap();
+        let Some(SquatValue::Object(SquatObject::Map(map))) = self.stack.pop() else {
+            unreachable!("Index OpCode expects a map below the key");
+        };
+        match map.get(&key) {
+            Some(value) => self.stack.push(value.clone()),
+            None if !key.is_hashable() => {
+                self.runtime_error(&format!("'{}' cannot be used as a map key", key))
+            }
+            None => {
+                self.runtime_error(&format!("Key {} is not in the map", key.to_nested_string()))
+            }
+        }
+    }
+
+    /// Stops with an error if the string on top of the stack is longer than
+    /// `--max-string-length`, so scripts can't use up all of the memory
+    fn check_string_length(&mut self) {
+        if self.max_string_length == 0 {
+            return;
+        }
+        if let Some(SquatValue::String(value)) = self.stack.last() {
+            if value.len() > self.max_string_length {
+                let length = value.len();
+                self.runtime_error(&format!(
+                    "String of length {} is longer than the max length of {}",
+                    length, self.max_string_length
+                ));
+            }
+        }
+    }
+
+    /// Stops with an error if the program holds more objects than
+    /// `--max-objects`, so scripts can't use up all of the memory
+    fn check_object_count(&mut self) {
+        let object_count = self.objects.get();
+        if object_count > self.max_objects {
+            self.runtime_error(&format!(
+                "Program holds {} objects, more than the max of {}",
+                object_count, self.max_objects
+            ));
+        }
+    }
+
     fn binary_cmp<F>(&mut self, op: F)
     where
         F: FnOnce(SquatValue, SquatValue) -> bool,
@@ -455,26 +1297,45 @@ impl VM {
         let right = self.stack.pop();
         let left = self.stack.pop();
 
-        if left.is_some() && right.is_some() {
-            self.stack
-                .push(SquatValue::Bool(op(left.unwrap(), right.unwrap())));
+        if let (Some(left), Some(right)) = (left, right) {
+            self.stack.push(SquatValue::Bool(op(left, right)));
         } else {
             unreachable!("Binary comparisons require 2 values in the stack");
         }
     }
 
+    /// Name and call location of every function on the call stack, innermost
+    /// first
+    fn call_trace(&self) -> Vec<String> {
+        let chunk = &self.chunks[self.current_chunk];
+        self.call_stack
+            .iter()
+            .rev()
+            .map(|call_frame| {
+                format!(
+                    "function '{}' called at {}",
+                    call_frame.func_name,
+                    chunk.location(chunk.get_instruction_line(call_frame.return_address))
+                )
+            })
+            .collect()
+    }
+
     fn runtime_error(&mut self, message: &str) {
+        // A `try` block is going to catch it, see `catch_error`
+        if !self.catch_handlers.is_empty() {
+            self.caught_error = Some(message.to_owned());
+            self.had_error = true;
+            return;
+        }
         println!("Error callstack:");
-        for call_frame in self.call_stack.iter().rev() {
-            println!(
-                "\tfunction '{}' called at line {}",
-                call_frame.func_name,
-                self.chunks[self.current_chunk].get_instruction_line(call_frame.return_address)
-            );
+        for line in self.call_trace() {
+            println!("\t{}", line);
         }
+        let chunk = &self.chunks[self.current_chunk];
         println!(
-            "[ERROR] (Line {}) {}",
-            self.chunks[self.current_chunk].get_current_instruction_line(),
+            "[ERROR] ({}) {}",
+            chunk.location(chunk.get_last_instruction_line()),
             message
         );
         self.had_error = true;
@@ -487,14 +1348,24 @@ impl VM {
             SquatFunctionTypeData::new(vec![], SquatType::String),
         );
         self.define_native_func(
+            "prompt",
+            native::io::prompt,
+            SquatFunctionTypeData::new(vec![SquatType::String], SquatType::String),
+        );
+        self.define_native_func(
+            "read_all",
+            native::io::read_all,
+            SquatFunctionTypeData::new(vec![], SquatType::String),
+        );
+        self.define_state_native_func(
             "print",
             native::io::print,
-            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Nil),
+            SquatFunctionTypeData::new(vec![], SquatType::Nil).variadic(),
         );
-        self.define_native_func(
+        self.define_state_native_func(
             "println",
             native::io::println,
-            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Nil),
+            SquatFunctionTypeData::new(vec![], SquatType::Nil).variadic(),
         );
 
         self.define_native_func(
@@ -525,28 +1396,393 @@ impl VM {
             native::number::to_float,
             SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Float),
         );
+        self.define_native_func(
+            "try_to_int",
+            native::number::try_to_int,
+            SquatFunctionTypeData::new(
+                vec![SquatType::String],
+                SquatType::Optional(Box::new(SquatType::Int)),
+            ),
+        );
+        self.define_native_func(
+            "try_to_float",
+            native::number::try_to_float,
+            SquatFunctionTypeData::new(
+                vec![SquatType::String],
+                SquatType::Optional(Box::new(SquatType::Float)),
+            ),
+        );
+        self.define_native_func(
+            "format_float",
+            native::number::format_float,
+            SquatFunctionTypeData::new(vec![SquatType::Number, SquatType::Int], SquatType::String),
+        );
 
         self.define_native_func(
             "exit",
             native::misc::exit,
             SquatFunctionTypeData::new(vec![SquatType::Int], SquatType::Nil),
         );
+        self.define_state_native_func(
+            "set_exit_code",
+            native::misc::set_exit_code,
+            SquatFunctionTypeData::new(vec![SquatType::Int], SquatType::Nil),
+        );
+        self.define_call_context_native_func(
+            "caller_line",
+            native::introspection::caller_line,
+            SquatFunctionTypeData::new(vec![], SquatType::Int),
+        );
+        self.define_call_context_native_func(
+            "stack_depth",
+            native::introspection::stack_depth,
+            SquatFunctionTypeData::new(vec![], SquatType::Int),
+        );
         self.define_native_func(
             "time",
             native::misc::time,
             SquatFunctionTypeData::new(vec![], SquatType::Float),
         );
         self.define_native_func(
-            "type",
-            native::misc::get_type,
-            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Type),
+            "clock",
+            native::misc::clock,
+            SquatFunctionTypeData::new(vec![], SquatType::Int),
+        );
+        self.define_native_func(
+            "format_duration",
+            native::misc::format_duration,
+            SquatFunctionTypeData::new(vec![SquatType::Number], SquatType::String),
+        );
+        self.define_native_func(
+            "type",
+            native::misc::get_type,
+            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Type),
+        );
+        self.define_native_func(
+            "type_name",
+            native::misc::type_name,
+            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::String),
+        );
+        self.define_native_func(
+            "reverse",
+            native::misc::reverse,
+            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Any),
+        );
+        self.define_native_func(
+            "arity",
+            native::misc::arity,
+            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Int),
+        );
+        self.define_native_func(
+            "param_types",
+            native::misc::param_types,
+            SquatFunctionTypeData::new(
+                vec![SquatType::Any],
+                SquatType::Array(Box::new(SquatType::Type)),
+            ),
+        );
+
+        self.define_native_func(
+            "any",
+            native::array::any,
+            SquatFunctionTypeData::new(
+                vec![SquatType::Array(Box::new(SquatType::Any))],
+                SquatType::Bool,
+            ),
+        );
+        self.define_native_func(
+            "all",
+            native::array::all,
+            SquatFunctionTypeData::new(
+                vec![SquatType::Array(Box::new(SquatType::Any))],
+                SquatType::Bool,
+            ),
+        );
+        self.define_native_func(
+            "array_contains",
+            native::array::array_contains,
+            SquatFunctionTypeData::new(
+                vec![SquatType::Array(Box::new(SquatType::Any)), SquatType::Any],
+                SquatType::Bool,
+            ),
+        );
+        self.define_native_func(
+            "array_index_of",
+            native::array::array_index_of,
+            SquatFunctionTypeData::new(
+                vec![SquatType::Array(Box::new(SquatType::Any)), SquatType::Any],
+                SquatType::Int,
+            ),
+        );
+        self.define_native_func(
+            "range",
+            native::array::range,
+            SquatFunctionTypeData::new(vec![SquatType::Int, SquatType::Int], SquatType::Range),
+        );
+        self.define_state_native_func(
+            "shuffle",
+            native::array::shuffle,
+            SquatFunctionTypeData::new(
+                vec![SquatType::Array(Box::new(SquatType::Any))],
+                SquatType::Array(Box::new(SquatType::Any)),
+            ),
+        );
+        self.define_callback_native_func(
+            "sort_by_key",
+            native::array::sort_by_key,
+            SquatFunctionTypeData::new(
+                vec![
+                    SquatType::Array(Box::new(SquatType::Any)),
+                    SquatType::Function(SquatFunctionTypeData::new(
+                        vec![SquatType::Any],
+                        SquatType::Any,
+                    )),
+                ],
+                SquatType::Array(Box::new(SquatType::Any)),
+            ),
+        );
+        self.define_native_func(
+            "slice",
+            native::array::slice,
+            SquatFunctionTypeData::new(
+                vec![
+                    SquatType::Array(Box::new(SquatType::Any)),
+                    SquatType::Int,
+                    SquatType::Int,
+                ],
+                SquatType::Array(Box::new(SquatType::Any)),
+            ),
+        );
+        self.define_native_func(
+            "join",
+            native::array::join,
+            SquatFunctionTypeData::new(
+                vec![
+                    SquatType::Array(Box::new(SquatType::String)),
+                    SquatType::String,
+                ],
+                SquatType::String,
+            ),
         );
 
         self.define_native_func(
+            "read_lines",
+            native::fs::read_lines,
+            SquatFunctionTypeData::new(
+                vec![SquatType::String],
+                SquatType::Array(Box::new(SquatType::String)),
+            ),
+        );
+        self.define_native_func(
+            "list_dir",
+            native::fs::list_dir,
+            SquatFunctionTypeData::new(
+                vec![SquatType::String],
+                SquatType::Array(Box::new(SquatType::String)),
+            ),
+        );
+
+        self.define_state_native_func(
             "to_str",
             native::string::to_str,
             SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::String),
         );
+
+        self.define_state_native_func(
+            "assert_that",
+            native::test::assert_that,
+            SquatFunctionTypeData::new(vec![SquatType::Bool, SquatType::String], SquatType::Nil)
+                .variadic(),
+        );
+
+        self.define_native_func(
+            "to_map",
+            native::map::to_map,
+            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Map),
+        );
+        self.define_native_func(
+            "pad_left",
+            native::string::pad_left,
+            SquatFunctionTypeData::new(
+                vec![SquatType::String, SquatType::Int, SquatType::String],
+                SquatType::String,
+            ),
+        );
+        self.define_native_func(
+            "pad_right",
+            native::string::pad_right,
+            SquatFunctionTypeData::new(
+                vec![SquatType::String, SquatType::Int, SquatType::String],
+                SquatType::String,
+            ),
+        );
+        self.define_native_func(
+            "size",
+            native::misc::size,
+            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Int),
+        );
+        self.define_native_func(
+            "between",
+            native::number::between,
+            SquatFunctionTypeData::new(
+                vec![SquatType::Number, SquatType::Number, SquatType::Number],
+                SquatType::Bool,
+            ),
+        );
+        self.define_native_func(
+            "chars",
+            native::string::chars,
+            SquatFunctionTypeData::new(
+                vec![SquatType::String],
+                SquatType::Array(Box::new(SquatType::String)),
+            ),
+        );
+        self.define_native_func(
+            "string_builder",
+            native::string::string_builder,
+            SquatFunctionTypeData::new(vec![], SquatType::StringBuilder),
+        );
+        self.define_state_native_func(
+            "append",
+            native::string::append,
+            SquatFunctionTypeData::new(
+                vec![SquatType::StringBuilder, SquatType::Any],
+                SquatType::Nil,
+            ),
+        );
+        self.define_native_func(
+            "build",
+            native::string::build,
+            SquatFunctionTypeData::new(vec![SquatType::StringBuilder], SquatType::String),
+        );
+        self.define_native_func(
+            "hash",
+            native::misc::hash,
+            SquatFunctionTypeData::new(vec![SquatType::Any], SquatType::Int),
+        );
+        self.define_native_func(
+            "format_number",
+            native::number::format_number,
+            SquatFunctionTypeData::new(vec![SquatType::Int], SquatType::String),
+        );
+        self.define_native_func(
+            "read_key",
+            native::io::read_key,
+            SquatFunctionTypeData::new(vec![], SquatType::String),
+        );
+        self.define_native_func(
+            "abs",
+            native::number::abs,
+            SquatFunctionTypeData::new(vec![SquatType::Number], SquatType::Number),
+        );
+        self.define_native_func(
+            "to_bytes",
+            native::string::to_bytes,
+            SquatFunctionTypeData::new(
+                vec![SquatType::String],
+                SquatType::Array(Box::new(SquatType::Int)),
+            ),
+        );
+        self.define_native_func(
+            "from_bytes",
+            native::string::from_bytes,
+            SquatFunctionTypeData::new(
+                vec![SquatType::Array(Box::new(SquatType::Int))],
+                SquatType::String,
+            ),
+        );
+        self.define_state_native_func(
+            "defined",
+            native::introspection::defined,
+            SquatFunctionTypeData::new(vec![SquatType::String], SquatType::Bool),
+        );
+        self.define_native_func(
+            "pq_new",
+            native::priority_queue::pq_new,
+            SquatFunctionTypeData::new(vec![], SquatType::PriorityQueue),
+        );
+        self.define_native_func(
+            "pq_push",
+            native::priority_queue::pq_push,
+            SquatFunctionTypeData::new(
+                vec![SquatType::PriorityQueue, SquatType::Any],
+                SquatType::Nil,
+            ),
+        );
+        self.define_native_func(
+            "pq_pop",
+            native::priority_queue::pq_pop,
+            SquatFunctionTypeData::new(vec![SquatType::PriorityQueue], SquatType::Any),
+        );
+        self.define_native_func(
+            "pq_len",
+            native::priority_queue::pq_len,
+            SquatFunctionTypeData::new(vec![SquatType::PriorityQueue], SquatType::Int),
+        );
+
+        self.define_state_native_func(
+            "format",
+            native::string::format,
+            SquatFunctionTypeData::new(vec![SquatType::String], SquatType::String).variadic(),
+        );
+
+        self.define_native_func(
+            "os_name",
+            native::misc::os_name,
+            SquatFunctionTypeData::new(vec![], SquatType::String),
+        );
+        self.define_native_func(
+            "arch",
+            native::misc::arch,
+            SquatFunctionTypeData::new(vec![], SquatType::String),
+        );
+
+        self.define_native_func(
+            "now",
+            native::misc::now,
+            SquatFunctionTypeData::new(vec![], SquatType::Int),
+        );
+        self.define_native_func(
+            "format_time",
+            native::misc::format_time,
+            SquatFunctionTypeData::new(
+                vec![SquatType::Number, SquatType::String],
+                SquatType::String,
+            ),
+        );
+
+        self.define_native_func(
+            "from_map",
+            native::map::from_map,
+            SquatFunctionTypeData::new(vec![SquatType::Any, SquatType::Map], SquatType::Any),
+        );
+
+        self.define_native_func(
+            "base64_encode",
+            native::encoding::base64_encode,
+            SquatFunctionTypeData::new(vec![SquatType::String], SquatType::String),
+        );
+        self.define_native_func(
+            "base64_decode",
+            native::encoding::base64_decode,
+            SquatFunctionTypeData::new(vec![SquatType::String], SquatType::String),
+        );
+
+        self.define_native_func(
+            "squat_version",
+            native::misc::squat_version,
+            SquatFunctionTypeData::new(vec![], SquatType::String),
+        );
+        self.define_native_func(
+            "version_at_least",
+            native::misc::version_at_least,
+            SquatFunctionTypeData::new(vec![SquatType::String], SquatType::Bool),
+        );
+        self.define_call_context_native_func(
+            "mem_usage",
+            native::introspection::mem_usage,
+            SquatFunctionTypeData::new(vec![], SquatType::Int),
+        );
     }
 
     fn define_native_func(
@@ -555,7 +1791,46 @@ impl VM {
         func: native::NativeFunc,
         func_data: SquatFunctionTypeData,
     ) {
-        let native_func = SquatNativeFunction::new(name, func);
+        let native_func = SquatNativeFunction::new(name, func, func_data);
+        self.push_native_func(native_func);
+    }
+
+    /// Defines a native that uses the native state, see `NativeState`
+    fn define_state_native_func(
+        &mut self,
+        name: &str,
+        func: native::NativeFuncWithState,
+        func_data: SquatFunctionTypeData,
+    ) {
+        let native_func = SquatNativeFunction::with_state(name, func, func_data);
+        self.push_native_func(native_func);
+    }
+
+    /// Defines a native that reads the call context set by the VM
+    fn define_call_context_native_func(
+        &mut self,
+        name: &str,
+        func: native::NativeFuncWithState,
+        func_data: SquatFunctionTypeData,
+    ) {
+        let native_func =
+            SquatNativeFunction::with_state(name, func, func_data).with_call_context();
+        self.push_native_func(native_func);
+    }
+
+    /// Defines a native that calls squat functions it is given
+    fn define_callback_native_func(
+        &mut self,
+        name: &str,
+        func: native::NativeFuncWithCallback,
+        func_data: SquatFunctionTypeData,
+    ) {
+        let native_func = SquatNativeFunction::with_callback(name, func, func_data);
+        self.push_native_func(native_func);
+    }
+
+    fn push_native_func(&mut self, native_func: SquatNativeFunction) {
+        let func_data = native_func.type_data.clone();
         let native_object = SquatObject::NativeFunction(native_func);
         let native_value = SquatValue::Object(native_object);
 
@@ -563,4 +1838,2057 @@ impl VM {
             CompilerNative::new(native_value, SquatType::NativeFunction(func_data));
         self.natives.push(native_compiler);
     }
+
+    fn call_context(&self) -> CallContext {
+        let chunk = &self.chunks[self.current_chunk];
+        let frame = self.call_stack.last().unwrap();
+        // The instruction before the return address is the call of the function
+        let call_instruction = match self.call_stack.len() {
+            1 => chunk.current_instruction - 1,
+            _ => frame.return_address - 1,
+        };
+        CallContext::new(
+            chunk.get_instruction_line(call_instruction) as i64,
+            self.call_stack.len() as i64,
+            self.objects.get() as i64,
+        )
+    }
+}
+
+/// The compiler can't check the arguments a native or the host passes to a
+/// function, so they are checked against its parameters before the call
+fn check_arguments(func_data: &SquatFunction, args: &[SquatValue]) -> Result<(), String> {
+    let param_types = &func_data.type_data.param_types;
+    if args.len() != param_types.len() {
+        return Err(format!(
+            "Function '{}' expects {} arguments but {} were given",
+            func_data.name,
+            param_types.len(),
+            args.len()
+        ));
+    }
+    for (index, (arg, param_type)) in args.iter().zip(param_types).enumerate() {
+        let arg_type = arg.get_type();
+        if arg_type != *param_type {
+            return Err(format!(
+                "Argument {} of '{}': expected {} but found {}",
+                index + 1,
+                func_data.name,
+                param_type,
+                arg_type
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Character of the string at the given index. Strings are indexed by
+/// characters, not bytes.
+fn char_at(value: &str, index: i64) -> Result<SquatValue, String> {
+    if index < 0 {
+        return Err(format!("Given index {} is a negative number", index));
+    }
+    match value.chars().nth(index as usize) {
+        Some(character) => Ok(SquatValue::String(character.to_string().into())),
+        None => Err(format!(
+            "Index out of range, max possible index is {} but {} was given",
+            value.chars().count() as i64 - 1,
+            index
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::object::{SquatFunction, SquatRange, SquatStruct};
+
+    fn run(source: &str) -> (VM, InterpretResult) {
+        let mut vm = VM::new();
+        let result = vm.interpret_source(source.to_owned(), &Options::default());
+        (vm, result)
+    }
+
+    #[test]
+    fn string_is_indexed_by_characters() {
+        assert_eq!(char_at("héllo", 1), Ok(SquatValue::String("é".into())));
+        assert_eq!(char_at("héllo", 4), Ok(SquatValue::String("o".into())));
+        assert_eq!(
+            char_at("héllo", 5),
+            Err("Index out of range, max possible index is 4 but 5 was given".to_owned())
+        );
+        assert_eq!(
+            char_at("héllo", -1),
+            Err("Given index -1 is a negative number".to_owned())
+        );
+    }
+
+    #[test]
+    fn call_restores_current_chunk() {
+        let (vm, result) = run("
+            func add(int a, int b) int {
+                return a + b;
+            }
+
+            func main() {
+                int sum = add(1, 2);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.current_chunk, 0);
+    }
+
+    #[test]
+    fn dead_functions_do_not_change_behavior() {
+        let (vm, _) = run("
+            int result = 0;
+
+            func square(int a) int {
+                return a * a;
+            }
+
+            func main() {
+                result = square(7);
+            }
+        ");
+        let (vm_with_dead_function, _) = run("
+            int result = 0;
+
+            func unused() int {
+                return 1;
+            }
+
+            func square(int a) int {
+                return a * a;
+            }
+
+            func main() {
+                result = square(7);
+            }
+        ");
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(49)));
+        assert_eq!(vm.globals[0], vm_with_dead_function.globals[0]);
+    }
+
+    #[test]
+    fn arity_of_two_arg_function() {
+        let (vm, result) = run("
+            int result = 0;
+
+            func add(int a, int b) int {
+                return a + b;
+            }
+
+            func main() {
+                result = arity(add);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(2)));
+    }
+
+    #[test]
+    fn compiled_program_can_be_executed_again() {
+        let mut vm = VM::new();
+        let options = Options::default();
+        let program = vm
+            .compile(
+                &"
+                int result = 0;
+
+                func main() {
+                    result = result + 21;
+                    result = result * 2;
+                }
+                "
+                .to_owned(),
+                &options,
+            )
+            .unwrap();
+
+        assert!(vm.execute(&program, &options) == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(42)));
+        assert!(vm.execute(&program, &options) == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(42)));
+    }
+
+    #[test]
+    fn loop_invariant_globals_are_hoisted() {
+        let mut vm = VM::new();
+        let options = Options::default();
+        let program = vm
+            .compile(
+                &"
+                int limit = 5;
+                int step = 2;
+                int total = 0;
+
+                func main() {
+                    for (int i = 0; i < limit; i = i + 1) {
+                        int doubled = i * step;
+                        total = total + doubled;
+                    }
+                }
+                "
+                .to_owned(),
+                &options,
+            )
+            .unwrap();
+
+        let chunk = &program.chunk;
+        let loop_index = (0..chunk.get_size())
+            .rfind(|op_index| matches!(chunk.get_instruction(*op_index), OpCode::Loop(_)))
+            .unwrap();
+        let OpCode::Loop(loop_start) = chunk.get_instruction(loop_index) else {
+            unreachable!()
+        };
+        let in_loop_reads: Vec<OpCode> = (loop_start..loop_index)
+            .map(|op_index| chunk.get_instruction(op_index))
+            .filter(|op_code| matches!(op_code, OpCode::GetGlobal(_)))
+            .collect();
+        // Only total is written in the loop
+        assert_eq!(in_loop_reads, vec![OpCode::GetGlobal(2)]);
+
+        assert!(vm.execute(&program, &options) == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[2], Some(SquatValue::Int(20)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn globals_written_by_callbacks_are_not_hoisted() {
+        let (vm, result) = run("
+            int counter = 0;
+            int seen = 0;
+            func key(string c) string {
+                counter = counter + 1;
+                return c;
+            }
+            func main() {
+                int round = 0;
+                while (round < 3) {
+                    any sorted = sort_by_key(chars(\"cab\"), key);
+                    seen = counter;
+                    round = round + 1;
+                }
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(9)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(9)));
+    }
+
+    #[test]
+    fn else_if_chain_jumps_to_its_end() {
+        let mut vm = VM::new();
+        let options = Options::default();
+        let program = vm
+            .compile(
+                &"
+                int first = 0;
+                int second = 0;
+                int third = 0;
+
+                func classify(int n) int {
+                    if (n < 10) {
+                        return 1;
+                    } else if (n < 20) {
+                        return 2;
+                    } else if (n < 30) {
+                        return 3;
+                    } else if (n < 40) {
+                        return 4;
+                    } else {
+                        return 5;
+                    }
+                    return 0;
+                }
+
+                func main() {
+                    first = classify(5);
+                    second = classify(35);
+                    third = classify(100);
+                }
+                "
+                .to_owned(),
+                &options,
+            )
+            .unwrap();
+
+        // The first Jump goes over classify, the next four end its branches
+        let chunk = &program.chunk;
+        let targets: Vec<usize> = (0..chunk.get_size())
+            .filter_map(|op_index| match chunk.get_instruction(op_index) {
+                OpCode::Jump(offset) => Some(op_index + 1 + offset),
+                _ => None,
+            })
+            .skip(1)
+            .take(4)
+            .collect();
+        assert_eq!(targets.len(), 4);
+        assert!(targets.iter().all(|target| *target == targets[0]));
+
+        assert!(vm.execute(&program, &options) == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(1)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(4)));
+        assert_eq!(vm.globals[2], Some(SquatValue::Int(5)));
+    }
+
+    #[test]
+    fn deinit_runs_when_local_goes_out_of_scope() {
+        let (vm, result) = run("
+            int closed = 0;
+            int closed_in_block = 0;
+
+            struct Resource {
+                int id;
+                deinit {
+                    closed = closed + self.id;
+                }
+            }
+
+            func main() {
+                Resource outer = Resource(7);
+                {
+                    Resource inner = Resource(5);
+                }
+                closed_in_block = closed;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(5)));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(12)));
+    }
+
+    #[test]
+    fn int_and_float_equality() {
+        let (vm, result) = run("
+            bool same = false;
+            bool different = false;
+
+            func main() {
+                if (1 == 1.0) {
+                    same = true;
+                }
+                if (1 == 2.0) {
+                    different = true;
+                }
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Bool(true)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Bool(false)));
+    }
+
+    #[test]
+    fn ints_and_floats_are_mixed_in_arithmetic() {
+        let source = "
+            float sum = 0.0;
+            bool less = false;
+
+            func main() {
+                int i = 3;
+                sum = 1 + 2.0 + i * 0.5;
+                less = i < 3.5;
+            }
+        "
+        .to_owned();
+        let (vm, result) = run(&source);
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Float(4.5)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Bool(true)));
+
+        let mut vm = VM::new();
+        let opts = Options {
+            strict_types: true,
+            ..Default::default()
+        };
+        assert!(vm.interpret_source(source, &opts) == InterpretResult::InterpretCompileError);
+    }
+
+    #[test]
+    fn floats_are_printed_with_float_precision() {
+        let mut vm = VM::new();
+        let options = Options {
+            float_precision: Some(6),
+            ..Default::default()
+        };
+        let result = vm.interpret_source(
+            "
+            string sum = \"\";
+
+            func main() {
+                sum = to_str(0.1 + 0.2);
+            }
+            "
+            .to_owned(),
+            &options,
+        );
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::String("0.300000".into())));
+
+        // No digits after the decimal point rounds to a whole number
+        let mut vm = VM::new();
+        let options = Options {
+            float_precision: Some(0),
+            ..Default::default()
+        };
+        let result = vm.interpret_source(
+            "
+            string rounded = \"\";
+
+            func main() {
+                rounded = to_str(2.7);
+            }
+            "
+            .to_owned(),
+            &options,
+        );
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::String("3".into())));
+
+        let (vm, _) = run("
+            string sum = \"\";
+
+            func main() {
+                sum = to_str(0.1 + 0.2);
+            }
+        ");
+        assert_eq!(
+            vm.globals[0],
+            Some(SquatValue::String("0.30000000000000004".into()))
+        );
+    }
+
+    #[test]
+    fn string_longer_than_max_length_is_an_error() {
+        let mut vm = VM::new();
+        let options = Options {
+            max_string_length: 64,
+            ..Default::default()
+        };
+        let result = vm.interpret_source(
+            "
+            func main() {
+                string value = \"ab\";
+                while (true) {
+                    value = value + value;
+                }
+            }
+            "
+            .to_owned(),
+            &options,
+        );
+        assert!(result == InterpretResult::InterpretRuntimeError);
+    }
+
+    #[test]
+    fn call_context_natives() {
+        let (vm, result) = run("
+            int depth = 0;
+            int line = 0;
+
+            func inner() {
+                depth = stack_depth();
+                line = caller_line();
+            }
+
+            func outer() {
+                inner();
+            }
+
+            func main() {
+                outer();
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(3)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(11)));
+    }
+
+    #[test]
+    fn stack_dump_labels_locals() {
+        let mut vm = VM::new();
+        let options = Options::default();
+        let program = vm
+            .compile(
+                &"
+                func add(int first, int second) int {
+                    int sum = first + second;
+                    return sum;
+                }
+
+                func main() {
+                    int total = add(1, 2);
+                }
+                "
+                .to_owned(),
+                &options,
+            )
+            .unwrap();
+
+        // Stack as it is inside of add, called from main
+        vm.local_names = program.local_names.clone();
+        vm.stack = vec![
+            SquatValue::Nil,
+            SquatValue::Int(1),
+            SquatValue::Int(2),
+            SquatValue::Int(3),
+        ];
+        vm.call_stack = vec![
+            CallFrame::new(0, 0, 0, "main".to_owned()),
+            CallFrame::new(1, 0, 0, "add".to_owned()),
+        ];
+
+        assert_eq!(
+            vm.stack_dump(),
+            "\t[Nil] main.total\n\t[Int(1)] add.first\n\t[Int(2)] add.second\n\t[Int(3)] add.sum\n"
+        );
+    }
+
+    #[test]
+    fn profile_counts_function_calls() {
+        let mut vm = VM::new();
+        let options = Options {
+            profile: true,
+            ..Default::default()
+        };
+        let result = vm.interpret_source(
+            "
+            func square(int a) int {
+                return a * a;
+            }
+
+            func main() {
+                int i = 0;
+                while (i < 7) {
+                    square(i);
+                    i = i + 1;
+                }
+            }
+            "
+            .to_owned(),
+            &options,
+        );
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.function_calls.get("square"), Some(&7));
+    }
+
+    #[test]
+    fn stats_of_small_program() {
+        let mut vm = VM::new();
+        let options = Options::default();
+        let program = vm
+            .compile(
+                &"
+                int result = 0;
+
+                func add(int a, int b) int {
+                    return a + b;
+                }
+
+                func main() {
+                    result = add(1, 2);
+                }
+                "
+                .to_owned(),
+                &options,
+            )
+            .unwrap();
+        vm.execute(&program, &options);
+
+        assert_eq!(
+            vm.stats(&program),
+            ProgramStats {
+                instruction_count: 20,
+                constant_count: 4,
+                global_count: 3,
+                function_count: 2,
+                max_stack_depth: 5,
+                executed_instructions: 19,
+            }
+        );
+    }
+
+    #[test]
+    fn panic_reports_the_instruction() {
+        let mut chunk = Chunk::new("Test", true);
+        chunk.write(OpCode::Start, 1);
+        chunk.write(OpCode::Constant(0), 1);
+        chunk.write(OpCode::Constant(0), 2);
+        // Index expects an int on top of the stack
+        chunk.write(OpCode::Index, 3);
+        chunk.write(OpCode::Stop, 3);
+        chunk.write(OpCode::JumpTo(1), 3);
+        let mut constants = ValueArray::new("Constants");
+        constants.write(SquatValue::String("squat".into()));
+
+        let mut vm = VM::new();
+        let program = CompiledProgram::new(chunk, constants, 0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            vm.execute(&program, &Options::default())
+        }));
+        assert!(result.is_err());
+        assert_eq!(
+            vm.panic_location(),
+            "[INTERNAL ERROR] VM panicked in Test Chunk at instruction 3 Index (<repl>:3)"
+        );
+    }
+
+    /// Runs the instructions as main, the returned VM holds the globals
+    fn run_instructions(code: Vec<OpCode>, values: Vec<SquatValue>, global_count: usize) -> VM {
+        let mut chunk = Chunk::new("Test", true);
+        chunk.write(OpCode::Start, 1);
+        for op_code in code {
+            chunk.write(op_code, 1);
+        }
+        chunk.write(OpCode::Stop, 1);
+        chunk.write(OpCode::JumpTo(1), 1);
+        let mut constants = ValueArray::new("Constants");
+        for value in values {
+            constants.write(value);
+        }
+
+        let mut vm = VM::new();
+        let program = CompiledProgram::new(chunk, constants, global_count);
+        assert!(vm.execute(&program, &Options::default()) == InterpretResult::InterpretOk(0));
+        vm
+    }
+
+    #[test]
+    fn arithmetic_opcodes() {
+        let vm = run_instructions(
+            vec![
+                OpCode::Constant(0),
+                OpCode::Constant(1),
+                OpCode::Multiply,
+                OpCode::Constant(2),
+                OpCode::Subtract,
+                OpCode::DefineGlobal(0),
+                OpCode::Constant(0),
+                OpCode::Negate,
+                OpCode::Constant(2),
+                OpCode::Mod,
+                OpCode::DefineGlobal(1),
+            ],
+            vec![SquatValue::Int(7), SquatValue::Int(8), SquatValue::Int(4)],
+            2,
+        );
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(52)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(-3)));
+    }
+
+    #[test]
+    fn nop_is_skipped() {
+        let vm = run_instructions(
+            vec![
+                OpCode::Constant(0),
+                OpCode::Nop,
+                OpCode::Constant(1),
+                OpCode::Nop,
+                OpCode::Add,
+                OpCode::DefineGlobal(0),
+            ],
+            vec![SquatValue::Int(2), SquatValue::Int(3)],
+            1,
+        );
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(5)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn control_flow_opcodes() {
+        let vm = run_instructions(
+            vec![
+                OpCode::False,
+                OpCode::JumpIfFalse(3),
+                OpCode::Pop,
+                OpCode::Constant(0),
+                OpCode::Jump(2),
+                OpCode::Pop,
+                OpCode::Constant(1),
+                OpCode::DefineGlobal(0),
+            ],
+            vec![SquatValue::Int(1), SquatValue::Int(2)],
+            1,
+        );
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(2)));
+
+        // Counts the global up to 3 with a loop
+        let vm = run_instructions(
+            vec![
+                OpCode::Constant(0),
+                OpCode::DefineGlobal(0),
+                OpCode::GetGlobal(0),
+                OpCode::Constant(1),
+                OpCode::Less,
+                OpCode::JumpIfFalse(7),
+                OpCode::Pop,
+                OpCode::GetGlobal(0),
+                OpCode::Constant(2),
+                OpCode::Add,
+                OpCode::SetGlobal(0),
+                OpCode::Pop,
+                OpCode::Loop(3),
+                OpCode::Pop,
+            ],
+            vec![SquatValue::Int(0), SquatValue::Int(3), SquatValue::Int(1)],
+            1,
+        );
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(3)));
+    }
+
+    #[test]
+    fn coverage_reports_branch_that_never_ran() {
+        let mut vm = VM::new();
+        let options = Options {
+            coverage: true,
+            ..Default::default()
+        };
+        let result = vm.interpret_source(
+            "
+            int result = 0;
+
+            func main() {
+                if (false) {
+                    result = 1;
+                }
+                result = 2;
+            }
+            "
+            .to_owned(),
+            &options,
+        );
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.uncovered_lines(), vec![6]);
+    }
+
+    #[test]
+    fn set_exit_code_is_returned_after_main() {
+        let (_, result) = run("
+            func main() {
+                set_exit_code(3);
+                println(\"still running\");
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(3));
+    }
+
+    #[test]
+    fn block_expression_value_is_assigned() {
+        let (vm, result) = run("
+            int doubled = 0;
+            int total = 0;
+
+            func compute() int {
+                return 21;
+            }
+
+            func main() {
+                int before = 1;
+                int x = {
+                    int t = compute();
+                    int unused = 5;
+                    t * 2
+                };
+                int after = 3;
+                doubled = x;
+                int sum = { int y = x + after; y } + before;
+                total = sum + after;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(42)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(49)));
+    }
+
+    #[test]
+    fn return_from_main_is_the_exit_code() {
+        let (_, result) = run("
+            func main() {
+                int a = 2;
+                if (a > 1) {
+                    int b = 3;
+                    return b + a;
+                }
+                return 1;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(5));
+    }
+
+    #[test]
+    fn for_each_pulls_range_elements() {
+        let (vm, result) = run("
+            int sum = 0;
+            var numbers = range(0, 1000000000000);
+
+            func main() {
+                for (int i : range(0, 5)) {
+                    sum = sum + i;
+                }
+                for (var i : range(3, 1)) {
+                    sum = sum + 100;
+                }
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(10)));
+        // The range only keeps its bounds, no array is allocated for it
+        assert_eq!(
+            vm.globals[1],
+            Some(SquatValue::Object(SquatObject::Range(SquatRange::new(
+                0,
+                1000000000000
+            ))))
+        );
+    }
+
+    #[test]
+    fn for_each_iterates_arrays() {
+        let (vm, result) = run("
+            string joined = \"\";
+
+            func main() {
+                for (var name : reverse(param_types(to_str))) {
+                    joined = joined + to_str(name);
+                }
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::String("<type Any>".into())));
+    }
+
+    #[test]
+    fn omitted_fields_take_their_defaults() {
+        let (vm, result) = run("
+            struct Counter {
+                string name;
+                int count = 0;
+                float scale = 1.5;
+            }
+
+            int count = -1;
+            float scale = 0.;
+            int given_count = -1;
+
+            func main() {
+                Counter counter = Counter(\"clicks\");
+                count = counter.count;
+                scale = counter.scale;
+                given_count = Counter(\"views\", 7).count;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(0)));
+        assert_eq!(vm.globals[2], Some(SquatValue::Float(1.5)));
+        assert_eq!(vm.globals[3], Some(SquatValue::Int(7)));
+    }
+
+    #[test]
+    fn chained_assignment_sets_every_target() {
+        let (vm, result) = run("
+            int a = 0;
+            int b = 0;
+            int c = 0;
+
+            func main() {
+                int local = 1;
+                a = local = b = 5;
+                c = local + 1;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(5)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(5)));
+        assert_eq!(vm.globals[2], Some(SquatValue::Int(6)));
+    }
+
+    #[test]
+    fn nested_ternaries_are_right_associative() {
+        let (vm, result) = run("
+            int inner = 0;
+            int outer = 0;
+            int first = 0;
+
+            func main() {
+                bool t = true;
+                bool f = false;
+                inner = t ? f ? 1 : 2 : 3;
+                outer = f ? 1 : f ? 2 : 3;
+                first = t ? 1 : t ? 2 : 3;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(2)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(3)));
+        assert_eq!(vm.globals[2], Some(SquatValue::Int(1)));
+    }
+
+    /// Tight loop for measuring dispatch speed, run it with
+    /// `cargo test --release dispatch_throughput -- --nocapture`. Reordering
+    /// the arms of the dispatch match by hotness made no measurable
+    /// difference, rustc already compiles the match into a jump table.
+    #[test]
+    fn dispatch_throughput() {
+        let start = std::time::Instant::now();
+        let (vm, result) = run("
+            int total = 0;
+
+            func add(int a, int b) int {
+                return a + b;
+            }
+
+            func main() {
+                int i = 0;
+                while (i < 20000) {
+                    total = add(total, i % 7);
+                    i = i + 1;
+                }
+            }
+        ");
+        let elapsed = start.elapsed();
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(59997)));
+        println!(
+            "{} instructions in {:?}, {:.0} instructions per second",
+            vm.executed_instructions,
+            elapsed,
+            vm.executed_instructions as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn sort_by_key_calls_squat_functions() {
+        let (mut vm, result) = run("
+            struct Player {
+                string name;
+                int score;
+            }
+
+            func score_of(Player player) int {
+                return player.score;
+            }
+
+            func main() {
+                int _score = score_of(Player(\"nobody\", 0));
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        let key_function = vm
+            .globals
+            .iter()
+            .flatten()
+            .find(|value| {
+                matches!(value, SquatValue::Object(SquatObject::Function(func)) if func.name == "score_of")
+            })
+            .unwrap()
+            .clone();
+        let player = |name: &str, score: i64| {
+            SquatValue::Object(SquatObject::Instance(SquatInstance::new(
+                "Player",
+                vec![SquatValue::String(name.into()), SquatValue::Int(score)],
+            )))
+        };
+
+        let sorted = native::array::sort_by_key(
+            vec![
+                SquatValue::Object(SquatObject::Array(
+                    vec![
+                        player("b", 20),
+                        player("a", 10),
+                        player("c", 30),
+                        player("d", 10),
+                    ]
+                    .into(),
+                )),
+                key_function,
+            ],
+            &mut |function, args| vm.call_value(function, args, &Options::default()),
+        );
+        assert_eq!(
+            sorted,
+            Ok(SquatValue::Object(SquatObject::Array(
+                vec![
+                    player("a", 10),
+                    player("d", 10),
+                    player("b", 20),
+                    player("c", 30),
+                ]
+                .into()
+            )))
+        );
+    }
+
+    #[test]
+    fn callbacks_are_given_arguments_of_their_parameter_types() {
+        let (_, result) = run("
+            func key(int x) int {
+                return x * 2;
+            }
+            func main() {
+                any sorted = sort_by_key(chars(\"ba\"), key);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretRuntimeError);
+
+        let (_, result) = run("
+            func main() {
+                any sorted = sort_by_key(chars(\"ba\"), 3);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretCompileError);
+    }
+
+    #[test]
+    fn trace_names_functions_called_through_variables() {
+        let (vm, result) = run("
+            func fail(int i) int {
+                string s = \"ab\";
+                string _c = s[i];
+                return 0;
+            }
+
+            func main() {
+                func(int) int f = fail;
+                int _x = f(5);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretRuntimeError);
+        let trace = vm.call_trace();
+        assert_eq!(trace.len(), 2);
+        assert!(trace[0].starts_with("function 'fail' called at"));
+        assert!(trace[1].starts_with("function 'main' called at"));
+    }
+
+    #[test]
+    fn unnamed_functions_are_anonymous() {
+        let function =
+            SquatFunction::new("", 0, SquatFunctionTypeData::new(vec![], SquatType::Nil));
+        assert_eq!(function.name, "<anonymous>");
+    }
+
+    /// The compiler only resolves globals after their declaration, so this
+    /// ordering has to be built by hand
+    #[test]
+    fn reading_a_global_before_its_definition_is_an_error() {
+        let mut chunk = Chunk::new("Test", true);
+        chunk.write(OpCode::Start, 1);
+        chunk.write(OpCode::GetGlobal(0), 1);
+        chunk.write(OpCode::Pop, 1);
+        chunk.write(OpCode::Constant(0), 2);
+        chunk.write(OpCode::DefineGlobal(0), 2);
+        chunk.write(OpCode::Stop, 2);
+        chunk.write(OpCode::JumpTo(1), 2);
+        let mut constants = ValueArray::new("Constants");
+        constants.write(SquatValue::Int(1));
+
+        let mut vm = VM::new();
+        let program = CompiledProgram::new(chunk, constants, 1);
+        assert!(
+            vm.execute(&program, &Options::default()) == InterpretResult::InterpretRuntimeError
+        );
+        assert_eq!(vm.globals[0], None);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn type_values_compare_exactly() {
+        let (vm, result) = run("
+            any same_type = false;
+            any int_is_float = true;
+            any int_is_not_float = false;
+            any any_is_int = true;
+
+            func main() {
+                same_type = type(1) == type(2);
+                int_is_float = type(1) == type(1.0);
+                int_is_not_float = type(1) != type(1.0);
+                var types = param_types(to_str);
+                any_is_int = types[0] == type(1);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Bool(true)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Bool(false)));
+        assert_eq!(vm.globals[2], Some(SquatValue::Bool(true)));
+        assert_eq!(vm.globals[3], Some(SquatValue::Bool(false)));
+    }
+
+    #[test]
+    fn assert_that_stops_only_when_false() {
+        let (_, result) = run("
+            func main() {
+                int x = 3;
+                assert_that(x == 3, \"x is {}\", x);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+
+        let (_, result) = run("
+            func main() {
+                int x = 3;
+                assert_that(x > 5, \"x was {} and not {}\", x, \"bigger\");
+            }
+        ");
+        assert!(result == InterpretResult::InterpretRuntimeError);
+    }
+
+    #[test]
+    fn local_increments_add_in_place() {
+        let (vm, result) = run("
+            int total = 0;
+            float scaled = 0.0;
+
+            func main() {
+                for (int i = 0; i < 10; i = i + 3) {
+                    total = total + i;
+                }
+                int down = 5;
+                down = down + -7;
+                total = total + down;
+                float f = 1.5;
+                f = f + 1.0;
+                scaled = f;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(16)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Float(2.5)));
+    }
+
+    /// Passing a string around only bumps its reference count, the string
+    /// that comes back out of the calls is the very same allocation
+    #[test]
+    fn strings_are_shared_between_calls() {
+        let (vm, result) = run("
+            string original = \"x\";
+            string passed = \"\";
+
+            func pass(string value, int depth) string {
+                if (depth == 0) {
+                    return value;
+                }
+                string copy = value;
+                return pass(copy, depth - 1);
+            }
+
+            func main() {
+                for (int i = 0; i < 16; i = i + 1) {
+                    original = original + original;
+                }
+                passed = pass(original, 500);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        let (Some(SquatValue::String(original)), Some(SquatValue::String(passed))) =
+            (&vm.globals[0], &vm.globals[1])
+        else {
+            panic!("Expected two strings");
+        };
+        assert_eq!(original.len(), 65536);
+        assert!(std::rc::Rc::ptr_eq(original, passed));
+    }
+
+    #[test]
+    fn instances_convert_to_maps() {
+        let (vm, result) = run("
+            struct Point {
+                int x;
+                string label = \"origin\";
+            }
+
+            int x = 0;
+            string label = \"\";
+
+            func main() {
+                Point point = Point(3);
+                var fields = to_map(point);
+                x = fields[\"x\"];
+                label = fields[\"label\"];
+                println(fields[\"missing\"]);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretRuntimeError);
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(3)));
+        assert_eq!(vm.globals[2], Some(SquatValue::String("origin".into())));
+    }
+
+    #[test]
+    fn hex_float_literals() {
+        let (vm, result) = run("
+            float twelve = 0.0;
+            float quarter = 0.0;
+
+            func main() {
+                twelve = 0x1.8p3;
+                quarter = 0X1P-2 + 0x0p0;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Float(12.0)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Float(0.25)));
+    }
+
+    #[test]
+    fn functions_are_called_by_name() {
+        let source = "
+            int calls = 0;
+            func add(int a, int b) int { calls = calls + 1; return a + b; }
+            func fail(int i) string { return \"ab\"[i]; }
+            func main() { println(\"main ran\"); }
+        "
+        .to_owned();
+        let opts = Options::default();
+        let mut vm = VM::new();
+        vm.keep_unused_functions();
+        let program = vm.compile(&source, &opts).unwrap();
+        assert!(vm.load(&program, &opts) == InterpretResult::InterpretOk(0));
+
+        assert_eq!(
+            vm.call_function("add", vec![SquatValue::Int(2), SquatValue::Int(3)]),
+            Ok(SquatValue::Int(5))
+        );
+        assert_eq!(
+            vm.call_function("add", vec![SquatValue::Int(4), SquatValue::Int(4)]),
+            Ok(SquatValue::Int(8))
+        );
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(2)));
+        assert_eq!(
+            vm.call_function("sub", vec![]),
+            Err("Function 'sub' is not defined".to_owned())
+        );
+        assert!(vm.call_function("add", vec![SquatValue::Int(1)]).is_err());
+        assert_eq!(
+            vm.call_function("add", vec![SquatValue::Bool(true), SquatValue::Int(1)]),
+            Err("Argument 1 of 'add': expected <type Int> but found <type Bool>".to_owned())
+        );
+        assert!(vm.call_function("fail", vec![SquatValue::Int(5)]).is_err());
+        assert_eq!(
+            vm.call_function("add", vec![SquatValue::Int(1), SquatValue::Int(1)]),
+            Ok(SquatValue::Int(2))
+        );
+    }
+
+    /// Natives keep their state on the VM that calls them, loading another
+    /// program in another VM leaves it alone
+    #[test]
+    fn every_vm_has_its_own_native_state() {
+        let source = "
+            func show(float value) string { return to_str(value); }
+            func main() {}
+        "
+        .to_owned();
+        let precise_opts = Options {
+            float_precision: Some(2),
+            ..Default::default()
+        };
+        let mut precise_vm = VM::new();
+        precise_vm.keep_unused_functions();
+        let program = precise_vm.compile(&source, &precise_opts).unwrap();
+        assert!(precise_vm.load(&program, &precise_opts) == InterpretResult::InterpretOk(0));
+
+        let opts = Options::default();
+        let mut vm = VM::new();
+        vm.keep_unused_functions();
+        let program = vm.compile(&source, &opts).unwrap();
+        assert!(vm.load(&program, &opts) == InterpretResult::InterpretOk(0));
+
+        let show = |vm: &mut VM| vm.call_function("show", vec![SquatValue::Float(0.5)]);
+        assert_eq!(show(&mut precise_vm), Ok(SquatValue::String("0.50".into())));
+        assert_eq!(show(&mut vm), Ok(SquatValue::String("0.5".into())));
+    }
+
+    #[test]
+    fn unused_functions_are_only_callable_when_kept() {
+        let source = "func add(int a, int b) int { return a + b; } func main() {}".to_owned();
+        let opts = Options::default();
+        let mut vm = VM::new();
+        let program = vm.compile(&source, &opts).unwrap();
+        assert!(vm.load(&program, &opts) == InterpretResult::InterpretOk(0));
+        assert_eq!(
+            vm.call_function("add", vec![SquatValue::Int(2), SquatValue::Int(3)]),
+            Err("Function 'add' is not defined".to_owned())
+        );
+    }
+
+    #[test]
+    fn instances_are_created_from_structs() {
+        let (vm, result) = run("
+            struct Point { int x; int y; }
+            int x = 0;
+            int y = 0;
+            func main() {
+                Point p = Point(3, 4);
+                x = p.x;
+                y = p.y;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(3)));
+        assert_eq!(vm.globals[2], Some(SquatValue::Int(4)));
+    }
+
+    /// The compiler checks both of these, a hand built chunk gets past it
+    #[test]
+    fn creating_an_instance_is_validated() {
+        let create_instance = |struct_value: SquatValue, arg_count: usize| {
+            let mut chunk = Chunk::new("Test", true);
+            chunk.write(OpCode::Start, 1);
+            chunk.write(OpCode::Constant(0), 1);
+            chunk.write(OpCode::Constant(1), 1);
+            chunk.write(OpCode::CreateInstance(arg_count), 1);
+            chunk.write(OpCode::Stop, 1);
+            chunk.write(OpCode::JumpTo(1), 1);
+            let mut constants = ValueArray::new("Constants");
+            constants.write(struct_value);
+            constants.write(SquatValue::Int(1));
+
+            let mut vm = VM::new();
+            let program = CompiledProgram::new(chunk, constants, 0);
+            vm.execute(&program, &Options::default())
+        };
+        let point = SquatValue::Object(SquatObject::Struct(SquatStruct::new(
+            "Point",
+            vec!["x".to_owned()],
+        )));
+
+        assert!(create_instance(point.clone(), 1) == InterpretResult::InterpretOk(0));
+        assert!(create_instance(point.clone(), 0) == InterpretResult::InterpretRuntimeError);
+        assert!(create_instance(point, 2) == InterpretResult::InterpretRuntimeError);
+        assert!(create_instance(SquatValue::Int(2), 1) == InterpretResult::InterpretRuntimeError);
+    }
+
+    #[test]
+    fn memoized_functions_run_once_per_argument() {
+        let source = "
+            int calls = 0;
+            ANNOTATION
+            func fib(int n) int {
+                calls = calls + 1;
+                if (n < 2) {
+                    return n;
+                }
+                return fib(n - 1) + fib(n - 2);
+            }
+            int result = 0;
+            func main() {
+                result = fib(20);
+                result = fib(20);
+            }
+        ";
+        let (vm, result) = run(&source.replace("ANNOTATION", "@memoize"));
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[2], Some(SquatValue::Int(6765)));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(21)));
+
+        let (vm, result) = run(&source.replace("ANNOTATION", ""));
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[2], Some(SquatValue::Int(6765)));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(2 * 21891)));
+    }
+
+    /// Natives can only call back into functions, but the host can compile
+    /// while a program is loaded
+    #[test]
+    fn compiling_leaves_the_loaded_program_alone() {
+        let opts = Options::default();
+        let mut vm = VM::new();
+        vm.keep_unused_functions();
+        let source = "func add(int a, int b) int { return a + b; } func main() {}".to_owned();
+        let program = vm.compile(&source, &opts).unwrap();
+        assert!(vm.load(&program, &opts) == InterpretResult::InterpretOk(0));
+
+        let other_source = "
+            func sub(int a, int b) int { return a - b; }
+            func main() { sub(1, 2); }
+        "
+        .to_owned();
+        let other_program = vm.compile(&other_source, &opts).unwrap();
+        assert_eq!(
+            vm.call_function("add", vec![SquatValue::Int(2), SquatValue::Int(3)]),
+            Ok(SquatValue::Int(5))
+        );
+
+        assert!(vm.execute(&other_program, &opts) == InterpretResult::InterpretOk(0));
+        assert!(vm.call_function("add", vec![]).is_err());
+        assert!(vm.load(&program, &opts) == InterpretResult::InterpretOk(0));
+        assert_eq!(
+            vm.call_function("add", vec![SquatValue::Int(2), SquatValue::Int(3)]),
+            Ok(SquatValue::Int(5))
+        );
+    }
+
+    #[test]
+    fn nan_and_infinity_literals() {
+        let (vm, result) = run("
+            float positive = inf;
+            float negative = -inf;
+            bool above = false;
+            bool below = false;
+            bool nan_equal = true;
+            bool nan_unequal = false;
+            func main() {
+                above = inf > 1000000000000000000000.0;
+                below = -inf < -1000000000000000000000.0;
+                float not_a_number = nan;
+                nan_equal = not_a_number == not_a_number;
+                nan_unequal = nan != nan;
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Float(f64::INFINITY)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Float(f64::NEG_INFINITY)));
+        for global in 2..6 {
+            let expected = global != 4;
+            assert_eq!(vm.globals[global], Some(SquatValue::Bool(expected)));
+        }
+    }
+
+    #[test]
+    fn print_takes_any_number_of_values() {
+        let (_, result) = run("
+            func main() {
+                println();
+                println(1);
+                println(1, 2.5, \"three\");
+                print();
+                print(1, 2, 3);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+    }
+
+    #[test]
+    fn same_seed_shuffles_the_same_way() {
+        let shuffled = |seed: u64| {
+            let source = "
+                any shuffled = nil;
+                func main() {
+                    shuffled = shuffle(chars(\"abcdefghijklmnop\"));
+                }
+            "
+            .to_owned();
+            let opts = Options {
+                seed,
+                ..Options::default()
+            };
+            let mut vm = VM::new();
+            assert!(vm.interpret_source(source, &opts) == InterpretResult::InterpretOk(0));
+            vm.globals[0].clone().unwrap()
+        };
+        assert_eq!(shuffled(42), shuffled(42));
+        assert_ne!(shuffled(42), shuffled(43));
+    }
+
+    #[test]
+    fn string_builders_collect_many_pieces() {
+        let (vm, result) = run("
+            string built = \"\";
+            func main() {
+                var builder = string_builder();
+                for (int i : range(0, 1000)) {
+                    append(builder, i % 10);
+                }
+                built = build(builder);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        let expected = "0123456789".repeat(100);
+        assert_eq!(vm.globals[0], Some(SquatValue::String(expected.into())));
+    }
+
+    #[test]
+    fn statements_start_with_only_locals_on_the_stack() {
+        let verify = Options {
+            verify_stack: true,
+            ..Options::default()
+        };
+        let source = "
+            int total = 0;
+            int limit = 10;
+            func add(int a, int b) int {
+                defer { total = total + 0; }
+                return a + b;
+            }
+            func main() {
+                int i = 0;
+                while (i < limit) {
+                    int doubled = i * 2;
+                    total = add(total, doubled);
+                    i = i + 1;
+                }
+                for (int j : range(0, 3)) {
+                    total = total + j;
+                }
+                int k = 0;
+                while (k < limit) {
+                    int next = k + 1;
+                    k = next;
+                }
+            }
+        "
+        .to_owned();
+        let mut vm = VM::new();
+        assert!(vm.interpret_source(source, &verify) == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(93)));
+
+        // The constant of the first statement is never popped
+        let mut chunk = Chunk::new("Test", true);
+        chunk.write(OpCode::Start, 1);
+        chunk.mark_statement(0);
+        chunk.write(OpCode::Constant(0), 1);
+        chunk.mark_statement(0);
+        chunk.write(OpCode::Nil, 2);
+        chunk.write(OpCode::Pop, 2);
+        chunk.write(OpCode::Stop, 2);
+        chunk.write(OpCode::JumpTo(1), 2);
+        let mut constants = ValueArray::new("Constants");
+        constants.write(SquatValue::Int(1));
+        let program = CompiledProgram::new(chunk, constants, 0);
+
+        let mut vm = VM::new();
+        assert!(vm.execute(&program, &Options::default()) == InterpretResult::InterpretOk(0));
+        assert!(vm.execute(&program, &verify) == InterpretResult::InterpretRuntimeError);
+    }
+
+    #[test]
+    fn functions_are_declared_inside_main() {
+        let (vm, result) = run("
+            int result = 0;
+            func main() {
+                int base = 10;
+                func add(int a, int b) int {
+                    int sum = a + b;
+                    return sum;
+                }
+                result = add(base, add(1, 2));
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(13)));
+    }
+
+    #[test]
+    fn rewound_programs_run_the_same_again() {
+        let source = "
+            int total = 0;
+            int runs = 0;
+            func main() {
+                runs = runs + 1;
+                for (int i = 1; i <= 4; i = i + 1) {
+                    total = total + i * runs;
+                }
+            }
+        ";
+        let (mut vm, result) = run(source);
+        assert!(result == InterpretResult::InterpretOk(0));
+        let first_run = vm.globals.clone();
+        assert_eq!(first_run[0], Some(SquatValue::Int(10)));
+
+        vm.rewind(&Options::default());
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.call_stack.len(), 1);
+        assert!(vm.globals.iter().all(Option::is_none));
+        assert!(vm.run(&Options::default()) == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals, first_run);
+    }
+
+    #[test]
+    fn lambdas_are_passed_and_called() {
+        let (vm, result) = run("
+            any sorted = nil;
+            int doubled = 0;
+            func main() {
+                sorted = sort_by_key(chars(\"cab\"), func(string c) string { return c; });
+                func(int) int twice = func(int x) int { return x * 2; };
+                doubled = twice(21);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(
+            vm.globals[0].as_ref().unwrap().to_string(),
+            r#"["a", "b", "c"]"#
+        );
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(42)));
+    }
+
+    #[test]
+    fn lambdas_capture_enclosing_locals() {
+        let (vm, result) = run("
+            any sorted = nil;
+            int before = 0;
+            int after = 0;
+            func adder(int amount) func(int) int {
+                return func(int x) int {
+                    func(int) int inner = func(int y) int { return y + amount; };
+                    return inner(x);
+                };
+            }
+            func main() {
+                string prefix = \"z\";
+                sorted = sort_by_key(chars(\"cab\"), func(string c) string { return prefix + c; });
+                int offset = 10;
+                func(int) int shifted = func(int x) int { return x + offset; };
+                before = shifted(1) + adder(100)(1);
+                offset = 20;
+                after = shifted(1);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(
+            vm.globals[0].as_ref().unwrap().to_string(),
+            r#"["a", "b", "c"]"#
+        );
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(112)));
+        // Captured when the lambda was made
+        assert_eq!(vm.globals[2], Some(SquatValue::Int(11)));
+    }
+
+    #[test]
+    fn local_functions_call_themselves() {
+        let (vm, result) = run("
+            int result = 0;
+            func main() {
+                int base = 1;
+                func factorial(int n) int {
+                    if (n < 2) {
+                        return base;
+                    }
+                    func(int) int next = func(int m) int { return factorial(m); };
+                    return n * next(n - 1);
+                }
+                result = factorial(5);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(120)));
+    }
+
+    #[test]
+    fn globals_are_sized_for_each_program() {
+        let mut vm = VM::new();
+        let opts = Options::default();
+        let mut run_program = |source: &str| {
+            let program = vm.compile(&source.to_owned(), &opts).unwrap();
+            assert!(vm.execute(&program, &opts) == InterpretResult::InterpretOk(0));
+            assert_eq!(vm.globals.len(), program.global_count);
+            vm.globals[0].clone()
+        };
+        assert_eq!(
+            run_program("int a = 1; int b = 2; int c = 3; func main() {}"),
+            Some(SquatValue::Int(1))
+        );
+        assert_eq!(
+            run_program("string d = \"d\"; func main() {}"),
+            Some(SquatValue::String("d".into()))
+        );
+        assert_eq!(
+            run_program("int e = 5; int f = 6; int g = 7; int h = 8; func main() {}"),
+            Some(SquatValue::Int(5))
+        );
+    }
+
+    #[test]
+    fn abs_is_compiled_to_its_op_code() {
+        let source = "
+            int i = 0;
+            float f = 0.0;
+            func main() {
+                int x = -7;
+                i = abs(x);
+                f = abs(-2.5);
+            }
+        "
+        .to_owned();
+        let mut vm = VM::new();
+        let opts = Options::default();
+        let program = vm.compile(&source, &opts).unwrap();
+        let code: Vec<OpCode> = (0..program.chunk.get_size())
+            .map(|op_index| program.chunk.get_instruction(op_index))
+            .collect();
+        assert_eq!(code.iter().filter(|op| **op == OpCode::Abs).count(), 2);
+        assert!(!code.iter().any(|op| matches!(op, OpCode::Call(_))));
+
+        assert!(vm.execute(&program, &opts) == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(7)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Float(2.5)));
+
+        let (_, result) = run("any s = \"-1\"; func main() { any a = abs(s); }");
+        assert!(result == InterpretResult::InterpretRuntimeError);
+    }
+
+    #[test]
+    fn defined_finds_globals_by_name() {
+        let (vm, result) = run("
+            int count = 0;
+            func helper() {}
+            bool results = false;
+            func main() {
+                results = defined(\"main\") and defined(\"count\") and defined(\"helper\")
+                    and !defined(\"nope\") and !defined(\"println\");
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[2], Some(SquatValue::Bool(true)));
+    }
+
+    #[test]
+    fn break_and_continue_pop_the_locals_of_the_body() {
+        let verify = Options {
+            verify_stack: true,
+            ..Options::default()
+        };
+        // The globals read in the loops are hoisted into hidden locals
+        let source = "
+            int total = 0;
+            int limit = 10;
+            int steps = 0;
+            func main() {
+                int before = 1;
+                for (int i = 0; i < limit; i = i + 1) {
+                    int doubled = i * 2;
+                    if (i == 2) {
+                        int skipped = doubled;
+                        continue;
+                    }
+                    if (doubled > 10) {
+                        int extra = before;
+                        break;
+                    }
+                    total = total + doubled;
+                }
+                while (true) {
+                    int step = steps + 1;
+                    steps = step;
+                    for (int c : range(0, limit)) {
+                        int square = c * c;
+                        if (square > steps) {
+                            break;
+                        }
+                    }
+                    if (step < 5) {
+                        continue;
+                    }
+                    break;
+                }
+                total = total + before;
+            }
+        "
+        .to_owned();
+        let mut vm = VM::new();
+        assert!(vm.interpret_source(source, &verify) == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Int(27)));
+        assert_eq!(vm.globals[2], Some(SquatValue::Int(5)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn format_fills_the_placeholders() {
+        let (vm, result) = run("
+            string text = \"\";
+            func main() {
+                string name = \"squat\";
+                text = format(\"{} is {} years old\", name, 3);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(
+            vm.globals[0],
+            Some(SquatValue::String("squat is 3 years old".into()))
+        );
+    }
+
+    #[test]
+    fn literal_format_strings_are_checked_when_compiling() {
+        let (_, result) = run("
+            func main() {
+                string text = format(\"{} and {}\", 1);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretCompileError);
+        let (_, result) = run("
+            func main() {
+                assert_that(true, \"{}\");
+            }
+        ");
+        assert!(result == InterpretResult::InterpretCompileError);
+        // Only known when running
+        let (_, result) = run("
+            func main() {
+                string pattern = \"{} and {}\";
+                string text = format(pattern, 1);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretRuntimeError);
+    }
+
+    #[test]
+    fn arguments_that_fail_to_compile_are_compile_errors() {
+        for source in [
+            "func f(int x) {} func main() { f(m); }",
+            "func main() { println(m); }",
+            "func main() { println(9223372036854775808); }",
+        ] {
+            let (_, result) = run(source);
+            assert!(result == InterpretResult::InterpretCompileError);
+        }
+    }
+
+    #[test]
+    fn profile_samples_are_written_as_folded_stacks() {
+        let path = std::env::temp_dir().join("squat_profile_out_test.folded");
+        let options = Options {
+            profile_out: path.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let mut vm = VM::new();
+        let result = vm.interpret_source(
+            "
+            int total = 0;
+            func inner(int n) {
+                for (int i = 0; i < n; i = i + 1) {
+                    total = total + i;
+                }
+            }
+            func outer() {
+                inner(200);
+            }
+            func main() {
+                for (int i = 0; i < 20; i = i + 1) {
+                    outer();
+                }
+            }
+            "
+            .to_owned(),
+            &options,
+        );
+        assert!(result == InterpretResult::InterpretOk(0));
+
+        let folded = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let samples: HashMap<&str, usize> = folded
+            .lines()
+            .map(|line| {
+                let (stack, count) = line.rsplit_once(' ').unwrap();
+                (stack, count.parse().unwrap())
+            })
+            .collect();
+        assert!(samples.keys().all(|stack| stack.starts_with("main")));
+        let inner = samples["main;outer;inner"];
+        // Nearly all of the instructions run in the loop of inner
+        let sample_count: usize = samples.values().sum();
+        assert!(sample_count >= vm.executed_instructions / PROFILE_SAMPLE_INTERVAL);
+        assert!(inner * 10 > sample_count * 9);
+    }
+
+    #[test]
+    fn runtime_errors_are_caught_in_try_blocks() {
+        let verify = Options {
+            verify_stack: true,
+            ..Options::default()
+        };
+        let source = "
+            string message = \"\";
+            int after = 0;
+            int zero = 0;
+            func safe_divide(int a, int b) int {
+                try {
+                    return a / b;
+                } catch (_error) {
+                    return 0;
+                }
+            }
+            func main() {
+                int before = 1;
+                try {
+                    int doubled = before * 2;
+                    after = doubled / zero;
+                    after = -1;
+                } catch (error) {
+                    message = error;
+                }
+                for (int i = 0; i < 3; i = i + 1) {
+                    try {
+                        if (i == 1) {
+                            break;
+                        }
+                    } catch (_error) {}
+                }
+                after = before + safe_divide(6, 3) + safe_divide(1, zero);
+            }
+        "
+        .to_owned();
+        let mut vm = VM::new();
+        assert!(vm.interpret_source(source, &verify) == InterpretResult::InterpretOk(0));
+        assert_eq!(
+            vm.globals[0],
+            Some(SquatValue::String("Division by zero".into()))
+        );
+        assert_eq!(vm.globals[1], Some(SquatValue::Int(3)));
+        assert!(vm.stack.is_empty());
+        assert!(vm.catch_handlers.is_empty());
+    }
+
+    #[test]
+    fn thrown_values_are_caught_as_strings() {
+        let (vm, result) = run("
+            string message = \"\";
+            func check_port(int port) int {
+                if (port > 65535) {
+                    throw \"Port \" + to_str(port) + \" is out of range\";
+                }
+                return port;
+            }
+            func main() {
+                try {
+                    int port = check_port(70000);
+                } catch (error) {
+                    message = error;
+                }
+                try {
+                    throw 42;
+                } catch (error) {
+                    message = message + \", \" + error;
+                }
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(
+            vm.globals[0],
+            Some(SquatValue::String("Port 70000 is out of range, 42".into()))
+        );
+
+        let (_, result) = run("
+            func main() {
+                throw \"Nobody catches this\";
+            }
+        ");
+        assert!(result == InterpretResult::InterpretRuntimeError);
+    }
+
+    #[test]
+    fn finally_blocks_run_on_every_exit() {
+        let (vm, result) = run("
+            string log = \"\";
+            func read(int divisor) int {
+                try {
+                    return 10 / divisor;
+                } catch (error) {
+                    log = log + error + \", \";
+                } finally {
+                    log = log + \"closed \" + to_str(divisor) + \", \";
+                }
+                return 0;
+            }
+            func main() {
+                try {
+                    log = log + \"ok, \";
+                } catch (error) {
+                    log = log + \"never, \";
+                } finally {
+                    log = log + \"done, \";
+                }
+                int total = read(5) + read(0);
+                try {
+                    try {
+                        throw \"first\";
+                    } catch (error) {
+                        throw \"second\";
+                    } finally {
+                        log = log + \"inner, \";
+                    }
+                } catch (error) {
+                    log = log + error;
+                }
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(
+            vm.globals[0],
+            Some(SquatValue::String(
+                "ok, done, closed 5, Division by zero, closed 0, inner, second".into()
+            ))
+        );
+        assert!(vm.stack.is_empty());
+        assert!(vm.catch_handlers.is_empty());
+    }
+
+    #[test]
+    fn holding_more_objects_than_the_max_is_an_error() {
+        let (vm, result) = run("
+            int before = 0;
+            int during = 0;
+            int after = 0;
+            int shared = 0;
+            func hold() {
+                any letters = chars(\"abcd\");
+                any copy = letters;
+                during = mem_usage();
+            }
+            func main() {
+                before = mem_usage();
+                hold();
+                after = mem_usage();
+                any builder = string_builder();
+                any same = builder;
+                shared = mem_usage();
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        let usage: Vec<i64> = vm.globals[0..4]
+            .iter()
+            .map(|usage| match usage {
+                Some(SquatValue::Int(usage)) => *usage,
+                _ => panic!("Expected the usage as ints"),
+            })
+            .collect();
+        // The array and its copy, dropped when the function returns
+        assert_eq!(usage[1] - usage[0], 2);
+        assert_eq!(usage[2], usage[0]);
+        // Copies of a builder share it
+        assert_eq!(usage[3] - usage[2], 1);
+
+        let mut vm = VM::new();
+        let options = Options {
+            max_objects: 100,
+            ..Default::default()
+        };
+        let result = vm.interpret_source(
+            "
+            func hold(int depth) int {
+                any letters = chars(\"ab\");
+                return hold(depth + 1) + size(letters);
+            }
+            func main() {
+                int total = hold(0);
+            }
+            "
+            .to_owned(),
+            &options,
+        );
+        assert!(result == InterpretResult::InterpretRuntimeError);
+    }
+
+    #[test]
+    fn any_is_called_like_other_natives() {
+        let (vm, result) = run("
+            bool some = false;
+            bool none = true;
+            func main() {
+                some = any(chars(\"ab\"));
+                none = any(slice(chars(\"ab\"), 0, 0));
+            }
+        ");
+        assert!(result == InterpretResult::InterpretOk(0));
+        assert_eq!(vm.globals[0], Some(SquatValue::Bool(true)));
+        assert_eq!(vm.globals[1], Some(SquatValue::Bool(false)));
+
+        // Calling something that is not a function is an error, not a panic
+        let (_, result) = run("
+            func main() {
+                int value = 3(4);
+            }
+        ");
+        assert!(result == InterpretResult::InterpretCompileError);
+    }
 }
@@ -0,0 +1,20 @@
+use std::{fs, process::Command};
+
+/// Debug builds should not print anything that release builds don't, the
+/// expected outputs of the test scripts are generated with release builds
+#[test]
+fn debug_build_matches_expected_output() {
+    let root = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+    let output = Command::new(env!("CARGO_BIN_EXE_squat"))
+        .current_dir(root)
+        .args(["-f", "test_scripts/recursion.squat"])
+        .output()
+        .unwrap();
+    let expected = fs::read_to_string(format!(
+        "{}/test_scripts/expected_output/recursion.out",
+        root
+    ))
+    .unwrap();
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
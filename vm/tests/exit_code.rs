@@ -0,0 +1,36 @@
+use std::{fs, process::Command};
+
+/// Exit code and standard error of running the source as a file
+fn run(name: &str, source: &str) -> (Option<i32>, String) {
+    let file = std::env::temp_dir().join(format!("squat_exit_code_{}.squat", name));
+    fs::write(&file, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_squat"))
+        .args(["-f", file.to_str().unwrap()])
+        .output()
+        .unwrap();
+    fs::remove_file(&file).unwrap();
+    (
+        output.status.code(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+fn exit_code(name: &str, source: &str) -> Option<i32> {
+    run(name, source).0
+}
+
+#[test]
+fn failures_have_their_own_exit_codes() {
+    // 101 is also the exit code of a panic, the message tells them apart
+    let (code, stderr) = run("compile", "func main() { int x = \"x\"; }");
+    assert_eq!(code, Some(101));
+    assert!(stderr.contains("[ERROR] Compilation failed"));
+    assert!(!stderr.contains("panicked"));
+
+    let (code, stderr) = run("runtime", "any s = \"-1\"; func main() { any a = abs(s); }");
+    assert_eq!(code, Some(102));
+    assert!(stderr.contains("[ERROR] Execution failed with a runtime error"));
+
+    assert_eq!(exit_code("normal", "func main() { return 7; }"), Some(7));
+    assert_eq!(exit_code("empty", "func main() {}"), Some(0));
+}
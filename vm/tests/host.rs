@@ -0,0 +1,43 @@
+use squat::{
+    options::Options,
+    value::squat_value::SquatValue,
+    vm::{InterpretResult, VM},
+};
+
+/// Loads the program without running main, ready for the host to call
+fn load(source: &str) -> VM {
+    let opts = Options::default();
+    let mut vm = VM::new();
+    vm.keep_unused_functions();
+    let program = vm.compile(&source.to_owned(), &opts).unwrap();
+    assert!(vm.load(&program, &opts) == InterpretResult::InterpretOk(0));
+    vm
+}
+
+#[test]
+fn host_calls_functions_by_name() {
+    let mut vm = load(
+        "
+        int calls = 0;
+        func add(int a, int b) int {
+            calls = calls + 1;
+            return a + b;
+        }
+        func count() int { return calls; }
+        func main() {}
+        ",
+    );
+    assert_eq!(
+        vm.call_function("add", vec![SquatValue::Int(2), SquatValue::Int(3)]),
+        Ok(SquatValue::Int(5))
+    );
+    // Globals are kept between calls
+    assert_eq!(vm.call_function("count", vec![]), Ok(SquatValue::Int(1)));
+    assert!(vm
+        .call_function("add", vec![SquatValue::Int(2), SquatValue::Bool(true)])
+        .is_err());
+    assert_eq!(
+        vm.call_function("missing", vec![]),
+        Err("Function 'missing' is not defined".to_owned())
+    );
+}
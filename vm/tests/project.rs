@@ -0,0 +1,31 @@
+use std::{fs, process::Command};
+
+#[test]
+fn project_files_are_compiled_together() {
+    let directory = std::env::temp_dir().join("squat_project_test");
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir(&directory).unwrap();
+    // Sorted by name the file with main comes first, the helper still has to
+    // be compiled before it
+    fs::write(
+        directory.join("app.squat"),
+        "func main() {\n    println(greet(\"squat\"));\n}\n",
+    )
+    .unwrap();
+    fs::write(
+        directory.join("helpers.squat"),
+        "func greet(string name) string {\n    return \"hello \" + name;\n}\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_squat"))
+        .args(["-r", directory.to_str().unwrap()])
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .ends_with("hello squat\n"));
+}
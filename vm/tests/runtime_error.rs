@@ -0,0 +1,32 @@
+use std::{fs, process::Command};
+
+/// Standard output of running the source as a file
+fn run(name: &str, source: &str) -> String {
+    let file = std::env::temp_dir().join(format!("squat_runtime_error_{}.squat", name));
+    fs::write(&file, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_squat"))
+        .args(["-f", file.to_str().unwrap()])
+        .output()
+        .unwrap();
+    fs::remove_file(&file).unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn errors_are_reported_at_the_line_of_the_operator() {
+    let stdout = run(
+        "operator_line",
+        "func main() {
+    int zero = 0;
+    int result = 10 /
+        zero
+        + 1;
+}
+",
+    );
+    let error = stdout
+        .lines()
+        .find(|line| line.starts_with("[ERROR]"))
+        .unwrap();
+    assert!(error.ends_with(":3) Division by zero"), "{}", error);
+}